@@ -0,0 +1,181 @@
+use std::fs::OpenOptions;
+use std::net::IpAddr;
+
+use anyhow::{Result, Context};
+use chrono::{DateTime, Local};
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::{Body, Request, StatusCode};
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Instant;
+
+use crate::config::{self, access_log::Format};
+
+/// Lines waiting for the writer, further lines are dropped.
+const QUEUE_SIZE: usize = 10_000;
+
+/// Inserted into response extensions by the request handler
+/// so the access log can report who made the request.
+#[derive(Clone)]
+pub struct AuthenticatedUser(pub String);
+
+/// Entries are written by a background task, requests only queue them.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: Format,
+    sender: Sender<String>,
+}
+
+impl AccessLog {
+    pub fn new(config: &config::AccessLog) -> Result<Self> {
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = match &config.path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open access log {:?}", path))?;
+
+                Box::new(tokio::fs::File::from_std(file))
+            },
+            None => Box::new(io::stdout()),
+        };
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+
+        tokio::spawn(write_lines(writer, receiver));
+
+        Ok(Self {
+            format: config.format,
+            sender,
+        })
+    }
+
+    /// Called once the response body is sent, `bytes` is its size as actually sent.
+    pub fn log(&self, request: &RequestInfo, client_ip: IpAddr, status: StatusCode, user: Option<&str>, bytes: u64) {
+        let status = status.as_u16();
+        let latency_ms = request.started.elapsed().as_millis();
+
+        let line = match self.format {
+            Format::Combined => format!(
+                "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}\n",
+                client_ip,
+                user.map(escape).unwrap_or_else(|| "-".into()),
+                request.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                request.method,
+                escape(&request.target),
+                request.version,
+                status,
+                // Like `%b`, which logs empty bodies as `-`
+                Some(bytes).filter(|&bytes| bytes > 0).map(|bytes| bytes.to_string()).unwrap_or_else(|| "-".into()),
+                request.referer.as_deref().map(escape).unwrap_or_else(|| "-".into()),
+                request.user_agent.as_deref().map(escape).unwrap_or_else(|| "-".into()),
+                latency_ms,
+            ),
+            Format::Json => serde_json::json!({
+                "time": request.time.to_rfc3339(),
+                "client_ip": client_ip,
+                "user": user,
+                "method": request.method,
+                "target": request.target,
+                "version": request.version,
+                "status": status,
+                "bytes": bytes,
+                "referer": request.referer,
+                "user_agent": request.user_agent,
+                "latency_ms": latency_ms as u64,
+            })
+            .to_string() + "\n",
+        };
+
+        match self.sender.try_send(line) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => eprintln!("Access log queue is full, dropping an entry"),
+            Err(TrySendError::Closed(_)) => eprintln!("Access log writer stopped, dropping an entry"),
+        }
+    }
+}
+
+async fn write_lines(mut writer: Box<dyn AsyncWrite + Send + Unpin>, mut receiver: mpsc::Receiver<String>) {
+    while let Some(line) = receiver.recv().await {
+        let written = async {
+            writer.write_all(line.as_bytes()).await?;
+            writer.flush().await
+        };
+
+        if let Err(err) = written.await {
+            eprintln!("Failed to write access log: {}", err);
+        }
+    }
+}
+
+/// The parts of a request needed for logging,
+/// captured before the request is consumed by the proxy.
+pub struct RequestInfo {
+    started: Instant,
+    time: DateTime<Local>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestInfo {
+    pub fn new(request: &Request<Body>) -> Self {
+        let header = |name| request.headers().get(name)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+
+        Self {
+            started: Instant::now(),
+            time: Local::now(),
+            method: request.method().to_string(),
+            target: request.uri().path_and_query()
+                .map(|target| target.to_string())
+                .unwrap_or_else(|| request.uri().to_string()),
+            version: format!("{:?}", request.version()),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{self, Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_are_written_in_the_background() {
+        let path = std::env::temp_dir().join(format!("oauth_gateway-test-{}.log", std::process::id()));
+        let access_log = AccessLog::new(&config::AccessLog {
+            path: Some(path.clone()),
+            format: Format::Combined,
+        }).unwrap();
+        let request = Request::get("/items?page=2").body(Body::empty()).unwrap();
+
+        access_log.log(&RequestInfo::new(&request), "192.0.2.1".parse().unwrap(), StatusCode::OK, Some("alice"), 1234);
+
+        let mut log = String::new();
+
+        for _ in 0..100 {
+            log = std::fs::read_to_string(&path).unwrap_or_default();
+
+            if !log.is_empty() {
+                break;
+            }
+
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(log.starts_with("192.0.2.1 - alice ["), "{}", log);
+        assert!(log.contains("\"GET /items?page=2 HTTP/1.1\" 200 1234 \"-\" \"-\" "), "{}", log);
+    }
+}
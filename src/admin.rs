@@ -0,0 +1,426 @@
+//! Control plane for operators, served on a separate (loopback) listener.
+//! Requests must carry an `X-Gateway-Admin` header, and `Authorization: Bearer <token>` if `token` is set.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::{Result, Context, ensure};
+use hyper::header::{AUTHORIZATION, ORIGIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use openidconnect::AccessToken;
+use ring::constant_time::verify_slices_are_equal;
+use serde_json::json;
+
+use crate::App;
+use crate::auth;
+use crate::config;
+use crate::header::X_GATEWAY_ADMIN;
+
+/// Binds the admin listener, if configured, and serves it in the background.
+pub fn start(app: Arc<App>) -> Result<()> {
+    let config = match &app.config.admin {
+        Some(config) => config.clone(),
+        None => return Ok(()),
+    };
+
+    ensure!(
+        config.listen.ip().is_loopback() || config.allow_remote,
+        "Admin interface must listen on a loopback address unless `allow_remote` is set",
+    );
+
+    let make_service = make_service_fn(move |_| {
+        let app = app.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let app = app.clone();
+
+                async move {
+                    let response = handle(&app, request).await.unwrap_or_else(|err| {
+                        eprintln!("Admin request failed: {:#}", err);
+                        text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}\n", err))
+                    });
+
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&config.listen)
+        .with_context(|| format!("Failed to listen on {}", config.listen))?
+        .serve(make_service);
+
+    println!("Admin interface listening on {}", config.listen);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            eprintln!("Admin server failed: {:#}", err);
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle(app: &App, request: Request<Body>) -> Result<Response<Body>> {
+    if let Some(response) = app.config.admin.as_ref().and_then(|config| reject(config, &request)) {
+        return Ok(response);
+    }
+
+    match (request.method(), request.uri().path()) {
+        (&Method::POST, "/revoke") => revoke(app, request).await,
+        (&Method::POST, "/jti/deny") => deny_jti(app, request).await,
+        (&Method::POST, "/jti/allow") => allow_jti(app, request).await,
+        (&Method::POST, "/jti/reload") => reload_jti_denylist(app).await,
+        (&Method::GET, "/status") => status(app).await,
+        (&Method::GET, "/metrics") => metrics(app).await,
+        (&Method::POST, "/drain") => drain(app).await,
+        (&Method::GET, "/listeners") => list_listeners(app).await,
+        (&Method::POST, "/listeners/start") => start_listener(app, request).await,
+        (&Method::POST, "/listeners/stop") => stop_listener(app, request).await,
+        (&Method::POST, "/maintenance/enable") => set_maintenance(app, request, true).await,
+        (&Method::POST, "/maintenance/disable") => set_maintenance(app, request, false).await,
+        _ => Ok(text_response(StatusCode::NOT_FOUND, "Not found\n")),
+    }
+}
+
+/// Answers requests a browser may have been tricked into sending, e.g. by a malicious page
+/// or DNS rebinding, and requests without the configured token.
+fn reject(config: &config::Admin, request: &Request<Body>) -> Option<Response<Body>> {
+    let headers = request.headers();
+
+    // Browsers send `Origin` with cross-origin and non-GET requests, unlike admin tooling
+    if headers.contains_key(ORIGIN) {
+        return Some(text_response(StatusCode::FORBIDDEN, "Requests from browsers are not allowed\n"));
+    }
+
+    // Forms can't set custom headers, and scripts can't cross-origin without a preflight this server fails
+    if !headers.contains_key(X_GATEWAY_ADMIN) {
+        return Some(text_response(StatusCode::FORBIDDEN, format!("The {} header is required\n", X_GATEWAY_ADMIN)));
+    }
+
+    if let Some(token) = &config.token {
+        let is_authorized = headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|sent| verify_slices_are_equal(sent.as_bytes(), token.as_bytes()).is_ok());
+
+        if !is_authorized {
+            return Some(text_response(StatusCode::UNAUTHORIZED, "A valid admin token is required\n"));
+        }
+    }
+
+    None
+}
+
+/// Revokes the access token sent as the request body.
+async fn revoke(app: &App, request: Request<Body>) -> Result<Response<Body>> {
+    let body = hyper::body::to_bytes(request.into_body()).await
+        .context("Failed to read request body")?;
+    let token = String::from_utf8_lossy(&body).trim().to_owned();
+
+    if token.is_empty() {
+        return Ok(text_response(StatusCode::BAD_REQUEST, "Expected an access token as request body\n"));
+    }
+
+    auth::revoke_access_token(&app.oidc, &app.revoked_tokens, AccessToken::new(token)).await?;
+
+    Ok(text_response(StatusCode::OK, "Revoked\n"))
+}
+
+/// Rejects tokens with the ID sent as the request body from now on.
+async fn deny_jti(app: &App, request: Request<Body>) -> Result<Response<Body>> {
+    let jti = match read_jti(request).await? {
+        Ok(jti) => jti,
+        Err(response) => return Ok(response),
+    };
+
+    app.jti_denylist.insert(jti);
+
+    Ok(text_response(StatusCode::OK, "Denied\n"))
+}
+
+/// Removes the token ID sent as the request body from the denylist.
+async fn allow_jti(app: &App, request: Request<Body>) -> Result<Response<Body>> {
+    let jti = match read_jti(request).await? {
+        Ok(jti) => jti,
+        Err(response) => return Ok(response),
+    };
+
+    if !app.jti_denylist.remove(&jti) {
+        return Ok(text_response(StatusCode::NOT_FOUND, "Not denied\n"));
+    }
+
+    Ok(text_response(StatusCode::OK, "Allowed\n"))
+}
+
+/// Rereads the `jti_denylist` file.
+async fn reload_jti_denylist(app: &App) -> Result<Response<Body>> {
+    let denied = app.jti_denylist.reload()?;
+
+    Ok(text_response(StatusCode::OK, format!("{} token IDs denied\n", denied)))
+}
+
+async fn read_jti(request: Request<Body>) -> Result<Result<String, Response<Body>>> {
+    let body = hyper::body::to_bytes(request.into_body()).await
+        .context("Failed to read request body")?;
+    let jti = String::from_utf8_lossy(&body).trim().to_owned();
+
+    if jti.is_empty() {
+        return Ok(Err(text_response(StatusCode::BAD_REQUEST, "Expected a token ID as request body\n")));
+    }
+
+    Ok(Ok(jti))
+}
+
+/// Exports metrics in the Prometheus text format.
+async fn metrics(app: &App) -> Result<Response<Body>> {
+    let mut metrics = String::new();
+
+    app.introspection_metrics.render(&mut metrics)
+        .and_then(|()| app.tls_manager.render_metrics(&mut metrics))
+        .and_then(|()| render_metrics(app, &mut metrics))
+        .context("Failed to render metrics")?;
+
+    Ok(text_response(StatusCode::OK, metrics))
+}
+
+/// The metrics of the circuit breakers and the response cache, which `/status` reports as well.
+fn render_metrics(app: &App, out: &mut String) -> fmt::Result {
+    // Like in `/status`, servers sharing a name are reported once
+    let circuit_breakers = app.config.servers.iter()
+        .zip(&app.circuit_breakers)
+        .filter_map(|(server, circuit_breaker)| Some((server.name.as_str(), circuit_breaker.as_ref()?.state_name())))
+        .collect::<BTreeMap<_, _>>();
+
+    writeln!(out, "# HELP oauth_gateway_circuit_breaker_state Whether the circuit breaker of each server is in the state.")?;
+    writeln!(out, "# TYPE oauth_gateway_circuit_breaker_state gauge")?;
+
+    for (server, current_state) in circuit_breakers {
+        for state in ["closed", "open", "half_open"] {
+            writeln!(
+                out,
+                "oauth_gateway_circuit_breaker_state{{server={:?},state=\"{}\"}} {}",
+                server,
+                state,
+                (state == current_state) as u8,
+            )?;
+        }
+    }
+
+    if let Some(response_cache) = &app.response_cache {
+        writeln!(out, "# HELP oauth_gateway_response_cache_total Responses served from the cache (hit) or fetched from upstreams (miss).")?;
+        writeln!(out, "# TYPE oauth_gateway_response_cache_total counter")?;
+        writeln!(out, "oauth_gateway_response_cache_total{{result=\"hit\"}} {}", response_cache.hits())?;
+        writeln!(out, "oauth_gateway_response_cache_total{{result=\"miss\"}} {}", response_cache.misses())?;
+    }
+
+    Ok(())
+}
+
+/// Reports in-flight work and readiness as JSON, e.g. to wait for a drain to finish.
+async fn status(app: &App) -> Result<Response<Body>> {
+    let listening = app.listener_manager.listen_addrs().await;
+    let mut listen_addrs = app.config.servers.iter()
+        .flat_map(|server| server.listen.iter().copied())
+        .chain(listening.iter().copied())
+        .collect::<Vec<_>>();
+
+    listen_addrs.sort();
+    listen_addrs.dedup();
+
+    let listeners = listen_addrs.iter()
+        .map(|listen_addr| json!({
+            "address": listen_addr.to_string(),
+            "listening": listening.contains(listen_addr),
+        }))
+        .collect::<Vec<_>>();
+
+    let circuit_breakers = app.config.servers.iter()
+        .zip(&app.circuit_breakers)
+        .filter_map(|(server, circuit_breaker)| Some((server.name.clone(), circuit_breaker.as_ref()?.state_name().into())))
+        .collect::<serde_json::Map<_, _>>();
+
+    let saturation = app.config.servers.iter()
+        .zip(&app.server_limits)
+        .filter(|(server, _)| server.max_concurrent_requests.is_some())
+        .map(|(server, limit)| (server.name.clone(), json!({
+            "queued": limit.queued(),
+            "rejected": limit.rejected(),
+        })))
+        .collect::<serde_json::Map<_, _>>();
+
+    let mut maintenance = app.config.servers.iter()
+        .zip(&app.maintenance)
+        .filter(|(_, maintenance)| maintenance.is_enabled())
+        .map(|(server, _)| server.name.clone())
+        .collect::<Vec<_>>();
+
+    maintenance.dedup();
+
+    let status = json!({
+        "draining": app.draining.load(Ordering::Relaxed),
+        "oidc_ready": app.oidc.get().is_some(),
+        "in_flight_requests": app.in_flight_requests.get(),
+        "open_connections": app.open_connections.get(),
+        "listeners": listeners,
+        "circuit_breakers": circuit_breakers,
+        "saturation": saturation,
+        "maintenance": maintenance,
+        "cache": app.response_cache.as_ref().map(|response_cache| json!({
+            "hits": response_cache.hits(),
+            "misses": response_cache.misses(),
+        })),
+    });
+
+    let response = Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(status.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Stops accepting new connections on all listeners, while open connections are served
+/// to completion. Poll `/status` until `open_connections` reaches zero before shutting down.
+async fn drain(app: &App) -> Result<Response<Body>> {
+    app.drain().await;
+
+    Ok(text_response(StatusCode::OK, "Draining\n"))
+}
+
+/// Lists the addresses currently accepting connections, one per line.
+async fn list_listeners(app: &App) -> Result<Response<Body>> {
+    let body = app.listener_manager.listen_addrs().await
+        .into_iter()
+        .map(|listen_addr| format!("{}\n", listen_addr))
+        .collect::<String>();
+
+    Ok(text_response(StatusCode::OK, body))
+}
+
+/// Starts listening on the address sent as the request body.
+async fn start_listener(app: &App, request: Request<Body>) -> Result<Response<Body>> {
+    let listen_addr = match read_listen_addr(request).await? {
+        Ok(listen_addr) => listen_addr,
+        Err(response) => return Ok(response),
+    };
+
+    if !app.config.servers.iter().any(|server| server.listens_on(&listen_addr)) {
+        eprintln!("WARNING: no server is configured for {}, all requests will be rejected", listen_addr);
+    }
+
+    app.listener_manager.start_listening_on(listen_addr).await
+        .with_context(|| format!("Failed to listen on {}", listen_addr))?;
+
+    println!("Listening on {}", listen_addr);
+
+    Ok(text_response(StatusCode::OK, format!("Listening on {}\n", listen_addr)))
+}
+
+/// Stops listening on the address sent as the request body.
+async fn stop_listener(app: &App, request: Request<Body>) -> Result<Response<Body>> {
+    let listen_addr = match read_listen_addr(request).await? {
+        Ok(listen_addr) => listen_addr,
+        Err(response) => return Ok(response),
+    };
+
+    if !app.listener_manager.stop_listening_on(listen_addr).await {
+        return Ok(text_response(StatusCode::NOT_FOUND, format!("Not listening on {}\n", listen_addr)));
+    }
+
+    println!("Stopped listening on {}", listen_addr);
+
+    Ok(text_response(StatusCode::OK, format!("Stopped listening on {}\n", listen_addr)))
+}
+
+/// Toggles maintenance mode for the servers named in the request body, or all servers if it is empty.
+async fn set_maintenance(app: &App, request: Request<Body>, enabled: bool) -> Result<Response<Body>> {
+    let body = hyper::body::to_bytes(request.into_body()).await
+        .context("Failed to read request body")?;
+    let name = String::from_utf8_lossy(&body).trim().to_owned();
+    let mut toggled = 0;
+
+    for (server, maintenance) in app.config.servers.iter().zip(&app.maintenance) {
+        if name.is_empty() || server.name == name {
+            maintenance.set_enabled(enabled);
+            toggled += 1;
+        }
+    }
+
+    if toggled == 0 {
+        return Ok(text_response(StatusCode::NOT_FOUND, format!("No server named {:?}\n", name)));
+    }
+
+    let state = if enabled { "enabled" } else { "disabled" };
+
+    println!("Maintenance {} for {} servers", state, toggled);
+
+    Ok(text_response(StatusCode::OK, format!("Maintenance {} for {} servers\n", state, toggled)))
+}
+
+async fn read_listen_addr(request: Request<Body>) -> Result<Result<SocketAddr, Response<Body>>> {
+    let body = hyper::body::to_bytes(request.into_body()).await
+        .context("Failed to read request body")?;
+    let body = String::from_utf8_lossy(&body);
+
+    match body.trim().parse() {
+        Ok(listen_addr) => Ok(Ok(listen_addr)),
+        Err(_) => Ok(Err(text_response(StatusCode::BAD_REQUEST, "Expected a socket address as request body\n"))),
+    }
+}
+
+fn text_response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(body.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::post("/drain");
+
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn rejection(config: &config::Admin, headers: &[(&str, &str)]) -> Option<StatusCode> {
+        reject(config, &request(headers)).map(|response| response.status())
+    }
+
+    #[test]
+    fn browser_requests_are_rejected() {
+        let config: config::Admin = toml::from_str(r#"listen = "127.0.0.1:9901""#).unwrap();
+
+        assert_eq!(rejection(&config, &[("x-gateway-admin", "1")]), None);
+        assert_eq!(rejection(&config, &[]), Some(StatusCode::FORBIDDEN));
+        assert_eq!(rejection(&config, &[("x-gateway-admin", "1"), ("origin", "https://evil.example")]), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn configured_token_is_required() {
+        let config: config::Admin = toml::from_str(r#"
+            listen = "0.0.0.0:9901"
+            allow_remote = true
+            token = "secret"
+        "#).unwrap();
+
+        assert_eq!(rejection(&config, &[("x-gateway-admin", "1"), ("authorization", "Bearer secret")]), None);
+        assert_eq!(rejection(&config, &[("x-gateway-admin", "1"), ("authorization", "Bearer wrong")]), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(rejection(&config, &[("x-gateway-admin", "1")]), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(rejection(&config, &[("authorization", "Bearer secret")]), Some(StatusCode::FORBIDDEN));
+    }
+}
@@ -1,11 +1,17 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{Result, Context};
-use hyper::{Body, Request, header::AUTHORIZATION};
-use oauth2::{StandardErrorResponse};
+use anyhow::{Result, Context, Error, anyhow};
+use hyper::{Body, HeaderMap, Request};
+use hyper::header::{AUTHORIZATION, COOKIE, HeaderName, HeaderValue};
+use oauth2::{AuthType, StandardErrorResponse};
 use openidconnect::EmptyAdditionalClaims;
-use openidconnect::{AccessToken, ClientId, ClientSecret, IntrospectionUrl, IssuerUrl, StandardTokenIntrospectionResponse, TokenIntrospectionResponse as _};
-use openidconnect::reqwest::async_http_client;
+use parking_lot::Mutex;
+use openidconnect::{AccessToken, ClientId, ClientSecret, ConfigurationError, IntrospectionUrl, IssuerUrl, RevocationUrl, StandardTokenIntrospectionResponse, TokenIntrospectionResponse as _};
 use openidconnect::core::{
     CoreAuthDisplay,
     CoreAuthPrompt,
@@ -25,9 +31,29 @@ use openidconnect::core::{
 use serde::{Deserialize, Serialize};
 
 mod async_client;
+mod client_assertion;
+mod denylist;
+mod discovery;
 pub mod extensions;
+pub mod metrics;
+mod negative_cache;
+mod revocation;
+pub mod sessions;
+mod single_flight;
+
+pub use client_assertion::ClientAssertion;
+pub use denylist::JtiDenylist;
+pub use discovery::OidcClient;
+pub use negative_cache::NegativeCache;
+pub use revocation::RevokedTokens;
+pub use sessions::Sessions;
+pub use single_flight::SingleFlight;
 
 use crate::Config;
+use crate::config;
+use crate::response_cache;
+use crate::config::openid::TokenEndpointAuthMethod;
+use self::metrics::{IntrospectionMetrics, Outcome};
 
 pub type Client = openidconnect::Client<
     EmptyAdditionalClaims,
@@ -49,69 +75,510 @@ pub type Client = openidconnect::Client<
 
 pub type TokenIntrospectionResponse = StandardTokenIntrospectionResponse<ExtraTokenFields, CoreTokenType>;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ExtraTokenFields(pub extensions::Token);
 
 impl oauth2::ExtraTokenFields for ExtraTokenFields {}
 
 pub type IntrospectionResult = StandardTokenIntrospectionResponse<ExtraTokenFields, CoreTokenType>;
 
-pub async fn create_oidc_client(config: &Config) -> Result<Client> {
+/// Introspections currently in flight, by token.
+pub type Introspections = SingleFlight<Result<Option<IntrospectionResult>, AuthError>>;
+
+/// Why a token couldn't be verified. Invalid tokens are no error, they verify as `None`.
+#[derive(Debug, Clone)]
+pub enum AuthError {
+    /// Authentication can't be performed until OIDC discovery has succeeded.
+    Unavailable,
+    /// The introspection endpoint failed or asked to back off, so the token's validity is unknown.
+    Introspection(Arc<Error>),
+    /// The gateway itself failed, e.g. to build the introspection request.
+    Internal(Arc<Error>),
+}
+
+impl AuthError {
+    /// Whether the auth backend is at fault rather than the gateway.
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, Self::Unavailable | Self::Introspection(_))
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unavailable => f.write_str("auth temporarily unavailable"),
+            Self::Introspection(err) => write!(f, "token introspection failed: {:#}", err),
+            Self::Internal(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Discovers the provider and its keys. Also returns the lowest `max-age` of the responses, if any,
+/// to know when to discover again.
+pub async fn create_oidc_client(config: &Config) -> Result<(Client, Option<Duration>)> {
     let openid = &config.openid;
+    let max_age = Mutex::new(None::<Duration>);
     let provider_metadata = CoreProviderMetadata::discover_async(
             IssuerUrl::new(openid.issuer_url.to_string())?,
-            async_client::async_http_client,
+            |request| async {
+                let response = async_client::async_http_client(request).await?;
+
+                if let Some(response_max_age) = response_cache::directive_value(&response.headers, "max-age") {
+                    let response_max_age = Duration::from_secs(response_max_age);
+                    let mut max_age = max_age.lock();
+
+                    *max_age = Some(max_age.map_or(response_max_age, |max_age| max_age.min(response_max_age)));
+                }
+
+                Ok::<_, oauth2::reqwest::Error<reqwest::Error>>(response)
+            },
         )
         .await
         .context("Failed to discover oauth endpoints")?;
 
+    let client = oidc_client_from_metadata(openid, provider_metadata)?;
+
+    Ok((client, max_age.into_inner()))
+}
+
+/// Builds the client from a discovery document saved to disk, for deployments that can't
+/// or shouldn't reach the provider's discovery endpoint.
+pub fn oidc_client_from_document(openid: &config::Openid, path: &Path) -> Result<Client> {
+    let document = fs::read(path)
+        .with_context(|| format!("Failed to read discovery document {:?}", path))?;
+    let provider_metadata = serde_json::from_slice(&document)
+        .with_context(|| format!("Failed to parse discovery document {:?}", path))?;
+
+    oidc_client_from_metadata(openid, provider_metadata)
+}
+
+/// Builds the client from already discovered provider metadata, e.g. to inject a mock provider in tests.
+pub fn oidc_client_from_metadata(openid: &config::Openid, provider_metadata: CoreProviderMetadata) -> Result<Client> {
     let client_id = ClientId::new(openid.client_id.clone());
     let introspection_url = IntrospectionUrl::new(openid.introspect_url.clone())
         .context("Failed to create introspection URL")?;
-    let client_secret = ClientSecret::new(openid.client_secret.clone());
+    let client_secret = || openid.client_secret.clone()
+        .map(ClientSecret::new)
+        .context("`client_secret` is required for this `token_endpoint_auth_method`");
+
+    let (auth_type, client_secret) = match openid.token_endpoint_auth_method {
+        TokenEndpointAuthMethod::ClientSecretBasic => (AuthType::BasicAuth, Some(client_secret()?)),
+        TokenEndpointAuthMethod::ClientSecretPost => (AuthType::RequestBody, Some(client_secret()?)),
+        // Without a secret only `client_id` goes into the body,
+        // `async_client::authenticated_http_client` adds the assertion
+        TokenEndpointAuthMethod::PrivateKeyJwt => (AuthType::RequestBody, None),
+    };
+
+    let mut oidc_client = Client::from_provider_metadata(provider_metadata, client_id, client_secret)
+        .set_introspection_uri(introspection_url)
+        .set_auth_type(auth_type);
+
+    if let Some(revocation_url) = &openid.revocation_url {
+        let revocation_url = RevocationUrl::new(revocation_url.clone())
+            .context("Failed to create revocation URL")?;
 
-    let oidc_client = Client::from_provider_metadata(provider_metadata, client_id, Some(client_secret))
-        .set_introspection_uri(introspection_url);
+        oidc_client = oidc_client.set_revocation_uri(revocation_url);
+    }
 
     Ok(oidc_client)
 }
 
-fn extract_access_token(request: &Request<Body>) -> Option<AccessToken> {
-    let auth = request.headers().get(AUTHORIZATION)?;
-    let auth = str::from_utf8(auth.as_bytes()).ok()?;
-    let mut auth = auth.split_whitespace();
-
-    let kind = auth.next()?;
-    let token = auth.next()?;
+/// Where requests carry their access token.
+#[derive(Debug, Clone)]
+pub struct TokenSource<'a> {
+    pub header: HeaderName,
+    /// Read if the header is missing.
+    pub cookie: Option<&'a str>,
+}
 
-    if !kind.eq_ignore_ascii_case("token") && !kind.eq_ignore_ascii_case("bearer") {
-        return None;
+impl<'a> TokenSource<'a> {
+    /// The server's settings take precedence over the ones in `[openid]`.
+    pub fn new(openid: &'a config::Openid, server: &'a config::Server) -> Self {
+        Self {
+            header: server.token_header.clone()
+                .or_else(|| openid.token_header.clone())
+                .unwrap_or(AUTHORIZATION),
+            cookie: server.token_cookie.as_deref().or(openid.token_cookie.as_deref()),
+        }
     }
+}
+
+fn extract_access_token(request: &Request<Body>, token_source: &TokenSource) -> Option<AccessToken> {
+    let token = match request.headers().get(&token_source.header) {
+        Some(value) => token_from_header(value, token_source.header == AUTHORIZATION)?,
+        None => cookie_value(request.headers(), token_source.cookie?)?,
+    };
 
     let token = AccessToken::new(token.to_string());
 
     Some(token)
 }
 
-pub async fn verify_access_token(oidc: &Client, request: &Request<Body>) -> Result<Option<IntrospectionResult>> {
-    let access_token = match extract_access_token(request) {
+/// `Bearer <token>` or `Token <token>`. Custom headers may also carry the bare token.
+fn token_from_header(value: &HeaderValue, requires_scheme: bool) -> Option<&str> {
+    let value = str::from_utf8(value.as_bytes()).ok()?;
+    let mut value = value.split_whitespace();
+
+    let first = value.next()?;
+
+    match value.next() {
+        Some(token) if first.eq_ignore_ascii_case("token") || first.eq_ignore_ascii_case("bearer") => Some(token),
+        Some(_) => None,
+        None if !requires_scheme => Some(first),
+        None => None,
+    }
+}
+
+/// Removes the cookie `name` from the request, keeping the others.
+pub fn remove_cookie(headers: &mut HeaderMap, name: &str) {
+    if cookie_value(headers, name).is_none() {
+        return;
+    }
+
+    let cookies = headers.get_all(COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|pair| pair.split_once('=').is_none_or(|(cookie, _)| cookie != name))
+        .filter(|pair| !pair.is_empty())
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    headers.remove(COOKIE);
+
+    if let Ok(cookies) = HeaderValue::from_str(&cookies) {
+        if !cookies.is_empty() {
+            headers.insert(COOKIE, cookies);
+        }
+    }
+}
+
+/// The value of the cookie `name`, if the request sent it.
+pub fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_access_token(
+    oidc: &OidcClient,
+    negative_cache: &NegativeCache,
+    revoked_tokens: &RevokedTokens,
+    jti_denylist: &JtiDenylist,
+    introspections: &Introspections,
+    metrics: &IntrospectionMetrics,
+    token_type_hint: bool,
+    token_source: &TokenSource<'_>,
+    request: &Request<Body>,
+) -> Result<Option<IntrospectionResult>, AuthError> {
+    let access_token = match extract_access_token(request, token_source) {
         Some(access_token) => access_token,
         None => {
-            eprintln!("access token missing in header");
+            eprintln!("access token missing in request");
             return Ok(None)
         },
     };
 
-    let introspection = oidc.introspect(&access_token)
-        .context("Failed to create introspection request")?
-        .request_async(async_http_client) // FIXME: async_http_client does not reuse http client
-        .await
-        .context("Token introspection failed")?;
+    if revoked_tokens.contains(access_token.secret()) {
+        eprintln!("token has been revoked");
+        metrics.record_local_rejection();
+        return Ok(None);
+    }
+
+    if negative_cache.contains(access_token.secret()) {
+        eprintln!("token recently failed introspection");
+        metrics.record_local_rejection();
+        return Ok(None);
+    }
+
+    if negative_cache.is_backing_off() {
+        return Err(AuthError::Introspection(Arc::new(anyhow!("Introspection endpoint asked to back off"))));
+    }
+
+    let client_assertion = oidc.client_assertion();
+    let oidc = oidc.get().ok_or(AuthError::Unavailable)?;
+
+    // Concurrent requests with the same token share one introspection
+    let introspection = introspections.run(access_token.secret(), || {
+        introspect(&oidc, client_assertion, negative_cache, metrics, &access_token, token_type_hint)
+    })
+    .await?;
+
+    // Checked after introspection, since the denylist can change while it is in flight
+    if let Some(jti) = introspection.as_ref().and_then(|introspection| introspection.jti()) {
+        if jti_denylist.contains(jti) {
+            eprintln!("token id {:?} is denied", jti);
+            return Ok(None);
+        }
+    }
+
+    Ok(introspection)
+}
+
+async fn introspect(
+    oidc: &Client,
+    client_assertion: Option<&ClientAssertion>,
+    negative_cache: &NegativeCache,
+    metrics: &IntrospectionMetrics,
+    access_token: &AccessToken,
+    token_type_hint: bool,
+) -> Result<Option<IntrospectionResult>, AuthError> {
+    let mut introspection = oidc.introspect(access_token)
+        .context("Failed to create introspection request")
+        .map_err(|err| AuthError::Internal(Arc::new(err)))?;
+
+    if token_type_hint {
+        introspection = introspection.set_token_type_hint("access_token");
+    }
+
+    let start = Instant::now();
+    let introspection = introspection
+        .request_async(|request| async {
+            let mut response = async_client::authenticated_http_client(client_assertion, request).await?;
+
+            negative_cache.observe_response(&response);
+            join_scope_array(&mut response.body);
+
+            Ok::<_, oauth2::reqwest::Error<reqwest::Error>>(response)
+        })
+        .await;
+
+    let introspection = match introspection {
+        Ok(introspection) => introspection,
+        Err(err) => {
+            metrics.record_introspection(start.elapsed(), Outcome::Error);
+            // Not cached, the token may well be valid once the provider is reachable again
+            return Err(AuthError::Introspection(Arc::new(Error::new(err))));
+        },
+    };
 
     if !introspection.active() {
+        metrics.record_introspection(start.elapsed(), Outcome::Inactive);
         eprintln!("token is not valid anymore");
+        negative_cache.insert(access_token.secret());
         return Ok(None);
     }
 
+    metrics.record_introspection(start.elapsed(), Outcome::Active);
+
     Ok(Some(introspection))
 }
+
+/// Some providers send `scope` as an array instead of a space delimited string,
+/// which the introspection response would fail to parse.
+fn join_scope_array(body: &mut Vec<u8>) {
+    let mut introspection = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(introspection)) => introspection,
+        _ => return,
+    };
+
+    let scopes = match introspection.get("scope") {
+        Some(serde_json::Value::Array(scopes)) => scopes.iter()
+            .filter_map(serde_json::Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => return,
+    };
+
+    introspection.insert("scope".into(), scopes.into());
+    *body = serde_json::Value::Object(introspection).to_string().into_bytes();
+}
+
+/// Rejects `access_token` from now on and asks the provider to revoke it,
+/// if a revocation endpoint is configured.
+pub async fn revoke_access_token(
+    oidc: &OidcClient,
+    revoked_tokens: &RevokedTokens,
+    access_token: AccessToken,
+) -> Result<()> {
+    revoked_tokens.insert(access_token.secret().clone());
+
+    let client_assertion = oidc.client_assertion();
+    let oidc = match oidc.get() {
+        Some(oidc) => oidc,
+        None => {
+            eprintln!("OIDC discovery pending, token was only revoked locally");
+            return Ok(());
+        },
+    };
+
+    let revocation = match oidc.revoke_token(CoreRevocableToken::AccessToken(access_token)) {
+        Ok(revocation) => revocation,
+        Err(ConfigurationError::MissingUrl(_)) => {
+            eprintln!("No revocation endpoint configured, token was only revoked locally");
+            return Ok(());
+        },
+        Err(err) => return Err(err).context("Failed to create revocation request"),
+    };
+
+    revocation
+        .request_async(|request| async_client::authenticated_http_client(client_assertion, request))
+        .await
+        .context("Token revocation failed")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::Response;
+    use hyper::service::{make_service_fn, service_fn};
+    use oauth2::url::form_urlencoded;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn openid() -> config::Openid {
+        toml::from_str(r#"
+            issuer_url = "https://oauth.example.org"
+            introspect_url = "https://oauth.example.org/token/introspect"
+            client_id = "client"
+            client_secret = "secret"
+        "#).unwrap()
+    }
+
+    #[test]
+    fn client_can_be_built_from_discovery_document() {
+        assert!(oidc_client_from_document(&openid(), Path::new("testdata/discovery.json")).is_ok());
+    }
+
+    /// Answers introspections as active and passes on the headers and form parameters of each request.
+    fn spawn_introspection_endpoint() -> (SocketAddr, mpsc::UnboundedReceiver<(HeaderMap, HashMap<String, String>)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sender = sender.clone();
+
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await?;
+                        let params = form_urlencoded::parse(&body).into_owned().collect();
+                        let _ = sender.send((parts.headers, params));
+
+                        Ok::<_, hyper::Error>(Response::builder()
+                            .header("content-type", "application/json")
+                            .body(Body::from(r#"{"active":true}"#))
+                            .unwrap())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        (addr, receiver)
+    }
+
+    /// Introspects a token with `openid` and returns what the introspection endpoint received.
+    async fn introspection_request(mut openid: config::Openid) -> (HeaderMap, HashMap<String, String>) {
+        let (addr, mut requests) = spawn_introspection_endpoint();
+        openid.introspect_url = format!("http://{}/introspect", addr);
+
+        let client_assertion = ClientAssertion::from_config(&openid).unwrap();
+        let oidc = oidc_client_from_document(&openid, Path::new("testdata/discovery.json")).unwrap();
+        let negative_cache = NegativeCache::new(Duration::ZERO, Duration::from_secs(300));
+        let introspection = introspect(
+            &oidc,
+            client_assertion.as_ref(),
+            &negative_cache,
+            &IntrospectionMetrics::new(),
+            &AccessToken::new("abc".into()),
+            false,
+        ).await;
+
+        assert!(introspection.unwrap().is_some());
+
+        requests.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_secret_basic_is_sent_as_authorization() {
+        let (headers, params) = introspection_request(openid()).await;
+
+        assert_eq!(headers[AUTHORIZATION], format!("Basic {}", base64::encode("client:secret")));
+        assert_eq!(params["token"], "abc");
+        assert!(!params.contains_key("client_secret"));
+    }
+
+    #[tokio::test]
+    async fn client_secret_post_is_sent_in_the_body() {
+        let mut openid = openid();
+        openid.token_endpoint_auth_method = TokenEndpointAuthMethod::ClientSecretPost;
+
+        let (headers, params) = introspection_request(openid).await;
+
+        assert!(!headers.contains_key(AUTHORIZATION));
+        assert_eq!(params["token"], "abc");
+        assert_eq!(params["client_id"], "client");
+        assert_eq!(params["client_secret"], "secret");
+    }
+
+    #[tokio::test]
+    async fn private_key_jwt_sends_a_client_assertion() {
+        let mut openid = openid();
+        openid.client_secret = None;
+        openid.token_endpoint_auth_method = TokenEndpointAuthMethod::PrivateKeyJwt;
+        openid.signing_key = Some("testdata/localhost.key.pem".into());
+
+        let (headers, params) = introspection_request(openid).await;
+
+        assert!(!headers.contains_key(AUTHORIZATION));
+        assert_eq!(params["token"], "abc");
+        assert_eq!(params["client_id"], "client");
+        assert!(!params.contains_key("client_secret"));
+        assert_eq!(params["client_assertion_type"], "urn:ietf:params:oauth:client-assertion-type:jwt-bearer");
+        assert_eq!(params["client_assertion"].split('.').count(), 3);
+    }
+
+    #[test]
+    fn scope_arrays_are_joined() {
+        let mut body = br#"{"active":true,"scope":["read","write"]}"#.to_vec();
+        join_scope_array(&mut body);
+
+        let introspection: IntrospectionResult = serde_json::from_slice(&body).unwrap();
+        let scopes = introspection.scopes().unwrap().iter().map(|scope| scope.as_str()).collect::<Vec<_>>();
+        assert_eq!(scopes, ["read", "write"]);
+    }
+
+    #[test]
+    fn tokens_are_read_from_the_configured_source() {
+        let request = |header: &'static str, value: &'static str| Request::get("/")
+            .header(header, value)
+            .body(Body::empty())
+            .unwrap();
+        let token = |request: &Request<Body>, token_source: &TokenSource| {
+            extract_access_token(request, token_source).map(|token| token.secret().clone())
+        };
+        let default = TokenSource { header: AUTHORIZATION, cookie: None };
+        let legacy = TokenSource { header: HeaderName::from_static("x-access-token"), cookie: Some("access_token") };
+
+        assert_eq!(token(&request("authorization", "Bearer abc"), &default).as_deref(), Some("abc"));
+        assert_eq!(token(&request("authorization", "abc"), &default), None);
+        assert_eq!(token(&request("x-access-token", "abc"), &default), None);
+
+        assert_eq!(token(&request("x-access-token", "abc"), &legacy).as_deref(), Some("abc"));
+        assert_eq!(token(&request("x-access-token", "bearer abc"), &legacy).as_deref(), Some("abc"));
+        assert_eq!(token(&request("cookie", "theme=dark; access_token=abc"), &legacy).as_deref(), Some("abc"));
+        assert_eq!(token(&request("authorization", "Bearer abc"), &legacy), None);
+    }
+
+    #[test]
+    fn missing_discovery_document_is_an_error() {
+        assert!(oidc_client_from_document(&openid(), Path::new("testdata/missing.json")).is_err());
+    }
+}
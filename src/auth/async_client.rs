@@ -7,6 +7,8 @@ use oauth2::{HttpRequest, HttpResponse, reqwest::Error};
 pub use reqwest;
 use reqwest::Client;
 
+use super::ClientAssertion;
+
 ///
 /// Asynchronous HTTP client.
 ///
@@ -18,7 +20,9 @@ pub async fn async_http_client(
             let builder = Client::builder();
 
             // Following redirects opens the client up to SSRF vulnerabilities.
-            // but this is not possible to prevent on wasm targets
+            // but this is not possible to prevent on wasm targets.
+            // This client only carries OIDC traffic and must never follow redirects,
+            // the proxy path has its own clients (see `upstream_client`).
             #[cfg(not(target_arch = "wasm32"))]
             let builder = builder.redirect(reqwest::redirect::Policy::none());
 
@@ -45,3 +49,16 @@ pub async fn async_http_client(
         body: chunks.to_vec(),
     })
 }
+
+/// Like `async_http_client`, but authenticates the client with a signed assertion, if configured.
+pub async fn authenticated_http_client(
+    client_assertion: Option<&ClientAssertion>,
+    mut request: HttpRequest,
+) -> Result<HttpResponse, Error<reqwest::Error>> {
+    if let Some(client_assertion) = client_assertion {
+        client_assertion.authenticate(&mut request)
+            .map_err(|err| Error::Other(format!("{:#}", err)))?;
+    }
+
+    async_http_client(request).await
+}
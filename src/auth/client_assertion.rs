@@ -0,0 +1,190 @@
+//! `private_key_jwt` client authentication (RFC 7523), which oauth2 doesn't do itself.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context, anyhow, bail};
+use oauth2::HttpRequest;
+use oauth2::url::form_urlencoded;
+use rand::Rng;
+use ring::rand::SystemRandom;
+use ring::signature::{RSA_PKCS1_SHA256, RsaKeyPair};
+use serde_json::json;
+
+use crate::config;
+use crate::config::openid::TokenEndpointAuthMethod;
+
+const ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+/// Assertions are signed for each request, they only need to outlive it.
+const LIFETIME_SECS: u64 = 60;
+
+pub struct ClientAssertion {
+    client_id: String,
+    key_id: Option<String>,
+    key_pair: RsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl ClientAssertion {
+    /// Loads `signing_key` if `private_key_jwt` is configured, and checks that the
+    /// other methods have a `client_secret`.
+    pub fn from_config(openid: &config::Openid) -> Result<Option<Self>> {
+        if openid.token_endpoint_auth_method != TokenEndpointAuthMethod::PrivateKeyJwt {
+            if openid.client_secret.is_none() {
+                bail!("`client_secret` is required unless `token_endpoint_auth_method` is `private_key_jwt`");
+            }
+
+            return Ok(None);
+        }
+
+        let path = openid.signing_key.as_ref()
+            .context("`signing_key` is required for `private_key_jwt`")?;
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open signing key {:?}", path))?;
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(file))
+            .with_context(|| format!("Failed to read signing key {:?}", path))?
+            .into_iter()
+            .next()
+            .with_context(|| format!("No PKCS#8 key found in {:?}", path))?;
+        let key_pair = RsaKeyPair::from_pkcs8(&key)
+            .map_err(|err| anyhow!("Signing key {:?} is not a valid RSA key: {}", path, err))?;
+
+        Ok(Some(Self {
+            client_id: openid.client_id.clone(),
+            key_id: openid.signing_key_id.clone(),
+            key_pair,
+            rng: SystemRandom::new(),
+        }))
+    }
+
+    /// Adds a freshly signed assertion to the form encoded body of `request`,
+    /// with the endpoint it is sent to as audience.
+    pub fn authenticate(&self, request: &mut HttpRequest) -> Result<()> {
+        let mut audience = request.url.clone();
+        audience.set_query(None);
+        audience.set_fragment(None);
+
+        let assertion = self.sign(audience.as_str())?;
+        let params = form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_assertion_type", ASSERTION_TYPE)
+            .append_pair("client_assertion", &assertion)
+            .finish();
+
+        if !request.body.is_empty() {
+            request.body.push(b'&');
+        }
+
+        request.body.extend_from_slice(params.as_bytes());
+
+        Ok(())
+    }
+
+    fn sign(&self, audience: &str) -> Result<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .context("System time is before the epoch")?
+            .as_secs();
+        let mut header = json!({
+            "alg": "RS256",
+            "typ": "JWT",
+        });
+
+        if let Some(key_id) = &self.key_id {
+            header["kid"] = key_id.as_str().into();
+        }
+
+        let claims = json!({
+            "iss": self.client_id,
+            "sub": self.client_id,
+            "aud": audience,
+            "jti": base64::encode_config(rand::thread_rng().gen::<[u8; 16]>(), base64::URL_SAFE_NO_PAD),
+            "iat": now,
+            "exp": now + LIFETIME_SECS,
+        });
+        let message = format!("{}.{}", encode_segment(&header), encode_segment(&claims));
+        let mut signature = vec![0; self.key_pair.public_modulus_len()];
+
+        self.key_pair.sign(&RSA_PKCS1_SHA256, &self.rng, message.as_bytes(), &mut signature)
+            .map_err(|_| anyhow!("Failed to sign client assertion"))?;
+
+        Ok(format!("{}.{}", message, base64::encode_config(signature, base64::URL_SAFE_NO_PAD)))
+    }
+}
+
+fn encode_segment(value: &serde_json::Value) -> String {
+    base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use oauth2::http::{HeaderMap, Method};
+    use oauth2::url::Url;
+    use ring::signature::{KeyPair, RSA_PKCS1_2048_8192_SHA256, UnparsedPublicKey};
+
+    use super::*;
+
+    fn openid(extra: &str) -> config::Openid {
+        toml::from_str(&format!(r#"
+            issuer_url = "https://oauth.example.org"
+            introspect_url = "https://oauth.example.org/token/introspect"
+            client_id = "client"
+            token_endpoint_auth_method = "private_key_jwt"
+            {}
+        "#, extra)).unwrap()
+    }
+
+    fn decode_segment(segment: &str) -> serde_json::Value {
+        serde_json::from_slice(&base64::decode_config(segment, base64::URL_SAFE_NO_PAD).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn assertions_are_signed_for_the_endpoint() {
+        let openid = openid(r#"
+            signing_key = "testdata/localhost.key.pem"
+            signing_key_id = "key-1"
+        "#);
+        let client_assertion = ClientAssertion::from_config(&openid).unwrap().unwrap();
+        let mut request = HttpRequest {
+            url: Url::parse("https://oauth.example.org/token/introspect?debug=1").unwrap(),
+            method: Method::POST,
+            headers: HeaderMap::new(),
+            body: b"token=abc&client_id=client".to_vec(),
+        };
+
+        client_assertion.authenticate(&mut request).unwrap();
+
+        let params = form_urlencoded::parse(&request.body).into_owned().collect::<HashMap<_, _>>();
+        assert_eq!(params["token"], "abc");
+        assert_eq!(params["client_id"], "client");
+        assert_eq!(params["client_assertion_type"], ASSERTION_TYPE);
+
+        let (message, signature) = params["client_assertion"].rsplit_once('.').unwrap();
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        let public_key = UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, client_assertion.key_pair.public_key().as_ref());
+        assert!(public_key.verify(message.as_bytes(), &signature).is_ok());
+
+        let (header, claims) = message.split_once('.').unwrap();
+        let header = decode_segment(header);
+        let claims = decode_segment(claims);
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(header["kid"], "key-1");
+        assert_eq!(claims["iss"], "client");
+        assert_eq!(claims["sub"], "client");
+        assert_eq!(claims["aud"], "https://oauth.example.org/token/introspect");
+        assert_eq!(claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap(), LIFETIME_SECS);
+    }
+
+    #[test]
+    fn credentials_are_required() {
+        assert!(ClientAssertion::from_config(&openid("")).is_err());
+
+        let mut openid = openid("");
+        openid.token_endpoint_auth_method = TokenEndpointAuthMethod::ClientSecretBasic;
+        assert!(ClientAssertion::from_config(&openid).is_err());
+
+        openid.client_secret = Some("secret".into());
+        assert!(ClientAssertion::from_config(&openid).unwrap().is_none());
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Result, Context};
+use parking_lot::RwLock;
+
+/// Token IDs (`jti` claims) to reject even if the provider still reports them as active.
+/// Loaded from a file with one ID per line, `#` starts a comment.
+#[derive(Default)]
+pub struct JtiDenylist {
+    path: Option<PathBuf>,
+    jtis: RwLock<HashSet<String>>,
+}
+
+impl JtiDenylist {
+    pub fn new(path: Option<PathBuf>) -> Result<Self> {
+        let this = Self {
+            path,
+            jtis: <_>::default(),
+        };
+
+        this.reload()?;
+
+        Ok(this)
+    }
+
+    /// Replaces the denylist with the contents of the file,
+    /// dropping IDs denied through the admin interface since.
+    /// Returns the number of denied IDs.
+    pub fn reload(&self) -> Result<usize> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(self.jtis.read().len()),
+        };
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read JTI denylist {:?}", path))?;
+        let jtis = parse(&contents);
+        let len = jtis.len();
+
+        *self.jtis.write() = jtis;
+
+        Ok(len)
+    }
+
+    pub fn contains(&self, jti: &str) -> bool {
+        self.jtis.read().contains(jti)
+    }
+
+    pub fn insert(&self, jti: String) {
+        self.jtis.write().insert(jti);
+    }
+
+    pub fn remove(&self, jti: &str) -> bool {
+        self.jtis.write().remove(jti)
+    }
+}
+
+fn parse(contents: &str) -> HashSet<String> {
+    contents.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_and_blank_lines_are_ignored() {
+        let jtis = parse("# incident 42\nabc-123\n\n  def-456  # leaked in logs\n");
+
+        assert_eq!(jtis, ["abc-123".to_owned(), "def-456".to_owned()].into_iter().collect());
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+
+use crate::Config;
+use super::{Client, ClientAssertion, create_oidc_client};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Lower bound for refreshes, in case the provider sends a tiny `max-age`.
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+
+/// The oidc client, available once provider discovery has succeeded.
+#[derive(Default)]
+pub struct OidcClient {
+    client: RwLock<Option<Arc<Client>>>,
+    ready: Notify,
+    /// Signs requests to the provider's endpoints for `private_key_jwt`, independent of discovery.
+    client_assertion: Option<ClientAssertion>,
+}
+
+impl OidcClient {
+    pub fn new(client_assertion: Option<ClientAssertion>) -> Self {
+        Self {
+            client_assertion,
+            ..Self::default()
+        }
+    }
+
+    pub fn client_assertion(&self) -> Option<&ClientAssertion> {
+        self.client_assertion.as_ref()
+    }
+
+    pub fn get(&self) -> Option<Arc<Client>> {
+        self.client.read().clone()
+    }
+
+    /// Makes `client` available right away, without discovery.
+    pub fn set(&self, client: Client) {
+        *self.client.write() = Some(Arc::new(client));
+        self.ready.notify_waiters();
+    }
+
+    /// Waits until a client is available.
+    pub async fn wait(&self) {
+        loop {
+            // Created before checking, so a client set in between isn't missed
+            let ready = self.ready.notified();
+
+            if self.get().is_some() {
+                return;
+            }
+
+            ready.await;
+        }
+    }
+
+    /// Retries discovery with exponential backoff until it succeeds.
+    /// Returns the `max-age` sent by the provider.
+    pub async fn discover(&self, config: &Config) -> Option<Duration> {
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            match create_oidc_client(config).await {
+                Ok((client, max_age)) => {
+                    self.set(client);
+                    println!("OIDC discovery succeeded");
+                    return max_age;
+                },
+                Err(err) => {
+                    eprintln!("{:#}, retrying in {}s", err, backoff.as_secs());
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                },
+            }
+        }
+    }
+
+    /// Discovers the provider, then discovers it again whenever the provider's `max-age`
+    /// or `discovery_refresh_secs` runs out. Failed refreshes keep the last discovered client.
+    pub async fn discover_and_refresh(&self, config: &Config) {
+        let mut max_age = self.discover(config).await;
+        let interval = Duration::from_secs(config.openid.discovery_refresh_secs);
+
+        if interval.is_zero() {
+            return;
+        }
+
+        loop {
+            time::sleep(max_age.map_or(interval, |max_age| max_age.max(MIN_REFRESH))).await;
+
+            max_age = match create_oidc_client(config).await {
+                Ok((client, max_age)) => {
+                    self.set(client);
+                    max_age
+                },
+                Err(err) => {
+                    eprintln!("WARNING: OIDC discovery refresh failed, keeping the previous keys: {:#}", err);
+                    None
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::auth::oidc_client_from_document;
+
+    #[tokio::test]
+    async fn wait_returns_once_client_is_set() {
+        let oidc = Arc::new(OidcClient::new(None));
+        let mut waiting = tokio::spawn({
+            let oidc = oidc.clone();
+            async move { oidc.wait().await }
+        });
+
+        assert!(time::timeout(Duration::from_millis(50), &mut waiting).await.is_err());
+
+        let openid = toml::from_str(r#"
+            issuer_url = "https://oauth.example.org"
+            introspect_url = "https://oauth.example.org/token/introspect"
+            client_id = "client"
+            client_secret = "secret"
+        "#).unwrap();
+        oidc.set(oidc_client_from_document(&openid, Path::new("testdata/discovery.json")).unwrap());
+
+        time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+    }
+}
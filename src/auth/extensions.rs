@@ -1,9 +1,85 @@
 use serde::{Deserialize, Serialize};
 
+pub mod generic;
 pub mod keybase;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Token {
     Keybase(keybase::Token),
+    Generic(generic::Token),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::IntrospectionResult;
+
+    use super::*;
+
+    #[test]
+    fn auth0_token_falls_back_to_generic() {
+        let introspection = serde_json::json!({
+            "active": true,
+            "sub": "auth0|1234",
+            "permissions": ["read:items", "write:items"],
+        });
+        let introspection: IntrospectionResult = serde_json::from_value(introspection).unwrap();
+
+        let token = match &introspection.extra_fields().0 {
+            Token::Generic(token) => token,
+            token => panic!("expected generic token, got {:?}", token),
+        };
+
+        assert_eq!(token.roles("permissions"), ["read:items", "write:items"]);
+        assert!(token.roles("realm_access.roles").is_empty());
+    }
+
+    #[test]
+    fn generic_token_has_groups() {
+        let introspection = serde_json::json!({
+            "active": true,
+            "sub": "1234",
+            "groups": ["/staff", "/admins"],
+        });
+        let introspection: IntrospectionResult = serde_json::from_value(introspection).unwrap();
+
+        let token = match &introspection.extra_fields().0 {
+            Token::Generic(token) => token,
+            token => panic!("expected generic token, got {:?}", token),
+        };
+
+        assert_eq!(token.groups(), ["/staff", "/admins"]);
+    }
+
+    #[test]
+    fn keycloak_token_is_keybase() {
+        let introspection = serde_json::json!({
+            "active": true,
+            "realm_access": { "roles": ["admin"] },
+        });
+        let introspection: IntrospectionResult = serde_json::from_value(introspection).unwrap();
+
+        assert!(matches!(introspection.extra_fields().0, Token::Keybase(_)));
+    }
+
+    #[test]
+    fn keybase_roles_follow_the_roles_claim() {
+        let introspection = serde_json::json!({
+            "active": true,
+            "realm_access": { "roles": ["realm-role"] },
+            "resource_access": { "my.client": { "roles": ["client-role"] } },
+            "https://example.org/roles": ["mapped-role"],
+            "groups": ["/staff"],
+        });
+        let introspection: IntrospectionResult = serde_json::from_value(introspection).unwrap();
+
+        let token = match &introspection.extra_fields().0 {
+            Token::Keybase(token) => token,
+            token => panic!("expected keybase token, got {:?}", token),
+        };
+
+        assert_eq!(token.roles("realm_access.roles", "my.client"), ["realm-role", "client-role"]);
+        assert_eq!(token.roles("/https:~1~1example.org~1roles", "other"), ["mapped-role"]);
+        assert_eq!(token.groups(), ["/staff"]);
+    }
 }
@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_value::Value;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Token {
+    #[serde(flatten)]
+    pub claims: BTreeMap<String, Value>,
+}
+
+impl Token {
+    /// Looks up a claim by a dotted path like `realm_access.roles`,
+    /// or a JSON pointer like `/https:~1~1example.org~1roles` for keys containing dots.
+    pub fn claim(&self, path: &str) -> Option<&Value> {
+        match path.strip_prefix('/') {
+            Some(pointer) => {
+                let segments = pointer.split('/')
+                    .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+                    .collect::<Vec<_>>();
+
+                self.lookup(segments.iter().map(String::as_str))
+            },
+            None => self.lookup(path.split('.')),
+        }
+    }
+
+    /// Looks up a claim by its path segments.
+    pub fn lookup<'a>(&self, segments: impl IntoIterator<Item = &'a str>) -> Option<&Value> {
+        let mut segments = segments.into_iter();
+        let mut value = self.claims.get(segments.next()?)?;
+
+        for segment in segments {
+            value = match value {
+                Value::Map(map) => map.get(&Value::String(segment.to_owned()))?,
+                _ => return None,
+            };
+        }
+
+        Some(value)
+    }
+
+    /// Reads roles from `path`, which may either hold an array of strings
+    /// or a single space delimited string.
+    pub fn roles(&self, path: &str) -> Vec<&str> {
+        strings(self.claim(path))
+    }
+
+    /// The `groups` claim, as sent by Keycloak's group membership mapper and others.
+    pub fn groups(&self) -> Vec<&str> {
+        strings(self.claim("groups"))
+    }
+}
+
+/// The strings of an array, or the words of a space delimited string.
+pub fn strings(value: Option<&Value>) -> Vec<&str> {
+    match value {
+        Some(Value::Seq(values)) => values.iter()
+            .filter_map(|value| match value {
+                Value::String(value) => Some(value.as_str()),
+                _ => None,
+            })
+            .collect(),
+        Some(Value::String(values)) => values.split_whitespace().collect(),
+        _ => Vec::new(),
+    }
+}
@@ -1,11 +1,46 @@
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, Debug)]
+use super::generic;
+
+/// A Keycloak-style token, recognized by its `realm_access` claim.
+/// Claims are kept as is, so roles can be read from wherever mappers put them.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "generic::Token", into = "generic::Token")]
 pub struct Token {
-    pub realm_access: RealmAccess,
+    pub claims: generic::Token,
+}
+
+impl Token {
+    /// Roles at `roles_claim`, followed by the client roles of `resource_access_client`.
+    pub fn roles<'a>(&'a self, roles_claim: &str, resource_access_client: &str) -> Vec<&'a str> {
+        let mut roles = self.claims.roles(roles_claim);
+        let client_roles = self.claims.lookup(["resource_access", resource_access_client, "roles"]);
+
+        roles.extend(generic::strings(client_roles));
+        roles
+    }
+
+    pub fn groups(&self) -> Vec<&str> {
+        self.claims.groups()
+    }
+}
+
+impl TryFrom<generic::Token> for Token {
+    type Error = &'static str;
+
+    fn try_from(claims: generic::Token) -> Result<Self, Self::Error> {
+        if !claims.claims.contains_key("realm_access") {
+            return Err("missing `realm_access` claim");
+        }
+
+        Ok(Self { claims })
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct RealmAccess {
-    pub roles: Vec<String>,
+impl From<Token> for generic::Token {
+    fn from(token: Token) -> Self {
+        token.claims
+    }
 }
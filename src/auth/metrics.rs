@@ -0,0 +1,110 @@
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::time::Duration;
+
+/// Upper bounds of the introspection latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub enum Outcome {
+    Active,
+    Inactive,
+    Error,
+}
+
+/// Counters of the auth backend, the gateway's riskiest external dependency.
+#[derive(Default)]
+pub struct IntrospectionMetrics {
+    /// Not cumulative, unlike the exported buckets.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    duration_micros: AtomicU64,
+    active: AtomicU64,
+    inactive: AtomicU64,
+    errors: AtomicU64,
+    /// Tokens rejected by the negative cache or as revoked, without asking the provider.
+    rejected_locally: AtomicU64,
+}
+
+impl IntrospectionMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_introspection(&self, duration: Duration, outcome: Outcome) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.duration_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+
+        let counter = match outcome {
+            Outcome::Active => &self.active,
+            Outcome::Inactive => &self.inactive,
+            Outcome::Error => &self.errors,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_local_rejection(&self) {
+        self.rejected_locally.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Appends the metrics in the Prometheus text format.
+    pub fn render(&self, out: &mut String) -> fmt::Result {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        writeln!(out, "# HELP oauth_gateway_introspection_duration_seconds Latency of token introspection requests.")?;
+        writeln!(out, "# TYPE oauth_gateway_introspection_duration_seconds histogram")?;
+
+        let mut count = 0;
+
+        for (le, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            count += load(bucket);
+            writeln!(out, "oauth_gateway_introspection_duration_seconds_bucket{{le=\"{}\"}} {}", le, count)?;
+        }
+
+        count += load(&self.buckets[BUCKETS.len()]);
+        writeln!(out, "oauth_gateway_introspection_duration_seconds_bucket{{le=\"+Inf\"}} {}", count)?;
+        writeln!(out, "oauth_gateway_introspection_duration_seconds_sum {}", load(&self.duration_micros) as f64 / 1e6)?;
+        writeln!(out, "oauth_gateway_introspection_duration_seconds_count {}", count)?;
+
+        writeln!(out, "# HELP oauth_gateway_introspections_total Token introspections by result.")?;
+        writeln!(out, "# TYPE oauth_gateway_introspections_total counter")?;
+        writeln!(out, "oauth_gateway_introspections_total{{result=\"active\"}} {}", load(&self.active))?;
+        writeln!(out, "oauth_gateway_introspections_total{{result=\"inactive\"}} {}", load(&self.inactive))?;
+        writeln!(out, "oauth_gateway_introspections_total{{result=\"error\"}} {}", load(&self.errors))?;
+
+        writeln!(out, "# HELP oauth_gateway_tokens_rejected_locally_total Tokens rejected as revoked or recently inactive, without introspection.")?;
+        writeln!(out, "# TYPE oauth_gateway_tokens_rejected_locally_total counter")?;
+        writeln!(out, "oauth_gateway_tokens_rejected_locally_total {}", load(&self.rejected_locally))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let metrics = IntrospectionMetrics::new();
+
+        metrics.record_introspection(Duration::from_millis(3), Outcome::Active);
+        metrics.record_introspection(Duration::from_millis(30), Outcome::Inactive);
+        metrics.record_introspection(Duration::from_secs(30), Outcome::Error);
+
+        let mut out = String::new();
+        metrics.render(&mut out).unwrap();
+
+        assert!(out.contains("_bucket{le=\"0.005\"} 1\n"), "{}", out);
+        assert!(out.contains("_bucket{le=\"0.05\"} 2\n"), "{}", out);
+        assert!(out.contains("_bucket{le=\"10\"} 2\n"), "{}", out);
+        assert!(out.contains("_bucket{le=\"+Inf\"} 3\n"), "{}", out);
+        assert!(out.contains("_count 3\n"), "{}", out);
+        assert!(out.contains("introspections_total{result=\"error\"} 1\n"), "{}", out);
+    }
+}
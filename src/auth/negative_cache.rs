@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use oauth2::HttpResponse;
+use oauth2::http::StatusCode;
+use oauth2::http::header::RETRY_AFTER;
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+const MAX_ENTRIES: usize = 10_000;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Remembers tokens that recently failed introspection
+/// and whether the provider asked us to back off.
+pub struct NegativeCache {
+    ttl: Duration,
+    /// Caps backoffs, so a huge `Retry-After` can't stop introspection for days.
+    max_backoff: Duration,
+    entries: Mutex<HashMap<String, Instant>>,
+    backoff_until: Mutex<Option<Instant>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration, max_backoff: Duration) -> Self {
+        Self {
+            ttl,
+            max_backoff,
+            entries: <_>::default(),
+            backoff_until: <_>::default(),
+        }
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        let mut entries = self.entries.lock();
+
+        match entries.get(token) {
+            Some(expires_at) if *expires_at > Instant::now() => true,
+            Some(_) => {
+                entries.remove(token);
+                false
+            },
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, token: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, expires_at| *expires_at > now);
+        }
+
+        if entries.len() >= MAX_ENTRIES {
+            return;
+        }
+
+        if let Some(expires_at) = now.checked_add(with_jitter(self.ttl)) {
+            entries.insert(token.to_owned(), expires_at);
+        }
+    }
+
+    pub fn is_backing_off(&self) -> bool {
+        let mut backoff_until = self.backoff_until.lock();
+
+        match *backoff_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                *backoff_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Starts a global backoff if the provider responded with 429 or 503,
+    /// honoring its `Retry-After` header if present, up to `max_backoff`.
+    pub fn observe_response(&self, response: &HttpResponse) {
+        let status = response.status_code;
+
+        if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+            return;
+        }
+
+        let backoff = response.headers.get(RETRY_AFTER)
+            .and_then(|retry_after| retry_after.to_str().ok())
+            .and_then(parse_retry_after)
+            .unwrap_or(DEFAULT_BACKOFF)
+            .min(self.max_backoff);
+
+        eprintln!("Introspection endpoint responded with {}, backing off for {:?}", status, backoff);
+
+        *self.backoff_until.lock() = Instant::now().checked_add(with_jitter(backoff));
+    }
+}
+
+fn parse_retry_after(retry_after: &str) -> Option<Duration> {
+    if let Ok(secs) = retry_after.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(retry_after).ok()?;
+    let backoff = date.duration_since(SystemTime::now()).unwrap_or_default();
+
+    Some(backoff)
+}
+
+/// Adds up to 10% random jitter so entries don't all expire at once.
+fn with_jitter(duration: Duration) -> Duration {
+    let jitter = rand::thread_rng().gen_range(0..=100);
+
+    duration.saturating_add((duration / 1000).saturating_mul(jitter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_is_parsed_as_seconds_or_date() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon"), None);
+
+        let in_a_minute = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let backoff = parse_retry_after(&in_a_minute).unwrap();
+        assert!(backoff > Duration::from_secs(50) && backoff <= Duration::from_secs(60), "{:?}", backoff);
+    }
+
+    #[test]
+    fn oversized_backoffs_are_capped() {
+        assert_eq!(parse_retry_after("18446744073709551615"), Some(Duration::from_secs(u64::MAX)));
+
+        let negative_cache = NegativeCache::new(Duration::from_secs(60), Duration::from_secs(300));
+        let response = HttpResponse {
+            status_code: StatusCode::TOO_MANY_REQUESTS,
+            headers: [(RETRY_AFTER, "18446744073709551615".parse().unwrap())].into_iter().collect(),
+            body: Vec::new(),
+        };
+
+        negative_cache.observe_response(&response);
+
+        let backoff_until = (*negative_cache.backoff_until.lock()).unwrap();
+        assert!(backoff_until <= Instant::now() + Duration::from_secs(330));
+    }
+
+    #[test]
+    fn negative_entries_expire() {
+        let negative_cache = NegativeCache::new(Duration::from_millis(50), Duration::from_secs(300));
+
+        negative_cache.insert("token");
+        assert!(negative_cache.contains("token"));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!negative_cache.contains("token"));
+    }
+}
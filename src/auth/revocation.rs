@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use parking_lot::RwLock;
+use tokio::time::{Duration, Instant};
+
+const MAX_ENTRIES: usize = 10_000;
+
+/// Access tokens revoked through the admin interface.
+/// These are rejected even if the provider still reports them as active,
+/// until `ttl` passed and the tokens should have expired anyway.
+pub struct RevokedTokens {
+    ttl: Duration,
+    tokens: RwLock<HashMap<String, Instant>>,
+}
+
+impl RevokedTokens {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            tokens: <_>::default(),
+        }
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.tokens.read()
+            .get(token)
+            .is_some_and(|expires_at| *expires_at > Instant::now())
+    }
+
+    /// Once full, the entry expiring soonest makes room, so the latest revocations always apply.
+    pub fn insert(&self, token: String) {
+        let now = Instant::now();
+        let mut tokens = self.tokens.write();
+
+        if tokens.len() >= MAX_ENTRIES && !tokens.contains_key(&token) {
+            tokens.retain(|_, expires_at| *expires_at > now);
+        }
+
+        if tokens.len() >= MAX_ENTRIES && !tokens.contains_key(&token) {
+            let soonest = tokens.iter()
+                .min_by_key(|(_, expires_at)| **expires_at)
+                .map(|(token, _)| token.clone());
+
+            if let Some(soonest) = soonest {
+                eprintln!("Too many revoked tokens, forgetting the one expiring soonest");
+                tokens.remove(&soonest);
+            }
+        }
+
+        tokens.insert(token, now + self.ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revocations_expire() {
+        let revoked_tokens = RevokedTokens::new(Duration::ZERO);
+
+        revoked_tokens.insert("token".into());
+
+        assert!(!revoked_tokens.contains("token"));
+    }
+
+    #[test]
+    fn the_size_is_capped() {
+        let revoked_tokens = RevokedTokens::new(Duration::from_secs(60));
+
+        revoked_tokens.insert("oldest".into());
+        std::thread::sleep(Duration::from_millis(10));
+
+        for i in 1..MAX_ENTRIES {
+            revoked_tokens.insert(format!("token{}", i));
+        }
+
+        revoked_tokens.insert("latest".into());
+
+        assert_eq!(revoked_tokens.tokens.read().len(), MAX_ENTRIES);
+        assert!(revoked_tokens.contains("latest"));
+        assert!(!revoked_tokens.contains("oldest"));
+        assert!(revoked_tokens.contains("token1"));
+    }
+}
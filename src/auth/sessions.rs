@@ -0,0 +1,369 @@
+//! Browser sessions of servers acting as backend for frontend: tokens stay with the gateway,
+//! browsers only get a session cookie. Access tokens are refreshed shortly before they expire.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, Context, bail};
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+use oauth2::url::Url;
+use oauth2::{AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, Scope};
+use openidconnect::{AccessToken, Nonce, OAuth2TokenResponse};
+use openidconnect::core::{CoreAuthenticationFlow, CoreTokenResponse};
+use parking_lot::Mutex;
+use rand::Rng;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{SHA256, digest};
+use tokio::time::{Duration, Instant};
+
+use crate::config::server;
+use super::{Client, ClientAssertion, OidcClient, SingleFlight, async_client};
+
+const MAX_SESSIONS: usize = 100_000;
+const MAX_PENDING_LOGINS: usize = 10_000;
+/// How long users have to log in at the provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+struct Session {
+    server: String,
+    access_token: AccessToken,
+    refresh_token: Option<RefreshToken>,
+    expires_at: Option<Instant>,
+    last_used: Instant,
+}
+
+impl Session {
+    fn new(server: &str, token_response: &CoreTokenResponse) -> Self {
+        let mut session = Self {
+            server: server.to_owned(),
+            access_token: token_response.access_token().clone(),
+            refresh_token: None,
+            expires_at: None,
+            last_used: Instant::now(),
+        };
+
+        session.update(token_response);
+        session
+    }
+
+    fn update(&mut self, token_response: &CoreTokenResponse) {
+        self.access_token = token_response.access_token().clone();
+        self.expires_at = token_response.expires_in().map(|expires_in| Instant::now() + expires_in);
+
+        // Providers not rotating refresh tokens don't send them again
+        if let Some(refresh_token) = token_response.refresh_token() {
+            self.refresh_token = Some(refresh_token.clone());
+        }
+    }
+}
+
+struct PendingLogin {
+    server: String,
+    pkce_verifier: String,
+    return_to: String,
+    started: Instant,
+}
+
+/// A completed login, the browser gets `session_id` as cookie and is sent back to `return_to`.
+pub struct Login {
+    pub session_id: String,
+    pub return_to: String,
+}
+
+pub struct Sessions {
+    /// By session ID.
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Logins waiting for the provider's callback, by `state`.
+    pending_logins: Mutex<HashMap<String, PendingLogin>>,
+    /// Refreshes in flight, by session ID. Rotated refresh tokens are only usable once.
+    refreshes: SingleFlight<Option<AccessToken>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self {
+            sessions: <_>::default(),
+            pending_logins: <_>::default(),
+            refreshes: SingleFlight::new(),
+        }
+    }
+
+    /// Returns the provider's authorization URL to send the browser to and the login's `state`,
+    /// which the browser must get as `login_state_cookie`. `return_to` is where it ends up after logging in.
+    pub fn start_login(&self, oidc: &Client, server: &str, config: &server::Session, return_to: String) -> Result<(Url, String)> {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        // Only the access token is used, so there is no ID token to check the nonce of
+        let mut authorization = redirecting_client(oidc, config)?
+            .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .set_pkce_challenge(pkce_challenge);
+
+        for scope in &config.scopes {
+            authorization = authorization.add_scope(Scope::new(scope.clone()));
+        }
+
+        let (url, state, _nonce) = authorization.url();
+        let now = Instant::now();
+        let mut pending_logins = self.pending_logins.lock();
+
+        if pending_logins.len() >= MAX_PENDING_LOGINS {
+            pending_logins.retain(|_, login| now.duration_since(login.started) < LOGIN_TIMEOUT);
+        }
+
+        // Anyone can start logins, so refusing new ones would let floods of them block real users
+        while pending_logins.len() >= MAX_PENDING_LOGINS {
+            let oldest = pending_logins.iter()
+                .min_by_key(|(_, login)| login.started)
+                .map(|(state, _)| state.clone());
+
+            match oldest {
+                Some(oldest) => pending_logins.remove(&oldest),
+                None => break,
+            };
+        }
+
+        pending_logins.insert(state.secret().clone(), PendingLogin {
+            server: server.to_owned(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            return_to,
+            started: now,
+        });
+
+        Ok((url, state.secret().clone()))
+    }
+
+    /// Exchanges the `code` the provider passed to the callback for tokens and starts a session.
+    /// Returns `None` if `state` doesn't belong to a login started at this server by this browser,
+    /// judging by the `Cookie` headers in `headers`. Otherwise, attackers could send their victims
+    /// the callback URL of a login of their own, logging them into the attacker's account.
+    pub async fn complete_login(
+        &self,
+        oidc: &Client,
+        client_assertion: Option<&ClientAssertion>,
+        server: &str,
+        config: &server::Session,
+        headers: &HeaderMap,
+        state: &str,
+        code: String,
+    ) -> Result<Option<Login>> {
+        let started_by_browser = super::cookie_value(headers, &login_state_cookie_name(config))
+            .is_some_and(|state_hash| verify_slices_are_equal(state_hash.as_bytes(), hash_state(state).as_bytes()).is_ok());
+
+        if !started_by_browser {
+            return Ok(None);
+        }
+
+        let pending_login = self.pending_logins.lock().remove(state)
+            .filter(|login| login.server == server && login.started.elapsed() < LOGIN_TIMEOUT);
+        let pending_login = match pending_login {
+            Some(pending_login) => pending_login,
+            None => return Ok(None),
+        };
+
+        let token_response = redirecting_client(oidc, config)?
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pending_login.pkce_verifier))
+            .request_async(|request| async_client::authenticated_http_client(client_assertion, request))
+            .await
+            .context("Authorization code exchange failed")?;
+
+        let session_id = base64::encode_config(rand::thread_rng().gen::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+        let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+        let mut sessions = self.sessions.lock();
+
+        if sessions.len() >= MAX_SESSIONS {
+            sessions.retain(|_, session| session.last_used.elapsed() < idle_timeout);
+        }
+
+        if sessions.len() >= MAX_SESSIONS {
+            bail!("Too many sessions");
+        }
+
+        sessions.insert(session_id.clone(), Session::new(server, &token_response));
+
+        Ok(Some(Login {
+            session_id,
+            return_to: pending_login.return_to,
+        }))
+    }
+
+    /// Ends the session, if it belongs to `server`.
+    pub fn end(&self, server: &str, session_id: &str) {
+        let mut sessions = self.sessions.lock();
+
+        if sessions.get(session_id).is_some_and(|session| session.server == server) {
+            sessions.remove(session_id);
+        }
+    }
+
+    /// Returns the access token of the session, refreshed first if it is about to expire.
+    /// `None` if there is no such session or it has ended.
+    pub async fn access_token(
+        &self,
+        oidc: &OidcClient,
+        server: &str,
+        config: &server::Session,
+        session_id: &str,
+    ) -> Option<AccessToken> {
+        let refresh_token = {
+            let mut sessions = self.sessions.lock();
+            let session = sessions.get_mut(session_id).filter(|session| session.server == server)?;
+
+            if session.last_used.elapsed() >= Duration::from_secs(config.idle_timeout_secs) {
+                sessions.remove(session_id);
+                return None;
+            }
+
+            session.last_used = Instant::now();
+
+            let refresh_before = Duration::from_secs(config.refresh_before_expiry_secs);
+            let is_expiring = session.expires_at
+                .is_some_and(|expires_at| expires_at.saturating_duration_since(Instant::now()) <= refresh_before);
+
+            match &session.refresh_token {
+                Some(refresh_token) if is_expiring => refresh_token.clone(),
+                _ => return Some(session.access_token.clone()),
+            }
+        };
+
+        self.refreshes.run(session_id, || self.refresh(oidc, session_id, refresh_token)).await
+    }
+
+    async fn refresh(&self, oidc: &OidcClient, session_id: &str, refresh_token: RefreshToken) -> Option<AccessToken> {
+        let access_token = {
+            let sessions = self.sessions.lock();
+            let session = sessions.get(session_id)?;
+
+            // Refreshed by another request since `refresh_token` was read
+            if session.refresh_token.as_ref().map(RefreshToken::secret) != Some(refresh_token.secret()) {
+                return Some(session.access_token.clone());
+            }
+
+            session.access_token.clone()
+        };
+
+        let client_assertion = oidc.client_assertion();
+        // Introspection will answer as unavailable, too
+        let oidc = match oidc.get() {
+            Some(oidc) => oidc,
+            None => return Some(access_token),
+        };
+
+        let token_response = oidc.exchange_refresh_token(&refresh_token)
+            .request_async(|request| async_client::authenticated_http_client(client_assertion, request))
+            .await;
+        let mut sessions = self.sessions.lock();
+
+        match token_response {
+            Ok(token_response) => {
+                let session = sessions.get_mut(session_id)?;
+
+                session.update(&token_response);
+
+                Some(session.access_token.clone())
+            },
+            Err(RequestTokenError::ServerResponse(err)) => {
+                eprintln!("Session refresh was rejected, ending the session: {}", err);
+                sessions.remove(session_id);
+
+                None
+            },
+            // The current token may still be good, introspection decides
+            Err(err) => {
+                eprintln!("Session refresh failed: {:#}", anyhow::Error::new(err));
+
+                Some(access_token)
+            },
+        }
+    }
+}
+
+/// The client with the callback as redirect URL, which the provider insists on for code flows.
+fn redirecting_client(oidc: &Client, config: &server::Session) -> Result<Client> {
+    let redirect_url = RedirectUrl::new(config.redirect_url.clone())
+        .context("Failed to create session redirect URL")?;
+
+    Ok(oidc.clone().set_redirect_uri(redirect_url))
+}
+
+/// The session ID sent by the browser, if any.
+pub fn session_id<'a>(headers: &'a HeaderMap, cookie: &str) -> Option<&'a str> {
+    super::cookie_value(headers, cookie)
+}
+
+/// Keeps the session ID from upstreams, which could take over the session otherwise.
+pub fn remove_session_cookie(headers: &mut HeaderMap, cookie: &str) {
+    super::remove_cookie(headers, cookie);
+}
+
+/// The `Set-Cookie` value handing the session to the browser.
+pub fn session_cookie(config: &server::Session, session_id: &str, secure: bool) -> Result<HeaderValue> {
+    cookie(&format!("{}={}; Path=/", config.cookie, session_id), secure)
+}
+
+/// The `Set-Cookie` value ending the session in the browser.
+pub fn end_session_cookie(config: &server::Session, secure: bool) -> Result<HeaderValue> {
+    cookie(&format!("{}=; Path=/; Max-Age=0", config.cookie), secure)
+}
+
+/// The `Set-Cookie` value binding a login to the browser that started it, see `Sessions::complete_login`.
+/// Carries a hash of `state`, only for the callback and as long as the login may take.
+pub fn login_state_cookie(config: &server::Session, state: &str, secure: bool) -> Result<HeaderValue> {
+    cookie(&format!(
+        "{}={}; Path={}; Max-Age={}",
+        login_state_cookie_name(config),
+        hash_state(state),
+        config.callback_path,
+        LOGIN_TIMEOUT.as_secs(),
+    ), secure)
+}
+
+/// The `Set-Cookie` value removing `login_state_cookie` once the login is done.
+pub fn remove_login_state_cookie(config: &server::Session, secure: bool) -> Result<HeaderValue> {
+    cookie(&format!("{}=; Path={}; Max-Age=0", login_state_cookie_name(config), config.callback_path), secure)
+}
+
+fn cookie(cookie: &str, secure: bool) -> Result<HeaderValue> {
+    // `Lax` still sends the cookie when the provider sends the browser back to the callback
+    let mut cookie = format!("{}; HttpOnly; SameSite=Lax", cookie);
+
+    if secure {
+        cookie.push_str("; Secure");
+    }
+
+    HeaderValue::from_str(&cookie).context("Invalid session cookie")
+}
+
+fn login_state_cookie_name(config: &server::Session) -> String {
+    format!("{}_login", config.cookie)
+}
+
+fn hash_state(state: &str) -> String {
+    base64::encode_config(digest(&SHA256, state.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::COOKIE;
+
+    use super::*;
+
+    #[test]
+    fn session_cookies_are_kept_from_upstreams() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark; gateway_session=abc"));
+        headers.append(COOKIE, HeaderValue::from_static("lang=en"));
+
+        assert_eq!(session_id(&headers, "gateway_session"), Some("abc"));
+
+        remove_session_cookie(&mut headers, "gateway_session");
+
+        assert_eq!(headers[COOKIE], "theme=dark; lang=en");
+        assert_eq!(session_id(&headers, "gateway_session"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("gateway_session=abc"));
+        remove_session_cookie(&mut headers, "gateway_session");
+
+        assert!(!headers.contains_key(COOKIE));
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::Future;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+/// Coalesces concurrent computations for the same key.
+/// The first caller computes the value, everyone arriving meanwhile waits for its result.
+/// Results are not cached beyond that.
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, Flight<T>>>,
+    next_id: AtomicU64,
+}
+
+struct Flight<T> {
+    /// Tells the computing caller's `Slot` whether the entry is still its own.
+    id: u64,
+    receiver: watch::Receiver<Option<T>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            in_flight: <_>::default(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: &str, f: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let mut f = Some(f);
+
+        loop {
+            // Looked up and claimed under one lock, so only one caller computes at a time
+            let leader = match self.in_flight.lock().entry(key.to_owned()) {
+                Entry::Occupied(entry) => Err(entry.get().receiver.clone()),
+                Entry::Vacant(entry) => {
+                    let (sender, receiver) = watch::channel(None);
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+                    entry.insert(Flight { id, receiver });
+
+                    Ok((sender, id))
+                },
+            };
+
+            let mut receiver = match leader {
+                Err(receiver) => receiver,
+                Ok((sender, id)) => {
+                    // Frees the slot even if this future is dropped
+                    let _slot = Slot { single_flight: self, key, id };
+                    let f = f.take().expect("BUG: computed twice");
+                    let value = f().await;
+
+                    let _ = sender.send(Some(value.clone()));
+
+                    return value;
+                },
+            };
+
+            loop {
+                if let Some(value) = receiver.borrow().clone() {
+                    return value;
+                }
+
+                if receiver.changed().await.is_err() {
+                    break;
+                }
+            }
+
+            // The computing caller went away, possibly right after sending its result
+            let value = receiver.borrow().clone();
+
+            if let Some(value) = value {
+                return value;
+            }
+        }
+    }
+}
+
+struct Slot<'a, T> {
+    single_flight: &'a SingleFlight<T>,
+    key: &'a str,
+    id: u64,
+}
+
+impl<T> Drop for Slot<'_, T> {
+    fn drop(&mut self) {
+        let mut in_flight = self.single_flight.in_flight.lock();
+
+        // A caller taking over after this one went away may have claimed the key already
+        if in_flight.get(self.key).is_some_and(|flight| flight.id == self.id) {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future;
+
+    use tokio::time::{self, Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_computation() {
+        let single_flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+        let compute = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            time::sleep(Duration::from_millis(50)).await;
+            42
+        };
+
+        let results = future::join_all((0..10).map(|_| single_flight.run("token", compute))).await;
+
+        assert_eq!(results, vec![42; 10]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(single_flight.in_flight.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned_computation_is_taken_over() {
+        let single_flight = SingleFlight::new();
+        let mut leader = Box::pin(single_flight.run("token", future::pending));
+        let mut follower = Box::pin(single_flight.run("token", || async { 2 }));
+
+        assert!(futures::poll!(leader.as_mut()).is_pending());
+        assert!(futures::poll!(follower.as_mut()).is_pending());
+
+        drop(leader);
+
+        assert_eq!(follower.await, 2);
+    }
+
+    #[tokio::test]
+    async fn late_callers_join_the_computation_taken_over() {
+        let single_flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+        let compute = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            time::sleep(Duration::from_millis(50)).await;
+            3
+        };
+        let mut leader = Box::pin(single_flight.run("token", future::pending));
+        let mut follower = Box::pin(single_flight.run("token", compute));
+
+        assert!(futures::poll!(leader.as_mut()).is_pending());
+        assert!(futures::poll!(follower.as_mut()).is_pending());
+
+        drop(leader);
+
+        // The follower took over, the first leader's slot must not have removed its entry
+        assert!(futures::poll!(follower.as_mut()).is_pending());
+        assert_eq!(single_flight.in_flight.lock().len(), 1);
+
+        let late = single_flight.run("token", compute);
+
+        assert_eq!(future::join(follower, late).await, (3, 3));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(single_flight.in_flight.lock().is_empty());
+    }
+}
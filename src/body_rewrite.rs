@@ -0,0 +1,190 @@
+//! Find/replace on streamed upstream response bodies, see `body_rewrite` of servers.
+
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use futures::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response};
+
+use crate::config::Server;
+
+pub struct BodyRewrite {
+    rules: Arc<Vec<(Vec<u8>, Vec<u8>)>>,
+    content_types: Vec<String>,
+    max_bytes: u64,
+}
+
+impl BodyRewrite {
+    /// Returns `None` if the server has no `body_rewrite` rules.
+    pub fn new(server: &Server) -> Result<Option<Self>> {
+        if server.body_rewrite.is_empty() {
+            return Ok(None)
+        }
+
+        if server.body_rewrite.iter().any(|rule| rule.from.is_empty()) {
+            bail!("`from` of `body_rewrite` rules must not be empty");
+        }
+
+        let rules = server.body_rewrite.iter()
+            .map(|rule| (rule.from.clone().into_bytes(), rule.to.clone().into_bytes()))
+            .collect();
+
+        Ok(Some(Self {
+            rules: Arc::new(rules),
+            content_types: server.body_rewrite_content_types.iter()
+                .map(|content_type| content_type.to_ascii_lowercase())
+                .collect(),
+            max_bytes: server.body_rewrite_max_bytes,
+        }))
+    }
+
+    /// Rewrites the body while it is streamed to the client, if its type is configured
+    /// and it isn't known to exceed `max_bytes`. Compressed bodies are left alone.
+    pub fn apply(&self, response: &mut Response<Body>) {
+        let headers = response.headers();
+        let media_type = headers.get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+        let content_length = headers.get(CONTENT_LENGTH)
+            .and_then(|content_length| content_length.to_str().ok())
+            .and_then(|content_length| content_length.parse::<u64>().ok());
+
+        if !media_type.is_some_and(|media_type| self.content_types.contains(&media_type))
+            || headers.contains_key(CONTENT_ENCODING)
+            || content_length.is_some_and(|content_length| content_length > self.max_bytes)
+        {
+            return
+        }
+
+        let headers = response.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+
+        let body = std::mem::take(response.body_mut());
+        let rewriter = Rewriter::new(self.rules.clone(), self.max_bytes);
+        let chunks = stream::unfold(Some((body, rewriter)), |state| async move {
+            let (mut body, mut rewriter) = state?;
+
+            match body.next().await {
+                Some(Ok(chunk)) => Some((Ok(rewriter.push(&chunk)), Some((body, rewriter)))),
+                Some(Err(err)) => Some((Err(err), None)),
+                None => Some((Ok(rewriter.finish()), None)),
+            }
+        });
+
+        *response.body_mut() = Body::wrap_stream(chunks);
+    }
+}
+
+/// Holds back the end of a chunk while it may be the start of a match.
+struct Rewriter {
+    rules: Arc<Vec<(Vec<u8>, Vec<u8>)>>,
+    max_from_len: usize,
+    pending: Vec<u8>,
+    /// Input left to rewrite, the rest is passed through as is.
+    remaining: u64,
+}
+
+impl Rewriter {
+    fn new(rules: Arc<Vec<(Vec<u8>, Vec<u8>)>>, max_bytes: u64) -> Self {
+        Self {
+            max_from_len: rules.iter().map(|(from, _)| from.len()).max().unwrap_or_default(),
+            rules,
+            pending: Vec::new(),
+            remaining: max_bytes,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        if self.remaining == 0 {
+            return Bytes::copy_from_slice(chunk)
+        }
+
+        let budget = chunk.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+        self.remaining -= budget as u64;
+        self.pending.extend_from_slice(&chunk[..budget]);
+
+        let mut output = self.rewrite(self.remaining == 0);
+        output.extend_from_slice(&chunk[budget..]);
+
+        output.into()
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.rewrite(true).into()
+    }
+
+    /// Rewrites `pending`, keeping a possibly incomplete match at its end unless `last`.
+    fn rewrite(&mut self, last: bool) -> Vec<u8> {
+        let input = std::mem::take(&mut self.pending);
+        let mut output = Vec::with_capacity(input.len());
+        let mut position = 0;
+        let mut unmatched_start = 0;
+
+        while position < input.len() {
+            if !last && input.len() - position < self.max_from_len {
+                self.pending = input[position..].to_vec();
+                break;
+            }
+
+            let rule = self.rules.iter().find(|(from, _)| input[position..].starts_with(from));
+
+            match rule {
+                Some((from, to)) => {
+                    output.extend_from_slice(&input[unmatched_start..position]);
+                    output.extend_from_slice(to);
+                    position += from.len();
+                    unmatched_start = position;
+                },
+                None => position += 1,
+            }
+        }
+
+        output.extend_from_slice(&input[unmatched_start..position]);
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(max_bytes: u64) -> Rewriter {
+        let rules = vec![
+            (b"http://internal:8080".to_vec(), b"https://example.org".to_vec()),
+            (b"secret".to_vec(), b"".to_vec()),
+        ];
+
+        Rewriter::new(Arc::new(rules), max_bytes)
+    }
+
+    fn rewrite(rewriter: &mut Rewriter, chunks: &[&str]) -> String {
+        let mut output = Vec::new();
+
+        for chunk in chunks {
+            output.extend_from_slice(&rewriter.push(chunk.as_bytes()));
+        }
+
+        output.extend_from_slice(&rewriter.finish());
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn matches_spanning_chunks_are_replaced() {
+        assert_eq!(
+            rewrite(&mut rewriter(1024), &["<a href=\"http://inter", "nal:8080/x\">sec", "ret</a>"]),
+            "<a href=\"https://example.org/x\"></a>",
+        );
+        assert_eq!(rewrite(&mut rewriter(1024), &["http://internal", ""]), "http://internal");
+        assert_eq!(rewrite(&mut rewriter(1024), &["se", "cre", "t", "s"]), "s");
+    }
+
+    #[test]
+    fn input_beyond_max_bytes_is_passed_through() {
+        assert_eq!(rewrite(&mut rewriter(10), &["secret", "secret"]), "secret");
+        assert_eq!(rewrite(&mut rewriter(3), &["secret"]), "secret");
+    }
+}
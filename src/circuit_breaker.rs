@@ -0,0 +1,135 @@
+//! Stops forwarding to upstreams that keep failing, giving them time to recover.
+
+use parking_lot::Mutex;
+use tokio::time::{Duration, Instant};
+
+use crate::config;
+
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// Requests pass. Counts failures since `window_start`.
+    Closed { failures: u32, window_start: Instant },
+    /// Requests are rejected until `until`.
+    Open { until: Instant },
+    /// A single trial request decides whether to close or reopen.
+    HalfOpen { trial_in_flight: bool },
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: &config::server::CircuitBreaker) -> Self {
+        Self {
+            name: name.to_owned(),
+            failure_threshold: config.failure_threshold.max(1),
+            window: Duration::from_secs(config.window_secs),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(State::Closed { failures: 0, window_start: Instant::now() }),
+        }
+    }
+
+    /// Returns `None` if the request must be rejected.
+    /// The outcome has to be reported through the returned [`Attempt`].
+    pub fn try_acquire(&self) -> Option<Attempt<'_>> {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+
+        match *state {
+            State::Closed { .. } => Some(Attempt::new(self, false)),
+            State::Open { until } if now < until => None,
+            State::Open { .. } | State::HalfOpen { trial_in_flight: false } => {
+                if let State::Open { .. } = *state {
+                    eprintln!("Circuit breaker for {} is half-open, sending a trial request", self.name);
+                }
+
+                *state = State::HalfOpen { trial_in_flight: true };
+
+                Some(Attempt::new(self, true))
+            },
+            State::HalfOpen { trial_in_flight: true } => None,
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match *self.state.lock() {
+            State::Closed { .. } => "closed",
+            State::Open { until } if Instant::now() < until => "open",
+            State::Open { .. } | State::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    fn record(&self, is_trial: bool, success: bool) {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+
+        match (*state, success) {
+            (State::HalfOpen { .. }, true) if is_trial => {
+                eprintln!("Circuit breaker for {} closed", self.name);
+                *state = State::Closed { failures: 0, window_start: now };
+            },
+            (State::HalfOpen { .. }, false) if is_trial => self.open(&mut state, now),
+            (State::Closed { failures, window_start }, false) => {
+                let (failures, window_start) = match now.duration_since(window_start) < self.window {
+                    true => (failures + 1, window_start),
+                    false => (1, now),
+                };
+
+                if failures >= self.failure_threshold {
+                    self.open(&mut state, now);
+                } else {
+                    *state = State::Closed { failures, window_start };
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn open(&self, state: &mut State, now: Instant) {
+        eprintln!("Circuit breaker for {} opened for {}s", self.name, self.cooldown.as_secs());
+        *state = State::Open { until: now + self.cooldown };
+    }
+
+    /// A trial request went away without an outcome, let the next request try instead.
+    fn abandon_trial(&self) {
+        let mut state = self.state.lock();
+
+        if let State::HalfOpen { .. } = *state {
+            *state = State::HalfOpen { trial_in_flight: false };
+        }
+    }
+}
+
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    is_trial: bool,
+    recorded: bool,
+}
+
+impl<'a> Attempt<'a> {
+    fn new(breaker: &'a CircuitBreaker, is_trial: bool) -> Self {
+        Self {
+            breaker,
+            is_trial,
+            recorded: false,
+        }
+    }
+
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(self.is_trial, success);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.recorded && self.is_trial {
+            self.breaker.abandon_trial();
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Compresses response bodies while they are streamed to clients, see `[compression]`.
+
+use std::io;
+
+use async_compression::Level;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
+use futures::TryStreamExt;
+use hyper::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY, HeaderValue};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::config;
+use crate::config::compression::Encoding;
+
+/// Media types that are compressed already, compressing them again only costs time.
+const COMPRESSED_TYPES: &[&str] = &[
+    "image/",
+    "video/",
+    "audio/",
+    "font/woff",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zstd",
+    "application/octet-stream",
+];
+
+/// Media types that are streamed in small pieces, which compressors would hold back.
+const STREAMED_TYPES: &[&str] = &[
+    "text/event-stream",
+    "application/grpc",
+];
+
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_bytes: u64,
+}
+
+impl Compression {
+    pub fn new(config: &config::Compression) -> Self {
+        Self {
+            encodings: config.encodings.clone(),
+            min_bytes: config.min_bytes,
+        }
+    }
+
+    /// Compresses the body with the first configured encoding that `request_headers` accept,
+    /// unless the upstream compressed it already, its type is compressed anyway or it is too small.
+    pub fn apply(&self, request_headers: &HeaderMap, response: &mut Response<Body>) {
+        let encoding = match self.negotiate(request_headers) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        if !self.is_compressible(response) {
+            return
+        }
+
+        let headers = response.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        // The compressed body differs byte for byte, so strong validators would be wrong
+        if let Some(etag) = headers.get(ETAG).filter(|etag| !etag.as_bytes().starts_with(b"W/")) {
+            let mut weak_etag = b"W/".to_vec();
+            weak_etag.extend_from_slice(etag.as_bytes());
+
+            if let Ok(weak_etag) = HeaderValue::from_bytes(&weak_etag) {
+                headers.insert(ETAG, weak_etag);
+            }
+        }
+
+        let body = std::mem::take(response.body_mut());
+        let body = StreamReader::new(body.map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+
+        *response.body_mut() = match encoding {
+            // The default quality takes far too long for compressing on the fly
+            Encoding::Br => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(body, Level::Precise(4)))),
+            Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(body))),
+        };
+    }
+
+    /// The first configured encoding the client accepts, according to `Accept-Encoding`.
+    fn negotiate(&self, request_headers: &HeaderMap) -> Option<Encoding> {
+        let accepted = request_headers.get_all(ACCEPT_ENCODING).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next()?.trim().to_ascii_lowercase();
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|quality| quality.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                Some((name, quality))
+            })
+            .filter(|(name, _)| !name.is_empty())
+            .collect::<Vec<_>>();
+
+        self.encodings.iter()
+            .copied()
+            .find(|encoding| {
+                let quality = accepted.iter()
+                    .find(|(name, _)| name == encoding.name())
+                    .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+                    .map(|(_, quality)| *quality);
+
+                quality.is_some_and(|quality| quality > 0.0)
+            })
+    }
+
+    fn is_compressible(&self, response: &Response<Body>) -> bool {
+        let headers = response.headers();
+        let media_type = headers.get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let content_length = headers.get(CONTENT_LENGTH)
+            .and_then(|content_length| content_length.to_str().ok())
+            .and_then(|content_length| content_length.parse::<u64>().ok());
+        let no_transform = headers.get_all(CACHE_CONTROL).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+
+        // Partial content and bodiless statuses are left alone
+        response.status() == StatusCode::OK
+            && !headers.contains_key(CONTENT_ENCODING)
+            && !headers.contains_key(CONTENT_RANGE)
+            && !no_transform
+            && !media_type.is_empty()
+            && !COMPRESSED_TYPES.iter().any(|compressed| media_type.starts_with(compressed))
+            && !STREAMED_TYPES.iter().any(|streamed| media_type.starts_with(streamed))
+            && !content_length.is_some_and(|content_length| content_length < self.min_bytes)
+    }
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Br => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipDecoder;
+    use hyper::header::HeaderName;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn compression() -> Compression {
+        Compression::new(&config::Compression {
+            encodings: vec![Encoding::Br, Encoding::Gzip],
+            min_bytes: 16,
+        })
+    }
+
+    fn accept_encoding(value: &'static str) -> HeaderMap {
+        [(ACCEPT_ENCODING, HeaderValue::from_static(value))].into_iter().collect()
+    }
+
+    fn response(headers: &[(HeaderName, &'static str)], body: &'static str) -> Response<Body> {
+        let mut response = Response::builder();
+
+        for (name, value) in headers {
+            response = response.header(name, *value);
+        }
+
+        response.body(Body::from(body)).unwrap()
+    }
+
+    #[test]
+    fn configured_encodings_are_preferred() {
+        let compression = compression();
+
+        assert_eq!(compression.negotiate(&accept_encoding("gzip, deflate, br")), Some(Encoding::Br));
+        assert_eq!(compression.negotiate(&accept_encoding("gzip, br;q=0")), Some(Encoding::Gzip));
+        assert_eq!(compression.negotiate(&accept_encoding("*")), Some(Encoding::Br));
+        assert_eq!(compression.negotiate(&accept_encoding("identity")), None);
+        assert_eq!(compression.negotiate(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn text_is_compressed() {
+        let body = "hello hello hello hello hello hello";
+        let mut response = response(&[
+            (CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (CONTENT_LENGTH, "35"),
+            (ETAG, "\"v1\""),
+        ], body);
+
+        compression().apply(&accept_encoding("gzip"), &mut response);
+
+        let headers = response.headers();
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[VARY], "accept-encoding");
+        assert_eq!(headers[ETAG], "W/\"v1\"");
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+
+        let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut decompressed = String::new();
+        GzipDecoder::new(&compressed[..]).read_to_string(&mut decompressed).await.unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn compressed_and_small_responses_are_left_alone() {
+        let compression = compression();
+        let accept_encoding = accept_encoding("gzip");
+        let long = "hello hello hello hello hello hello";
+
+        for mut response in [
+            response(&[(CONTENT_TYPE, "image/png")], long),
+            response(&[(CONTENT_TYPE, "text/plain"), (CONTENT_ENCODING, "br")], long),
+            response(&[(CONTENT_TYPE, "text/plain"), (CONTENT_LENGTH, "5")], "hello"),
+            response(&[(CONTENT_TYPE, "text/event-stream")], long),
+            response(&[(CONTENT_TYPE, "text/plain"), (CACHE_CONTROL, "no-transform")], long),
+        ] {
+            compression.apply(&accept_encoding, &mut response);
+
+            assert!(!response.headers().contains_key(VARY));
+        }
+    }
+}
@@ -4,6 +4,7 @@ use std::fs;
 use anyhow::{Result, Context};
 use serde::Deserialize;
 
+mod env;
 
 pub mod openid;
 pub use openid::Openid;
@@ -11,12 +12,73 @@ pub use openid::Openid;
 pub mod server;
 pub use server::Server;
 
+pub mod routes;
+pub use routes::{AllowedMethods, Routes};
+
+pub mod authorization;
+pub use authorization::Authorization;
+
+pub mod limits;
+pub use limits::Limits;
+
+pub mod access_log;
+pub use access_log::AccessLog;
+
+pub mod tls;
+pub use tls::Tls;
+
+pub mod admin;
+pub use admin::Admin;
+
+pub mod forwarding;
+pub use forwarding::Forwarding;
+
+pub mod http;
+pub use http::Http;
+
+pub mod listener;
+pub use listener::Listener;
+
+pub mod cache;
+pub use cache::Cache;
+
+pub mod compression;
+pub use compression::Compression;
+
+pub mod http1;
+pub use http1::Http1;
+
+pub mod http2;
+pub use http2::Http2;
+
+pub mod tracing;
+pub use tracing::Tracing;
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub openid: Openid,
     #[serde(rename = "server")]
     pub servers: Vec<Server>,
+    #[serde(default)]
+    pub limits: Limits,
+    pub access_log: Option<AccessLog>,
+    #[serde(default)]
+    pub tls: Tls,
+    pub admin: Option<Admin>,
+    #[serde(default)]
+    pub forwarding: Forwarding,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub listener: Listener,
+    pub cache: Option<Cache>,
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub http1: Http1,
+    #[serde(default)]
+    pub http2: Http2,
+    pub tracing: Option<Tracing>,
 }
 
 impl Config {
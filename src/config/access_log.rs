@@ -0,0 +1,20 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLog {
+    /// File to append to. Logs to stdout if unset.
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub format: Format,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Combined,
+    Json,
+}
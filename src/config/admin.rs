@@ -0,0 +1,72 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Deserializer, de};
+
+use super::env::optional_env_loadable;
+
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub listen: SocketAddr,
+    /// Allow binding the admin interface to a non-loopback address. Requires `token`.
+    pub allow_remote: bool,
+    /// Required as `Authorization: Bearer <token>` on all admin requests, if set.
+    pub token: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for Admin {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct RawAdmin {
+            listen: SocketAddr,
+            #[serde(default)]
+            allow_remote: bool,
+            #[serde(default, deserialize_with = "optional_env_loadable")]
+            token: Option<String>,
+        }
+
+        let RawAdmin { listen, allow_remote, token } = RawAdmin::deserialize(de)?;
+
+        if token.as_deref() == Some("") {
+            return Err(de::Error::custom("`token` must not be empty"));
+        }
+
+        // Anyone reaching a remote admin interface could drain or stop the gateway otherwise
+        if allow_remote && token.is_none() {
+            return Err(de::Error::custom("`allow_remote` requires a `token`"));
+        }
+
+        Ok(Self { listen, allow_remote, token })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(config: &str) -> Result<Admin, toml::de::Error> {
+        toml::from_str(config)
+    }
+
+    #[test]
+    fn remote_admin_requires_a_token() {
+        assert!(admin(r#"listen = "127.0.0.1:9901""#).is_ok());
+        assert!(admin(r#"
+            listen = "0.0.0.0:9901"
+            allow_remote = true
+        "#).is_err());
+        assert!(admin(r#"
+            listen = "0.0.0.0:9901"
+            allow_remote = true
+            token = ""
+        "#).is_err());
+        assert!(admin(r#"
+            listen = "0.0.0.0:9901"
+            allow_remote = true
+            token = "secret"
+        "#).is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Deserializer};
+
+use super::Routes;
+
+/// Restricts routes to tokens with certain roles or groups.
+/// Matching routes always require authentication.
+///
+/// ```toml
+/// authorization = [
+///     { routes = ['/admin/.*'], roles = "admin,ops" },
+///     { routes = ['/billing/.*'], rule = { all_of = [{ role = "billing" }, { group = "finance" }] } },
+/// ]
+/// ```
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Authorization {
+    pub routes: Routes,
+    /// Any of these roles is sufficient, given as a list or comma-separated.
+    #[serde(default, deserialize_with = "deserialize_roles")]
+    pub roles: Vec<String>,
+    /// Evaluated in addition to `roles`.
+    /// Without `roles` and `rule`, any authenticated user is allowed.
+    pub rule: Option<Rule>,
+}
+
+impl Authorization {
+    pub fn allows(&self, roles: &[&str], groups: &[&str]) -> bool {
+        let has_any_role = self.roles.is_empty()
+            || self.roles.iter().any(|role| roles.contains(&role.as_str()));
+
+        has_any_role && self.rule.as_ref().is_none_or(|rule| rule.evaluate(roles, groups))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Rule {
+    Role(String),
+    Group(String),
+    AnyOf(Vec<Rule>),
+    AllOf(Vec<Rule>),
+    Not(Box<Rule>),
+}
+
+impl Rule {
+    pub fn evaluate(&self, roles: &[&str], groups: &[&str]) -> bool {
+        match self {
+            Rule::Role(role) => roles.contains(&role.as_str()),
+            Rule::Group(group) => groups.contains(&group.as_str()),
+            Rule::AnyOf(rules) => rules.iter().any(|rule| rule.evaluate(roles, groups)),
+            Rule::AllOf(rules) => rules.iter().all(|rule| rule.evaluate(roles, groups)),
+            Rule::Not(rule) => !rule.evaluate(roles, groups),
+        }
+    }
+}
+
+fn deserialize_roles<'de, D>(de: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Roles {
+        Separated(String),
+        List(Vec<String>),
+    }
+
+    let roles = match Roles::deserialize(de)? {
+        Roles::Separated(roles) => roles.split(',')
+            .map(str::trim)
+            .filter(|role| !role.is_empty())
+            .map(String::from)
+            .collect(),
+        Roles::List(roles) => roles,
+    };
+
+    Ok(roles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authorization(config: &str) -> Authorization {
+        toml::from_str(&format!("routes = ['.*']\n{}", config)).unwrap()
+    }
+
+    #[test]
+    fn roles_shorthand_requires_any_role() {
+        let authorization = authorization(r#"roles = "admin, ops""#);
+
+        assert!(authorization.allows(&["ops"], &[]));
+        assert!(authorization.allows(&["user", "admin"], &[]));
+        assert!(!authorization.allows(&["user"], &["admin"]));
+    }
+
+    #[test]
+    fn all_of_requires_every_rule() {
+        let authorization = authorization(r#"rule = { all_of = [{ role = "billing" }, { group = "finance" }] }"#);
+
+        assert!(authorization.allows(&["billing"], &["finance"]));
+        assert!(!authorization.allows(&["billing"], &[]));
+        assert!(!authorization.allows(&[], &["finance"]));
+    }
+
+    #[test]
+    fn any_of_requires_one_rule() {
+        let authorization = authorization(r#"rule = { any_of = [{ role = "admin" }, { group = "ops" }] }"#);
+
+        assert!(authorization.allows(&["admin"], &[]));
+        assert!(authorization.allows(&[], &["ops"]));
+        assert!(!authorization.allows(&["ops"], &["admin"]));
+    }
+
+    #[test]
+    fn not_negates_rules() {
+        let authorization = authorization(r#"
+            roles = ["analyst", "admin"]
+            rule = { not = { any_of = [{ role = "contractor" }, { group = "suspended" }] } }
+        "#);
+
+        assert!(authorization.allows(&["analyst"], &[]));
+        assert!(!authorization.allows(&["analyst", "contractor"], &[]));
+        assert!(!authorization.allows(&["admin"], &["suspended"]));
+        assert!(!authorization.allows(&["contractor"], &[]));
+    }
+}
@@ -0,0 +1,22 @@
+use serde::Deserialize;
+
+/// In-memory cache for `GET` responses, shared by all servers.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Cache {
+    /// The least recently used entries are evicted beyond this.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Larger responses, and responses without `Content-Length`, are not cached.
+    /// Requests waiting for the same response fetch it on their own then.
+    #[serde(default = "default_max_object_bytes")]
+    pub max_object_bytes: u64,
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+fn default_max_object_bytes() -> u64 {
+    1024 * 1024
+}
@@ -0,0 +1,28 @@
+use serde::Deserialize;
+
+/// Compresses responses for clients accepting it, unless the upstream already did. Shared by all servers.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+    /// In order of preference, the first one the client accepts is used.
+    #[serde(default = "default_encodings")]
+    pub encodings: Vec<Encoding>,
+    /// Smaller responses are sent as they are. Responses of unknown length are always compressed.
+    #[serde(default = "default_min_bytes")]
+    pub min_bytes: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Br,
+    Gzip,
+}
+
+fn default_encodings() -> Vec<Encoding> {
+    vec![Encoding::Br, Encoding::Gzip]
+}
+
+fn default_min_bytes() -> u64 {
+    1024
+}
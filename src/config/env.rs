@@ -0,0 +1,34 @@
+use std::env;
+
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, de};
+
+/// Deserializes a string, resolving `ENV[NAME]` to the value of the environment variable `NAME`.
+pub fn env_loadable<'de, D: Deserializer<'de>>(de: D) -> Result<String, D::Error> {
+    let value = String::deserialize(de)?;
+
+    resolve(value).map_err(de::Error::custom)
+}
+
+pub fn optional_env_loadable<'de, D: Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+    let value = match Option::<String>::deserialize(de)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    resolve(value).map(Some).map_err(de::Error::custom)
+}
+
+fn resolve(value: String) -> anyhow::Result<String> {
+    let env_key = match extract_env_key(&value) {
+        Some(env_key) => env_key,
+        None => return Ok(value),
+    };
+
+    env::var(env_key)
+        .with_context(|| format!("failed to load env var {env_key:?}"))
+}
+
+fn extract_env_key(value: &str) -> Option<&str> {
+    value.strip_prefix("ENV[")?.strip_suffix(']')
+}
@@ -0,0 +1,36 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+/// Controls which peers may tell us the real client address.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Forwarding {
+    /// Peers whose `X-Forwarded-For` header is trusted.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// How many `X-Forwarded-For` entries (from the right) may be skipped
+    /// while looking for the client.
+    #[serde(default = "default_trusted_hops")]
+    pub trusted_hops: usize,
+}
+
+impl Forwarding {
+    pub fn is_trusted_proxy(&self, addr: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(addr))
+    }
+}
+
+impl Default for Forwarding {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: Vec::new(),
+            trusted_hops: default_trusted_hops(),
+        }
+    }
+}
+
+fn default_trusted_hops() -> usize {
+    1
+}
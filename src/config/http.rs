@@ -0,0 +1,15 @@
+use serde::Deserialize;
+
+/// Settings for upstream http clients.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    /// Maximum idle connections kept per upstream host. Unlimited by default.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long idle connections are kept. Defaults to reqwest's 90 seconds.
+    pub pool_idle_timeout_secs: Option<u64>,
+    /// Send a request to every upstream after startup and log the ones that can't be reached.
+    /// Failures are fatal when started with `--strict-startup`.
+    #[serde(default)]
+    pub check_upstreams_on_start: bool,
+}
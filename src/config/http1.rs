@@ -0,0 +1,31 @@
+use serde::Deserialize;
+
+/// Settings for HTTP/1.1 client connections.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Http1 {
+    /// Serve more than one request per connection. Disabling it also rules out pipelining.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: bool,
+    /// Maximum bytes buffered per connection, which bounds how many pipelined requests
+    /// a client can queue up as well as the size of incoming request headers.
+    /// At least 8192, defaults to hyper's ~400 KiB.
+    pub max_buf_size: Option<usize>,
+    /// Aggregate responses to pipelined requests into fewer writes.
+    #[serde(default)]
+    pub pipeline_flush: bool,
+}
+
+impl Default for Http1 {
+    fn default() -> Self {
+        Self {
+            keep_alive: default_keep_alive(),
+            max_buf_size: None,
+            pipeline_flush: false,
+        }
+    }
+}
+
+fn default_keep_alive() -> bool {
+    true
+}
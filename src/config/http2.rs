@@ -0,0 +1,45 @@
+use serde::Deserialize;
+
+/// Settings for HTTP/2 client connections.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Http2 {
+    /// Streams a single connection may have open at once. Every stream can occupy
+    /// a request slot, so a high value lets one client exhaust `max_concurrent_requests`.
+    #[serde(default = "default_max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+    /// Bytes a client may send per stream before it has to wait for the gateway to read them.
+    /// Larger windows speed up uploads but increase memory held per stream. Defaults to 1 MiB.
+    pub initial_stream_window_size: Option<u32>,
+    /// Like `initial_stream_window_size`, but shared by all streams of a connection. Defaults to 1 MiB.
+    pub initial_connection_window_size: Option<u32>,
+    /// Grow windows based on the measured bandwidth-delay product, overriding the sizes above.
+    #[serde(default)]
+    pub adaptive_window: bool,
+    /// Ping idle clients this often to keep the connection alive and detect dead peers.
+    pub keep_alive_interval_secs: Option<u64>,
+    /// Close the connection if a ping isn't acknowledged within this time.
+    #[serde(default = "default_keep_alive_timeout_secs")]
+    pub keep_alive_timeout_secs: u64,
+}
+
+impl Default for Http2 {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: default_max_concurrent_streams(),
+            initial_stream_window_size: None,
+            initial_connection_window_size: None,
+            adaptive_window: false,
+            keep_alive_interval_secs: None,
+            keep_alive_timeout_secs: default_keep_alive_timeout_secs(),
+        }
+    }
+}
+
+fn default_max_concurrent_streams() -> u32 {
+    100
+}
+
+fn default_keep_alive_timeout_secs() -> u64 {
+    20
+}
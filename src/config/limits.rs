@@ -0,0 +1,41 @@
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    pub queue_timeout_ms: u64,
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+    /// Accepted connections waiting to be handled. Further connections are closed immediately.
+    #[serde(default = "default_max_unaccepted_sockets")]
+    pub max_unaccepted_sockets: usize,
+    /// Log requests turned away because a server's `max_concurrent_requests` are in use.
+    #[serde(default = "default_log_saturation")]
+    pub log_saturation: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: None,
+            queue_timeout_ms: 0,
+            retry_after_secs: default_retry_after_secs(),
+            max_unaccepted_sockets: default_max_unaccepted_sockets(),
+            log_saturation: default_log_saturation(),
+        }
+    }
+}
+
+fn default_retry_after_secs() -> u64 {
+    1
+}
+
+fn default_max_unaccepted_sockets() -> usize {
+    100
+}
+
+fn default_log_saturation() -> bool {
+    true
+}
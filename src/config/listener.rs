@@ -0,0 +1,73 @@
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    /// Bind with `SO_REUSEPORT` so several gateway processes can share the same addresses.
+    /// On Linux the kernel spreads incoming connections across all of them.
+    /// On macOS and the BSDs binding succeeds, but connections are not balanced
+    /// and usually all go to the most recently started process.
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Whether IPv6 listeners like `[::]:443` also accept IPv4 connections, as IPv4-mapped addresses.
+    /// Set explicitly instead of relying on the OS default of `IPV6_V6ONLY`, which differs,
+    /// e.g. Linux accepts both by default and the BSDs only IPv6.
+    /// Disable it to bind `0.0.0.0` and `[::]` on the same port. IPv4 listeners are not affected.
+    #[serde(default = "default_dual_stack")]
+    pub dual_stack: bool,
+    /// Enables TCP keepalive on client connections, probing after they were idle this long.
+    /// Keeps load balancers and NATs from silently dropping idle connections.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Time between unanswered probes. Only supported on Linux.
+    pub tcp_keepalive_interval_secs: Option<u64>,
+    /// Unanswered probes before the connection is dropped. Only supported on Linux.
+    pub tcp_keepalive_retries: Option<u32>,
+    /// How often binding is retried while the address is in use, e.g. by a process that is
+    /// still shutting down during a fast restart.
+    #[serde(default = "default_bind_retries")]
+    pub bind_retries: u32,
+    /// Delay before the first retry, doubled for each further one.
+    #[serde(default = "default_bind_retry_delay_ms")]
+    pub bind_retry_delay_ms: u64,
+    /// Bind listeners right away, but don't accept connections until OIDC discovery succeeded,
+    /// so authenticated routes never see a `503` while discovery is still being retried.
+    /// Connections wait in the listen backlog meanwhile.
+    #[serde(default)]
+    pub wait_for_oidc: bool,
+    /// On `SIGTERM` or Ctrl-C, listeners are drained and open connections get this long to complete.
+    /// Connections still open afterwards are closed, so the process exits in bounded time.
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Self {
+            reuse_port: false,
+            dual_stack: default_dual_stack(),
+            tcp_keepalive_secs: None,
+            tcp_keepalive_interval_secs: None,
+            tcp_keepalive_retries: None,
+            bind_retries: default_bind_retries(),
+            bind_retry_delay_ms: default_bind_retry_delay_ms(),
+            wait_for_oidc: false,
+            shutdown_grace_period_secs: default_shutdown_grace_period_secs(),
+        }
+    }
+}
+
+fn default_dual_stack() -> bool {
+    true
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    30
+}
+
+fn default_bind_retries() -> u32 {
+    5
+}
+
+fn default_bind_retry_delay_ms() -> u64 {
+    100
+}
@@ -1,33 +1,104 @@
-use std::env;
+use std::path::PathBuf;
 
-use anyhow::Context;
-use serde::{Deserialize, Deserializer, de};
+use hyper::header::HeaderName;
+use serde::Deserialize;
+
+use super::env::{env_loadable, optional_env_loadable};
+use super::server::deserialize_optional_header_name;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Openid {
     pub issuer_url: String,
     pub introspect_url: String,
+    /// OpenID discovery document to use instead of fetching it from `issuer_url`.
+    pub discovery_document: Option<PathBuf>,
+    /// How often discovery is repeated to pick up rotated keys and changed endpoints,
+    /// unless the provider sends a `max-age`. `0` disables refreshing.
+    #[serde(default = "default_discovery_refresh_secs")]
+    pub discovery_refresh_secs: u64,
+    /// Tokens revoked through the admin interface are also revoked here.
+    pub revocation_url: Option<String>,
+    /// How long tokens revoked through the admin interface are rejected locally.
+    /// Should exceed the lifetime of access tokens, since the provider may still report them as active.
+    #[serde(default = "default_revoked_token_ttl_secs")]
+    pub revoked_token_ttl_secs: u64,
+    /// File with token IDs (`jti` claims) to reject, one per line.
+    /// Reloaded with `POST /jti/reload` on the admin interface.
+    pub jti_denylist: Option<PathBuf>,
     #[serde(deserialize_with = "env_loadable")]
     pub client_id: String,
-    #[serde(deserialize_with = "env_loadable")]
-    pub client_secret: String,
+    /// Required unless `token_endpoint_auth_method` is `private_key_jwt`.
+    #[serde(default, deserialize_with = "optional_env_loadable")]
+    pub client_secret: Option<String>,
+    /// How the client authenticates against the introspection, revocation and token endpoints.
+    #[serde(default)]
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    /// PKCS#8 PEM file with the RSA key signing client assertions for `private_key_jwt`.
+    /// Its public key must be registered with the provider.
+    pub signing_key: Option<PathBuf>,
+    /// Sent as `kid` of client assertions, for providers knowing several keys of the client.
+    pub signing_key_id: Option<String>,
+    /// Key under `resource_access` to read client roles from, in addition to `roles_claim`.
+    /// Defaults to `client_id`.
+    pub resource_access_client: Option<String>,
+    /// Dotted path or JSON pointer to the roles claim, e.g. `realm_access.roles`
+    /// or `/https:~1~1example.org~1roles` for keys containing dots.
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+    /// How long tokens that failed introspection are rejected without asking the provider again.
+    #[serde(default)]
+    pub negative_cache_ttl_secs: u64,
+    /// Longest pause of all introspections when the provider answers 429 or 503,
+    /// whatever its `Retry-After` asks for.
+    #[serde(default = "default_introspection_max_backoff_secs")]
+    pub introspection_max_backoff_secs: u64,
+    /// Send `token_type_hint=access_token` with introspection requests (RFC 7662).
+    /// Disable for providers that reject the hint.
+    #[serde(default = "default_token_type_hint")]
+    pub token_type_hint: bool,
+    /// Header to read access tokens from instead of `Authorization`, e.g. `X-Access-Token`.
+    /// The `Bearer` scheme is optional there. Servers can override it.
+    #[serde(default, deserialize_with = "deserialize_optional_header_name")]
+    pub token_header: Option<HeaderName>,
+    /// Cookie to read access tokens from if the token header is missing. Servers can override it.
+    pub token_cookie: Option<String>,
 }
 
-fn env_loadable<'de, D: Deserializer<'de>>(de: D) -> Result<String, D::Error> {
-    let value = String::deserialize(de)?;
+/// Named like the `token_endpoint_auth_methods_supported` values of the discovery document.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenEndpointAuthMethod {
+    /// `client_id` and `client_secret` as HTTP basic auth.
+    #[default]
+    ClientSecretBasic,
+    /// `client_id` and `client_secret` in the form encoded request body.
+    ClientSecretPost,
+    /// A JWT signed with `signing_key` as `client_assertion` in the form encoded request body (RFC 7523).
+    PrivateKeyJwt,
+}
 
-    let env_key = match extract_env_key(&value) {
-        Some(env_key) => env_key,
-        None => return Ok(value),
-    };
+impl Openid {
+    pub fn resource_access_client(&self) -> &str {
+        self.resource_access_client.as_deref().unwrap_or(&self.client_id)
+    }
+}
 
-    let value = env::var(env_key)
-        .with_context(|| format!("failed to load env var {env_key:?}"))
-        .map_err(de::Error::custom)?;
+fn default_discovery_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_introspection_max_backoff_secs() -> u64 {
+    300
+}
+
+fn default_revoked_token_ttl_secs() -> u64 {
+    86400
+}
 
-    Ok(value)
+fn default_roles_claim() -> String {
+    "realm_access.roles".into()
 }
 
-fn extract_env_key(value: &str) -> Option<&str> {
-    value.strip_prefix("ENV[")?.strip_suffix(']')
+fn default_token_type_hint() -> bool {
+    true
 }
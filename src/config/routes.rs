@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+
+use hyper::Method;
+use regex::RegexSet;
+use serde::{Deserialize, Deserializer, de};
+
+/// Path patterns, each optionally restricted to a set of methods.
+///
+/// Patterns are anchored regexes. A plain string matches all methods:
+///
+/// ```toml
+/// public_routes = ['/version', { pattern = '/items', methods = ["GET"] }]
+/// ```
+#[derive(Debug, Clone)]
+pub struct Routes {
+    patterns: RegexSet,
+    /// Indexed like `patterns`, `None` allows all methods.
+    methods: Vec<Option<HashSet<Method>>>,
+}
+
+impl Routes {
+    pub fn is_match(&self, method: &Method, path: &str) -> bool {
+        self.patterns.matches(path)
+            .iter()
+            .any(|index| match &self.methods[index] {
+                Some(methods) => methods.contains(method),
+                None => true,
+            })
+    }
+
+    /// The configured patterns matching `path` for any method.
+    pub fn matching_patterns(&self, path: &str) -> Vec<&str> {
+        let patterns = self.patterns.patterns();
+
+        self.patterns.matches(path)
+            .iter()
+            .map(|index| unanchored(&patterns[index]))
+            .collect()
+    }
+
+    /// Patterns without regex syntax, which match exactly one path.
+    pub fn literal_paths(&self) -> impl Iterator<Item = &str> {
+        self.patterns.patterns()
+            .iter()
+            .map(|pattern| unanchored(pattern))
+            .filter(|pattern| !pattern.contains(['\\', '.', '+', '*', '?', '(', ')', '|', '[', ']', '{', '}', '^', '$']))
+    }
+}
+
+/// Anchors `pattern` to the whole path. The group keeps alternations like `/a|/b` from
+/// matching anything that merely starts with `/a` or ends with `/b`.
+pub fn anchored(pattern: &str) -> String {
+    format!("^(?:{})$", pattern)
+}
+
+/// Strips what `anchored` added.
+fn unanchored(pattern: &str) -> &str {
+    &pattern["^(?:".len()..pattern.len() - ")$".len()]
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Self {
+            patterns: RegexSet::empty(),
+            methods: Vec::new(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Routes {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Route {
+            Pattern(String),
+            Scoped {
+                pattern: String,
+                methods: Vec<String>,
+            },
+        }
+
+        let routes = Vec::<Route>::deserialize(de)?;
+        let mut patterns = Vec::with_capacity(routes.len());
+        let mut methods = Vec::with_capacity(routes.len());
+
+        for route in routes {
+            match route {
+                Route::Pattern(pattern) => {
+                    patterns.push(anchored(&pattern));
+                    methods.push(None);
+                },
+                Route::Scoped { pattern, methods: route_methods } => {
+                    let route_methods = route_methods.iter()
+                        .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+                        .collect::<Result<HashSet<_>, _>>()
+                        .map_err(de::Error::custom)?;
+
+                    patterns.push(anchored(&pattern));
+                    methods.push(Some(route_methods));
+                },
+            }
+        }
+
+        let patterns = RegexSet::new(&patterns)
+            .map_err(de::Error::custom)?;
+
+        Ok(Self { patterns, methods })
+    }
+}
+
+/// Path patterns that only accept certain methods. The first matching pattern applies:
+///
+/// ```toml
+/// allowed_methods = [{ pattern = '/webhook', methods = ["POST"] }]
+/// ```
+#[derive(Debug, Clone)]
+pub struct AllowedMethods {
+    patterns: RegexSet,
+    /// Indexed like `patterns`.
+    methods: Vec<Vec<Method>>,
+}
+
+impl AllowedMethods {
+    /// The methods allowed for `path`, or `None` if it isn't restricted.
+    pub fn for_path(&self, path: &str) -> Option<&[Method]> {
+        let index = self.patterns.matches(path).iter().next()?;
+
+        Some(&self.methods[index])
+    }
+}
+
+impl Default for AllowedMethods {
+    fn default() -> Self {
+        Self {
+            patterns: RegexSet::empty(),
+            methods: Vec::new(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AllowedMethods {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Route {
+            pattern: String,
+            methods: Vec<String>,
+        }
+
+        let routes = Vec::<Route>::deserialize(de)?;
+        let mut patterns = Vec::with_capacity(routes.len());
+        let mut methods = Vec::with_capacity(routes.len());
+
+        for route in routes {
+            let route_methods = route.methods.iter()
+                .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(de::Error::custom)?;
+
+            if route_methods.is_empty() {
+                return Err(de::Error::custom(format!("no methods allowed for '{}'", route.pattern)));
+            }
+
+            patterns.push(anchored(&route.pattern));
+            methods.push(route_methods);
+        }
+
+        let patterns = RegexSet::new(&patterns)
+            .map_err(de::Error::custom)?;
+
+        Ok(Self { patterns, methods })
+    }
+}
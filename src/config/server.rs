@@ -1,52 +1,1429 @@
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::io::{BufRead, BufReader, Cursor};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
-use hyper::Uri;
+use anyhow::{Result, Context, bail};
+use hyper::{HeaderMap, Method, Uri};
+use hyper::http::uri::{Authority, PathAndQuery, Scheme};
+use hyper::header::{COOKIE, HeaderName};
+use ipnet::IpNet;
 use regex::RegexSet;
 use serde::{Deserialize, Deserializer, de};
 
+use super::env::optional_env_loadable;
+use super::{AllowedMethods, Authorization, Routes, routes};
+use crate::auth;
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Server {
+    /// The host name to answer for. A leading `*.` matches any single label,
+    /// e.g. `*.example.com` matches `a.example.com`, but neither `example.com` nor `a.b.example.com`.
+    /// Exact names take precedence.
     pub name: String,
-    pub listen: SocketAddr,
-    pub upstream: String,
+    /// Further host names to answer for, as case-insensitive patterns anchored like `public_routes`,
+    /// e.g. `'.+\.example\.com'` for subdomains of any depth. Exact names take precedence,
+    /// otherwise the first server with a matching wildcard name or pattern is picked.
+    /// TLS needs a certificate covering the matched names, e.g. with a wildcard DNS name.
+    #[serde(default, deserialize_with = "deserialize_case_insensitive_patterns")]
+    pub name_patterns: Option<RegexSet>,
+    /// One address or a list of addresses.
+    #[serde(deserialize_with = "deserialize_listen")]
+    pub listen: Vec<SocketAddr>,
+    #[serde(default)]
+    pub mode: Mode,
+    /// Targets of `mode = "forward_proxy"` as `<host>:<port>`. Hosts are names, `*.example.org`
+    /// for any subdomain, `*` for any name, or IP networks like `10.0.0.0/8` (`[fd00::/8]` for IPv6).
+    /// Ports are numbers or `*`. Names are resolved before connecting and loopback, private and
+    /// link-local addresses are only allowed by a network entry containing them.
+    #[serde(default = "default_connect_allow")]
+    pub connect_allow: Vec<ConnectTarget>,
+    /// One address or a list of addresses, optionally weighted and named as
+    /// `{ address = "...", weight = 3, name = "canary" }`.
+    /// Addresses may also be base URLs like `http://backend:8080/v2` to prefix request paths,
+    /// or Unix sockets like `unix:/run/app.sock`, which don't support TLS.
+    /// Required if `mode` is `"reverse_proxy"`.
+    #[serde(default, deserialize_with = "deserialize_upstream")]
+    pub upstream: Vec<Upstream>,
+    /// How requests are spread across multiple upstreams.
+    #[serde(default)]
+    pub balance: Balance,
+    /// Evaluated in order before balancing, the first matching rule picks the upstream.
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
+    /// Picks the upstream by the `iss` claim of verified tokens, e.g. for tenants with their own IdP
+    /// sharing a host name. Takes precedence over `routing_rules`.
+    /// Tokens of other issuers are rejected with 403, since the other upstreams belong to other tenants.
+    /// Requests without a token use the other rules.
+    #[serde(default)]
+    pub issuer_routes: Vec<IssuerRoute>,
+    /// Sends a share of the remaining traffic to one upstream, which balancing leaves out otherwise.
+    pub canary: Option<Canary>,
     #[serde(default)]
     pub upstream_tls: bool,
-    #[serde(deserialize_with = "deserialize_patterns")]
-    pub public_routes: RegexSet,
+    /// Talk HTTP/2 to the upstream (with prior knowledge unless `upstream_tls` is set).
+    /// Required to forward trailers, which only HTTP/2 clients can send and receive:
+    /// without it, trailers of chunked HTTP/1 responses and of requests are dropped.
+    /// Unix socket upstreams pass trailers through either way.
+    /// gRPC requests (`Content-Type: application/grpc`) always use HTTP/2.
+    #[serde(default)]
+    pub upstream_http2: bool,
+    /// PEM file with CA certificates to trust for the upstream, in addition to the public roots.
+    /// Servers sharing a bundle and other client settings share a client.
+    pub upstream_ca_bundle: Option<PathBuf>,
+    /// Trust only `upstream_ca_bundle`, not the public roots, e.g. for upstreams signed by an internal CA.
+    #[serde(default)]
+    pub upstream_ca_only: bool,
+    /// Accept any upstream certificate. Only meant for testing.
+    #[serde(default)]
+    pub upstream_tls_insecure: bool,
+    /// Host name to send as SNI and to verify upstream certificates against, instead of the
+    /// upstream's own host, e.g. `service.internal` for upstreams given by pod IP.
+    /// Certificates must name it and be signed by `upstream_ca_bundle` or a public root.
+    /// It's also the upstream's `Host`, unless `preserve_host` is set.
+    /// Upstreams given by DNS name are resolved only once, at startup, and not re-resolved
+    /// when their addresses change. Requires TLS to all upstreams.
+    pub upstream_sni: Option<String>,
+    /// Follow redirects issued by the upstream instead of passing them to the client.
+    /// Not supported with `upstream_http2`.
+    #[serde(default)]
+    pub upstream_follow_redirects: bool,
+    /// Send the client's `Host` to the upstream instead of the upstream authority.
+    #[serde(default)]
+    pub preserve_host: bool,
+    /// Rewrite `Location` headers pointing at the upstream to the public origin.
+    /// Relative locations like `/v2/items` lose the upstream's base path.
+    #[serde(default)]
+    pub rewrite_location: bool,
+    /// Send the client's TLS version and cipher suite as `X-Forwarded-TLS-Version`
+    /// and `X-Forwarded-TLS-Cipher`, e.g. `TLSv1.3` and `TLS13_AES_256_GCM_SHA384`.
+    /// Only present on TLS listeners.
+    #[serde(default)]
+    pub forward_tls_info: bool,
+    /// Pass the header or cookie carrying the client's token on to upstreams validating tokens themselves.
+    #[serde(default)]
+    pub forward_token: bool,
+    /// Overrides `token_header` of `[openid]` for this server.
+    #[serde(default, deserialize_with = "deserialize_optional_header_name")]
+    pub token_header: Option<HeaderName>,
+    /// Overrides `token_cookie` of `[openid]` for this server.
+    pub token_cookie: Option<String>,
+    /// Add `X-User-*` headers with the claims of verified tokens.
+    #[serde(default = "default_true")]
+    pub enrich_headers: bool,
+    /// Rewrite `Set-Cookie` headers set for the upstream to the public host.
+    pub cookie_rewrite: Option<CookieRewrite>,
+    /// Add security headers to responses, e.g. `security_headers = {}` for the defaults.
+    pub security_headers: Option<SecurityHeaders>,
+    /// Find/replace on response bodies, e.g. `[{ from = "http://internal:8080", to = "https://example.org" }]`.
+    /// Rules are tried in order at every position of the body while it is streamed, which copies
+    /// each chunk and holds back the end of a chunk that may start a match. Rewritten responses
+    /// lose `Content-Length` and compressed ones are passed through as is.
+    #[serde(default)]
+    pub body_rewrite: Vec<BodyRewriteRule>,
+    /// Media types `body_rewrite` applies to.
+    #[serde(default = "default_body_rewrite_content_types")]
+    pub body_rewrite_content_types: Vec<String>,
+    /// Larger responses are not rewritten. Without `Content-Length`, bytes past this are passed through as is.
+    #[serde(default = "default_body_rewrite_max_bytes")]
+    pub body_rewrite_max_bytes: u64,
+    #[serde(default)]
+    pub public_routes: Routes,
+    /// How to report `public_routes` that look broader than intended, see `Server::public_route_warnings`.
+    #[serde(default)]
+    pub strict_public_routes: StrictPublicRoutes,
+    /// Always require authentication, even if also matched by `public_routes`.
+    #[serde(default)]
+    pub protected_routes: Routes,
+    /// Authenticate requests that carry a valid token, let all others through anonymously.
+    /// Takes precedence over `public_routes`.
+    #[serde(default)]
+    pub optional_auth_routes: Routes,
+    /// Answer `405` for other methods on these routes, without contacting the upstream.
+    #[serde(default)]
+    pub allowed_methods: AllowedMethods,
+    /// Role requirements, the first entry matching a route applies.
+    #[serde(default)]
+    pub authorization: Vec<Authorization>,
     pub tls: Option<Tls>,
+    /// Port to redirect to with `mode = "redirect"`. Defaults to 443.
+    pub redirect_port: Option<u16>,
+    /// Path requested by the startup upstream check. Without it, `HEAD /` is sent and any response counts.
+    pub health_path: Option<String>,
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests waiting for one of `max_concurrent_requests`, up to `queue_timeout_ms` of `[limits]`.
+    /// Further requests are answered with `503` right away. Unbounded if unset.
+    pub max_queued_requests: Option<usize>,
+    /// Maximum time to establish a connection to the upstream.
+    pub connect_timeout_ms: Option<u64>,
+    /// Deadline for the complete upstream exchange, including streaming the response body.
+    /// With `upstream_http2` and for gRPC requests it only covers the response headers.
+    /// Upgrade requests (e.g. WebSockets) are exempt since they are long-lived by design.
+    pub request_timeout_ms: Option<u64>,
+    /// If set, only request headers matching these patterns are forwarded upstream.
+    #[serde(default, deserialize_with = "deserialize_case_insensitive_patterns")]
+    pub request_header_allow: Option<RegexSet>,
+    /// Request headers matching these patterns are never forwarded upstream.
+    #[serde(default, deserialize_with = "deserialize_case_insensitive_patterns")]
+    pub request_header_deny: Option<RegexSet>,
+    /// Answer `431` instead of sending requests upstream whose headers, including the ones
+    /// added by the gateway, exceed this many bytes.
+    pub max_request_header_bytes: Option<usize>,
+    /// If set, only response headers matching these patterns are returned to the client.
+    #[serde(default, deserialize_with = "deserialize_case_insensitive_patterns")]
+    pub response_header_allow: Option<RegexSet>,
+    /// Response headers matching these patterns are never returned to the client.
+    #[serde(default, deserialize_with = "deserialize_case_insensitive_patterns")]
+    pub response_header_deny: Option<RegexSet>,
+    /// Answer `502` instead of passing on upstream responses whose headers exceed this many bytes.
+    pub max_response_header_bytes: Option<usize>,
+    /// Additionally send selected token claims to the upstream as a single header.
+    pub identity_header: Option<IdentityHeader>,
+    /// Sends the token's `scope` (or `scp`) claim space delimited, along with the `X-User-*` headers.
+    #[serde(default = "default_scopes_header", deserialize_with = "deserialize_header_name")]
+    pub scopes_header: HeaderName,
+    /// Reject requests with `503` for a while after the upstream failed repeatedly.
+    pub circuit_breaker: Option<CircuitBreaker>,
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    /// Required if `mode` is `"static"`.
+    pub static_response: Option<StaticResponse>,
+    /// Let `debug_peers` override the upstream with an `X-Debug-Upstream` header,
+    /// e.g. `X-Debug-Upstream: http://localhost:9000`. Never enable this in production!
+    #[serde(default)]
+    pub debug_routing: bool,
+    /// Peers allowed to use `debug_routing`, only loopback by default. Not for requests they forward
+    /// on behalf of other clients, as told by `X-Forwarded-For` of trusted proxies.
+    #[serde(default = "default_debug_peers")]
+    pub debug_peers: Vec<IpNet>,
+    /// Answer requests for this path with the gateway's view of the caller's token
+    /// as JSON instead of proxying, e.g. `"/.well-known/whoami"`. Meant for debugging.
+    pub whoami_path: Option<String>,
+    /// Tell clients which server answered and how the route was treated with
+    /// `X-Gateway-Server` and `X-Gateway-Route: public|protected|403`. Meant for debugging.
+    #[serde(default)]
+    pub debug_headers: bool,
+    /// Add a `Server-Timing` header with the time spent on token verification (`auth`),
+    /// waiting for the upstream's response headers (`upstream`) and in total, for browser devtools.
+    /// Exposes internal timings, so only enable it where that's fine.
+    #[serde(default)]
+    pub server_timing: bool,
+    /// Log browsers in and keep their tokens, see [`Session`].
+    pub session: Option<Session>,
+}
+
+/// Acts as backend for frontend: browsers log in through the gateway and only get a session cookie,
+/// while access and refresh tokens stay with the gateway. Requests carrying the cookie instead of
+/// an `Authorization` header use the session's access token, refreshed shortly before it expires.
+///
+/// Sessions are kept in memory, so they end when the gateway restarts.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Session {
+    /// Sends the browser to the provider, e.g. `/login?return_to=/app`.
+    #[serde(default = "default_session_login_path")]
+    pub login_path: String,
+    /// Where the provider sends the browser back to.
+    #[serde(default = "default_session_callback_path")]
+    pub callback_path: String,
+    /// Ends the session, e.g. `/logout?return_to=/`.
+    #[serde(default = "default_session_logout_path")]
+    pub logout_path: String,
+    /// Public URL of `callback_path` as registered with the provider,
+    /// e.g. `https://app.example.org/oauth/callback`.
+    pub redirect_url: String,
+    /// Additional scopes to request, e.g. `offline_access` for providers that require it for refresh tokens.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default = "default_session_cookie")]
+    pub cookie: String,
+    #[serde(default = "default_session_refresh_before_expiry_secs")]
+    pub refresh_before_expiry_secs: u64,
+    /// Sessions unused for this long end.
+    #[serde(default = "default_session_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+fn default_debug_peers() -> Vec<IpNet> {
+    vec![
+        "127.0.0.1/32".parse().unwrap(),
+        "::1/128".parse().unwrap(),
+    ]
+}
+
+fn default_session_login_path() -> String {
+    "/login".into()
+}
+
+fn default_session_callback_path() -> String {
+    "/oauth/callback".into()
+}
+
+fn default_session_logout_path() -> String {
+    "/logout".into()
+}
+
+fn default_session_cookie() -> String {
+    "gateway_session".into()
+}
+
+fn default_session_refresh_before_expiry_secs() -> u64 {
+    60
+}
+
+fn default_session_idle_timeout_secs() -> u64 {
+    8 * 60 * 60
+}
+
+/// Answer `503` instead of proxying. Can be toggled at runtime through the admin interface.
+/// `health_path` and `exempt_routes` stay reachable.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Maintenance {
+    /// Whether the server starts in maintenance mode.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Defaults to `retry_after_secs` in `[limits]`.
+    pub retry_after_secs: Option<u64>,
+    /// HTML page served to browsers.
+    pub page: Option<PathBuf>,
+    pub message: Option<String>,
+    #[serde(default)]
+    pub exempt_routes: Routes,
+}
+
+/// Headers added to responses unless the upstream already set them. Empty values disable a header.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityHeaders {
+    /// Only sent on TLS listeners.
+    #[serde(default = "default_strict_transport_security")]
+    pub strict_transport_security: String,
+    /// `X-Content-Type-Options`.
+    #[serde(default = "default_content_type_options")]
+    pub content_type_options: String,
+    /// `X-Frame-Options`.
+    #[serde(default = "default_frame_options")]
+    pub frame_options: String,
+    pub content_security_policy: Option<String>,
+    /// Replace the upstream's values, too.
+    #[serde(default)]
+    pub force: bool,
+}
+
+fn default_strict_transport_security() -> String {
+    "max-age=31536000".into()
+}
+
+fn default_content_type_options() -> String {
+    "nosniff".into()
+}
+
+fn default_frame_options() -> String {
+    "DENY".into()
+}
+
+/// Upstream errors and `502`/`503`/`504` responses count as failures.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreaker {
+    /// Failures within `window_secs` that open the breaker.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// How long the breaker stays open before a trial request is let through.
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+fn default_cooldown_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Proxy requests to `upstream`.
+    #[default]
+    ReverseProxy,
+    /// Tunnel authenticated `CONNECT` requests to the requested target.
+    /// Other methods are rejected.
+    ForwardProxy,
+    /// Answer every request with a redirect to the same URL on `https://`.
+    /// Meant for plaintext listeners, e.g. port 80.
+    Redirect,
+    /// Answer every request with `static_response`, after authentication.
+    Static,
+}
+
+/// The response of a server with `mode = "static"`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StaticResponse {
+    #[serde(default = "default_static_status")]
+    pub status: u16,
+    pub body: Option<String>,
+    /// File to read the body from at startup, alternative to `body`.
+    pub body_file: Option<PathBuf>,
+    #[serde(default = "default_static_content_type")]
+    pub content_type: String,
+}
+
+fn default_static_status() -> u16 {
+    200
+}
+
+fn default_static_content_type() -> String {
+    "text/plain; charset=utf-8".into()
+}
+
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    /// The upstream authority, i.e. `host:port`. Validated at config load.
+    /// `localhost` for Unix sockets.
+    pub authority: Authority,
+    /// Set if the upstream was given as a URL, overriding `upstream_tls`.
+    pub tls: Option<bool>,
+    /// Connect to this Unix socket instead of resolving `authority`.
+    pub unix_socket: Option<PathBuf>,
+    /// Prepended to request paths, without a trailing slash.
+    pub base_path: String,
+    pub weight: u32,
+    /// Referenced by `routing_rules`.
+    pub name: Option<String>,
+}
+
+impl Upstream {
+    /// Accepts a bare authority (`backend:8080`), a base URL (`http://backend:8080/v2`)
+    /// or a Unix socket path (`unix:/run/app.sock`).
+    pub fn parse(upstream: &str, weight: u32, name: Option<String>) -> Result<Self> {
+        if let Some(path) = upstream.strip_prefix("unix:") {
+            if !path.starts_with('/') {
+                bail!("Unix socket upstream '{}' must be an absolute path", upstream);
+            }
+
+            return Ok(Self {
+                authority: Authority::from_static("localhost"),
+                tls: Some(false),
+                unix_socket: Some(PathBuf::from(path)),
+                base_path: String::new(),
+                weight,
+                name,
+            })
+        }
+
+        let (tls, authority, path) = match upstream.split_once("://") {
+            Some((scheme, rest)) => {
+                let tls = match scheme.to_ascii_lowercase().as_str() {
+                    "http" => false,
+                    "https" => true,
+                    _ => bail!("unsupported upstream scheme '{}'", scheme),
+                };
+                let (authority, path) = match rest.find('/') {
+                    Some(index) => rest.split_at(index),
+                    None => (rest, ""),
+                };
+
+                if path.contains(['?', '#']) {
+                    bail!("upstream '{}' must not contain a query or fragment", upstream);
+                }
+
+                (Some(tls), authority, path)
+            },
+            None => (None, upstream, ""),
+        };
+
+        let authority = authority.parse::<Authority>()
+            .with_context(|| format!("invalid upstream authority '{}'", authority))?;
+        path.parse::<PathAndQuery>()
+            .with_context(|| format!("invalid upstream path '{}'", path))?;
+
+        Ok(Self {
+            authority,
+            tls,
+            unix_socket: None,
+            base_path: path.trim_end_matches('/').to_owned(),
+            weight,
+            name,
+        })
+    }
+
+    pub fn is_tls(&self, server: &Server) -> bool {
+        self.tls.unwrap_or(server.upstream_tls)
+    }
+
+    pub fn scheme(&self, server: &Server) -> Scheme {
+        match self.is_tls(server) {
+            true => Scheme::HTTPS,
+            false => Scheme::HTTP,
+        }
+    }
+
+    /// Joins the base path and a request path, e.g. `/v2` and `/items?page=2` to `/v2/items?page=2`.
+    pub fn join_path(&self, path_and_query: &str) -> String {
+        match path_and_query {
+            "" if self.base_path.is_empty() => "/".to_owned(),
+            "" => self.base_path.clone(),
+            _ if path_and_query.starts_with('/') => format!("{}{}", self.base_path, path_and_query),
+            _ => format!("{}/{}", self.base_path, path_and_query),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CookieRewrite {
+    /// Replace `Domain` attributes naming the upstream host with the public host.
+    #[serde(default = "default_true")]
+    pub domain: bool,
+    /// Strip the upstream base path from `Path` attributes.
+    #[serde(default = "default_true")]
+    pub path: bool,
+    /// Add `Secure` to all cookies.
+    #[serde(default)]
+    pub secure: bool,
+    /// Add `HttpOnly` to all cookies.
+    #[serde(default)]
+    pub http_only: bool,
+    /// Replace the `SameSite` attribute of all cookies.
+    pub same_site: Option<SameSite>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        }
+    }
+}
+
+/// Matches a request header or cookie, e.g. `{ header = "X-Canary", value = "true", upstream = "canary" }`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    #[serde(default, deserialize_with = "deserialize_optional_header_name")]
+    pub header: Option<HeaderName>,
+    pub cookie: Option<String>,
+    /// Required value of the header or cookie. If unset, any value matches.
+    pub value: Option<String>,
+    /// Name of the upstream to use.
+    pub upstream: String,
+}
+
+impl RoutingRule {
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let matches_value = |value: &str| self.value.as_ref().is_none_or(|expected| expected == value);
+
+        if let Some(header) = &self.header {
+            return headers.get_all(header).iter()
+                .filter_map(|value| value.to_str().ok())
+                .any(matches_value);
+        }
+
+        if let Some(cookie) = &self.cookie {
+            return headers.get_all(COOKIE).iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .any(|(name, value)| name == cookie && matches_value(value));
+        }
+
+        false
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BodyRewriteRule {
+    pub from: String,
+    pub to: String,
+}
+
+fn default_body_rewrite_content_types() -> Vec<String> {
+    vec!["text/html".into(), "application/json".into()]
+}
+
+fn default_body_rewrite_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StrictPublicRoutes {
+    Off,
+    /// Log a warning at startup.
+    #[default]
+    Warn,
+    /// Refuse to start.
+    Error,
+}
+
+/// Paths a pattern must match all of to count as matching everything.
+const CATCH_ALL_PROBES: &[&str] = &["/", "/admin", "/api/v1/users/1", "/.git/config", "/x7f3a9c/%00/..;/"];
+
+/// E.g. `{ upstream = "canary", percent = 5, sticky_by = "cookie:session" }`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Canary {
+    /// Name of the upstream to use.
+    pub upstream: String,
+    /// Share of clients sent to the canary, down to hundredths of a percent.
+    pub percent: f64,
+    #[serde(default)]
+    pub sticky_by: StickyBy,
+}
+
+/// What keeps a client on the same side of a canary.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StickyBy {
+    /// The client IP, as reported by trusted proxies.
+    #[default]
+    Ip,
+    /// `cookie:<name>`, falling back to the client IP for requests without the cookie.
+    Cookie(String),
+}
+
+impl<'de> Deserialize<'de> for StickyBy {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let sticky_by = String::deserialize(de)?;
+
+        match sticky_by.split_once(':') {
+            None if sticky_by == "ip" => Ok(StickyBy::Ip),
+            Some(("cookie", name)) if !name.is_empty() => Ok(StickyBy::Cookie(name.to_owned())),
+            _ => Err(de::Error::custom(format!("expected \"ip\" or \"cookie:<name>\", got {:?}", sticky_by))),
+        }
+    }
+}
+
+/// A hash that stays the same across restarts and gateway instances.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// E.g. `{ issuer = "https://idp.tenant-a.example.org", upstream = "tenant-a" }`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IssuerRoute {
+    /// Compared exactly with the `iss` claim.
+    pub issuer: String,
+    /// Name of the upstream to use.
+    pub upstream: String,
+}
+
+/// An entry of `connect_allow`, e.g. `*.example.org:443`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectTarget {
+    host: ConnectHost,
+    /// Any port if `None`.
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ConnectHost {
+    Any,
+    Name(String),
+    /// `*.example.org`, stored as `.example.org`.
+    Subdomain(String),
+    Network(IpNet),
+}
+
+impl ConnectTarget {
+    /// Whether `addr`, which `host` resolved to, may be tunneled to.
+    fn allows(&self, host: &str, addr: &SocketAddr) -> bool {
+        if self.port.is_some_and(|port| port != addr.port()) {
+            return false;
+        }
+
+        if let ConnectHost::Network(network) = &self.host {
+            return network.contains(&addr.ip());
+        }
+
+        if !is_public_address(addr.ip()) {
+            return false;
+        }
+
+        match &self.host {
+            ConnectHost::Any => true,
+            ConnectHost::Name(name) => host.eq_ignore_ascii_case(name),
+            ConnectHost::Subdomain(domain) => host.len() > domain.len()
+                && host.get(host.len() - domain.len()..).is_some_and(|suffix| suffix.eq_ignore_ascii_case(domain)),
+            ConnectHost::Network(_) => unreachable!(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectTarget {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let target = String::deserialize(de)?;
+        let invalid = || de::Error::custom(format!("expected \"<host>:<port>\", got {:?}", target));
+        let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| invalid())?),
+        };
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        let host = if host == "*" {
+            ConnectHost::Any
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            ConnectHost::Subdomain(format!(".{}", domain))
+        } else if let Ok(network) = host.parse::<IpNet>() {
+            ConnectHost::Network(network)
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            ConnectHost::Network(IpNet::from(ip))
+        } else if !host.is_empty() && !host.contains(['*', '/', ':']) {
+            ConnectHost::Name(host.to_owned())
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Self { host, port })
+    }
+}
+
+fn default_connect_allow() -> Vec<ConnectTarget> {
+    vec![ConnectTarget {
+        host: ConnectHost::Any,
+        port: Some(443),
+    }]
+}
+
+/// Whether `ip` is reachable on the internet, as opposed to e.g. loopback, private or link-local
+/// addresses, where forward proxies could reach internal services like the admin listener.
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // Carrier-grade NAT, 100.64.0.0/10
+            let is_shared = first == 100 && second & 0xc0 == 64;
+
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || ip.is_documentation() || is_shared || first == 0)
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_address(IpAddr::V4(ip)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteAuth {
+    /// Tokens are ignored.
+    Public,
+    /// Tokens are verified if present, but requests without a valid one are not rejected.
+    Optional,
+    Required,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// Round-robin honoring upstream weights.
+    Weighted,
+    /// Pick the upstream with the fewest requests in flight.
+    LeastConnections,
+}
+
+/// Token claims serialized to JSON and base64url-encoded into a single request header.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityHeader {
+    #[serde(default = "default_identity_header_name", deserialize_with = "deserialize_header_name")]
+    pub name: HeaderName,
+    /// Top-level introspection claims to include. Missing claims are left out.
+    #[serde(default = "default_identity_claims")]
+    pub claims: Vec<String>,
+}
+
+fn default_scopes_header() -> HeaderName {
+    HeaderName::from_static("x-user-scopes")
+}
+
+fn default_identity_header_name() -> HeaderName {
+    HeaderName::from_static("x-forwarded-user")
+}
+
+fn default_identity_claims() -> Vec<String> {
+    vec!["sub".into(), "username".into()]
 }
 
 impl Server {
-    pub fn is_public_route(&self, uri: &Uri) -> bool {
+    /// Whether forward proxies may tunnel to `addr`, which the requested `host` resolved to.
+    pub fn allows_connect(&self, host: &str, addr: &SocketAddr) -> bool {
+        self.connect_allow.iter().any(|target| target.allows(host, addr))
+    }
+
+    pub fn listens_on(&self, listen_addr: &SocketAddr) -> bool {
+        self.listen.contains(listen_addr)
+    }
+
+    /// Whether `name` is a wildcard matching `host_name`.
+    pub fn matches_wildcard(&self, host_name: &str) -> bool {
+        let domain = match self.name.strip_prefix("*.") {
+            Some(domain) => domain,
+            None => return false,
+        };
+
+        match host_name.split_once('.') {
+            Some((label, parent)) => !label.is_empty() && parent.eq_ignore_ascii_case(domain),
+            None => false,
+        }
+    }
+
+    /// Whether `host_name` matches the wildcard name or one of `name_patterns`.
+    pub fn matches_name_pattern(&self, host_name: &str) -> bool {
+        self.matches_wildcard(host_name)
+            || self.name_patterns.as_ref().is_some_and(|patterns| patterns.is_match(host_name))
+    }
+
+    pub fn is_exempt_from_maintenance(&self, method: &Method, uri: &Uri) -> bool {
+        self.health_path.as_deref() == Some(uri.path())
+            || self.maintenance.exempt_routes.is_match(method, uri.path())
+    }
+
+    /// Returns the index of the upstream picked by the first matching routing rule.
+    pub fn routed_upstream(&self, headers: &HeaderMap) -> Option<usize> {
+        let rule = self.routing_rules.iter().find(|rule| rule.matches(headers))?;
+
+        self.upstream_index(&rule.upstream)
+    }
+
+    /// Returns the index of the canary upstream if the client falls into its share.
+    /// Clients are bucketed by a hash of their sticky key, so they stay on one side.
+    pub fn canary_upstream(&self, headers: &HeaderMap, client_ip: IpAddr) -> Option<usize> {
+        let canary = self.canary.as_ref()?;
+        let cookie = match &canary.sticky_by {
+            StickyBy::Ip => None,
+            StickyBy::Cookie(name) => auth::cookie_value(headers, name).map(str::to_owned),
+        };
+        let key = cookie.unwrap_or_else(|| client_ip.to_string());
+        let bucket = fnv1a(key.as_bytes()) % 10_000;
+
+        if (bucket as f64) < canary.percent * 100.0 {
+            self.upstream_index(&canary.upstream)
+        } else {
+            None
+        }
+    }
+
+    /// The authority to request `upstream` under: `upstream_sni` with the upstream's port, if set.
+    pub fn request_authority(&self, upstream: &Upstream) -> Result<Authority> {
+        let upstream_sni = match &self.upstream_sni {
+            Some(upstream_sni) => upstream_sni,
+            None => return Ok(upstream.authority.clone()),
+        };
+        let port = upstream.authority.port_u16().unwrap_or(443);
+
+        format!("{}:{}", upstream_sni, port).parse()
+            .with_context(|| format!("invalid upstream_sni '{}'", upstream_sni))
+    }
+
+    /// Returns the index of the upstream for tokens issued by `issuer`.
+    pub fn issuer_upstream(&self, issuer: &str) -> Option<usize> {
+        let route = self.issuer_routes.iter().find(|route| route.issuer == issuer)?;
+
+        self.upstream_index(&route.upstream)
+    }
+
+    pub fn upstream_index(&self, name: &str) -> Option<usize> {
+        self.upstream.iter().position(|upstream| upstream.name.as_deref() == Some(name))
+    }
+
+    /// Describes `public_routes` that are likely broader than intended: patterns matching
+    /// every path or `/`, and patterns matching a literal path of `protected_routes` or `authorization`.
+    /// Protected routes still require a token, but the overlap hints at a mistake.
+    pub fn public_route_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let catch_all = CATCH_ALL_PROBES.iter()
+            .map(|path| self.public_routes.matching_patterns(path))
+            .reduce(|mut catch_all, matching| {
+                catch_all.retain(|pattern| matching.contains(pattern));
+                catch_all
+            })
+            .unwrap_or_default();
+
+        for pattern in &catch_all {
+            warnings.push(format!("public route '{}' matches every path", pattern));
+        }
+
+        for pattern in self.public_routes.matching_patterns("/") {
+            if !catch_all.contains(&pattern) {
+                warnings.push(format!("public route '{}' matches '/'", pattern));
+            }
+        }
+
+        let protected_paths = self.protected_routes.literal_paths()
+            .chain(self.authorization.iter().flat_map(|authorization| authorization.routes.literal_paths()));
+
+        for path in protected_paths {
+            for pattern in self.public_routes.matching_patterns(path) {
+                warnings.push(format!("public route '{}' matches protected path '{}'", pattern, path));
+            }
+        }
+
+        warnings
+    }
+
+    pub fn route_auth(&self, method: &Method, uri: &Uri) -> RouteAuth {
         let path = uri.path();
 
-        self.public_routes.is_match(path)
+        if self.protected_routes.is_match(method, path) || self.authorization(method, uri).is_some() {
+            RouteAuth::Required
+        } else if self.optional_auth_routes.is_match(method, path) {
+            RouteAuth::Optional
+        } else if self.public_routes.is_match(method, path) {
+            RouteAuth::Public
+        } else {
+            RouteAuth::Required
+        }
+    }
+
+    pub fn authorization(&self, method: &Method, uri: &Uri) -> Option<&Authorization> {
+        self.authorization.iter()
+            .find(|authorization| authorization.routes.is_match(method, uri.path()))
+    }
+
+    pub fn filter_request_headers(&self, headers: &mut HeaderMap) {
+        filter_headers(headers, &self.request_header_allow, &self.request_header_deny);
+    }
+
+    pub fn filter_response_headers(&self, headers: &mut HeaderMap) {
+        filter_headers(headers, &self.response_header_allow, &self.response_header_deny);
+    }
+}
+
+fn filter_headers(headers: &mut HeaderMap, allow: &Option<RegexSet>, deny: &Option<RegexSet>) {
+    let is_denied = |name: &HeaderName| {
+        let name = name.as_str();
+        let allowed = allow.as_ref().is_none_or(|allow| allow.is_match(name));
+        let denied = deny.as_ref().is_some_and(|deny| deny.is_match(name));
+
+        !allowed || denied
+    };
+
+    let denied = headers.keys()
+        .filter(|name| is_denied(name))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in denied {
+        headers.remove(name);
     }
 }
 
-fn deserialize_patterns<'de, D>(de: D) -> Result<RegexSet, D::Error>
+fn deserialize_listen<'de, D>(de: D) -> Result<Vec<SocketAddr>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let mut patterns = Option::<Vec<String>>::deserialize(de)?
-        .unwrap_or_default();
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Listen {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+
+    let listen = match Listen::deserialize(de)? {
+        Listen::One(addr) => vec![addr],
+        Listen::Many(addrs) => addrs,
+    };
+
+    if listen.is_empty() {
+        return Err(de::Error::custom("at least one listen address is required"));
+    }
+
+    Ok(listen)
+}
+
+fn deserialize_upstream<'de, D>(de: D) -> Result<Vec<Upstream>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Upstreams {
+        One(String),
+        Many(Vec<Entry>),
+    }
 
-    for pattern in &mut patterns {
-        *pattern = format!("^{}$", pattern);
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Address(String),
+        Detailed {
+            address: String,
+            #[serde(default = "default_weight")]
+            weight: u32,
+            name: Option<String>,
+        },
     }
 
-    let patterns = RegexSet::new(&patterns)
+    fn default_weight() -> u32 {
+        1
+    }
+
+    let entries = match Upstreams::deserialize(de)? {
+        Upstreams::One(address) => vec![Entry::Address(address)],
+        Upstreams::Many(entries) => entries,
+    };
+
+    entries.into_iter()
+        .map(|entry| match entry {
+            Entry::Address(address) => Upstream::parse(&address, 1, None),
+            Entry::Detailed { weight: 0, .. } => bail!("upstream weight must be at least 1"),
+            Entry::Detailed { address, weight, name } => Upstream::parse(&address, weight, name),
+        })
+        .map(|upstream| upstream.map_err(|err| de::Error::custom(format!("{:#}", err))))
+        .collect()
+}
+
+fn deserialize_header_name<'de, D>(de: D) -> Result<HeaderName, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(de)?;
+
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(de::Error::custom)
+}
+
+pub(super) fn deserialize_optional_header_name<'de, D>(de: D) -> Result<Option<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_header_name")] HeaderName);
+
+    let name = Option::<Wrapper>::deserialize(de)?;
+
+    Ok(name.map(|Wrapper(name)| name))
+}
+
+fn deserialize_case_insensitive_patterns<'de, D>(de: D) -> Result<Option<RegexSet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let patterns = match Option::<Vec<String>>::deserialize(de)? {
+        Some(patterns) => patterns,
+        None => return Ok(None),
+    };
+
+    let patterns = patterns.iter()
+        .map(|pattern| format!("(?i){}", routes::anchored(pattern)));
+
+    let patterns = RegexSet::new(patterns)
         .map_err(de::Error::custom)?;
 
-    Ok(patterns)
+    Ok(Some(patterns))
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Tls {
-    pub cert: PathBuf,
-    pub key: PathBuf,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// PEM encoded certificate chain, alternative to `cert`.
+    #[serde(default, deserialize_with = "optional_env_loadable")]
+    pub cert_pem: Option<String>,
+    /// PEM encoded private key, alternative to `key`.
+    #[serde(default, deserialize_with = "optional_env_loadable")]
+    pub key_pem: Option<String>,
+    /// Don't fail if the certificate isn't valid for the server name.
+    #[serde(default)]
+    pub skip_cert_name_check: bool,
+    /// DER encoded OCSP response to staple.
+    pub ocsp: Option<PathBuf>,
+    /// Keep the `ocsp` file up to date by querying the certificate's OCSP responder.
+    /// Overwrites the file with each fresh response.
+    #[serde(default)]
+    pub ocsp_refresh: bool,
+    /// Further server names to serve the certificate for on the same listeners,
+    /// e.g. of other servers sharing it. Checked against the certificate like the server name.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+impl Tls {
+    /// The server name followed by the aliases.
+    pub fn server_names(&self, server_name: &str) -> Vec<String> {
+        let mut server_names = vec![server_name.to_owned()];
+
+        server_names.extend(self.aliases.iter().cloned());
+        server_names
+    }
+
+    pub fn cert_source(&self) -> Result<PemSource<'_>> {
+        PemSource::new("cert", &self.cert, &self.cert_pem)
+    }
+
+    pub fn key_source(&self) -> Result<PemSource<'_>> {
+        PemSource::new("key", &self.key, &self.key_pem)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum PemSource<'a> {
+    File(&'a Path),
+    Inline(&'a str),
+}
+
+impl<'a> PemSource<'a> {
+    fn new(name: &str, path: &'a Option<PathBuf>, pem: &'a Option<String>) -> Result<Self> {
+        match (path, pem) {
+            (Some(path), None) => Ok(Self::File(path)),
+            (None, Some(pem)) => Ok(Self::Inline(pem)),
+            (Some(_), Some(_)) => bail!("Only one of `{name}` and `{name}_pem` may be set"),
+            (None, None) => bail!("One of `{name}` or `{name}_pem` must be set"),
+        }
+    }
+
+    pub fn reader(&self) -> Result<Box<dyn BufRead + 'a>> {
+        Ok(match *self {
+            Self::File(path) => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open {:?}", path))?;
+
+                Box::new(BufReader::new(file))
+            },
+            Self::Inline(pem) => Box::new(Cursor::new(pem.as_bytes())),
+        })
+    }
+}
+
+impl fmt::Display for PemSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{:?}", path),
+            Self::Inline(_) => write!(f, "inline PEM"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(routes: &str) -> Server {
+        let config = format!(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "127.0.0.1:9090"
+            {}
+        "#, routes);
+
+        toml::from_str(&config).unwrap()
+    }
+
+    #[test]
+    fn upstream_base_url_is_joined_with_request_path() {
+        let upstream = Upstream::parse("https://backend:8080/v2/", 1, None).unwrap();
+
+        assert_eq!(upstream.authority, "backend:8080");
+        assert_eq!(upstream.tls, Some(true));
+        assert_eq!(upstream.join_path("/items?page=2"), "/v2/items?page=2");
+        assert_eq!(upstream.join_path("/"), "/v2/");
+        assert_eq!(upstream.join_path(""), "/v2");
+    }
+
+    #[test]
+    fn unix_socket_upstreams_use_plain_http() {
+        let upstream = Upstream::parse("unix:/run/app.sock", 1, None).unwrap();
+
+        assert_eq!(upstream.unix_socket.as_deref(), Some(Path::new("/run/app.sock")));
+        assert_eq!(upstream.authority, "localhost");
+        assert_eq!(upstream.tls, Some(false));
+        assert!(Upstream::parse("unix:app.sock", 1, None).is_err());
+    }
+
+    #[test]
+    fn bare_upstream_authority_keeps_request_path() {
+        let upstream = Upstream::parse("backend:8080", 1, None).unwrap();
+
+        assert_eq!(upstream.tls, None);
+        assert_eq!(upstream.join_path("/items"), "/items");
+        assert_eq!(upstream.join_path(""), "/");
+    }
+
+    #[test]
+    fn malformed_upstreams_are_rejected() {
+        assert!(Upstream::parse("backend:notaport", 1, None).is_err());
+        assert!(Upstream::parse("ftp://backend", 1, None).is_err());
+        assert!(Upstream::parse("http://backend/v2?x=1", 1, None).is_err());
+    }
+
+    fn is_public(server: &Server, method: Method, path: &str) -> bool {
+        server.route_auth(&method, &path.parse().unwrap()) == RouteAuth::Public
+    }
+
+    #[test]
+    fn protected_wins_over_public() {
+        let server = server(r#"
+            public_routes = ['.*']
+            protected_routes = ['/admin(/.*)?']
+        "#);
+
+        assert!(is_public(&server, Method::GET, "/"));
+        assert!(is_public(&server, Method::GET, "/administrator"));
+        assert!(!is_public(&server, Method::GET, "/admin"));
+        assert!(!is_public(&server, Method::GET, "/admin/users"));
+    }
+
+    #[test]
+    fn protected_routes_can_be_method_scoped() {
+        let server = server(r#"
+            public_routes = ['/items(/.*)?']
+            protected_routes = [{ pattern = '/items/.*', methods = ["DELETE"] }]
+        "#);
+
+        assert!(is_public(&server, Method::GET, "/items/1"));
+        assert!(is_public(&server, Method::DELETE, "/items"));
+        assert!(!is_public(&server, Method::DELETE, "/items/1"));
+    }
+
+    #[test]
+    fn alternations_match_whole_paths() {
+        let server = server(r#"
+            public_routes = ['/public|/x']
+            allowed_methods = [{ pattern = '/webhook|/hook', methods = ["POST"] }]
+        "#);
+
+        assert!(is_public(&server, Method::GET, "/public"));
+        assert!(is_public(&server, Method::GET, "/x"));
+        assert!(!is_public(&server, Method::GET, "/public/secret"));
+        assert!(!is_public(&server, Method::GET, "/admin/x"));
+        assert_eq!(server.public_routes.matching_patterns("/x"), ["/public|/x"]);
+        assert_eq!(server.allowed_methods.for_path("/hook"), Some(&[Method::POST][..]));
+        assert_eq!(server.allowed_methods.for_path("/webhooks"), None);
+    }
+
+    #[test]
+    fn first_matching_allowed_methods_apply() {
+        let server = server(r#"
+            allowed_methods = [
+                { pattern = '/webhook', methods = ["post"] },
+                { pattern = '/.*', methods = ["GET", "HEAD"] },
+            ]
+        "#);
+
+        assert_eq!(server.allowed_methods.for_path("/webhook"), Some(&[Method::POST][..]));
+        assert_eq!(server.allowed_methods.for_path("/items"), Some(&[Method::GET, Method::HEAD][..]));
+        assert_eq!(server.allowed_methods.for_path("items"), None);
+    }
+
+    #[test]
+    fn optional_auth_wins_over_public_but_not_protected() {
+        let server = server(r#"
+            public_routes = ['.*']
+            optional_auth_routes = ['/feed(/.*)?']
+            protected_routes = ['/feed/private']
+        "#);
+        let route_auth = |path: &str| server.route_auth(&Method::GET, &path.parse().unwrap());
+
+        assert_eq!(route_auth("/"), RouteAuth::Public);
+        assert_eq!(route_auth("/feed"), RouteAuth::Optional);
+        assert_eq!(route_auth("/feed/private"), RouteAuth::Required);
+    }
+
+    #[test]
+    fn nothing_is_public_by_default() {
+        let server = server(r#"
+            protected_routes = ['/admin']
+        "#);
+
+        assert!(!is_public(&server, Method::GET, "/"));
+        assert!(!is_public(&server, Method::GET, "/admin"));
+    }
+
+    #[test]
+    fn wildcard_names_match_a_single_label() {
+        let mut server = server("");
+        server.name = "*.example.org".into();
+
+        assert!(server.matches_wildcard("a.example.org"));
+        assert!(server.matches_wildcard("A.Example.org"));
+        assert!(!server.matches_wildcard("example.org"));
+        assert!(!server.matches_wildcard("a.b.example.org"));
+        assert!(!server.matches_wildcard(".example.org"));
+        assert!(!server("").matches_wildcard("a.example.org"));
+    }
+
+    #[test]
+    fn name_patterns_match_whole_names() {
+        let server = server(r#"
+            name_patterns = ['.+\.example\.org', 'api-(eu|us)\.example\.net']
+        "#);
+
+        assert!(server.matches_name_pattern("a.b.example.org"));
+        assert!(server.matches_name_pattern("A.Example.ORG"));
+        assert!(server.matches_name_pattern("api-eu.example.net"));
+        assert!(!server.matches_name_pattern("example.org"));
+        assert!(!server.matches_name_pattern("a.example.org.evil.com"));
+        assert!(!server.matches_name_pattern("api-eu.example.net.evil.com"));
+    }
+
+    #[test]
+    fn first_matching_routing_rule_picks_upstream() {
+        let server: Server = toml::from_str(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = [
+                { address = "127.0.0.1:9090", name = "stable" },
+                { address = "127.0.0.1:9091", name = "canary" },
+            ]
+            routing_rules = [
+                { header = "X-Canary", value = "true", upstream = "canary" },
+                { cookie = "beta", upstream = "canary" },
+                { header = "X-Stable", upstream = "stable" },
+            ]
+        "#).unwrap();
+        let headers = |pairs: &[(&'static str, &'static str)]| pairs.iter()
+            .map(|(name, value)| (HeaderName::from_static(name), value.parse().unwrap()))
+            .collect::<HeaderMap>();
+
+        assert_eq!(server.routed_upstream(&headers(&[("x-canary", "true")])), Some(1));
+        assert_eq!(server.routed_upstream(&headers(&[("x-canary", "false")])), None);
+        assert_eq!(server.routed_upstream(&headers(&[("cookie", "a=1; beta=yes")])), Some(1));
+        assert_eq!(server.routed_upstream(&headers(&[("cookie", "nobeta=1")])), None);
+        assert_eq!(server.routed_upstream(&headers(&[("x-stable", ""), ("x-canary", "true")])), Some(1));
+        assert_eq!(server.routed_upstream(&headers(&[("x-stable", "")])), Some(0));
+        assert_eq!(server.routed_upstream(&headers(&[])), None);
+    }
+
+    #[test]
+    fn issuer_routes_pick_upstream() {
+        let server: Server = toml::from_str(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = [
+                { address = "127.0.0.1:9090", name = "tenant-a" },
+                { address = "127.0.0.1:9091", name = "tenant-b" },
+            ]
+            issuer_routes = [
+                { issuer = "https://idp-a.example.org", upstream = "tenant-a" },
+                { issuer = "https://idp-b.example.org", upstream = "tenant-b" },
+            ]
+        "#).unwrap();
+
+        assert_eq!(server.issuer_upstream("https://idp-b.example.org"), Some(1));
+        assert_eq!(server.issuer_upstream("https://idp-a.example.org"), Some(0));
+        assert_eq!(server.issuer_upstream("https://idp-a.example.org/"), None);
+    }
+
+    #[test]
+    fn broad_public_routes_are_reported() {
+        let server: Server = toml::from_str(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "127.0.0.1:9090"
+            public_routes = ['/.*', '/(index.html)?', '/docs(/.*)?', '/admin/users']
+            protected_routes = ['/admin/users', '/admin/.*']
+            authorization = [{ routes = ['/docs/internal'], roles = "staff" }]
+        "#).unwrap();
+
+        assert_eq!(server.public_route_warnings(), [
+            "public route '/.*' matches every path",
+            "public route '/(index.html)?' matches '/'",
+            "public route '/.*' matches protected path '/admin/users'",
+            "public route '/admin/users' matches protected path '/admin/users'",
+            "public route '/.*' matches protected path '/docs/internal'",
+            "public route '/docs(/.*)?' matches protected path '/docs/internal'",
+        ]);
+
+        let server: Server = toml::from_str(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "127.0.0.1:9090"
+            public_routes = ['/version', { pattern = '/items(/.*)?', methods = ["GET"] }]
+            protected_routes = ['/items/secret.*']
+        "#).unwrap();
+
+        assert!(server.public_route_warnings().is_empty());
+    }
+
+    #[test]
+    fn canary_share_is_sticky() {
+        let server: Server = toml::from_str(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = [
+                { address = "127.0.0.1:9090", name = "stable" },
+                { address = "127.0.0.1:9091", name = "canary" },
+            ]
+            canary = { upstream = "canary", percent = 10, sticky_by = "cookie:session" }
+        "#).unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+        let cookie = |session: usize| [(COOKIE, format!("a=b; session={}", session).parse().unwrap())]
+            .into_iter()
+            .collect::<HeaderMap>();
+
+        let canaries = (0..10_000)
+            .filter(|&session| server.canary_upstream(&cookie(session), ip).is_some())
+            .collect::<Vec<_>>();
+
+        assert!((800..1200).contains(&canaries.len()), "{}", canaries.len());
+        assert_eq!(server.canary_upstream(&cookie(canaries[0]), ip), Some(1));
+        assert_eq!(server.canary_upstream(&HeaderMap::new(), ip), server.canary_upstream(&HeaderMap::new(), ip));
+
+        assert!(toml::from_str::<Canary>("upstream = 'canary'\npercent = 5\nsticky_by = 'cookie:'").is_err());
+    }
+
+    #[test]
+    fn forward_proxies_only_reach_allowed_targets() {
+        let server = server(r#"
+            mode = "forward_proxy"
+            connect_allow = ["*:443", "*.example.org:*", "10.1.0.0/16:5432", "[::1]:8080"]
+        "#);
+        let allows = |host: &str, addr: &str| server.allows_connect(host, &addr.parse().unwrap());
+
+        assert!(allows("example.com", "93.184.215.14:443"));
+        assert!(!allows("example.com", "93.184.215.14:80"));
+        assert!(allows("api.EXAMPLE.org", "93.184.215.14:80"));
+        assert!(!allows("example.org", "93.184.215.14:80"));
+        // Names resolving to internal addresses need a network entry
+        assert!(!allows("localhost", "127.0.0.1:443"));
+        assert!(!allows("admin.example.org", "127.0.0.1:9901"));
+        assert!(!allows("internal", "10.1.2.3:443"));
+        assert!(!allows("metadata", "169.254.169.254:443"));
+        assert!(!allows("mapped", "[::ffff:127.0.0.1]:443"));
+        assert!(allows("db.internal", "10.1.2.3:5432"));
+        assert!(allows("::1", "[::1]:8080"));
+
+        assert!(toml::from_str::<Server>(r#"
+            name = "proxy"
+            listen = "127.0.0.1:8080"
+            connect_allow = ["example.org"]
+        "#).is_err());
+    }
 }
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow, bail};
+use rustls::{SupportedCipherSuite, SupportedProtocolVersion};
+use rustls::kx::SupportedKxGroup;
+use serde::Deserialize;
+
+/// Settings shared by all TLS listeners.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// Warn about certificates expiring within this many days.
+    #[serde(default = "default_expiry_warning_days")]
+    pub expiry_warning_days: u64,
+    #[serde(default = "default_expiry_check_interval_secs")]
+    pub expiry_check_interval_secs: u64,
+    /// Served when the client sends no SNI or an unknown name.
+    pub default_cert: Option<PathBuf>,
+    pub default_key: Option<PathBuf>,
+    /// Oldest TLS version accepted from clients.
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Cipher suites to offer, in order of preference, e.g. `"TLS13_AES_256_GCM_SHA384"`.
+    /// Defaults to all suites supported by rustls.
+    pub cipher_suites: Option<Vec<String>>,
+    /// Key exchange groups to offer, in order of preference, e.g. `"X25519"`.
+    /// Defaults to all groups supported by rustls.
+    pub kx_groups: Option<Vec<String>>,
+    /// Which name selects the server of requests on TLS listeners.
+    #[serde(default)]
+    pub host_precedence: HostPrecedence,
+    /// What to do with clients whose SNI names none of the configured servers, or who send none.
+    #[serde(default)]
+    pub unknown_sni: UnknownSni,
+    /// Clients not completing the handshake within this time are disconnected,
+    /// so stalled handshakes don't tie up connections.
+    /// Also covers the first bytes that tell TLS and plain HTTP clients apart, on all listeners.
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownSni {
+    /// Serve `default_cert` and answer `404` at the HTTP layer.
+    /// Fails the handshake if there is no default certificate.
+    #[default]
+    DefaultCert,
+    /// Fail the handshake with an `access_denied` alert.
+    Reject,
+    /// Close the connection right after the ClientHello, without answering at all.
+    Close,
+}
+
+/// How the SNI name and the request's host, i.e. `:authority` for HTTP/2 and `Host` for HTTP/1.1,
+/// select the server. Either is used if the other one is missing.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HostPrecedence {
+    /// The SNI name wins.
+    #[default]
+    Sni,
+    /// The request's host wins.
+    Host,
+    /// Reject requests whose host differs from the SNI name with `421 Misdirected Request`.
+    RequireMatch,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            expiry_warning_days: default_expiry_warning_days(),
+            expiry_check_interval_secs: default_expiry_check_interval_secs(),
+            default_cert: None,
+            default_key: None,
+            min_version: TlsVersion::default(),
+            cipher_suites: None,
+            kx_groups: None,
+            host_precedence: HostPrecedence::default(),
+            unknown_sni: UnknownSni::default(),
+            handshake_timeout_ms: default_handshake_timeout_ms(),
+        }
+    }
+}
+
+impl Tls {
+    pub fn protocol_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self.min_version {
+            TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            TlsVersion::Tls13 => &[&rustls::version::TLS13],
+        }
+    }
+
+    pub fn cipher_suites(&self) -> Result<Vec<SupportedCipherSuite>> {
+        let names = match &self.cipher_suites {
+            Some(names) => names,
+            None => return Ok(rustls::ALL_CIPHER_SUITES.to_vec()),
+        };
+
+        names.iter()
+            .map(|name| {
+                rustls::ALL_CIPHER_SUITES.iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| anyhow!("Unknown cipher suite {:?}", name))
+            })
+            .collect()
+    }
+
+    pub fn kx_groups(&self) -> Result<Vec<&'static SupportedKxGroup>> {
+        let names = match &self.kx_groups {
+            Some(names) => names,
+            None => return Ok(rustls::ALL_KX_GROUPS.to_vec()),
+        };
+
+        let groups = names.iter()
+            .map(|name| {
+                rustls::ALL_KX_GROUPS.iter()
+                    .find(|group| format!("{:?}", group.name).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| anyhow!("Unknown key exchange group {:?}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if groups.is_empty() {
+            bail!("`kx_groups` must not be empty");
+        }
+
+        Ok(groups)
+    }
+}
+
+fn default_expiry_warning_days() -> u64 {
+    14
+}
+
+fn default_expiry_check_interval_secs() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    10_000
+}
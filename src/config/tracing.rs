@@ -0,0 +1,37 @@
+use serde::Deserialize;
+
+/// Exports a span per request, with child spans for token verification and the
+/// upstream exchange, to an OpenTelemetry collector via OTLP/HTTP (JSON encoding).
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Tracing {
+    /// The collector's traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Share of new traces that are recorded, from 0 to 1.
+    /// Traces continued from trusted proxies follow the proxy's sampling decision.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_export_interval_ms")]
+    pub export_interval_ms: u64,
+    /// Finished spans beyond this are dropped until the next export.
+    #[serde(default = "default_max_queued_spans")]
+    pub max_queued_spans: usize,
+}
+
+fn default_service_name() -> String {
+    "oauth_gateway".into()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_export_interval_ms() -> u64 {
+    5000
+}
+
+fn default_max_queued_spans() -> usize {
+    2048
+}
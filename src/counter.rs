@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts things currently in progress, such as requests or connections.
+#[derive(Default)]
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increments the counter until the returned guard is dropped.
+    pub fn enter(&self) -> CounterGuard<'_> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+
+        CounterGuard(self)
+    }
+
+    /// Like `enter`, for guards that outlive the borrow, e.g. when held by a response body.
+    pub fn enter_owned(self: &Arc<Self>) -> OwnedCounterGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+
+        OwnedCounterGuard(self.clone())
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct CounterGuard<'a>(&'a Counter);
+
+impl Drop for CounterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct OwnedCounterGuard(Arc<Counter>);
+
+impl Drop for OwnedCounterGuard {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,113 @@
+//! Bodies for responses generated by the gateway itself, as opposed to the upstream.
+
+use hyper::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, HeaderValue};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use rand::Rng;
+use serde_json::json;
+
+use crate::header::X_REQUEST_ID;
+
+/// Marks a response as a gateway error whose body is filled in by [`render`].
+#[derive(Debug, Clone)]
+struct GatewayError {
+    code: &'static str,
+    message: String,
+}
+
+/// Creates an error response. `code` is a stable machine-readable identifier,
+/// `message` a human-readable explanation.
+pub fn error_response(status: StatusCode, code: &'static str, message: impl Into<String>) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap();
+
+    response.extensions_mut().insert(GatewayError {
+        code,
+        message: message.into(),
+    });
+
+    response
+}
+
+/// Generates an id clients can quote when reporting an error.
+pub fn generate_request_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+/// Whether error bodies should be rendered as HTML for a request with these headers.
+pub fn wants_html(request_headers: &HeaderMap) -> bool {
+    request_headers.get_all(ACCEPT).iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.contains("text/html"))
+}
+
+/// Fills in the body of gateway errors as JSON, or HTML if `wants_html`.
+/// Other responses are left untouched.
+/// Each error is logged with `request_id`, so reports quoting it can be found in the log.
+pub fn render(response: &mut Response<Body>, wants_html: bool, request_id: &str) {
+    let error = match response.extensions_mut().remove::<GatewayError>() {
+        Some(error) => error,
+        None => return,
+    };
+
+    eprintln!("Request {} answered with {} {}: {}", request_id, response.status().as_u16(), error.code, error.message);
+
+    let (content_type, body) = match wants_html {
+        true => ("text/html; charset=utf-8", render_html(response.status(), &error, request_id)),
+        false => ("application/json", render_json(&error, request_id)),
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    if let Ok(request_id) = HeaderValue::from_str(request_id) {
+        headers.insert(X_REQUEST_ID, request_id);
+    }
+
+    *response.body_mut() = Body::from(body);
+}
+
+fn render_json(error: &GatewayError, request_id: &str) -> String {
+    json!({
+        "error": error.code,
+        "message": error.message,
+        "request_id": request_id,
+    })
+    .to_string()
+}
+
+fn render_html(status: StatusCode, error: &GatewayError, request_id: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <head><title>{status}</title></head>\n\
+        <body>\n\
+        <h1>{status}</h1>\n\
+        <p>{message}</p>\n\
+        <p><small>Request ID: {request_id}</small></p>\n\
+        </body>\n\
+        </html>\n",
+        status = escape_html(&status.to_string()),
+        message = escape_html(&error.message),
+        request_id = escape_html(request_id),
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,83 @@
+//! Tunnels for `CONNECT` requests to servers in forward proxy mode.
+
+use anyhow::{Result, Context};
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::io;
+use tokio::net::{self, TcpStream};
+use tokio::time::{self, Duration};
+
+use crate::config::Server;
+use crate::error_response::error_response;
+
+/// Connects to the requested target if `connect_allow` of `server` allows it and, once the client
+/// upgrades, copies bytes in both directions until either side closes.
+/// `guard` is held until then, e.g. the request's concurrency permits.
+pub async fn connect(request: Request<Body>, server: &Server, guard: impl Send + 'static) -> Result<Response<Body>> {
+    let (target, host, port) = match request.uri().authority() {
+        Some(authority) => match authority.port_u16() {
+            Some(port) => {
+                let host = authority.host();
+                let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+
+                (authority.to_string(), host.to_owned(), port)
+            },
+            None => return Ok(invalid_target()),
+        },
+        None => return Ok(invalid_target()),
+    };
+
+    // Only the checked addresses are connected to, so names can't resolve differently in between
+    let addrs = match net::lookup_host((host.as_str(), port)).await {
+        Ok(addrs) => addrs
+            .filter(|addr| server.allows_connect(&host, addr))
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            eprintln!("Failed to resolve {}: {}", target, err);
+
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "connect_failed", format!("Failed to resolve {}", target)));
+        },
+    };
+
+    if addrs.is_empty() {
+        eprintln!("CONNECT to {} is not allowed", target);
+
+        return Ok(error_response(StatusCode::FORBIDDEN, "forbidden_target", format!("Tunnels to {} are not allowed", target)));
+    }
+
+    let connect_timeout = server.connect_timeout_ms.map(Duration::from_millis);
+    let stream = TcpStream::connect(&*addrs);
+    let stream = match connect_timeout {
+        Some(connect_timeout) => time::timeout(connect_timeout, stream).await
+            .with_context(|| format!("Timed out connecting to {}", target))?,
+        None => stream.await,
+    };
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Failed to connect to {}: {}", target, err);
+
+            return Ok(error_response(StatusCode::BAD_GATEWAY, "connect_failed", format!("Failed to connect to {}", target)));
+        },
+    };
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut upgraded = match hyper::upgrade::on(request).await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                eprintln!("CONNECT upgrade to {} failed: {}", target, err);
+                return;
+            },
+        };
+
+        if let Err(err) = io::copy_bidirectional(&mut upgraded, &mut stream).await {
+            eprintln!("CONNECT tunnel to {} failed: {}", target, err);
+        }
+    });
+
+    Ok(Response::new(Body::empty()))
+}
+
+fn invalid_target() -> Response<Body> {
+    error_response(StatusCode::BAD_REQUEST, "invalid_target", "CONNECT requires a host:port target")
+}
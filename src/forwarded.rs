@@ -0,0 +1,204 @@
+//! The `Forwarded` header (RFC 7239).
+
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::HeaderMap;
+use hyper::header::{FORWARDED, HeaderValue, InvalidHeaderValue};
+
+use crate::header::X_FORWARDED_FOR;
+
+/// One hop of a `Forwarded` chain.
+#[derive(Debug, Default)]
+pub struct Element<'a> {
+    /// The client the request was received from.
+    pub for_addr: Option<IpAddr>,
+    /// The interface the request was received on.
+    pub by: Option<SocketAddr>,
+    /// The `Host` the request was sent to.
+    pub host: Option<&'a str>,
+    /// The scheme the request was received with.
+    pub proto: Option<&'a str>,
+}
+
+impl Element<'_> {
+    fn write(&self, out: &mut String) {
+        let mut pairs = Vec::new();
+
+        if let Some(for_addr) = self.for_addr {
+            pairs.push(("for", node(&Node::Ip(for_addr))));
+        }
+
+        if let Some(by) = self.by {
+            pairs.push(("by", node(&Node::Socket(by))));
+        }
+
+        if let Some(host) = self.host {
+            pairs.push(("host", value(host)));
+        }
+
+        if let Some(proto) = self.proto {
+            pairs.push(("proto", value(proto)));
+        }
+
+        for (i, (name, value)) in pairs.iter().enumerate() {
+            if i > 0 {
+                out.push(';');
+            }
+
+            out.push_str(name);
+            out.push('=');
+            out.push_str(value);
+        }
+    }
+}
+
+/// Appends `elements` to the chain already present in `headers`.
+pub fn build_forwarded_header(headers: &HeaderMap, elements: &[Element]) -> Result<HeaderValue, InvalidHeaderValue> {
+    let mut forwarded = headers.get_all(FORWARDED).iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    for element in elements {
+        if !forwarded.is_empty() {
+            forwarded.push_str(", ");
+        }
+
+        element.write(&mut forwarded);
+    }
+
+    HeaderValue::from_str(&forwarded)
+}
+
+/// Appends `peer_ip` to the `X-Forwarded-For` list already present in `headers`.
+pub fn build_x_forwarded_for_header(headers: &HeaderMap, peer_ip: IpAddr) -> Result<HeaderValue, InvalidHeaderValue> {
+    let mut forwarded_for = headers.get_all(X_FORWARDED_FOR).iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    let peer_ip = peer_ip.to_string();
+
+    forwarded_for.push(&peer_ip);
+
+    HeaderValue::from_str(&forwarded_for.join(", "))
+}
+
+enum Node {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+/// IPv6 addresses are bracketed, which (like ports) requires quoting.
+fn node(node: &Node) -> String {
+    let node = match node {
+        Node::Ip(IpAddr::V4(ip)) => ip.to_string(),
+        Node::Ip(IpAddr::V6(ip)) => format!("[{}]", ip),
+        Node::Socket(addr) => addr.to_string(),
+    };
+
+    value(&node)
+}
+
+/// Formats `value` as a token, or a quoted-string if it contains other characters.
+fn value(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        return value.to_owned();
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+
+        quoted.push(c);
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(headers: &HeaderMap, elements: &[Element]) -> String {
+        build_forwarded_header(headers, elements).unwrap().to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn ipv4() {
+        let element = Element {
+            for_addr: Some("192.0.2.60".parse().unwrap()),
+            by: Some("203.0.113.43:443".parse().unwrap()),
+            host: Some("example.com"),
+            proto: Some("https"),
+        };
+
+        assert_eq!(
+            build(&HeaderMap::new(), &[element]),
+            r#"for=192.0.2.60;by="203.0.113.43:443";host=example.com;proto=https"#,
+        );
+    }
+
+    #[test]
+    fn ipv6_is_bracketed_and_quoted() {
+        let element = Element {
+            for_addr: Some("2001:db8:cafe::17".parse().unwrap()),
+            by: Some("[2001:db8::1]:8443".parse().unwrap()),
+            host: Some("example.com:8443"),
+            proto: Some("http"),
+        };
+
+        assert_eq!(
+            build(&HeaderMap::new(), &[element]),
+            r#"for="[2001:db8:cafe::17]";by="[2001:db8::1]:8443";host="example.com:8443";proto=http"#,
+        );
+    }
+
+    #[test]
+    fn appends_to_existing_chain() {
+        let mut headers = HeaderMap::new();
+        headers.append(FORWARDED, HeaderValue::from_static("for=192.0.2.43"));
+        headers.append(FORWARDED, HeaderValue::from_static("for=198.51.100.17;proto=https, for=\"_hidden\""));
+
+        let element = Element {
+            for_addr: Some("203.0.113.1".parse().unwrap()),
+            ..Element::default()
+        };
+
+        assert_eq!(
+            build(&headers, &[element]),
+            r#"for=192.0.2.43, for=198.51.100.17;proto=https, for="_hidden", for=203.0.113.1"#,
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_appends_peer() {
+        let mut headers = HeaderMap::new();
+        let peer_ip = "203.0.113.1".parse().unwrap();
+
+        assert_eq!(build_x_forwarded_for_header(&headers, peer_ip).unwrap(), "203.0.113.1");
+
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("192.0.2.43, 198.51.100.17"));
+
+        assert_eq!(build_x_forwarded_for_header(&headers, peer_ip).unwrap(), "192.0.2.43, 198.51.100.17, 203.0.113.1");
+    }
+
+    #[test]
+    fn quoted_values_are_escaped() {
+        let element = Element {
+            host: Some(r#"a"b\c"#),
+            ..Element::default()
+        };
+
+        assert_eq!(build(&HeaderMap::new(), &[element]), r#"host="a\"b\\c""#);
+    }
+}
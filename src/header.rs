@@ -2,3 +2,17 @@
 pub const X_USER_ID: &str = "x-user-id";
 pub const X_USER_NAME: &str = "x-user-name";
 pub const X_USER_ROLE: &str = "x-user-role";
+pub const X_USER_GROUPS: &str = "x-user-groups";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+pub const X_FORWARDED_TLS_VERSION: &str = "x-forwarded-tls-version";
+pub const X_FORWARDED_TLS_CIPHER: &str = "x-forwarded-tls-cipher";
+pub const X_REQUEST_ID: &str = "x-request-id";
+pub const X_DEBUG_UPSTREAM: &str = "x-debug-upstream";
+pub const X_GATEWAY_SERVER: &str = "x-gateway-server";
+pub const X_GATEWAY_ROUTE: &str = "x-gateway-route";
+pub const X_GATEWAY_ADMIN: &str = "x-gateway-admin";
+pub const SERVER_TIMING: &str = "server-timing";
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
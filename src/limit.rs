@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Duration};
+
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    semaphore: Option<Arc<Semaphore>>,
+    queue_timeout: Duration,
+    max_queued: Option<usize>,
+    queued: Arc<AtomicUsize>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_concurrent: Option<usize>, max_queued: Option<usize>, queue_timeout: Duration) -> Self {
+        Self {
+            semaphore: max_concurrent.map(Semaphore::new).map(Arc::new),
+            queue_timeout,
+            max_queued,
+            queued: Arc::new(AtomicUsize::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Waits up to `queue_timeout` for a free slot, first come first served.
+    /// Fails right away if `max_queued` requests are already waiting.
+    /// The slot is held until the returned `Permit` is dropped.
+    pub async fn acquire(&self) -> Result<Permit, Saturated> {
+        let semaphore = match &self.semaphore {
+            Some(semaphore) => semaphore.clone(),
+            None => return Ok(Permit { _permit: None }),
+        };
+
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Permit { _permit: Some(permit) });
+        }
+
+        let permit = match self.enter_queue() {
+            Some(_queued) => time::timeout(self.queue_timeout, semaphore.acquire_owned()).await
+                .ok()
+                .and_then(Result::ok),
+            None => None,
+        };
+
+        match permit {
+            Some(permit) => Ok(Permit { _permit: Some(permit) }),
+            None => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(Saturated)
+            },
+        }
+    }
+
+    fn enter_queue(&self) -> Option<Queued> {
+        self.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| match self.max_queued {
+            Some(max_queued) if queued >= max_queued => None,
+            _ => Some(queued + 1),
+        }).ok()?;
+
+        Some(Queued(self.queued.clone()))
+    }
+
+    /// Requests turned away since startup.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+pub struct Saturated;
+
+/// Leaves the queue when dropped, whether a slot was acquired or not.
+struct Queued(Arc<AtomicUsize>);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn full_queue_rejects_immediately() {
+        let limit = ConcurrencyLimit::new(Some(1), Some(1), Duration::from_secs(60));
+        let permit = limit.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.is_ok() }
+        });
+
+        while limit.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(limit.acquire().await.is_err());
+        assert_eq!(limit.rejected(), 1);
+
+        drop(permit);
+        assert!(waiting.await.unwrap());
+        assert_eq!(limit.queued(), 0);
+    }
+}
@@ -1,29 +1,48 @@
+use std::io;
 use std::net::SocketAddr;
 
 use anyhow::{Result, Context};
 use async_shutdown::Shutdown;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::time::{self, Duration};
 
+use crate::config;
+
 pub struct Listener {
     listen_addr: SocketAddr,
     shutdown: Shutdown,
 }
 
 impl Listener {
-    pub async fn start(listen_addr: SocketAddr, sender: Sender<Accepted>) -> Result<Self> {
+    /// Accepts connections while `accepting` is true, until then they wait in the backlog.
+    pub async fn start(
+        listen_addr: SocketAddr,
+        config: &config::Listener,
+        sender: Sender<Accepted>,
+        mut accepting: watch::Receiver<bool>,
+    ) -> Result<Self> {
         let shutdown = Shutdown::new();
         let this = Self {
             listen_addr,
             shutdown: shutdown.clone(),
         };
 
-        let listener = TcpListener::bind(listen_addr).await
+        let listener = bind_with_retries(listen_addr, config).await
             .with_context(|| format!("Failed to listen on {}", listen_addr))?;
+        let tcp_keepalive = tcp_keepalive(config)?;
 
         let listener_loop = async move {
             loop {
+                while !*accepting.borrow() {
+                    if accepting.changed().await.is_err() {
+                        return;
+                    }
+                }
+
                 let (stream, remote_addr) = match listener.accept().await.context("Tcp accept failed") {
                     Ok(accepted) => accepted,
                     Err(err) => {
@@ -33,15 +52,25 @@ impl Listener {
                     },
                 };
 
+                if let Some(tcp_keepalive) = &tcp_keepalive {
+                    if let Err(err) = SockRef::from(&stream).set_tcp_keepalive(tcp_keepalive) {
+                        eprintln!("Failed to enable TCP keepalive for {}: {}", remote_addr, err);
+                    }
+                }
+
                 let accepted = Accepted {
                     listen_addr,
                     remote_addr,
                     stream,
                 };
 
-                // TODO: try to send immediately and limit capacity
-                if sender.send(accepted).await.is_err() {
-                    break;
+                match sender.try_send(accepted) {
+                    Ok(()) => {},
+                    // Dropping the stream closes the connection
+                    Err(TrySendError::Full(accepted)) => {
+                        eprintln!("Accept queue is full, dropping connection from {}", accepted.remote_addr);
+                    },
+                    Err(TrySendError::Closed(_)) => break,
                 }
             }
         };
@@ -69,8 +98,168 @@ impl Drop for Listener {
     }
 }
 
+async fn bind_with_retries(listen_addr: SocketAddr, config: &config::Listener) -> Result<TcpListener> {
+    let mut delay = Duration::from_millis(config.bind_retry_delay_ms);
+
+    for _ in 0..config.bind_retries {
+        match bind(listen_addr, config) {
+            Err(err) if is_addr_in_use(&err) => {
+                eprintln!("{} is in use, retrying in {:?}", listen_addr, delay);
+                time::sleep(delay).await;
+                delay *= 2;
+            },
+            result => return result,
+        }
+    }
+
+    bind(listen_addr, config)
+}
+
+fn is_addr_in_use(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::AddrInUse)
+}
+
+fn bind(listen_addr: SocketAddr, config: &config::Listener) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(listen_addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Same as `TcpListener::bind`
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    if config.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+
+    if listen_addr.is_ipv6() {
+        socket.set_only_v6(!config.dual_stack)
+            .context("Failed to set IPV6_V6ONLY")?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&listen_addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    socket.set_reuse_port(true)
+        .context("Failed to set SO_REUSEPORT")
+}
+
+#[cfg(not(all(unix, not(target_os = "solaris"), not(target_os = "illumos"))))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    anyhow::bail!("`reuse_port` is not supported on this platform")
+}
+
+fn tcp_keepalive(config: &config::Listener) -> Result<Option<TcpKeepalive>> {
+    let time = match config.tcp_keepalive_secs {
+        Some(time) => Duration::from_secs(time),
+        None => return Ok(None),
+    };
+
+    set_keepalive_probes(TcpKeepalive::new().with_time(time), config).map(Some)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_keepalive_probes(mut tcp_keepalive: TcpKeepalive, config: &config::Listener) -> Result<TcpKeepalive> {
+    if let Some(interval) = config.tcp_keepalive_interval_secs {
+        tcp_keepalive = tcp_keepalive.with_interval(Duration::from_secs(interval));
+    }
+
+    if let Some(retries) = config.tcp_keepalive_retries {
+        tcp_keepalive = tcp_keepalive.with_retries(retries);
+    }
+
+    Ok(tcp_keepalive)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_keepalive_probes(tcp_keepalive: TcpKeepalive, config: &config::Listener) -> Result<TcpKeepalive> {
+    if config.tcp_keepalive_interval_secs.is_some() || config.tcp_keepalive_retries.is_some() {
+        anyhow::bail!("`tcp_keepalive_interval_secs` and `tcp_keepalive_retries` are only supported on Linux");
+    }
+
+    Ok(tcp_keepalive)
+}
+
 pub struct Accepted {
     pub listen_addr: SocketAddr,
     pub remote_addr: SocketAddr,
     pub stream: TcpStream,
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_is_retried_until_the_address_is_free() {
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen_addr = occupied.local_addr().unwrap();
+        let config = config::Listener {
+            bind_retries: 5,
+            bind_retry_delay_ms: 50,
+            ..config::Listener::default()
+        };
+
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(100)).await;
+            drop(occupied);
+        });
+
+        assert!(bind_with_retries(listen_addr, &config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn bind_fails_once_retries_are_exhausted() {
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen_addr = occupied.local_addr().unwrap();
+        let config = config::Listener {
+            bind_retries: 1,
+            bind_retry_delay_ms: 10,
+            ..config::Listener::default()
+        };
+
+        assert!(bind_with_retries(listen_addr, &config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dual_stack_sets_ipv6_only() {
+        for dual_stack in [true, false] {
+            let config = config::Listener {
+                dual_stack,
+                ..config::Listener::default()
+            };
+
+            // Hosts without IPv6 can't tell
+            let listener = match bind("[::1]:0".parse().unwrap(), &config) {
+                Ok(listener) => listener,
+                Err(_) => return,
+            };
+
+            assert_eq!(SockRef::from(&listener).only_v6().unwrap(), !dual_stack);
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_wait_in_the_backlog_until_accepting() {
+        let listen_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (sender, mut receiver) = mpsc::channel(1);
+        let (accepting_tx, accepting_rx) = watch::channel(false);
+        let _listener = Listener::start(listen_addr, &config::Listener::default(), sender, accepting_rx).await.unwrap();
+
+        // Connects thanks to the backlog, without being accepted
+        let _client = TcpStream::connect(listen_addr).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(100), receiver.recv()).await.is_err());
+
+        accepting_tx.send(true).unwrap();
+
+        let accepted = time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap();
+        assert!(accepted.is_some());
+    }
+}
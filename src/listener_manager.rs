@@ -2,31 +2,45 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 
 use anyhow::{Result, Context};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, watch};
 use tokio::sync::mpsc::{self, Sender, Receiver};
 
+use crate::config;
 use crate::listener::{Accepted, Listener};
 
-const MAX_UNACCEPTED_SOCKETS: usize = 100;
-
 pub struct ListenerManager {
     listeners: Mutex<HashMap<SocketAddr, Listener>>,
     socket_tx: Sender<Accepted>,
     socket_rx: Mutex<Receiver<Accepted>>,
+    /// Whether listeners accept connections, see `wait_for_oidc`.
+    accepting_tx: watch::Sender<bool>,
+    accepting_rx: watch::Receiver<bool>,
+    config: config::Listener,
 }
 
 impl ListenerManager {
-    pub fn new() -> Self {
-        let (socket_tx, socket_rx) = mpsc::channel(MAX_UNACCEPTED_SOCKETS);
+    pub fn new(max_unaccepted_sockets: usize, config: config::Listener) -> Self {
+        // A zero capacity channel would panic
+        let (socket_tx, socket_rx) = mpsc::channel(max_unaccepted_sockets.max(1));
         let socket_rx = Mutex::new(socket_rx);
+        let (accepting_tx, accepting_rx) = watch::channel(!config.wait_for_oidc);
 
         Self {
             listeners: Mutex::default(),
             socket_tx,
             socket_rx,
+            accepting_tx,
+            accepting_rx,
+            config,
         }
     }
 
+    /// Lets listeners accept connections, which wait in the backlog until then.
+    pub fn start_accepting(&self) {
+        // Can't fail, `accepting_rx` keeps the channel open
+        let _ = self.accepting_tx.send(true);
+    }
+
     pub async fn start_listening_on(&self, listen_addr: SocketAddr) -> Result<()> {
         let mut listeners = self.listeners.lock().await;
 
@@ -34,7 +48,7 @@ impl ListenerManager {
             return Ok(());
         }
 
-        let listener = Listener::start(listen_addr, self.socket_tx.clone()).await
+        let listener = Listener::start(listen_addr, &self.config, self.socket_tx.clone(), self.accepting_rx.clone()).await
             .context("Failed to start listener")?;
 
         listeners.insert(listen_addr, listener);
@@ -42,14 +56,31 @@ impl ListenerManager {
         Ok(())
     }
 
-    pub async fn stop_listening_on(&self, addr: SocketAddr) {
+    /// Stops accepting connections on `addr`. Returns whether it was listened on.
+    /// Connections that were already accepted are not affected.
+    pub async fn stop_listening_on(&self, addr: SocketAddr) -> bool {
         let mut listeners = self.listeners.lock().await;
 
-        if let Some(listener) = listeners.remove(&addr) {
-            listener.shutdown().await;
+        match listeners.remove(&addr) {
+            Some(listener) => {
+                listener.shutdown().await;
+                true
+            },
+            None => false,
         }
     }
 
+    pub async fn listen_addrs(&self) -> Vec<SocketAddr> {
+        let mut listen_addrs = self.listeners.lock().await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+
+        listen_addrs.sort();
+
+        listen_addrs
+    }
+
     pub async fn accept(&self) -> Result<Accepted> {
         let mut socket_rx = self.socket_rx.lock().await;
 
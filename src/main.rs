@@ -1,73 +1,200 @@
-use std::convert::TryFrom;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
-use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{Result, Context, Error, anyhow};
+use anyhow::{Result, Context, Error, anyhow, bail};
 use auth::IntrospectionResult;
 use futures::TryFutureExt;
-use futures::future::{self, BoxFuture, FutureExt, Ready};
-use header::{X_USER_ID, X_USER_NAME, X_USER_ROLE};
-use hyper::{Body, Request, Response, StatusCode, Uri};
-use hyper::header::{AUTHORIZATION, FORWARDED, HOST, HeaderValue};
-use hyper::http::uri::Scheme;
+use futures::future::{self, BoxFuture, Future, FutureExt};
+use header::{SERVER_TIMING, X_DEBUG_UPSTREAM, X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO, X_FORWARDED_TLS_CIPHER, X_FORWARDED_TLS_VERSION, X_GATEWAY_ROUTE, X_GATEWAY_SERVER, X_USER_GROUPS, X_USER_ID, X_USER_NAME, X_USER_ROLE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri, Version};
+use hyper::header::{ACCEPT_ENCODING, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, FORWARDED, HOST, IF_NONE_MATCH, LOCATION, RETRY_AFTER, SET_COOKIE, TRANSFER_ENCODING, UPGRADE, EXPECT, HeaderMap, HeaderName, HeaderValue};
 use hyper::server::conn::Http;
+use hyper::http::uri::Authority;
 use oauth2::TokenIntrospectionResponse;
+use oauth2::url::form_urlencoded;
 use proto::Proto;
 use reqwest::Client;
 use rustls::sign::{CertifiedKey, RsaSigningKey};
-use rustls::{Certificate, PrivateKey};
+use rustls::{Certificate, PrivateKey, ProtocolVersion};
 use tls_manager::TlsManager;
 use tokio::io::BufReader;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
+use tokio::signal;
+use tokio::sync::watch;
 use tokio::time::{self, Duration};
 use unicase::Ascii;
 
-use self::auth::extensions::Token;
+use self::auth::AuthError;
+use self::auth::extensions::{Token, generic};
 use self::listener_manager::ListenerManager;
 use self::hyperion::Service;
 use self::config::Config;
+use self::config::server::{Mode, PemSource, RouteAuth, StrictPublicRoutes, Upstream};
+use self::config::tls::{HostPrecedence, UnknownSni};
 use self::listener::Accepted;
-
+use self::limit::{ConcurrencyLimit, Permit, Saturated};
+use self::access_log::{AccessLog, AuthenticatedUser, RequestInfo};
+use self::upstream_client::{UpstreamClient, UpstreamClients};
+use self::upstream_selector::UpstreamSelector;
+use self::error_response::error_response;
+use self::counter::Counter;
+use self::circuit_breaker::CircuitBreaker;
+use self::maintenance::Maintenance;
+use self::static_response::StaticResponse;
+use self::security_headers::SecurityHeaders;
+use self::body_rewrite::BodyRewrite;
+use self::compression::Compression;
+use self::response_body::ResponseBody;
+use self::response_cache::{Lookup, ResponseCache};
+use self::trace::{Span, SpanContext, SpanKind, Tracer};
+
+mod access_log;
+mod admin;
+mod circuit_breaker;
+mod compression;
 mod config;
+mod counter;
 mod auth;
+mod body_rewrite;
 mod header;
 mod hyperion;
+mod limit;
 mod listener;
 mod listener_manager;
+mod maintenance;
+mod error_response;
+mod forward_proxy;
+mod forwarded;
 mod tls_manager;
+mod trace;
+mod upstream_client;
+#[cfg(unix)]
+mod unix_connector;
+mod upstream_selector;
+mod ocsp;
 mod proto;
+mod response_body;
+mod response_cache;
+mod security_headers;
+mod set_cookie;
+mod static_response;
+mod whoami;
+mod x509;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
+    let strict_startup = std::env::args().skip(1).any(|arg| arg == "--strict-startup");
     let config = Config::read("config.toml")
         .context("failed to read config")?;
 
     let mut app = App::new(config).await?;
     let config = &app.config;
 
+    match (&config.tls.default_cert, &config.tls.default_key) {
+        (Some(cert), Some(key)) => {
+            let certified_key = load_certified_key(PemSource::File(cert), PemSource::File(key))
+                .context("Failed to load default tls certificate / key")?;
+
+            app.tls_manager.set_default_certified_key(certified_key)?;
+        },
+        (None, None) => {},
+        _ => bail!("`default_cert` and `default_key` must be set together"),
+    }
+
+    let mut ocsp_refreshers = Vec::new();
+
     for server_config in &config.servers {
         if let Some(tls_config) = &server_config.tls {
-            let certified_key = load_certified_key(tls_config)
-                .context("Failed to load tls certificate / key")?;
+            let mut certified_key = load_certified_key(tls_config.cert_source()?, tls_config.key_source()?)
+                .with_context(|| format!("Failed to load tls certificate / key for {}", server_config.name))?;
+
+            if let Some(ocsp_path) = &tls_config.ocsp {
+                certified_key.ocsp = load_ocsp_response(ocsp_path, &certified_key);
+            }
 
-            app.tls_manager.add_certified_key(
-                server_config.listen,
-                server_config.name.clone(),
+            let certified_key = app.tls_manager.add_certified_key(
+                &server_config.listen,
+                &tls_config.server_names(&server_config.name),
                 certified_key,
+                !tls_config.skip_cert_name_check,
             )?;
+
+            if let (Some(ocsp_path), true) = (&tls_config.ocsp, tls_config.ocsp_refresh) {
+                ocsp_refreshers.push((certified_key, ocsp_path.clone()));
+            }
         }
     }
 
-    for server_config in &config.servers {
-        app.listener_manager.start_listening_on(server_config.listen).await
-            .with_context(|| format!("Failed to listen on {}", server_config.listen))?;
-        println!("Listening on {}", server_config.listen);
+    let expiry_warning_period = chrono::Duration::days(config.tls.expiry_warning_days as i64);
+
+    app.tls_manager.check_expiry(expiry_warning_period);
+
+    for listen_addr in config.servers.iter().flat_map(|server| &server.listen) {
+        app.listener_manager.start_listening_on(*listen_addr).await
+            .with_context(|| format!("Failed to listen on {}", listen_addr))?;
+        println!("Listening on {}", listen_addr);
     }
 
     let app = Arc::new(app);
 
+    if app.config.http.check_upstreams_on_start {
+        if strict_startup {
+            if !check_upstreams(&app).await {
+                bail!("Some upstreams are unreachable");
+            }
+        } else {
+            let app = app.clone();
+            tokio::spawn(async move { check_upstreams(&app).await });
+        }
+    }
+
+    match &app.config.openid.discovery_document {
+        Some(path) => {
+            let oidc_client = auth::oidc_client_from_document(&app.config.openid, path)?;
+
+            app.oidc.set(oidc_client);
+        },
+        None => { tokio::spawn(discover_oidc_client(app.clone())); },
+    }
+    tokio::spawn(watch_cert_expiry(app.clone()));
+
+    if let Some(tracer) = &app.tracer {
+        tokio::spawn(tracer.clone().export_periodically(app.http.clone()));
+    }
+
+    admin::start(app.clone())
+        .context("Failed to start admin interface")?;
+
+    for (certified_key, ocsp_path) in ocsp_refreshers {
+        tokio::spawn(refresh_ocsp_response(app.clone(), certified_key, ocsp_path));
+    }
+
+    if app.config.listener.wait_for_oidc {
+        println!("Waiting for OIDC discovery before accepting connections");
+        app.oidc.wait().await;
+        app.listener_manager.start_accepting();
+    }
+
+    // Connections finish their requests and close once the first is sent,
+    // their tasks are only dropped, and the connections closed, once the second is sent
+    let (shut_down_gracefully, shutting_down) = watch::channel(());
+    let (force_close, force_closed) = watch::channel(());
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
     loop {
-        let accepted = match app.listener_manager.accept().await.context("Accept failed") {
+        let accepted = tokio::select! {
+            result = &mut shutdown => {
+                result.context("Failed to listen for shutdown signals")?;
+                break;
+            },
+            accepted = app.listener_manager.accept() => accepted,
+        };
+
+        let accepted = match accepted.context("Accept failed") {
             Ok(accepted) => accepted,
             Err(err) => {
                 eprintln!("{:#}", err);
@@ -76,35 +203,163 @@ pub async fn main() -> Result<()> {
             },
         };
 
-        tokio::spawn(
-            handle_client(
-                app.clone(),
-                accepted,
-            )
-            .map_err(|err| {
-                eprintln!("{:#}", err);
-            })
-        );
+        spawn_client(app.clone(), accepted, shutting_down.clone(), force_closed.clone());
+    }
+
+    shut_down(&app, shut_down_gracefully, force_close).await;
+
+    Ok(())
+}
+
+/// Keeps serving public routes while the identity provider is unreachable.
+async fn discover_oidc_client(app: Arc<App>) {
+    app.oidc.discover_and_refresh(&app.config).await;
+}
+
+/// Returns whether all upstreams responded.
+async fn check_upstreams(app: &App) -> bool {
+    let checks = app.config.servers.iter()
+        .zip(&app.upstream_clients)
+        .filter(|(server, _)| server.mode == Mode::ReverseProxy)
+        .flat_map(|(server, upstream_clients)| server.upstream.iter().map(move |upstream| (server, upstream, upstream_clients)))
+        .map(|(server, upstream, upstream_clients)| async move {
+            let result = check_upstream(server, upstream, &upstream_clients.for_upstream(upstream).default).await;
+
+            match &result {
+                Ok(()) => println!("Upstream {} of {} is reachable", upstream.authority, server.name),
+                Err(err) => eprintln!("Upstream check of {} for {} failed: {:#}", upstream.authority, server.name, err),
+            }
+
+            result.is_ok()
+        });
+
+    futures::future::join_all(checks).await
+        .into_iter()
+        .all(|ok| ok)
+}
+
+async fn check_upstream(server: &config::Server, upstream: &Upstream, upstream_client: &UpstreamClient) -> Result<()> {
+    let (method, path) = match &server.health_path {
+        Some(path) => (Method::GET, path.as_str()),
+        None => (Method::HEAD, "/"),
+    };
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("{}://{}{}", upstream.scheme(server), server.request_authority(upstream)?, upstream.join_path(path)))
+        .body(Body::empty())
+        .context("invalid upstream check request")?;
+
+    let response = upstream_client.send(request, Some(Duration::from_secs(5))).await?;
+
+    if server.health_path.is_some() && !response.status().is_success() {
+        bail!("health check responded with {}", response.status());
+    }
+
+    Ok(())
+}
+
+async fn watch_cert_expiry(app: Arc<App>) {
+    let tls_config = &app.config.tls;
+    let warning_period = chrono::Duration::days(tls_config.expiry_warning_days as i64);
+    let mut interval = time::interval(Duration::from_secs(tls_config.expiry_check_interval_secs));
+
+    // The first tick completes immediately and startup already checked
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        app.tls_manager.check_expiry(warning_period);
+    }
+}
+
+fn load_ocsp_response(path: &Path, certified_key: &CertifiedKey) -> Option<Vec<u8>> {
+    let der = match std::fs::read(path) {
+        Ok(der) => der,
+        Err(err) => {
+            eprintln!("Failed to read OCSP response {:?}: {}", path, err);
+            return None;
+        },
+    };
+
+    let cert = &certified_key.cert.first()?.0;
+    let issuer = certified_key.cert.get(1).map(|issuer| issuer.0.as_slice());
+
+    match ocsp::parse_response(der, cert, issuer) {
+        Ok(response) => Some(response.der),
+        Err(err) => {
+            eprintln!("Ignoring OCSP response {:?}: {:#}", path, err);
+            None
+        },
+    }
+}
+
+/// Periodically fetches a fresh OCSP response, staples it and persists it to `ocsp_path`.
+/// Refreshes happen halfway to the current response's `nextUpdate`.
+async fn refresh_ocsp_response(app: Arc<App>, mut certified_key: Arc<CertifiedKey>, ocsp_path: PathBuf) {
+    const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+    const DEFAULT_REFRESH_DELAY: Duration = Duration::from_secs(60 * 60);
+
+    let (cert, issuer) = match certified_key.cert.as_slice() {
+        [cert, issuer, ..] => (cert.0.clone(), issuer.0.clone()),
+        _ => {
+            eprintln!("Can't refresh OCSP response {:?}: certificate chain lacks the issuer", ocsp_path);
+            return;
+        },
+    };
+
+    let mut delay = certified_key.ocsp.clone()
+        .and_then(|der| ocsp::parse_response(der, &cert, Some(&issuer)).ok())
+        .and_then(|response| response.next_update)
+        .map(refresh_delay)
+        .unwrap_or_default();
+
+    loop {
+        time::sleep(delay).await;
+
+        let response = match ocsp::fetch(&app.http, &cert, &issuer).await {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Failed to refresh OCSP response {:?}: {:#}", ocsp_path, err);
+                delay = RETRY_DELAY;
+                continue;
+            },
+        };
+
+        if let Err(err) = tokio::fs::write(&ocsp_path, &response.der).await {
+            eprintln!("Failed to write OCSP response {:?}: {}", ocsp_path, err);
+        }
+
+        let mut new_certified_key = CertifiedKey::clone(&certified_key);
+        new_certified_key.ocsp = Some(response.der);
+        certified_key = app.tls_manager.replace_certified_key(&certified_key, new_certified_key);
+
+        delay = response.next_update
+            .map(refresh_delay)
+            .unwrap_or(DEFAULT_REFRESH_DELAY);
     }
 }
 
-fn load_certified_key(tls_config: &config::server::Tls) -> Result<CertifiedKey> {
-    let cert = std::fs::File::open(&tls_config.cert)
-        .with_context(|| format!("Failed to open {:?}", tls_config.cert))?;
-    let mut cert = std::io::BufReader::new(cert);
+fn refresh_delay(next_update: chrono::DateTime<chrono::Utc>) -> Duration {
+    const MIN_DELAY: Duration = Duration::from_secs(60);
+
+    let remaining = (next_update - chrono::Utc::now()).to_std().unwrap_or_default();
+
+    (remaining / 2).max(MIN_DELAY)
+}
+
+fn load_certified_key(cert_source: PemSource, key_source: PemSource) -> Result<CertifiedKey> {
+    let mut cert = cert_source.reader()?;
     let cert = rustls_pemfile::certs(&mut cert)
-        .with_context(|| format!("Failed to read cert from {:?}", tls_config.cert))?
+        .with_context(|| format!("Failed to read cert from {}", cert_source))?
         .into_iter()
         .map(Certificate)
         .collect::<Vec<_>>();
 
-    let key = std::fs::File::open(&tls_config.key)
-        .with_context(|| format!("Failed to open {:?}", tls_config.key))?;
-    let mut key = std::io::BufReader::new(key);
+    let mut key = key_source.reader()?;
     let key = rustls_pemfile::pkcs8_private_keys(&mut key)
-        .with_context(|| format!("Failed to read key from {:?}", tls_config.key))?
+        .with_context(|| format!("Failed to read key from {}", key_source))?
         .pop()
-        .with_context(|| format!("No keys found in {:?}", tls_config.key))?;
+        .with_context(|| format!("No keys found in {}", key_source))?;
     let key = PrivateKey(key);
     let key = RsaSigningKey::new(&key)
         .map_err(|_| anyhow!("Invalid key"))?;
@@ -113,287 +368,3173 @@ fn load_certified_key(tls_config: &config::server::Tls) -> Result<CertifiedKey>
     Ok(certified_key)
 }
 
+/// Serves the connection until it is done or `force_closed` changes.
+/// Once `shutting_down` changes, it is done after the requests in flight.
+fn spawn_client(
+    app: Arc<App>,
+    accepted: Accepted,
+    shutting_down: watch::Receiver<()>,
+    mut force_closed: watch::Receiver<()>,
+) {
+    let client = handle_client(
+        app,
+        accepted,
+        shutting_down,
+    )
+    .map_err(|err| {
+        eprintln!("{:#}", err);
+    });
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = client => {},
+            _ = force_closed.changed() => {},
+        }
+    });
+}
+
 async fn handle_client(
     app: Arc<App>,
     accepted: Accepted,
+    shutting_down: watch::Receiver<()>,
 ) -> Result<()> {
+    let _connection = app.open_connections.enter();
     let mut handler = RequestHandler {
         app: app.clone(),
         client_addr: accepted.remote_addr,
         listen_addr: accepted.listen_addr,
         sni_hostname: None,
+        tls_version: None,
+        tls_cipher: None,
+        is_tls: false,
     };
 
     let mut stream = BufReader::new(accepted.stream);
 
-    let proto = proto::detect(&mut stream).await
-        .context("Failed to detect protocol")?;
+    // Clients that connect and send nothing would otherwise keep the connection forever
+    let handshake_timeout = Duration::from_millis(app.config.tls.handshake_timeout_ms);
+    let handshake_deadline = time::Instant::now() + handshake_timeout;
+    let proto = match time::timeout_at(handshake_deadline, proto::detect(&mut stream)).await {
+        Ok(proto) => proto.context("Failed to detect protocol")?,
+        Err(_) => {
+            eprintln!("{} sent nothing within {:?}", accepted.remote_addr, handshake_timeout);
+            return Ok(());
+        },
+    };
 
     eprintln!("Proto: {:?}", proto);
 
     if proto == Proto::Plain {
-        Http::new().serve_connection(stream, handler.compat()).await?;
+        let connection = http_server(&app.config).serve_connection(stream, handler.compat()).with_upgrades();
+        serve_until_shutdown(connection, shutting_down, |connection| connection.graceful_shutdown()).await?;
         return Ok(());
     }
 
-    let tls_acceptor = app.tls_manager.acceptor(&accepted.listen_addr)
+    let server_config = app.tls_manager.server_config_for(&accepted.listen_addr)
         .with_context(|| format!("No TLS acceptor for {}", accepted.listen_addr))?;
 
-    let tls_stream = tls_acceptor.accept(stream).await
-        .context("Tls accept failed")?;
+    // `None` if the connection was closed for an unknown server name
+    let handshake = async {
+        let tls_stream = match app.config.tls.unknown_sni {
+            UnknownSni::Close => {
+                let acceptor = rustls::server::Acceptor::new()
+                    .context("Failed to create TLS acceptor")?;
+                let handshake = LazyConfigAcceptor::new(acceptor, stream).await
+                    .context("Failed to read ClientHello")?;
+                let server_name = handshake.client_hello().server_name();
+
+                if !server_name.is_some_and(|server_name| app.tls_manager.knows_server_name(&accepted.listen_addr, server_name)) {
+                    app.tls_manager.log_unknown_sni_rejection(server_name);
+                    return Ok(None);
+                }
+
+                handshake.into_stream(server_config).await
+            },
+            UnknownSni::DefaultCert | UnknownSni::Reject => TlsAcceptor::from(server_config).accept(stream).await,
+        };
+
+        tls_stream.map(Some).context("Tls accept failed")
+    };
+    let tls_stream = match time::timeout_at(handshake_deadline, handshake).await {
+        Ok(tls_stream) => tls_stream?,
+        Err(_) => {
+            eprintln!("TLS handshake with {} timed out after {:?}", accepted.remote_addr, handshake_timeout);
+            return Ok(());
+        },
+    };
+    let tls_stream = match tls_stream {
+        Some(tls_stream) => tls_stream,
+        None => return Ok(()),
+    };
+
+    let tls_connection = tls_stream.get_ref().1;
+    let is_http2 = tls_connection.alpn_protocol() == Some(b"h2");
 
-    handler.sni_hostname = tls_stream.get_ref().1.sni_hostname()
+    handler.sni_hostname = tls_connection.sni_hostname()
         .map(String::from)
         .map(Arc::new);
+    handler.tls_version = tls_connection.protocol_version().and_then(tls_version_name).map(HeaderValue::from_static);
+    handler.tls_cipher = tls_connection.negotiated_cipher_suite()
+        .and_then(|cipher_suite| HeaderValue::from_str(&format!("{:?}", cipher_suite.suite())).ok());
+    handler.is_tls = true;
 
-    Http::new().serve_connection(tls_stream, handler.compat()).await?;
+    // Clients without ALPN are still detected by hyper if they speak HTTP/2
+    let connection = http_server(&app.config)
+        .http2_only(is_http2)
+        .serve_connection(tls_stream, handler.compat())
+        .with_upgrades();
+
+    serve_until_shutdown(connection, shutting_down, |connection| connection.graceful_shutdown()).await?;
 
     Ok(())
 }
 
+/// Drives `connection` and calls `graceful_shutdown` on it once `shutting_down` changes:
+/// idle connections close right away instead of holding up the shutdown, busy ones after their requests.
+async fn serve_until_shutdown<C, E>(
+    connection: C,
+    mut shutting_down: watch::Receiver<()>,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+) -> Result<(), E>
+where
+    C: Future<Output = Result<(), E>>,
+{
+    tokio::pin!(connection);
+
+    tokio::select! {
+        result = &mut connection => return result,
+        Ok(()) = shutting_down.changed() => {},
+    }
+
+    graceful_shutdown(connection.as_mut());
+
+    connection.await
+}
+
+fn tls_version_name(version: ProtocolVersion) -> Option<&'static str> {
+    match version {
+        ProtocolVersion::TLSv1_2 => Some("TLSv1.2"),
+        ProtocolVersion::TLSv1_3 => Some("TLSv1.3"),
+        _ => None,
+    }
+}
+
+/// Applies the `[http1]` and `[http2]` settings to a connection builder.
+fn http_server(config: &Config) -> Http {
+    let http1 = &config.http1;
+    let http2 = &config.http2;
+    let mut http = Http::new();
+
+    http.http1_keep_alive(http1.keep_alive)
+        .pipeline_flush(http1.pipeline_flush)
+        .http2_max_concurrent_streams(http2.max_concurrent_streams)
+        .http2_initial_stream_window_size(http2.initial_stream_window_size)
+        .http2_initial_connection_window_size(http2.initial_connection_window_size)
+        .http2_adaptive_window(http2.adaptive_window)
+        .http2_keep_alive_interval(http2.keep_alive_interval_secs.map(Duration::from_secs))
+        .http2_keep_alive_timeout(Duration::from_secs(http2.keep_alive_timeout_secs));
+
+    if let Some(max_buf_size) = http1.max_buf_size {
+        http.max_buf_size(max_buf_size);
+    }
+
+    http
+}
+
 #[derive(Clone)]
 struct RequestHandler {
     app: Arc<App>,
     client_addr: SocketAddr,
     listen_addr: SocketAddr,
     sni_hostname: Option<Arc<String>>,
+    tls_version: Option<HeaderValue>,
+    tls_cipher: Option<HeaderValue>,
+    is_tls: bool,
 }
 
-impl<'a> Service<Request<Body>> for RequestHandler {
-    type Response = Response<Body>;
+impl Service<Request<Body>> for RequestHandler {
+    type Response = Response<ResponseBody>;
     type Error = Error;
-    type ReadyFuture = Ready<Result<(), Self::Error>>;
+    type ReadyFuture = future::Ready<Result<(), Self::Error>>;
     type CallFuture = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn ready(&mut self) -> Self::ReadyFuture {
         future::ok(())
     }
 
-    fn call(&mut self, request: Request<Body>) -> Self::CallFuture {
+    fn call(&mut self, mut request: Request<Body>) -> Self::CallFuture {
         let this = self.clone();
 
         async move {
-            let response = this.proxy_request(request).await;
+            // Taken here rather than in `ready`, which idle connections wait in, too
+            let admission = this.app.request_limit.acquire().await;
+            let request_info = RequestInfo::new(&request);
+            let client_ip = this.real_client_ip(&request);
+            let _in_flight = this.app.in_flight_requests.enter();
+            let wants_html = error_response::wants_html(request.headers());
+            let request_id = error_response::generate_request_id();
+            let mut span = this.request_span(&request);
+
+            if let Some(span) = &span {
+                request.extensions_mut().insert(span.context().clone());
+            }
 
-            if let Err(err) = response {
-                eprintln!("{:#}", err);
+            let mut response = this.handle_request(admission, request, client_ip, &request_id).await;
 
-                let response = Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::empty())
-                    .unwrap();
+            if let Some(span) = &mut span {
+                span.set_attribute("http.response.status_code", response.status().as_u16());
 
-                return Ok(response)
+                if response.status().is_server_error() {
+                    span.set_error();
+                }
+            }
+
+            error_response::render(&mut response, wants_html, &request_id);
+
+            let mut response = ResponseBody::new(response);
+
+            // Logged once the body is sent, with its actual size and the full latency
+            if let Some(access_log) = this.app.access_log.clone() {
+                let status = response.status();
+                let user = response.extensions().get::<AuthenticatedUser>().map(|user| user.0.clone());
+
+                response.body_mut().on_end(move |bytes_sent| {
+                    access_log.log(&request_info, client_ip, status, user.as_deref(), bytes_sent);
+                });
             }
 
-            response
+            Ok(response)
         }
         .boxed()
     }
 }
 
-impl RequestHandler {
-    async fn proxy_request(&self, mut request: Request<Body>) -> Result<Response<Body>> {
-        let host_name = match self.extract_host_name(&request) {
-            Ok(host_name) => host_name,
-            Err(err) => {
-                eprintln!("Failed to extract host header: {}", err);
+/// How `proxy_request` treated a request, for `debug_headers` and `server_timing`.
+#[derive(Default)]
+struct RouteDecision<'a> {
+    server: Option<&'a config::Server>,
+    /// `public`, `protected` or `403`, once known.
+    route: Option<&'static str>,
+    /// Time spent verifying the token.
+    auth: Option<Duration>,
+    /// Time until the upstream's response headers arrived.
+    upstream: Option<Duration>,
+}
 
-                let response = Response::builder()
-                    .status(400)
-                    .body(Body::empty())
-                    .unwrap();
+impl RouteDecision<'_> {
+    /// E.g. `auth;dur=12.5, upstream;dur=40.1, total;dur=53.0`, in milliseconds.
+    fn server_timing(&self, total: Duration) -> String {
+        [("auth", self.auth), ("upstream", self.upstream), ("total", Some(total))].iter()
+            .filter_map(|(name, duration)| Some(format!("{};dur={:.1}", name, duration?.as_secs_f64() * 1000.0)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
 
-                return Ok(response)
-            },
+impl RequestHandler {
+    /// Continues the trace of trusted proxies, so spans line up across services.
+    fn request_span(&self, request: &Request<Body>) -> Option<Span> {
+        let tracer = self.app.tracer.as_ref()?;
+        let parent = match self.app.config.forwarding.is_trusted_proxy(&self.client_addr.ip()) {
+            true => SpanContext::from_headers(request.headers()),
+            false => None,
         };
+        let mut span = tracer.start("request", SpanKind::Server, parent.as_ref());
 
-        let server = self.app.config.servers.iter()
-            .find(|server|
-                server.listen == self.listen_addr &&
-                Ascii::new(&server.name) == host_name
-            );
-        let server = match server {
-            Some(server) => server,
-            None => {
-                eprintln!("server for host '{}' not defined", host_name);
+        span.set_attribute("http.request.method", request.method().as_str());
+        span.set_attribute("url.path", request.uri().path());
 
-                let response = Response::builder()
-                    .status(400)
-                    .body(Body::empty())
-                    .unwrap();
+        Some(span)
+    }
 
-                return Ok(response)
+    async fn handle_request(
+        &self,
+        admission: Result<Permit, Saturated>,
+        request: Request<Body>,
+        client_ip: IpAddr,
+        request_id: &str,
+    ) -> Response<Body> {
+        let permit = match admission {
+            Ok(permit) => permit,
+            Err(Saturated) => {
+                eprintln!("Too many concurrent requests");
+
+                return service_unavailable(self.app.config.limits.retry_after_secs, "overloaded", "Too many concurrent requests")
             },
         };
 
-        println!("selected server '{}'", server.name);
-
-        let is_public_route = server.is_public_route(request.uri());
+        let started = time::Instant::now();
+        let mut decision = RouteDecision::default();
+        // Shared with `CONNECT` tunnels, which outlive the response
+        let permit = Arc::new(permit);
+        let mut response = match self.proxy_request(request, client_ip, &permit, &mut decision).await {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("Request {} failed: {:#}", request_id, err);
 
-        let token_info = if is_public_route {
-            None
-        } else {
-            let token_info = auth::verify_access_token(&self.app.oidc, &request).await
-                .context("Token verification failed")?;
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "The gateway failed to handle the request")
+            },
+        };
 
-            match token_info {
-                Some(token_info) => Some(token_info),
-                None => {
-                    eprintln!("Unauthenticated");
+        if let Some(server) = decision.server.filter(|server| server.debug_headers) {
+            let headers = response.headers_mut();
 
-                    let response = Response::builder()
-                        .status(StatusCode::UNAUTHORIZED)
-                        .body(Body::empty())
-                        .unwrap();
+            if let Ok(name) = HeaderValue::from_str(&server.name) {
+                headers.insert(X_GATEWAY_SERVER, name);
+            }
 
-                    return Ok(response)
-                }
+            if let Some(route) = decision.route {
+                headers.insert(X_GATEWAY_ROUTE, HeaderValue::from_static(route));
             }
-        };
+        }
 
-        if let Some(token_info) = &token_info {
-            eprintln!("{:#?}", token_info);
+        if decision.server.is_some_and(|server| server.server_timing) {
+            let server_timing = decision.server_timing(started.elapsed());
+
+            // Added to the upstream's own timings, if any
+            response.headers_mut().append(SERVER_TIMING, HeaderValue::from_str(&server_timing).unwrap());
         }
 
-        let upstream_authority = server.upstream.parse()
-            .context("failed to parse upstream_host as authority")?;
-        let upstream_scheme = match server.upstream_tls {
-            true => Scheme::HTTPS,
-            false => Scheme::HTTP,
-        };
-        let http_version = request.version();
+        // Counts as in flight until the body is sent, not just the headers
+        response_body::hold(&mut response, permit);
 
-        {
-            let mut parts = request.uri().clone().into_parts();
-            parts.scheme = Some(upstream_scheme);
-            parts.authority = Some(upstream_authority);
+        response
+    }
 
-            let upstream_uri = Uri::from_parts(parts)
-                .context("failed to build upstream uri")?;
+    /// Sends the browser to the provider to log in.
+    fn start_login(&self, server: &config::Server, session: &config::server::Session, request: &Request<Body>) -> Result<Response<Body>> {
+        let oidc = match self.app.oidc.get() {
+            Some(oidc) => oidc,
+            None => return Ok(service_unavailable(
+                self.app.config.limits.retry_after_secs,
+                "auth_unavailable",
+                "Authentication is temporarily unavailable",
+            )),
+        };
 
-            *request.uri_mut() = upstream_uri;
-        }
+        let return_to = local_return_to(request.uri());
+        let (authorization_url, state) = self.app.sessions.start_login(&oidc, &server.name, session, return_to)?;
 
-        remove_dangerous_headers(&mut request);
+        let response = Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, authorization_url.as_str())
+            .header(SET_COOKIE, auth::sessions::login_state_cookie(session, &state, self.is_tls)?)
+            .header(CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .context("failed to build login redirect")?;
 
-        let mut upstream_request = create_upstream_request(request, &self.client_addr);
+        Ok(response)
+    }
 
-        // let is_authenticated_str = if user_info.is_some() { "true" } else { "false" };
-        // upstream_request.headers_mut().insert("X-User-Authenticated", HeaderValue::from_static(is_authenticated_str));
+    /// Starts a session with the tokens for the code the provider sent the browser back with.
+    async fn complete_login(&self, server: &config::Server, session: &config::server::Session, request: &Request<Body>) -> Result<Response<Body>> {
+        let oidc = match self.app.oidc.get() {
+            Some(oidc) => oidc,
+            None => return Ok(service_unavailable(
+                self.app.config.limits.retry_after_secs,
+                "auth_unavailable",
+                "Authentication is temporarily unavailable",
+            )),
+        };
 
-        if let Some(token_info) = token_info {
-            enrich_request_with_token_info(&mut upstream_request, &token_info)?;
-        }
+        let (state, code) = match (query_param(request.uri(), "state"), query_param(request.uri(), "code")) {
+            (Some(state), Some(code)) => (state, code),
+            _ => {
+                // E.g. `error=access_denied` if the user declined
+                let error = query_param(request.uri(), "error").unwrap_or_else(|| "code or state missing".into());
+                eprintln!("Login failed: {}", error);
 
-        let mut upstream_response = self.app.http.execute(upstream_request).await
-            .context("upstream request failed")?;
-        let mut response = Response::builder()
-            // loses status line text
-            .status(upstream_response.status())
-            .version(http_version);
+                return Ok(error_response(StatusCode::UNAUTHORIZED, "login_failed", "The login was not completed"))
+            },
+        };
+
+        let login = match self.app.sessions.complete_login(&oidc, self.app.oidc.client_assertion(), &server.name, session, request.headers(), &state, code).await {
+            Ok(Some(login)) => login,
+            Ok(None) => return Ok(error_response(StatusCode::BAD_REQUEST, "invalid_login", "Unknown or expired login, please log in again")),
+            Err(err) => {
+                eprintln!("{:#}", err);
 
-        mem::swap(upstream_response.headers_mut(), response.headers_mut().context("failed to get builder headers")?);
+                return Ok(error_response(StatusCode::BAD_GATEWAY, "login_failed", "The login could not be completed"))
+            },
+        };
 
-        let body = Body::wrap_stream(upstream_response.bytes_stream());
-        let response = response.body(body).context("failed to set response body")?;
+        let response = Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, login.return_to)
+            .header(SET_COOKIE, auth::sessions::session_cookie(session, &login.session_id, self.is_tls)?)
+            .header(SET_COOKIE, auth::sessions::remove_login_state_cookie(session, self.is_tls)?)
+            .header(CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .context("failed to build login redirect")?;
 
         Ok(response)
     }
 
-    fn extract_host_name<'a>(&'a self, request: &'a Request<Body>) -> Result<Ascii<&'a str>> {
-        // TODO: maybe ensure that sni hostname matches request hostname
-
-        if let Some(sni_hostname) = &self.sni_hostname {
-            return Ok(Ascii::new(sni_hostname));
+    /// Ends the browser's session and sends it to `return_to`.
+    fn logout(&self, server: &config::Server, session: &config::server::Session, request: &Request<Body>) -> Result<Response<Body>> {
+        if let Some(session_id) = auth::sessions::session_id(request.headers(), &session.cookie) {
+            self.app.sessions.end(&server.name, session_id);
         }
 
-        let host = request.headers().get(HOST)
-            .context("Host header does is not set")?;
-        let host = host.to_str()
-            .context("Host header is invalid UTF-8")?;
-        let host = host.split_once(":")
-            .map(|(host, _port)| host)
-            .unwrap_or(host);
+        let response = Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, local_return_to(request.uri()))
+            .header(SET_COOKIE, auth::sessions::end_session_cookie(session, self.is_tls)?)
+            .header(CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .context("failed to build logout redirect")?;
 
-        Ok(Ascii::new(host))
+        Ok(response)
     }
-}
 
-struct App {
-    listener_manager: ListenerManager,
-    tls_manager: TlsManager,
-    oidc: auth::Client,
-    http: Client,
-    config: Config,
-}
+    async fn whoami(&self, server: &config::Server, request: &Request<Body>) -> Result<Response<Body>> {
+        let (method, path) = match whoami::target_route(request.uri()) {
+            Ok(route) => route,
+            Err(message) => return Ok(error_response(StatusCode::BAD_REQUEST, "invalid_route", message)),
+        };
 
-impl App {
-    async fn new(config: Config) -> Result<Self> {
-        let oidc = auth::create_oidc_client(&config).await
-            .context("failed to create oidc client")?;
+        let token_info = auth::verify_access_token(
+            &self.app.oidc,
+            &self.app.negative_cache,
+            &self.app.revoked_tokens,
+            &self.app.jti_denylist,
+            &self.app.introspections,
+            &self.app.introspection_metrics,
+            self.app.config.openid.token_type_hint,
+            &auth::TokenSource::new(&self.app.config.openid, server),
+            request,
+        ).await;
+
+        let verification = match &token_info {
+            Ok(token_info) => Ok(token_info.as_ref().map(|token_info| {
+                let (roles, groups) = token_roles_and_groups(token_info, &self.app.config.openid);
+
+                whoami::Identity { token_info, roles, groups }
+            })),
+            // Details may reveal internals, they only go to the log
+            Err(err) => {
+                eprintln!("whoami: {}", err);
 
-        Ok(Self {
-            listener_manager: ListenerManager::new(),
-            tls_manager: TlsManager::new(),
-            oidc,
-            http: Client::new(),
-            config,
-        })
+                Err(match err {
+                    AuthError::Unavailable => "auth temporarily unavailable",
+                    AuthError::Introspection(_) => "token introspection failed",
+                    AuthError::Internal(_) => "internal error",
+                }.to_owned())
+            },
+        };
+
+        Ok(whoami::response(server, &method, &path, verification))
     }
-}
 
-fn create_upstream_request(request: Request<Body>, client_addr: &SocketAddr) -> reqwest::Request {
-    let mut upstream_request = reqwest::Request::try_from(request)
-        .expect("failed to convert request");
-    {
-        let addr = match client_addr {
-            SocketAddr::V4(v4) => v4.to_string(),
-            SocketAddr::V6(v6) => format!("\"{}\"", v6),
+    async fn proxy_request<'a>(
+        &'a self,
+        mut request: Request<Body>,
+        client_ip: IpAddr,
+        permit: &Arc<Permit>,
+        decision: &mut RouteDecision<'a>,
+    ) -> Result<Response<Body>> {
+        if has_ambiguous_framing(request.headers()) {
+            eprintln!("Rejecting request with ambiguous Content-Length / Transfer-Encoding");
+
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "ambiguous_framing",
+                "Content-Length and Transfer-Encoding conflict",
+            ))
+        }
+
+        let host_name = match self.extract_host_name(&request) {
+            Ok(host_name) => host_name,
+            Err(HostError::Invalid(err)) => {
+                eprintln!("Failed to extract host header: {:#}", err);
+
+                return Ok(error_response(StatusCode::BAD_REQUEST, "invalid_host", "Missing or invalid Host header"))
+            },
+            Err(HostError::Mismatch { sni, host }) => {
+                eprintln!("Host {:?} doesn't match SNI {:?}", host, sni);
+
+                return Ok(error_response(
+                    StatusCode::MISDIRECTED_REQUEST,
+                    "misdirected_request",
+                    "The requested host doesn't match the TLS server name",
+                ))
+            },
         };
-        let forwarded = format!("for={}", addr);
-        let forwarded = HeaderValue::from_str(&forwarded)
-            .expect("Failed to construct forwarded header value");
 
-        upstream_request.headers_mut().insert(FORWARDED, forwarded);
-    }
-    upstream_request
-}
+        let is_connect = request.method() == Method::CONNECT;
+        let server = select_server(&self.app.config.servers, &self.listen_addr, host_name.as_ref(), is_connect);
+        let (server_index, server) = match server {
+            Some(server) => server,
+            None => {
+                eprintln!("server for host '{}' not defined", host_name);
 
-fn remove_dangerous_headers(request: &mut Request<Body>) {
-    let headers = request.headers_mut();
+                return Ok(error_response(
+                    StatusCode::NOT_FOUND,
+                    "unknown_host",
+                    format!("No server configured for host '{}'", host_name),
+                ))
+            },
+        };
 
-    headers.remove(HOST);
-    headers.remove(AUTHORIZATION);
-    headers.remove(X_USER_ID);
-    headers.remove(X_USER_NAME);
-}
+        println!("selected server '{}'", server.name);
+        decision.server = Some(server);
 
-fn enrich_request_with_token_info(request: &mut reqwest::Request, token_info: &IntrospectionResult) -> Result<()> {
-    let headers = request.headers_mut();
+        if server.mode == Mode::Redirect {
+            return redirect_to_https(&host_name, server.redirect_port, request.uri())
+        }
 
-    if let Some(user_id) = token_info.sub() {
-        headers.insert(X_USER_ID, user_id.parse()?);
-    }
+        let maintenance = &self.app.maintenance[server_index];
 
-    if let Some(username) = token_info.username() {
-        headers.insert(X_USER_NAME, username.parse()?);
-    }
+        if maintenance.is_enabled() && !server.is_exempt_from_maintenance(request.method(), request.uri()) {
+            return Ok(maintenance.response(error_response::wants_html(request.headers())))
+        }
 
-    match &token_info.extra_fields().0 {
-        Token::Keybase(token) => {
-            for role in &token.realm_access.roles {
-                let role = match role.parse::<HeaderValue>() {
-                    Ok(role) => role,
-                    Err(_) => {
-                        eprintln!("Role is not a valid header value: {}", role);
-                        continue
-                    },
-                };
-                headers.append(X_USER_ROLE, role);
-            }
-        },
-    }
+        // Dropped with this future, i.e. also on errors and when the client disconnects,
+        // or held by the body of proxied responses
+        let server_permit = match self.app.server_limits[server_index].acquire().await {
+            Ok(permit) => permit,
+            Err(Saturated) => {
+                if self.app.config.limits.log_saturation {
+                    eprintln!("Too many concurrent requests for server '{}'", server.name);
+                }
 
-    Ok(())
+                return Ok(service_unavailable(
+                    self.app.config.limits.retry_after_secs,
+                    "overloaded",
+                    "Too many concurrent requests for this server",
+                ))
+            },
+        };
+
+        let is_forward_proxy = server.mode == Mode::ForwardProxy;
+
+        if is_forward_proxy != is_connect {
+            let message = match is_forward_proxy {
+                true => "Only CONNECT is supported by this proxy",
+                false => "CONNECT is not supported by this server",
+            };
+
+            return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", message))
+        }
+
+        if !is_forward_proxy && server.whoami_path.as_deref() == Some(request.uri().path()) {
+            return self.whoami(server, &request).await
+        }
+
+        if let Some(session) = server.session.as_ref().filter(|_| !is_forward_proxy) {
+            if request.uri().path() == session.login_path {
+                return self.start_login(server, session, &request)
+            }
+
+            if request.uri().path() == session.callback_path {
+                return self.complete_login(server, session, &request).await
+            }
+
+            if request.uri().path() == session.logout_path {
+                return self.logout(server, session, &request)
+            }
+        }
+
+        if let Some(allowed_methods) = server.allowed_methods.for_path(request.uri().path()) {
+            if !allowed_methods.contains(request.method()) {
+                let allow = allowed_methods.iter()
+                    .map(Method::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let mut response = error_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "method_not_allowed",
+                    format!("Only {} is allowed for this route", allow),
+                );
+
+                response.headers_mut().insert(ALLOW, HeaderValue::from_str(&allow)?);
+
+                return Ok(response)
+            }
+        }
+
+        let route_auth = match is_forward_proxy {
+            true => RouteAuth::Required,
+            false => server.route_auth(request.method(), request.uri()),
+        };
+
+        decision.route = Some(match route_auth {
+            RouteAuth::Public => "public",
+            RouteAuth::Optional | RouteAuth::Required => "protected",
+        });
+
+        let token_source = auth::TokenSource::new(&self.app.config.openid, server);
+        let token_info = if route_auth == RouteAuth::Public {
+            None
+        } else {
+            // Browsers with a session send its cookie instead of a token
+            let session_id = server.session.as_ref()
+                .filter(|_| !request.headers().contains_key(&token_source.header))
+                .and_then(|session| Some((session, auth::sessions::session_id(request.headers(), &session.cookie)?.to_owned())));
+
+            if let Some((session, session_id)) = session_id {
+                if let Some(access_token) = self.app.sessions.access_token(&self.app.oidc, &server.name, session, &session_id).await {
+                    let authorization = HeaderValue::from_str(&format!("Bearer {}", access_token.secret()))
+                        .context("session access token is not a valid header value")?;
+
+                    request.headers_mut().insert(token_source.header.clone(), authorization);
+                }
+            }
+
+            let auth_started = time::Instant::now();
+            let mut verify_span = self.child_span(&request, "verify_access_token", SpanKind::Internal);
+            let token_info = auth::verify_access_token(
+                &self.app.oidc,
+                &self.app.negative_cache,
+                &self.app.revoked_tokens,
+                &self.app.jti_denylist,
+                &self.app.introspections,
+                &self.app.introspection_metrics,
+                self.app.config.openid.token_type_hint,
+                &token_source,
+                &request,
+            ).await;
+
+            decision.auth = Some(auth_started.elapsed());
+
+            if let Some(verify_span) = &mut verify_span {
+                verify_span.set_attribute("auth.active", matches!(token_info, Ok(Some(_))));
+
+                if token_info.is_err() {
+                    verify_span.set_error();
+                }
+            }
+
+            drop(verify_span);
+
+            // Optional auth never fails the request, not even if the IdP is unreachable
+            let token_info = match (token_info, route_auth) {
+                (Err(err), RouteAuth::Optional) => {
+                    eprintln!("Token verification failed, proceeding anonymously: {}", err);
+                    None
+                },
+                (Ok(token_info), _) => token_info,
+                (Err(err), _) if err.is_unavailable() => {
+                    eprintln!("{}", err);
+
+                    return Ok(service_unavailable(
+                        self.app.config.limits.retry_after_secs,
+                        "auth_unavailable",
+                        "Authentication is temporarily unavailable",
+                    ))
+                },
+                (Err(err), _) => return Err(Error::new(err).context("Token verification failed")),
+            };
+
+            match token_info {
+                Some(token_info) => Some(token_info),
+                None if route_auth == RouteAuth::Optional => None,
+                None => {
+                    eprintln!("Unauthenticated");
+
+                    return Ok(error_response(StatusCode::UNAUTHORIZED, "unauthenticated", "A valid access token is required"))
+                }
+            }
+        };
+
+        if let Some(token_info) = &token_info {
+            eprintln!("{:#?}", token_info);
+        }
+
+        // Matching routes always require a token, see `Server::route_auth`
+        let authorization = match is_forward_proxy {
+            true => None,
+            false => server.authorization(request.method(), request.uri()),
+        };
+
+        if let (Some(authorization), Some(token_info)) = (authorization, &token_info) {
+            let (roles, groups) = token_roles_and_groups(token_info, &self.app.config.openid);
+
+            if !authorization.allows(&roles, &groups) {
+                eprintln!("Forbidden: roles {:?} and groups {:?} are insufficient", roles, groups);
+                decision.route = Some("403");
+
+                return Ok(error_response(StatusCode::FORBIDDEN, "forbidden", "Insufficient roles for this route"))
+            }
+        }
+
+        // Tokens are verified before the upstream is selected, so their issuer can pick it
+        let issuer_upstream = token_info.as_ref()
+            .and_then(|token_info| token_info.iss())
+            .and_then(|issuer| server.issuer_upstream(issuer));
+
+        // Balancing would hand them to any tenant's upstream
+        if token_info.is_some() && issuer_upstream.is_none() && !server.issuer_routes.is_empty() {
+            eprintln!("Forbidden: issuer {:?} has no issuer route", token_info.as_ref().and_then(|token_info| token_info.iss()));
+            decision.route = Some("403");
+
+            return Ok(error_response(StatusCode::FORBIDDEN, "unknown_issuer", "Tokens of this issuer are not accepted here"))
+        }
+
+        let authenticated_user = token_info.as_ref()
+            .and_then(|token_info| token_info.sub())
+            .map(String::from)
+            .map(AuthenticatedUser);
+
+        if is_forward_proxy {
+            let mut response = forward_proxy::connect(request, server, (permit.clone(), server_permit)).await?;
+
+            if let Some(authenticated_user) = authenticated_user {
+                response.extensions_mut().insert(authenticated_user);
+            }
+
+            return Ok(response)
+        }
+
+        if let Some(static_response) = &self.app.static_responses[server_index] {
+            let mut response = static_response.response();
+
+            if let Some(authenticated_user) = authenticated_user {
+                response.extensions_mut().insert(authenticated_user);
+            }
+
+            return Ok(response)
+        }
+
+        let authenticated = token_info.is_some();
+        // `Vary` refers to the headers as sent by the client, before any filtering
+        // Tenants share cache keys, so their responses must not be cached
+        let cache = self.app.response_cache.as_ref()
+            .filter(|_| issuer_upstream.is_none())
+            .and_then(|response_cache| Some((
+                response_cache,
+                ResponseCache::key(&request, host_name.as_ref())?,
+                request.headers().clone(),
+            )));
+        // Compression only needs these, and applies after caching, so the cache keeps one uncompressed copy
+        let accept_encoding = request.headers().get_all(ACCEPT_ENCODING).iter()
+            .map(|value| (ACCEPT_ENCODING, value.clone()))
+            .collect::<HeaderMap>();
+        let mut revalidate_etag = None;
+        // Held until the response is stored, identical requests missing the cache meanwhile wait for it
+        let mut cache_fetch = None;
+
+        if let Some((response_cache, cache_key, request_headers)) = &cache {
+            let (lookup, fetch) = response_cache.lookup_collapsing(cache_key, request_headers, authenticated).await;
+
+            cache_fetch = fetch;
+
+            match lookup {
+                Lookup::Fresh(mut response) => {
+                    *response.version_mut() = request.version();
+
+                    if let Some(compression) = &self.app.compression {
+                        compression.apply(&accept_encoding, &mut response);
+                    }
+
+                    if let Some(authenticated_user) = authenticated_user {
+                        response.extensions_mut().insert(authenticated_user);
+                    }
+
+                    return Ok(response)
+                },
+                // Conditional requests of the client are passed through as they are
+                Lookup::Stale(etag) if !request_headers.contains_key(IF_NONE_MATCH) => revalidate_etag = Some(etag),
+                Lookup::Stale(_) | Lookup::Miss => {},
+            }
+        }
+
+        // Keeps least-connections counts up to date until the response body is sent
+        let upstream_selector = &self.app.upstream_selectors[server_index];
+        let routed_upstream = issuer_upstream
+            .or_else(|| server.routed_upstream(request.headers()))
+            .or_else(|| server.canary_upstream(request.headers(), client_ip));
+        let debug_upstream = self.debug_upstream(server, request.headers(), client_ip);
+        let (upstream, upstream_selection) = match &debug_upstream {
+            Some(debug_upstream) => (debug_upstream, None),
+            None => {
+                let upstream_selection = match routed_upstream {
+                    Some(index) => upstream_selector.pin(index),
+                    None => upstream_selector.select(),
+                };
+
+                (&server.upstream[upstream_selection.index], Some(upstream_selection))
+            },
+        };
+        let upstream_scheme = upstream.scheme(server);
+        let public_scheme = match self.is_tls {
+            true => "https",
+            false => "http",
+        };
+        let public_host = match request.headers().get(HOST) {
+            Some(host) => host.clone(),
+            None => HeaderValue::from_str(&server.name)
+                .context("server name is not a valid header value")?,
+        };
+        let public_origin = format!(
+            "{}://{}",
+            public_scheme,
+            public_host.to_str().context("Host header is invalid UTF-8")?,
+        );
+        let http_version = request.version();
+        let is_upgrade = request.headers().contains_key(UPGRADE);
+        let is_head = request.method() == Method::HEAD;
+
+        {
+            let mut parts = request.uri().clone().into_parts();
+            parts.scheme = Some(upstream_scheme);
+            // Debug upstreams are requested as they are
+            parts.authority = Some(match &debug_upstream {
+                Some(_) => upstream.authority.clone(),
+                None => server.request_authority(upstream)?,
+            });
+
+            if !upstream.base_path.is_empty() {
+                let path_and_query = parts.path_and_query.as_ref().map_or("/", |path_and_query| path_and_query.as_str());
+
+                parts.path_and_query = Some(upstream.join_path(path_and_query).parse()
+                    .context("failed to join upstream base path")?);
+            }
+
+            let upstream_uri = Uri::from_parts(parts)
+                .context("failed to build upstream uri")?;
+
+            *request.uri_mut() = upstream_uri;
+        }
+
+        server.filter_request_headers(request.headers_mut());
+        let peer_ip = self.client_addr.ip();
+        let is_trusted_peer = self.app.config.forwarding.is_trusted_proxy(&peer_ip);
+
+        let forwarded_token = match server.forward_token {
+            true => request.headers().get(&token_source.header).cloned(),
+            false => None,
+        };
+
+        remove_dangerous_headers(&mut request, is_trusted_peer);
+        request.headers_mut().remove(&token_source.header);
+
+        if let Some(forwarded_token) = forwarded_token {
+            request.headers_mut().insert(token_source.header.clone(), forwarded_token);
+        }
+
+        if let Some(token_cookie) = token_source.cookie.filter(|_| !server.forward_token) {
+            auth::remove_cookie(request.headers_mut(), token_cookie);
+        }
+
+        request.headers_mut().remove(&server.scopes_header);
+
+        if let Some(session) = &server.session {
+            auth::sessions::remove_session_cookie(request.headers_mut(), &session.cookie);
+        }
+
+        if let Some(identity_header) = &server.identity_header {
+            request.headers_mut().remove(&identity_header.name);
+        }
+
+        // hyper sends `100 Continue` once the body is first polled, which only happens
+        // after authorization when it is streamed upstream. The expectation is fulfilled
+        // at that point and the upstream must not be asked again.
+        request.headers_mut().remove(EXPECT);
+
+        if let Some(etag) = &revalidate_etag {
+            request.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+        }
+
+        let public_host_str = public_host.to_str().context("Host header is invalid UTF-8")?;
+        let mut forwarded = Vec::new();
+
+        // Keep the client reported by a trusted proxy via `X-Forwarded-For`
+        if client_ip != peer_ip && !request.headers().contains_key(FORWARDED) {
+            forwarded.push(forwarded::Element {
+                for_addr: Some(client_ip),
+                ..forwarded::Element::default()
+            });
+        }
+
+        forwarded.push(forwarded::Element {
+            for_addr: Some(peer_ip),
+            by: Some(self.listen_addr),
+            host: Some(public_host_str),
+            proto: Some(public_scheme),
+        });
+
+        let forwarded = forwarded::build_forwarded_header(request.headers(), &forwarded)
+            .context("Failed to build Forwarded header")?;
+        let forwarded_for = forwarded::build_x_forwarded_for_header(request.headers(), peer_ip)
+            .context("Failed to build X-Forwarded-For header")?;
+        let headers = request.headers_mut();
+
+        headers.insert(FORWARDED, forwarded);
+        headers.insert(X_FORWARDED_FOR, forwarded_for);
+        // Only left over if set by a trusted proxy, which knows better what the client used
+        headers.entry(X_FORWARDED_PROTO).or_insert(HeaderValue::from_static(public_scheme));
+        headers.entry(X_FORWARDED_HOST).or_insert(public_host.clone());
+
+        if server.forward_tls_info {
+            if let Some(tls_version) = &self.tls_version {
+                headers.insert(X_FORWARDED_TLS_VERSION, tls_version.clone());
+            }
+
+            if let Some(tls_cipher) = &self.tls_cipher {
+                headers.insert(X_FORWARDED_TLS_CIPHER, tls_cipher.clone());
+            }
+        }
+
+        // The http client only fills in the upstream authority if no host is set
+        if server.preserve_host {
+            headers.insert(HOST, public_host.clone());
+        }
+
+        // let is_authenticated_str = if user_info.is_some() { "true" } else { "false" };
+        // upstream_request.headers_mut().insert("X-User-Authenticated", HeaderValue::from_static(is_authenticated_str));
+
+        if let Some(token_info) = token_info {
+            if server.enrich_headers {
+                enrich_request_with_token_info(headers, &token_info, &self.app.config.openid, &server.scopes_header)?;
+            }
+
+            if let Some(identity_header) = &server.identity_header {
+                let identity = encode_identity(&token_info, &identity_header.claims)?;
+                headers.insert(identity_header.name.clone(), identity);
+            }
+        }
+
+        if let Some(max_request_header_bytes) = server.max_request_header_bytes {
+            let header_bytes = header_bytes(headers);
+
+            if header_bytes > max_request_header_bytes {
+                eprintln!("Request headers are {} bytes, the limit is {}", header_bytes, max_request_header_bytes);
+
+                return Ok(error_response(
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "request_headers_too_large",
+                    format!("Request headers must not exceed {} bytes, including the ones added by the gateway", max_request_header_bytes),
+                ))
+            }
+        }
+
+        let request_timeout = match is_upgrade {
+            true => None,
+            false => server.request_timeout_ms.map(Duration::from_millis),
+        };
+
+        let attempt = match &self.app.circuit_breakers[server_index] {
+            Some(circuit_breaker) => match circuit_breaker.try_acquire() {
+                Some(attempt) => Some(attempt),
+                None => return Ok(service_unavailable(
+                    self.app.config.limits.retry_after_secs,
+                    "upstream_unavailable",
+                    "The upstream is failing, try again later",
+                )),
+            },
+            None => None,
+        };
+
+        // Must not be spawned: hyper drops this future and the response body when the client disconnects,
+        // which is what cancels the upstream request and its body stream.
+        // Ends with the response headers, the body is streamed afterwards
+        let mut upstream_span = self.child_span(&request, "upstream", SpanKind::Client);
+
+        if let Some(upstream_span) = &mut upstream_span {
+            upstream_span.set_attribute("server.address", upstream.authority.as_str());
+            upstream_span.context().inject(request.headers_mut());
+        }
+
+        // Kept to fetch the response after all if the entry is evicted before the upstream answers `304`,
+        // the client didn't ask for one
+        let unconditional_request = match &revalidate_etag {
+            Some(_) if request.body().is_end_stream() => {
+                let mut unconditional = Request::new(Body::empty());
+
+                *unconditional.method_mut() = request.method().clone();
+                *unconditional.uri_mut() = request.uri().clone();
+                *unconditional.version_mut() = request.version();
+                *unconditional.headers_mut() = request.headers().clone();
+                unconditional.headers_mut().remove(IF_NONE_MATCH);
+
+                Some(unconditional)
+            },
+            _ => None,
+        };
+        let upstream_client = self.app.upstream_clients[server_index].for_upstream(upstream).for_request(&request);
+        let upstream_started = time::Instant::now();
+        let response = upstream_client.send(request, request_timeout).await;
+
+        decision.upstream = Some(upstream_started.elapsed());
+
+        if let Some(upstream_span) = &mut upstream_span {
+            match &response {
+                Ok(response) => upstream_span.set_attribute("http.response.status_code", response.status().as_u16()),
+                Err(_) => upstream_span.set_error(),
+            }
+        }
+
+        drop(upstream_span);
+
+        if let Some(attempt) = attempt {
+            let success = match &response {
+                Ok(response) => !matches!(
+                    response.status(),
+                    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+                ),
+                Err(_) => false,
+            };
+
+            attempt.record(success);
+        }
+
+        let mut response = response?;
+        let mut refreshed = None;
+
+        if let (Some(_), Some((response_cache, cache_key, _))) = (&revalidate_etag, &cache) {
+            if response.status() == StatusCode::NOT_MODIFIED {
+                refreshed = response_cache.refresh(cache_key, response.headers(), authenticated);
+
+                if refreshed.is_none() {
+                    match unconditional_request {
+                        Some(unconditional_request) => response = upstream_client.send(unconditional_request, request_timeout).await?,
+                        None => return Ok(error_response(
+                            StatusCode::BAD_GATEWAY,
+                            "bad_upstream_response",
+                            "The upstream response could not be revalidated",
+                        )),
+                    }
+                }
+            }
+        }
+
+        if let Some(max_response_header_bytes) = server.max_response_header_bytes {
+            let header_bytes = header_bytes(response.headers());
+
+            if header_bytes > max_response_header_bytes {
+                eprintln!("Upstream response headers are {} bytes, the limit is {}", header_bytes, max_response_header_bytes);
+
+                return Ok(error_response(
+                    StatusCode::BAD_GATEWAY,
+                    "bad_upstream_response",
+                    "The upstream responded with oversized headers",
+                ))
+            }
+        }
+
+        *response.version_mut() = http_version;
+
+        let headers = response.headers_mut();
+
+        server.filter_response_headers(headers);
+
+        if server.rewrite_location {
+            rewrite_location(headers, upstream.scheme(server).as_str(), upstream, &public_origin);
+        }
+
+        if let Some(cookie_rewrite) = &server.cookie_rewrite {
+            let public_host = public_host.to_str().context("Host header is invalid UTF-8")?;
+            let origins = set_cookie::Origins {
+                upstream_host: upstream.authority.host(),
+                upstream_base_path: &upstream.base_path,
+                public_host: public_host.split_once(':').map_or(public_host, |(host, _port)| host),
+            };
+
+            set_cookie::rewrite_headers(headers, cookie_rewrite, &origins);
+        }
+
+        if let Some(security_headers) = &self.app.security_headers[server_index] {
+            security_headers.apply(headers, self.is_tls);
+        }
+
+        if let Some(body_rewrite) = &self.app.body_rewrites[server_index] {
+            if !is_head && !is_upgrade {
+                body_rewrite.apply(&mut response);
+            }
+        }
+
+        // Never pass on a body an upstream wrongly sent for `HEAD`.
+        // `Content-Length` stays, it describes what a `GET` would return.
+        if is_head {
+            *response.body_mut() = Body::empty();
+        }
+
+        if let Some((response_cache, cache_key, request_headers)) = cache {
+            if let Some(mut refreshed) = refreshed {
+                *refreshed.version_mut() = http_version;
+                response = refreshed;
+            } else if response_cache.is_storable(&response, authenticated) {
+                let (parts, body) = response.into_parts();
+                let body = hyper::body::to_bytes(body).await
+                    .context("failed to read upstream response for caching")?;
+
+                response_cache.store(cache_key, &request_headers, authenticated, parts.headers.clone(), body.clone());
+                response = Response::from_parts(parts, Body::from(body));
+            }
+        }
+
+        // Waiters are served from the cache now, or fetch on their own if the response wasn't stored,
+        // e.g. because it exceeds `max_object_bytes` and is streamed
+        drop(cache_fetch);
+
+        if let Some(compression) = &self.app.compression {
+            if !is_head && !is_upgrade {
+                compression.apply(&accept_encoding, &mut response);
+            }
+        }
+
+        if let Some(authenticated_user) = authenticated_user {
+            response.extensions_mut().insert(authenticated_user);
+        }
+
+        response_body::hold(&mut response, server_permit);
+        response_body::hold(&mut response, upstream_selection);
+
+        Ok(response)
+    }
+
+    /// Returns the address of the client, as reported by `X-Forwarded-For`
+    /// if the connecting peer is a trusted proxy.
+    fn real_client_ip(&self, request: &Request<Body>) -> IpAddr {
+        let forwarding = &self.app.config.forwarding;
+        let peer_ip = self.client_addr.ip();
+
+        if !forwarding.is_trusted_proxy(&peer_ip) {
+            return peer_ip;
+        }
+
+        let forwarded_for = request.headers().get_all(X_FORWARDED_FOR).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+
+        let mut client_ip = peer_ip;
+
+        for addr in forwarded_for.iter().rev().take(forwarding.trusted_hops) {
+            let addr = match addr.parse::<IpAddr>() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    eprintln!("Invalid X-Forwarded-For entry: {:?}", addr);
+                    break;
+                },
+            };
+
+            client_ip = addr;
+
+            if !forwarding.is_trusted_proxy(&client_ip) {
+                break;
+            }
+        }
+
+        client_ip
+    }
+
+    /// Starts a span under the request's span, if tracing is enabled.
+    fn child_span(&self, request: &Request<Body>, name: &'static str, kind: SpanKind) -> Option<Span> {
+        let tracer = self.app.tracer.as_ref()?;
+        let parent = request.extensions().get::<SpanContext>()?;
+
+        Some(tracer.start(name, kind, Some(parent)))
+    }
+
+    /// Returns the upstream requested with `X-Debug-Upstream`, if the server and peer may override it.
+    /// Proxies may be debug peers, but the clients they forward for are not.
+    fn debug_upstream(&self, server: &config::Server, headers: &HeaderMap, client_ip: IpAddr) -> Option<Upstream> {
+        if !server.debug_routing {
+            return None;
+        }
+
+        let debug_upstream = headers.get(X_DEBUG_UPSTREAM)?;
+        let peer_ip = self.client_addr.ip();
+
+        if client_ip != peer_ip || !server.debug_peers.iter().any(|net| net.contains(&peer_ip)) {
+            eprintln!("Ignoring {} from {} via {}, which are not debug peers", X_DEBUG_UPSTREAM, client_ip, peer_ip);
+            return None;
+        }
+
+        // Unix sockets would give clients access to arbitrary local services
+        let upstream = debug_upstream.to_str().ok()
+            .and_then(|debug_upstream| Upstream::parse(debug_upstream, 1, None).ok())
+            .filter(|upstream| upstream.unix_socket.is_none());
+
+        match &upstream {
+            Some(upstream) => eprintln!(
+                "WARNING: {} overrides the upstream of {} with {} for {}",
+                X_DEBUG_UPSTREAM, server.name, upstream.authority, peer_ip,
+            ),
+            None => eprintln!("Ignoring invalid {} {:?}", X_DEBUG_UPSTREAM, debug_upstream),
+        }
+
+        upstream
+    }
+
+    /// Picks the SNI name or the request's host as configured by `host_precedence`.
+    fn extract_host_name<'a>(&'a self, request: &'a Request<Body>) -> Result<Ascii<&'a str>, HostError> {
+        let host = request_host(request).map_err(HostError::Invalid)?;
+        let sni = self.sni_hostname.as_deref().map(String::as_str);
+
+        let host_name = match (self.app.config.tls.host_precedence, sni, host) {
+            (_, None, None) => return Err(HostError::Invalid(anyhow!("Host header is not set"))),
+            (_, Some(name), None) | (_, None, Some(name)) => name,
+            (HostPrecedence::Sni, Some(sni), Some(_)) => sni,
+            (HostPrecedence::Host, Some(_), Some(host)) => host,
+            (HostPrecedence::RequireMatch, Some(sni), Some(host)) if sni.eq_ignore_ascii_case(host) => host,
+            (HostPrecedence::RequireMatch, Some(sni), Some(host)) => return Err(HostError::Mismatch {
+                sni: sni.to_owned(),
+                host: host.to_owned(),
+            }),
+        };
+
+        Ok(Ascii::new(host_name))
+    }
+}
+
+enum HostError {
+    Invalid(Error),
+    /// The request's host differs from the SNI name with `host_precedence = "require_match"`.
+    Mismatch {
+        sni: String,
+        host: String,
+    },
+}
+
+/// The host a request was sent to, without port.
+///
+/// HTTP/2 requests carry it in the `:authority` pseudo header, which hyper exposes as the uri's authority.
+/// They may also send `Host`, but `:authority` takes precedence (RFC 9113, section 8.3.1).
+/// HTTP/1.1 requests carry it in `Host`, unless the request target is in absolute form,
+/// which then takes precedence (RFC 7230, section 5.4).
+fn request_host(request: &Request<Body>) -> Result<Option<&str>> {
+    let uri_host = match request.version() {
+        Version::HTTP_2 => request.uri().host(),
+        _ => request.uri().authority()
+            .filter(|_| request.uri().scheme().is_some())
+            .map(|authority| authority.host()),
+    };
+
+    if let Some(uri_host) = uri_host {
+        return Ok(Some(uri_host));
+    }
+
+    let host = match request.headers().get(HOST) {
+        Some(host) => host.to_str()
+            .context("Host header is invalid UTF-8")?,
+        // E.g. `CONNECT` requests, whose target is in authority form
+        None => return Ok(request.uri().host()),
+    };
+    let host = host.split_once(":")
+        .map(|(host, _port)| host)
+        .unwrap_or(host);
+
+    Ok(Some(host))
+}
+
+struct App {
+    listener_manager: ListenerManager,
+    tls_manager: TlsManager,
+    oidc: auth::OidcClient,
+    negative_cache: auth::NegativeCache,
+    revoked_tokens: auth::RevokedTokens,
+    sessions: auth::Sessions,
+    jti_denylist: auth::JtiDenylist,
+    introspections: auth::Introspections,
+    introspection_metrics: auth::metrics::IntrospectionMetrics,
+    http: Client,
+    upstream_clients: Vec<UpstreamClients>,
+    request_limit: ConcurrencyLimit,
+    server_limits: Vec<ConcurrencyLimit>,
+    circuit_breakers: Vec<Option<CircuitBreaker>>,
+    maintenance: Vec<Maintenance>,
+    static_responses: Vec<Option<StaticResponse>>,
+    security_headers: Vec<Option<SecurityHeaders>>,
+    body_rewrites: Vec<Option<BodyRewrite>>,
+    upstream_selectors: Vec<Box<dyn UpstreamSelector>>,
+    access_log: Option<AccessLog>,
+    response_cache: Option<ResponseCache>,
+    compression: Option<Compression>,
+    tracer: Option<Tracer>,
+    in_flight_requests: Counter,
+    open_connections: Counter,
+    /// Set once all listeners were stopped to let in-flight requests finish.
+    draining: AtomicBool,
+    config: Config,
+}
+
+impl App {
+    async fn new(config: Config) -> Result<Self> {
+        for server in &config.servers {
+            if server.mode == Mode::ReverseProxy && server.upstream.is_empty() {
+                bail!("No upstream configured for {}", server.name);
+            }
+
+            if server.mode == Mode::Static && server.static_response.is_none() {
+                bail!("No static_response configured for {}", server.name);
+            }
+
+            if server.mode == Mode::Redirect && server.tls.is_some() {
+                bail!("Server {} redirects to https, it must not be configured for TLS itself", server.name);
+            }
+
+            for rule in &server.routing_rules {
+                if rule.header.is_some() == rule.cookie.is_some() {
+                    bail!("Routing rules of {} must set exactly one of `header` or `cookie`", server.name);
+                }
+
+                if server.upstream_index(&rule.upstream).is_none() {
+                    bail!("Routing rule of {} refers to unknown upstream {:?}", server.name, rule.upstream);
+                }
+            }
+
+            let public_route_warnings = server.public_route_warnings();
+
+            match server.strict_public_routes {
+                StrictPublicRoutes::Off => {},
+                StrictPublicRoutes::Warn => for warning in &public_route_warnings {
+                    eprintln!("WARNING: {} of {}", warning, server.name);
+                },
+                StrictPublicRoutes::Error => if let Some(warning) = public_route_warnings.first() {
+                    bail!("{} of {}, see `strict_public_routes`", warning, server.name);
+                },
+            }
+
+            if let Some(session) = &server.session {
+                oauth2::url::Url::parse(&session.redirect_url)
+                    .with_context(|| format!("Invalid session `redirect_url` of {}", server.name))?;
+
+                let paths = [&session.login_path, &session.callback_path, &session.logout_path];
+
+                if paths.iter().enumerate().any(|(i, path)| paths[..i].contains(path)) {
+                    bail!("Session `login_path`, `callback_path` and `logout_path` of {} must differ", server.name);
+                }
+            }
+
+            if let Some(canary) = &server.canary {
+                if server.upstream_index(&canary.upstream).is_none() {
+                    bail!("Canary of {} refers to unknown upstream {:?}", server.name, canary.upstream);
+                }
+
+                if server.upstream.len() < 2 {
+                    bail!("Canary of {} needs another upstream for the remaining traffic", server.name);
+                }
+
+                if !(0.0..=100.0).contains(&canary.percent) {
+                    bail!("Canary `percent` of {} must be between 0 and 100", server.name);
+                }
+            }
+
+            for route in &server.issuer_routes {
+                if server.upstream_index(&route.upstream).is_none() {
+                    bail!("Issuer route of {} refers to unknown upstream {:?}", server.name, route.upstream);
+                }
+            }
+        }
+
+        // hyper panics on smaller buffers
+        if config.http1.max_buf_size.is_some_and(|max_buf_size| max_buf_size < 8192) {
+            bail!("`max_buf_size` in `[http1]` must be at least 8192");
+        }
+
+        let negative_cache = auth::NegativeCache::new(
+            Duration::from_secs(config.openid.negative_cache_ttl_secs),
+            Duration::from_secs(config.openid.introspection_max_backoff_secs),
+        );
+        let queue_timeout = Duration::from_millis(config.limits.queue_timeout_ms);
+        let request_limit = ConcurrencyLimit::new(config.limits.max_concurrent_requests, None, queue_timeout);
+        let server_limits = config.servers.iter()
+            .map(|server| ConcurrencyLimit::new(server.max_concurrent_requests, server.max_queued_requests, queue_timeout))
+            .collect();
+        let circuit_breakers = config.servers.iter()
+            .map(|server| server.circuit_breaker.as_ref()
+                .map(|circuit_breaker| CircuitBreaker::new(&server.name, circuit_breaker)))
+            .collect();
+        let maintenance = config.servers.iter()
+            .map(|server| Maintenance::new(&server.maintenance, config.limits.retry_after_secs))
+            .collect::<Result<_>>()?;
+        let static_responses = config.servers.iter()
+            .map(|server| match (server.mode, &server.static_response) {
+                (Mode::Static, Some(static_response)) => StaticResponse::new(static_response)
+                    .with_context(|| format!("Invalid static_response of {}", server.name))
+                    .map(Some),
+                _ => Ok(None),
+            })
+            .collect::<Result<_>>()?;
+        let security_headers = config.servers.iter()
+            .map(|server| server.security_headers.as_ref()
+                .map(SecurityHeaders::new)
+                .transpose()
+                .with_context(|| format!("Invalid security_headers of {}", server.name)))
+            .collect::<Result<_>>()?;
+        let body_rewrites = config.servers.iter()
+            .map(|server| BodyRewrite::new(server)
+                .with_context(|| format!("Invalid body_rewrite of {}", server.name)))
+            .collect::<Result<_>>()?;
+        let upstream_selectors = config.servers.iter()
+            .map(|server| {
+                let canary = server.canary.as_ref().and_then(|canary| server.upstream_index(&canary.upstream));
+
+                upstream_selector::build(server.balance, &server.upstream, canary)
+            })
+            .collect();
+        let upstream_clients = upstream_client::build_clients(&config.http, &config.servers).await?;
+        let access_log = config.access_log.as_ref()
+            .map(AccessLog::new)
+            .transpose()
+            .context("failed to set up access log")?;
+        let tls_manager = TlsManager::new(&config.tls)?;
+        let client_assertion = auth::ClientAssertion::from_config(&config.openid)
+            .context("failed to set up client authentication")?;
+        let tracer = config.tracing.as_ref()
+            .map(Tracer::new)
+            .transpose()?;
+
+        Ok(Self {
+            listener_manager: ListenerManager::new(config.limits.max_unaccepted_sockets, config.listener.clone()),
+            tls_manager,
+            oidc: auth::OidcClient::new(client_assertion),
+            negative_cache,
+            revoked_tokens: auth::RevokedTokens::new(Duration::from_secs(config.openid.revoked_token_ttl_secs)),
+            sessions: auth::Sessions::new(),
+            jti_denylist: auth::JtiDenylist::new(config.openid.jti_denylist.clone())?,
+            introspections: auth::Introspections::new(),
+            introspection_metrics: auth::metrics::IntrospectionMetrics::new(),
+            http: Client::new(),
+            upstream_clients,
+            request_limit,
+            server_limits,
+            circuit_breakers,
+            maintenance,
+            static_responses,
+            security_headers,
+            body_rewrites,
+            upstream_selectors,
+            access_log,
+            response_cache: config.cache.as_ref().map(ResponseCache::new),
+            compression: config.compression.as_ref().map(Compression::new),
+            tracer,
+            in_flight_requests: Counter::new(),
+            open_connections: Counter::new(),
+            draining: AtomicBool::new(false),
+            config,
+        })
+    }
+
+    /// Stops accepting new connections on all listeners, while open connections are served to completion.
+    async fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+
+        for listen_addr in self.listener_manager.listen_addrs().await {
+            self.listener_manager.stop_listening_on(listen_addr).await;
+            println!("Stopped listening on {}", listen_addr);
+        }
+    }
+
+    /// Returns whether all connections closed within `timeout`.
+    async fn wait_for_connections(&self, timeout: Duration) -> bool {
+        let closed = async {
+            while self.open_connections.get() > 0 {
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        time::timeout(timeout, closed).await.is_ok()
+    }
+}
+
+/// Drains the listeners and waits up to `shutdown_grace_period_secs` for open connections,
+/// which close once their requests in flight are done.
+/// Connections still open after that, e.g. long downloads or stuck upstreams, are closed.
+async fn shut_down(app: &App, shut_down_gracefully: watch::Sender<()>, force_close: watch::Sender<()>) {
+    println!("Shutting down with {} open connections", app.open_connections.get());
+    app.drain().await;
+    let _ = shut_down_gracefully.send(());
+
+    let grace_period = Duration::from_secs(app.config.listener.shutdown_grace_period_secs);
+
+    if app.wait_for_connections(grace_period).await {
+        return;
+    }
+
+    eprintln!("Closing {} connections still open after the grace period", app.open_connections.get());
+    let _ = force_close.send(());
+
+    // Each connection closes once its task is polled next
+    app.wait_for_connections(Duration::from_secs(1)).await;
+}
+
+/// Resolves on `SIGTERM` or Ctrl-C.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+
+        tokio::select! {
+            result = signal::ctrl_c() => result?,
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+fn service_unavailable(retry_after_secs: u64, code: &'static str, message: &str) -> Response<Body> {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, code, message);
+
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+
+    response
+}
+
+/// Strips headers the gateway sets itself. Forwarding headers are kept
+/// if they come from a trusted proxy, to be extended by the gateway.
+fn remove_dangerous_headers(request: &mut Request<Body>, is_trusted_peer: bool) {
+    let headers = request.headers_mut();
+
+    if !is_trusted_peer {
+        headers.remove(FORWARDED);
+        headers.remove(X_FORWARDED_FOR);
+        headers.remove(X_FORWARDED_PROTO);
+        headers.remove(X_FORWARDED_HOST);
+    }
+
+    headers.remove(HOST);
+    headers.remove(AUTHORIZATION);
+    headers.remove(X_USER_ID);
+    headers.remove(X_USER_NAME);
+    headers.remove(X_USER_ROLE);
+    headers.remove(X_USER_GROUPS);
+    headers.remove(X_DEBUG_UPSTREAM);
+    headers.remove(X_FORWARDED_TLS_VERSION);
+    headers.remove(X_FORWARDED_TLS_CIPHER);
+}
+
+/// The server on `listen_addr` answering for `host_name`. Exact names take precedence
+/// over wildcard names and `name_patterns`, of which the first matching server is picked.
+fn select_server<'a>(
+    servers: &'a [config::Server],
+    listen_addr: &SocketAddr,
+    host_name: &str,
+    is_connect: bool,
+) -> Option<(usize, &'a config::Server)> {
+    let servers = servers.iter()
+        .enumerate()
+        .filter(|(_, server)| server.listens_on(listen_addr));
+
+    servers.clone().find(|(_, server)| Ascii::new(server.name.as_str()) == Ascii::new(host_name))
+        .or_else(|| servers.clone().find(|(_, server)| server.matches_name_pattern(host_name)))
+        // The host of a forward proxy request is the target, not the proxy itself
+        .or_else(|| servers.clone().find(|(_, server)| is_connect && server.mode == Mode::ForwardProxy))
+}
+
+/// Whether the body length is ambiguous, the basis of request smuggling: `Transfer-Encoding`
+/// together with `Content-Length`, or `Content-Length` values that differ or don't parse.
+/// hyper rejects some of these itself, but keeps both headers if `Content-Length` comes first.
+fn has_ambiguous_framing(headers: &HeaderMap) -> bool {
+    let mut content_lengths = headers.get_all(CONTENT_LENGTH).iter()
+        .flat_map(|value| value.to_str().unwrap_or("invalid").split(','))
+        .map(|value| value.trim().parse::<u64>().ok());
+
+    let first = match content_lengths.next() {
+        Some(first) => first,
+        None => return false,
+    };
+
+    headers.contains_key(TRANSFER_ENCODING)
+        || first.is_none()
+        || content_lengths.any(|content_length| content_length != first)
+}
+
+/// Size of `headers` as sent over HTTP/1.1, i.e. `name: value\r\n` for each header.
+fn header_bytes(headers: &HeaderMap) -> usize {
+    headers.iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The `return_to` query parameter, if it is a local path, so logins and logouts can't be abused as open redirects.
+fn local_return_to(uri: &Uri) -> String {
+    query_param(uri, "return_to")
+        .filter(|return_to| return_to.starts_with('/') && !return_to.starts_with("//") && !return_to.starts_with("/\\"))
+        .filter(|return_to| HeaderValue::from_str(return_to).is_ok())
+        .unwrap_or_else(|| "/".into())
+}
+
+fn redirect_to_https(host: &str, port: Option<u16>, uri: &Uri) -> Result<Response<Body>> {
+    let path_and_query = uri.path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+    let location = match port {
+        Some(443) | None => format!("https://{}{}", host, path_and_query),
+        Some(port) => format!("https://{}:{}{}", host, port, path_and_query),
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .context("failed to build redirect response")?;
+
+    Ok(response)
+}
+
+/// Rewrites a `Location` pointing at the upstream to point at the public origin instead.
+/// Absolute and scheme-relative (`//host/path`) locations are rewritten if they name the upstream,
+/// path-absolute ones (`/path`) only lose the upstream's base path. Other relative locations
+/// resolve against the public URL just as well and are kept.
+fn rewrite_location(headers: &mut HeaderMap, upstream_scheme: &str, upstream: &Upstream, public_origin: &str) {
+    let location = match headers.get(LOCATION).and_then(|location| location.to_str().ok()) {
+        Some(location) => location,
+        None => return,
+    };
+
+    let (is_absolute, path) = if let Some(rest) = location.strip_prefix("//") {
+        (true, strip_upstream_authority(rest, upstream_scheme, &upstream.authority))
+    } else if location.starts_with('/') {
+        (false, Some(location).filter(|_| !upstream.base_path.is_empty()))
+    } else {
+        let rest = strip_prefix_ignore_ascii_case(location, upstream_scheme)
+            .and_then(|rest| rest.strip_prefix("://"));
+
+        (true, rest.and_then(|rest| strip_upstream_authority(rest, upstream_scheme, &upstream.authority)))
+    };
+
+    let rest = match path.and_then(|path| strip_prefix_ignore_ascii_case(path, &upstream.base_path)) {
+        Some(rest) => rest,
+        None => return,
+    };
+
+    if !(rest.is_empty() || rest.starts_with(['/', '?', '#'])) {
+        return;
+    }
+
+    let location = match (is_absolute, rest) {
+        (true, rest) => format!("{}{}", public_origin, rest),
+        (false, "") => "/".to_owned(),
+        (false, rest) if !rest.starts_with('/') => format!("/{}", rest),
+        (false, rest) => rest.to_owned(),
+    };
+
+    match HeaderValue::from_str(&location) {
+        Ok(location) => { headers.insert(LOCATION, location); },
+        Err(_) => eprintln!("Rewritten location is not a valid header value: {}", location),
+    }
+}
+
+/// Strips the authority off `location` (after `scheme://`) if it is the upstream's,
+/// also if one of them spells out the scheme's default port.
+fn strip_upstream_authority<'a>(location: &'a str, upstream_scheme: &str, upstream_authority: &Authority) -> Option<&'a str> {
+    let end = location.find(['/', '?', '#']).unwrap_or(location.len());
+    let (authority, path) = location.split_at(end);
+    let authority = authority.parse::<Authority>().ok()?;
+    let default_port = match upstream_scheme {
+        "https" => 443,
+        _ => 80,
+    };
+
+    let is_upstream = authority.host().eq_ignore_ascii_case(upstream_authority.host())
+        && authority.port_u16().unwrap_or(default_port) == upstream_authority.port_u16().unwrap_or(default_port);
+
+    Some(path).filter(|_| is_upstream)
+}
+
+fn strip_prefix_ignore_ascii_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+
+    Some(&value[prefix.len()..])
+}
+
+/// Serializes the selected claims to JSON and encodes them as unpadded base64url.
+fn encode_identity(token_info: &IntrospectionResult, claims: &[String]) -> Result<HeaderValue> {
+    let token_info = match serde_json::to_value(token_info)? {
+        serde_json::Value::Object(token_info) => token_info,
+        _ => bail!("Token info is not an object"),
+    };
+
+    let identity = claims.iter()
+        .filter_map(|claim| Some((claim.clone(), token_info.get(claim)?.clone())))
+        .collect::<serde_json::Map<_, _>>();
+
+    let identity = serde_json::to_vec(&identity)?;
+    let identity = base64::encode_config(identity, base64::URL_SAFE_NO_PAD);
+
+    Ok(HeaderValue::from_str(&identity)?)
+}
+
+fn token_roles_and_groups<'a>(
+    token_info: &'a IntrospectionResult,
+    openid: &config::Openid,
+) -> (Vec<&'a str>, Vec<&'a str>) {
+    match &token_info.extra_fields().0 {
+        Token::Keybase(token) => (token.roles(&openid.roles_claim, openid.resource_access_client()), token.groups()),
+        Token::Generic(token) => (token.roles(&openid.roles_claim), token.groups()),
+    }
+}
+
+/// From the standard `scope` claim, or `scp` as used by e.g. Azure AD and Okta.
+fn token_scopes(token_info: &IntrospectionResult) -> Vec<&str> {
+    if let Some(scopes) = token_info.scopes() {
+        return scopes.iter().map(|scope| scope.as_str()).collect()
+    }
+
+    let claims = match &token_info.extra_fields().0 {
+        Token::Keybase(token) => &token.claims,
+        Token::Generic(token) => token,
+    };
+
+    generic::strings(claims.claim("scp"))
+}
+
+fn enrich_request_with_token_info(
+    headers: &mut HeaderMap,
+    token_info: &IntrospectionResult,
+    openid: &config::Openid,
+    scopes_header: &HeaderName,
+) -> Result<()> {
+    if let Some(user_id) = token_info.sub() {
+        headers.insert(X_USER_ID, user_id.parse()?);
+    }
+
+    if let Some(username) = token_info.username() {
+        headers.insert(X_USER_NAME, username.parse()?);
+    }
+
+    let (roles, groups) = token_roles_and_groups(token_info, openid);
+
+    for role in roles {
+        let role = match role.parse::<HeaderValue>() {
+            Ok(role) => role,
+            Err(_) => {
+                eprintln!("Role is not a valid header value: {}", role);
+                continue
+            },
+        };
+        headers.append(X_USER_ROLE, role);
+    }
+
+    for group in groups {
+        let group = match group.parse::<HeaderValue>() {
+            Ok(group) => group,
+            Err(_) => {
+                eprintln!("Group is not a valid header value: {}", group);
+                continue
+            },
+        };
+        headers.append(X_USER_GROUPS, group);
+    }
+
+    let scopes = token_scopes(token_info);
+
+    if !scopes.is_empty() {
+        match scopes.join(" ").parse::<HeaderValue>() {
+            Ok(scopes) => { headers.insert(scopes_header, scopes); },
+            Err(_) => eprintln!("Scopes are not a valid header value: {:?}", scopes),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::fmt;
+    use std::task::{self, Poll};
+
+    use hyper::body::Bytes;
+    use hyper::body::HttpBody;
+    use hyper::header::COOKIE;
+    use hyper::server::conn::AddrIncoming;
+    use hyper::service::{make_service_fn, service_fn};
+    use parking_lot::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    /// Serves each request with `handler` on a random port.
+    fn spawn_upstream<H, F, B>(handler: H) -> SocketAddr
+    where
+        H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+        F: Future<Output = Response<B>> + Send + 'static,
+        B: HttpBody + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        serve_upstream(hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()), handler)
+    }
+
+    /// Like `spawn_upstream`, but speaking HTTP/2 only.
+    fn spawn_http2_upstream<H, F, B>(handler: H) -> SocketAddr
+    where
+        H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+        F: Future<Output = Response<B>> + Send + 'static,
+        B: HttpBody + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        serve_upstream(hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).http2_only(true), handler)
+    }
+
+    fn serve_upstream<H, F, B>(builder: hyper::server::Builder<AddrIncoming>, handler: H) -> SocketAddr
+    where
+        H: Fn(Request<Body>) -> F + Clone + Send + 'static,
+        F: Future<Output = Response<B>> + Send + 'static,
+        B: HttpBody + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handler(request).map(Ok::<_, Infallible>)))
+            }
+        });
+        let server = builder.serve(make_service);
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        addr
+    }
+
+    /// Serves the request body back as the response body.
+    fn spawn_echo_upstream() -> SocketAddr {
+        spawn_upstream(|request| async move { Response::new(request.into_body()) })
+    }
+
+    /// Starts a gateway for a single public server in front of `upstream`.
+    /// `server_config` is appended to the server section.
+    async fn spawn_gateway(upstream: impl fmt::Display, server_config: &str) -> SocketAddr {
+        let (listener, app) = bind_gateway(upstream, server_config).await;
+
+        serve_gateway(listener, app)
+    }
+
+    /// Like `spawn_gateway`, but leaves the app to be customized before serving it.
+    async fn bind_gateway(upstream: impl fmt::Display, server_config: &str) -> (TcpListener, App) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let config = format!(r#"
+            [openid]
+            issuer_url = "http://127.0.0.1:1"
+            introspect_url = "http://127.0.0.1:1/introspect"
+            client_id = "client"
+            client_secret = "secret"
+
+            [[server]]
+            name = "localhost"
+            listen = "{}"
+            upstream = "{}"
+            public_routes = ['.*']
+            {}
+        "#, listen_addr, upstream, server_config);
+        let config = toml::from_str(&config).unwrap();
+        let app = App::new(config).await.unwrap();
+
+        (listener, app)
+    }
+
+    fn serve_gateway(listener: TcpListener, app: App) -> SocketAddr {
+        let listen_addr = listener.local_addr().unwrap();
+        let app = Arc::new(app);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote_addr) = listener.accept().await.unwrap();
+                let accepted = Accepted { listen_addr, remote_addr, stream };
+
+                // The sender is dropped right away, so these connections are never shut down
+                let (_, shutting_down) = watch::channel(());
+
+                tokio::spawn(handle_client(app.clone(), accepted, shutting_down));
+            }
+        });
+
+        listen_addr
+    }
+
+    async fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+
+        while !String::from_utf8_lossy(&received).contains(needle) {
+            let n = time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await
+                .expect("timed out waiting for response")
+                .unwrap();
+            assert!(n > 0, "connection closed, received: {:?}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        String::from_utf8(received).unwrap()
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_answered_before_body_is_sent() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_gateway(upstream, "").await;
+        let mut stream = TcpStream::connect(gateway).await.unwrap();
+
+        stream.write_all(
+            b"POST /upload HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Content-Length: 5\r\n\
+            Expect: 100-continue\r\n\
+            \r\n"
+        ).await.unwrap();
+
+        let interim = read_until(&mut stream, "\r\n\r\n").await;
+        assert!(interim.starts_with("HTTP/1.1 100 Continue\r\n"), "{:?}", interim);
+
+        stream.write_all(b"hello").await.unwrap();
+
+        let response = read_until(&mut stream, "hello").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+    }
+
+    /// Signals when dropped, i.e. when hyper cancels an upstream handler.
+    struct NotifyOnDrop(Option<oneshot::Sender<()>>);
+
+    impl Drop for NotifyOnDrop {
+        fn drop(&mut self) {
+            let _ = self.0.take().unwrap().send(());
+        }
+    }
+
+    async fn connect_and_abort(gateway: SocketAddr, wait_for: &str) {
+        let mut stream = TcpStream::connect(gateway).await.unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        match wait_for {
+            "" => time::sleep(Duration::from_millis(200)).await,
+            needle => { read_until(&mut stream, needle).await; },
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_request_is_cancelled_when_client_disconnects() {
+        let (cancelled_tx, cancelled_rx) = oneshot::channel();
+        let cancelled_tx = Arc::new(Mutex::new(Some(cancelled_tx)));
+        let upstream = spawn_upstream(move |_request| {
+            let notify = NotifyOnDrop(cancelled_tx.lock().take());
+
+            async move {
+                let _notify = notify;
+                futures::future::pending::<Response<Body>>().await
+            }
+        });
+
+        let gateway = spawn_gateway(upstream, "").await;
+        connect_and_abort(gateway, "").await;
+
+        time::timeout(Duration::from_secs(5), cancelled_rx).await
+            .expect("upstream request was not cancelled")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn upstream_body_stream_is_cancelled_when_client_disconnects() {
+        let (cancelled_tx, cancelled_rx) = oneshot::channel();
+        let cancelled_tx = Arc::new(Mutex::new(Some(cancelled_tx)));
+        let upstream = spawn_upstream(move |_request| {
+            let (mut sender, body) = Body::channel();
+            let notify = NotifyOnDrop(cancelled_tx.lock().take());
+
+            tokio::spawn(async move {
+                let _notify = notify;
+
+                while sender.send_data(Bytes::from_static(b"chunk\n")).await.is_ok() {
+                    time::sleep(Duration::from_millis(20)).await;
+                }
+            });
+
+            async { Response::new(body) }
+        });
+
+        let gateway = spawn_gateway(upstream, "").await;
+        connect_and_abort(gateway, "chunk").await;
+
+        time::timeout(Duration::from_secs(5), cancelled_rx).await
+            .expect("upstream body stream was not cancelled")
+            .unwrap();
+    }
+
+    /// A response body consisting of a single chunk followed by trailers.
+    struct WithTrailers {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    }
+
+    impl HttpBody for WithTrailers {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(self: Pin<&mut Self>, _cx: &mut task::Context) -> Poll<Option<Result<Bytes, Infallible>>> {
+            Poll::Ready(self.get_mut().data.take().map(Ok))
+        }
+
+        fn poll_trailers(self: Pin<&mut Self>, _cx: &mut task::Context) -> Poll<Result<Option<HeaderMap>, Infallible>> {
+            Poll::Ready(Ok(self.get_mut().trailers.take()))
+        }
+    }
+
+    #[tokio::test]
+    async fn response_trailers_are_forwarded() {
+        let upstream = spawn_http2_upstream(|_request| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+            Response::new(WithTrailers {
+                data: Some(Bytes::from_static(b"hello")),
+                trailers: Some(trailers),
+            })
+        });
+
+        let gateway = spawn_gateway(upstream, "upstream_http2 = true").await;
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+        let uri = format!("http://localhost:{}/", gateway.port());
+        let mut response = client.get(uri.parse().unwrap()).await.unwrap();
+
+        let body = response.body_mut().data().await.unwrap().unwrap();
+        assert_eq!(body, "hello");
+
+        let trailers = response.body_mut().trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+
+    const CERT: &str = include_str!("../testdata/localhost.cert.pem");
+    const KEY: &str = include_str!("../testdata/localhost.key.pem");
+
+    /// Like `spawn_gateway`, but speaking TLS with the `localhost` test certificate.
+    async fn spawn_tls_gateway(upstream: SocketAddr, server_config: &str) -> SocketAddr {
+        let (listener, mut app) = bind_gateway(upstream, server_config).await;
+        let certified_key = load_certified_key(PemSource::Inline(CERT), PemSource::Inline(KEY)).unwrap();
+        app.tls_manager.add_certified_key(&[listener.local_addr().unwrap()], &["localhost".into()], certified_key, true).unwrap();
+
+        serve_gateway(listener, app)
+    }
+
+    /// A client trusting the `localhost` test certificate.
+    fn tls_client(http2: bool) -> hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>> {
+        let mut roots = rustls::RootCertStore::empty();
+        let certs = rustls_pemfile::certs(&mut CERT.as_bytes()).unwrap();
+        roots.add_parsable_certificates(&certs);
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_only()
+            .enable_http1()
+            .enable_http2()
+            .build();
+
+        hyper::Client::builder()
+            .http2_only(http2)
+            .build(connector)
+    }
+
+    #[tokio::test]
+    async fn h2_is_negotiated_via_alpn() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_tls_gateway(upstream, "").await;
+        let client = tls_client(true);
+        let request = Request::post(format!("https://localhost:{}/", gateway.port()))
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn grpc_requests_use_http2_upstream_with_trailers() {
+        let upstream = spawn_http2_upstream(|request| async move {
+            assert_eq!(request.version(), hyper::Version::HTTP_2);
+
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+            Response::new(WithTrailers {
+                data: Some(Bytes::from_static(b"reply")),
+                trailers: Some(trailers),
+            })
+        });
+
+        let gateway = spawn_gateway(upstream, "").await;
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+        let request = Request::post(format!("http://localhost:{}/pkg.Service/Method", gateway.port()))
+            .header("content-type", "application/grpc+proto")
+            .header("te", "trailers")
+            .body(Body::from("request"))
+            .unwrap();
+        let mut response = client.request(request).await.unwrap();
+
+        let body = response.body_mut().data().await.unwrap().unwrap();
+        assert_eq!(body, "reply");
+
+        let trailers = response.body_mut().trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+    }
+
+    #[tokio::test]
+    async fn fresh_responses_are_served_from_cache() {
+        let upstream_requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let upstream = spawn_upstream({
+            let upstream_requests = upstream_requests.clone();
+
+            move |_request| {
+                upstream_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+                async {
+                    Response::builder()
+                        .header("cache-control", "max-age=60")
+                        .body(Body::from("cached"))
+                        .unwrap()
+                }
+            }
+        });
+
+        let gateway = spawn_gateway(upstream, "[cache]").await;
+        let client = hyper::Client::new();
+        let uri = format!("http://localhost:{}/items", gateway.port());
+
+        for _ in 0..2 {
+            let response = client.get(uri.parse().unwrap()).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            assert_eq!(body, "cached");
+        }
+
+        assert_eq!(upstream_requests.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    /// Answers introspection requests: `good` is an active token with the `admin` role
+    /// and the scopes `read write` as an array, anything else is inactive.
+    /// `flaky` is like `good`, but its first introspection fails.
+    /// `late` is like `good`, but inactive on its first introspection.
+    /// Its `/token` endpoint trades codes for `stale` tokens about to expire and refresh tokens for `good` ones.
+    fn spawn_mock_introspection() -> SocketAddr {
+        let flaky_failed = Arc::new(AtomicBool::new(false));
+        let late_activated = Arc::new(AtomicBool::new(false));
+
+        spawn_upstream(move |request| {
+            let flaky_failed = flaky_failed.clone();
+            let late_activated = late_activated.clone();
+
+            async move {
+                let is_token_request = request.uri().path() == "/token";
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let body = String::from_utf8_lossy(&body);
+
+                if is_token_request {
+                    let tokens = match body.split('&').any(|pair| pair == "grant_type=refresh_token") {
+                        true => serde_json::json!({ "access_token": "good", "token_type": "bearer", "expires_in": 300 }),
+                        false => serde_json::json!({
+                            "access_token": "stale",
+                            "token_type": "bearer",
+                            "expires_in": 1,
+                            "refresh_token": "refresh-1",
+                        }),
+                    };
+                    return Response::builder()
+                        .header("content-type", "application/json")
+                        .body(Body::from(tokens.to_string()))
+                        .unwrap();
+                }
+
+                let is_flaky = body.split('&').any(|pair| pair == "token=flaky");
+
+                if is_flaky && !flaky_failed.swap(true, Ordering::SeqCst) {
+                    return Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+
+                let is_late = body.split('&').any(|pair| pair == "token=late");
+                let is_good = is_flaky
+                    || (is_late && late_activated.swap(true, Ordering::SeqCst))
+                    || body.split('&').any(|pair| pair == "token=good");
+                let introspection = match is_good {
+                    true => serde_json::json!({
+                        "active": true,
+                        "sub": "user-1",
+                        "username": "alice",
+                        "realm_access": { "roles": ["admin"] },
+                        "scope": ["read", "write"],
+                    }),
+                    false => serde_json::json!({ "active": false }),
+                };
+
+                Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Body::from(introspection.to_string()))
+                    .unwrap()
+            }
+        })
+    }
+
+    /// Responds with the user headers it received, e.g. `user-1 alice admin`.
+    fn spawn_user_echo_upstream() -> SocketAddr {
+        spawn_header_echo_upstream(&[X_USER_ID, X_USER_NAME, X_USER_ROLE])
+    }
+
+    /// Responds with the values of the headers `names`, separated by spaces.
+    fn spawn_header_echo_upstream(names: &'static [&'static str]) -> SocketAddr {
+        spawn_upstream(move |request| async move {
+            let values = names.iter()
+                .flat_map(|name| request.headers().get_all(*name))
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            Response::new(Body::from(values))
+        })
+    }
+
+    /// Like `spawn_gateway`, but with an OIDC provider that is already discovered
+    /// and introspects tokens through `spawn_mock_introspection`.
+    async fn spawn_authenticating_gateway(upstream: SocketAddr, server_config: &str) -> SocketAddr {
+        let (listener, mut app) = bind_gateway(upstream, server_config).await;
+
+        mock_authentication(&mut app);
+
+        serve_gateway(listener, app)
+    }
+
+    /// Points `app` at `spawn_mock_introspection`.
+    fn mock_authentication(app: &mut App) {
+        let introspection = spawn_mock_introspection();
+
+        app.config.openid.introspect_url = format!("http://{}/introspect", introspection);
+
+        let provider_metadata = serde_json::from_value(serde_json::json!({
+            "issuer": app.config.openid.issuer_url,
+            "authorization_endpoint": format!("{}/auth", app.config.openid.issuer_url),
+            "token_endpoint": format!("http://{}/token", introspection),
+            "jwks_uri": format!("{}/certs", app.config.openid.issuer_url),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+        })).unwrap();
+        let oidc_client = auth::oidc_client_from_metadata(&app.config.openid, provider_metadata).unwrap();
+
+        app.oidc.set(oidc_client);
+    }
+
+    async fn get_with_token(gateway: SocketAddr, path: &str, token: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::get(format!("http://localhost:{}{}", gateway.port(), path));
+
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = hyper::Client::new().request(request.body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn public_routes_need_no_token() {
+        let upstream = spawn_user_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']").await;
+
+        assert_eq!(get_with_token(gateway, "/", None).await, (StatusCode::OK, String::new()));
+    }
+
+    #[tokio::test]
+    async fn valid_tokens_are_enriched_with_user_headers() {
+        let upstream = spawn_user_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']").await;
+
+        assert_eq!(
+            get_with_token(gateway, "/private", Some("good")).await,
+            (StatusCode::OK, "user-1 alice admin".to_owned()),
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_or_invalid_tokens_are_rejected() {
+        let upstream = spawn_user_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']").await;
+
+        assert_eq!(get_with_token(gateway, "/private", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_with_token(gateway, "/private", Some("bad")).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn tokens_of_unmapped_issuers_are_forbidden() {
+        let upstream = spawn_user_echo_upstream();
+        let (listener, mut app) = bind_gateway(upstream, "protected_routes = ['/private']").await;
+        let server = &mut app.config.servers[0];
+
+        server.upstream[0].name = Some("tenant-a".into());
+        server.issuer_routes = vec![config::server::IssuerRoute {
+            issuer: "https://idp-a.example.org".into(),
+            upstream: "tenant-a".into(),
+        }];
+        mock_authentication(&mut app);
+
+        let gateway = serve_gateway(listener, app);
+
+        // `good` tokens carry no `iss`
+        assert_eq!(get_with_token(gateway, "/private", Some("good")).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(get_with_token(gateway, "/private", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_with_token(gateway, "/", None).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn failed_introspections_are_not_cached() {
+        let upstream = spawn_user_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']").await;
+
+        assert_ne!(get_with_token(gateway, "/private", Some("flaky")).await.0, StatusCode::OK);
+        assert_eq!(get_with_token(gateway, "/private", Some("flaky")).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn tokens_are_introspected_again_once_their_negative_entry_expired() {
+        let upstream = spawn_user_echo_upstream();
+        let (listener, mut app) = bind_gateway(upstream, "protected_routes = ['/private']").await;
+        mock_authentication(&mut app);
+        app.negative_cache = auth::NegativeCache::new(Duration::from_millis(200), Duration::from_secs(300));
+        let gateway = serve_gateway(listener, app);
+
+        assert_eq!(get_with_token(gateway, "/private", Some("late")).await.0, StatusCode::UNAUTHORIZED);
+        // Active by now, but still rejected by the negative cache
+        assert_eq!(get_with_token(gateway, "/private", Some("late")).await.0, StatusCode::UNAUTHORIZED);
+
+        time::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(get_with_token(gateway, "/private", Some("late")).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn clients_cannot_spoof_user_headers() {
+        let upstream = spawn_user_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, "").await;
+        let request = Request::get(format!("http://localhost:{}/", gateway.port()))
+            .header(X_USER_ID, "admin")
+            .body(Body::empty())
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(body, "");
+    }
+
+    async fn get_with_spoofed_forwarding(gateway: SocketAddr) -> String {
+        let request = Request::get(format!("http://localhost:{}/", gateway.port()))
+            .header(X_FORWARDED_FOR, "192.0.2.66")
+            .header(X_FORWARDED_PROTO, "https")
+            .header(X_FORWARDED_HOST, "spoofed.example")
+            .header(FORWARDED, "for=192.0.2.66;host=spoofed.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    const FORWARDING_HEADERS: &[&str] = &[X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST, "forwarded"];
+
+    #[tokio::test]
+    async fn untrusted_clients_cannot_inject_forwarding_headers() {
+        let upstream = spawn_header_echo_upstream(FORWARDING_HEADERS);
+        let gateway = spawn_gateway(upstream, "").await;
+        let forwarding = get_with_spoofed_forwarding(gateway).await;
+
+        assert!(!forwarding.contains("192.0.2.66"), "{}", forwarding);
+        assert!(!forwarding.contains("spoofed.example"), "{}", forwarding);
+        assert!(forwarding.starts_with("127.0.0.1 http localhost:"), "{}", forwarding);
+    }
+
+    #[tokio::test]
+    async fn trusted_proxies_forwarding_headers_are_extended() {
+        let upstream = spawn_header_echo_upstream(FORWARDING_HEADERS);
+        let gateway = spawn_gateway(upstream, "[forwarding]\ntrusted_proxies = ['127.0.0.1/32']").await;
+        let forwarding = get_with_spoofed_forwarding(gateway).await;
+
+        assert!(forwarding.starts_with("192.0.2.66, 127.0.0.1 https spoofed.example for=192.0.2.66;host=spoofed.example, for=127.0.0.1"), "{}", forwarding);
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_answers_503_except_for_exempt_routes() {
+        let upstream = spawn_echo_upstream();
+        let (listener, app) = bind_gateway(upstream, r#"
+            [server.maintenance]
+            retry_after_secs = 60
+            exempt_routes = ['/status']
+        "#).await;
+
+        app.maintenance[0].set_enabled(true);
+
+        let gateway = serve_gateway(listener, app);
+        let client = hyper::Client::new();
+
+        let response = client.get(format!("http://{}/items", gateway).parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+
+        let response = client.get(format!("http://{}/status", gateway).parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn static_servers_answer_without_upstream() {
+        // Nothing listens here, the request must not be proxied
+        let upstream: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let gateway = spawn_gateway(upstream, r#"
+            mode = "static"
+
+            [server.static_response]
+            status = 503
+            body = "Coming soon"
+        "#).await;
+        let client = hyper::Client::new();
+
+        let response = client.get(format!("http://{}/", gateway).parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Coming soon");
+    }
+
+    #[tokio::test]
+    async fn debug_upstream_header_is_only_honored_if_enabled() {
+        let debug_upstream = spawn_echo_upstream();
+        let unreachable: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let client = hyper::Client::new();
+        let request = |gateway: SocketAddr, forwarded_for: Option<&str>| {
+            let mut request = Request::get(format!("http://{}/", gateway))
+                .header(X_DEBUG_UPSTREAM, format!("http://{}", debug_upstream));
+
+            if let Some(forwarded_for) = forwarded_for {
+                request = request.header(X_FORWARDED_FOR, forwarded_for);
+            }
+
+            client.request(request.body(Body::empty()).unwrap())
+        };
+        let get = |gateway: SocketAddr| request(gateway, None);
+
+        let gateway = spawn_gateway(unreachable, "debug_routing = true").await;
+        assert_eq!(get(gateway).await.unwrap().status(), StatusCode::OK);
+
+        let gateway = spawn_gateway(unreachable, "").await;
+        assert_ne!(get(gateway).await.unwrap().status(), StatusCode::OK);
+
+        let gateway = spawn_gateway(unreachable, "debug_routing = true\ndebug_peers = []").await;
+        assert_ne!(get(gateway).await.unwrap().status(), StatusCode::OK);
+
+        // Clients behind a trusted proxy on a debug peer can't use it
+        let gateway = spawn_gateway(unreachable, r#"
+            debug_routing = true
+
+            [forwarding]
+            trusted_proxies = ["127.0.0.1/32"]
+        "#).await;
+        assert_eq!(request(gateway, Some("127.0.0.1")).await.unwrap().status(), StatusCode::OK);
+        assert_ne!(request(gateway, Some("203.0.113.7")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn request_host_prefers_authority_of_http2_and_absolute_form() {
+        let request = |version, uri: &str, host: Option<&str>| {
+            let mut request = Request::builder().version(version).uri(uri);
+
+            if let Some(host) = host {
+                request = request.header(HOST, host);
+            }
+
+            request.body(Body::empty()).unwrap()
+        };
+
+        let http1 = request(Version::HTTP_11, "/", Some("example.org:8080"));
+        assert_eq!(request_host(&http1).unwrap(), Some("example.org"));
+
+        let absolute_form = request(Version::HTTP_11, "http://target.example/", Some("example.org"));
+        assert_eq!(request_host(&absolute_form).unwrap(), Some("target.example"));
+
+        let http2 = request(Version::HTTP_2, "https://authority.example/", Some("example.org"));
+        assert_eq!(request_host(&http2).unwrap(), Some("authority.example"));
+
+        let missing = request(Version::HTTP_11, "/", None);
+        assert_eq!(request_host(&missing).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_request_headers_are_rejected() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_gateway(upstream, "max_request_header_bytes = 1024").await;
+        let client = hyper::Client::new();
+        let get = |padding: usize| {
+            let request = Request::get(format!("http://{}/", gateway))
+                .header("x-padding", "a".repeat(padding))
+                .body(Body::empty())
+                .unwrap();
+
+            client.request(request)
+        };
+
+        assert_eq!(get(10).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get(2000).await.unwrap().status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn tls_info_is_forwarded_if_enabled() {
+        let upstream = spawn_header_echo_upstream(&[X_FORWARDED_TLS_VERSION, X_FORWARDED_TLS_CIPHER]);
+        let client = tls_client(false);
+        let get = |gateway: SocketAddr| {
+            let request = Request::get(format!("https://localhost:{}/", gateway.port()))
+                .header(X_FORWARDED_TLS_VERSION, "spoofed")
+                .body(Body::empty())
+                .unwrap();
+
+            client.request(request)
+        };
+
+        let gateway = spawn_tls_gateway(upstream, "forward_tls_info = true").await;
+        let body = hyper::body::to_bytes(get(gateway).await.unwrap().into_body()).await.unwrap();
+        assert!(body.starts_with(b"TLSv1."), "{:?}", body);
+        assert!(body.ends_with(b"_SHA256") || body.ends_with(b"_SHA384"), "{:?}", body);
+
+        let gateway = spawn_tls_gateway(upstream, "").await;
+        let body = hyper::body::to_bytes(get(gateway).await.unwrap().into_body()).await.unwrap();
+        assert!(body.is_empty(), "{:?}", body);
+    }
+
+    #[tokio::test]
+    async fn whoami_reports_identity_and_route_decision() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, r#"
+            whoami_path = '/whoami'
+            authorization = [{ routes = ['/admin/.*'], roles = "ops" }]
+        "#).await;
+
+        let (status, body) = get_with_token(gateway, "/whoami?path=/admin/users", Some("good")).await;
+        let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["authenticated"], true);
+        assert_eq!(body["subject"], "user-1");
+        assert_eq!(body["roles"], serde_json::json!(["admin"]));
+        assert_eq!(body["route"]["auth"], "required");
+        assert_eq!(body["route"]["allowed"], false);
+        assert!(!body.to_string().contains("good"), "{}", body);
+
+        let (_, body) = get_with_token(gateway, "/whoami", None).await;
+        let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["authenticated"], false);
+        assert_eq!(body["route"]["auth"], "public");
+        assert_eq!(body["route"]["allowed"], true);
+
+        assert_eq!(get_with_token(gateway, "/admin/users", Some("good")).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_upstreams_are_proxied_to() {
+        let socket_path = std::env::temp_dir().join(format!("oauth_gateway-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let upstream = tokio::net::UnixListener::bind(&socket_path).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = upstream.accept().await.unwrap();
+                let service = service_fn(|request: Request<Body>| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(request.uri().path().to_owned())))
+                });
+
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        });
+
+        let gateway = spawn_gateway(format!("unix:{}", socket_path.display()), "").await;
+        let response = hyper::Client::new().get(format!("http://{}/items", gateway).parse().unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let _ = std::fs::remove_file(&socket_path);
+
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"/items"[..]));
+    }
+
+    #[tokio::test]
+    async fn oversized_upstream_response_headers_are_replaced_with_502() {
+        let upstream = spawn_upstream(|request| async move {
+            let count = request.uri().path().trim_start_matches('/').parse::<usize>().unwrap();
+            let mut response = Response::builder();
+
+            for index in 0..count {
+                response = response.header(format!("x-header-{}", index), "a".repeat(100));
+            }
+
+            response.body(Body::empty()).unwrap()
+        });
+
+        let gateway = spawn_gateway(upstream, "max_response_header_bytes = 4096").await;
+        let client = hyper::Client::new();
+        let get = |count: usize| client.get(format!("http://{}/{}", gateway, count).parse().unwrap());
+
+        assert_eq!(get(3).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(get(100).await.unwrap().status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn disallowed_methods_are_answered_with_405() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_gateway(upstream, r#"allowed_methods = [{ pattern = '/webhook', methods = ["POST", "PUT"] }]"#).await;
+        let client = hyper::Client::new();
+        let request = |method: Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}", gateway, path))
+                .body(Body::empty())
+                .unwrap();
+
+            client.request(request)
+        };
+
+        let response = request(Method::GET, "/webhook").await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "POST, PUT");
+
+        assert_eq!(request(Method::POST, "/webhook").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(request(Method::GET, "/other").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn head_responses_never_have_a_body() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    read_until(&mut stream, "\r\n\r\n").await;
+                    // Sent for `HEAD`, too
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await;
+                });
+            }
+        });
+
+        let gateway = spawn_gateway(upstream_addr, "").await;
+        let mut stream = TcpStream::connect(gateway).await.unwrap();
+
+        stream.write_all(b"HEAD / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.to_ascii_lowercase().contains("content-length: 5\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn token_forwarding_and_enrichment_are_independent() {
+        let upstream = spawn_header_echo_upstream(&["authorization", X_USER_ID]);
+        let get = |server_config: &'static str| async move {
+            let gateway = spawn_authenticating_gateway(upstream, server_config).await;
+
+            get_with_token(gateway, "/private", Some("good")).await
+        };
+
+        assert_eq!(get("protected_routes = ['/private']").await, (StatusCode::OK, "user-1".into()));
+        assert_eq!(
+            get("protected_routes = ['/private']\nforward_token = true").await,
+            (StatusCode::OK, "Bearer good user-1".into()),
+        );
+        assert_eq!(
+            get("protected_routes = ['/private']\nforward_token = true\nenrich_headers = false").await,
+            (StatusCode::OK, "Bearer good".into()),
+        );
+    }
+
+    #[tokio::test]
+    async fn scopes_are_forwarded() {
+        let upstream = spawn_header_echo_upstream(&["x-user-scopes", "x-scopes"]);
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']").await;
+
+        assert_eq!(get_with_token(gateway, "/private", Some("good")).await, (StatusCode::OK, "read write".into()));
+
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']\nscopes_header = 'X-Scopes'").await;
+
+        assert_eq!(get_with_token(gateway, "/private", Some("good")).await, (StatusCode::OK, "read write".into()));
+    }
+
+    #[test]
+    fn locations_pointing_at_the_upstream_are_rewritten() {
+        let upstream = Upstream::parse("http://backend:8080/v2", 1, None).unwrap();
+        let rewrite = |location: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(LOCATION, HeaderValue::from_static(location));
+            rewrite_location(&mut headers, "http", &upstream, "https://example.org");
+            headers[LOCATION].to_str().unwrap().to_owned()
+        };
+
+        assert_eq!(rewrite("http://backend:8080/v2/items?page=2"), "https://example.org/items?page=2");
+        assert_eq!(rewrite("HTTP://Backend:8080/v2"), "https://example.org");
+        assert_eq!(rewrite("//backend:8080/v2/items"), "https://example.org/items");
+        assert_eq!(rewrite("/v2/items#top"), "/items#top");
+        assert_eq!(rewrite("/v2"), "/");
+        assert_eq!(rewrite("/other"), "/other");
+        assert_eq!(rewrite("items"), "items");
+        assert_eq!(rewrite("http://backend:8080/v2x"), "http://backend:8080/v2x");
+        assert_eq!(rewrite("http://backend/v2/items"), "http://backend/v2/items");
+        assert_eq!(rewrite("https://elsewhere.org/v2"), "https://elsewhere.org/v2");
+
+        let upstream = Upstream::parse("backend", 1, None).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("http://backend:80/login"));
+        rewrite_location(&mut headers, "http", &upstream, "https://example.org");
+        assert_eq!(headers[LOCATION], "https://example.org/login");
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections_after_the_grace_period() {
+        let upstream = spawn_echo_upstream();
+        let (listener, mut app) = bind_gateway(upstream, "").await;
+        app.config.listener.shutdown_grace_period_secs = 0;
+
+        let app = Arc::new(app);
+        let listen_addr = listener.local_addr().unwrap();
+        let (shut_down_gracefully, shutting_down) = watch::channel(());
+        let (force_close, force_closed) = watch::channel(());
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+
+        spawn_client(app.clone(), Accepted { listen_addr, remote_addr, stream }, shutting_down, force_closed);
+
+        // Sends the request line only, so the connection is busy and not closed gracefully
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+
+        shut_down(&app, shut_down_gracefully, force_close).await;
+        assert_eq!(app.open_connections.get(), 0);
+
+        let mut rest = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await
+            .expect("connection was not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_idle_keep_alive_connections_right_away() {
+        let upstream = spawn_echo_upstream();
+        let (listener, mut app) = bind_gateway(upstream, "").await;
+        app.config.listener.shutdown_grace_period_secs = 60;
+
+        let app = Arc::new(app);
+        let listen_addr = listener.local_addr().unwrap();
+        let (shut_down_gracefully, shutting_down) = watch::channel(());
+        let (force_close, force_closed) = watch::channel(());
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+
+        spawn_client(app.clone(), Accepted { listen_addr, remote_addr, stream }, shutting_down, force_closed);
+
+        // Stays open after the response thanks to keep-alive
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        read_until(&mut client, "\r\n\r\n").await;
+
+        time::timeout(Duration::from_secs(5), shut_down(&app, shut_down_gracefully, force_close)).await
+            .expect("the idle connection held up the shutdown");
+        assert_eq!(app.open_connections.get(), 0);
+
+        let mut rest = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await
+            .expect("connection was not closed")
+            .unwrap();
+    }
+
+    #[test]
+    fn exact_server_names_win_over_patterns() {
+        let config: Config = toml::from_str(r#"
+            [openid]
+            issuer_url = "http://127.0.0.1:1"
+            introspect_url = "http://127.0.0.1:1/introspect"
+            client_id = "client"
+            client_secret = "secret"
+
+            [[server]]
+            name = "*.example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "127.0.0.1:9090"
+
+            [[server]]
+            name = "apps.example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "127.0.0.1:9091"
+            name_patterns = ['.+\.apps\.example\.org']
+
+            [[server]]
+            name = "api.example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "127.0.0.1:9092"
+
+            [[server]]
+            name = "other.example.org"
+            listen = "127.0.0.1:8081"
+            upstream = "127.0.0.1:9093"
+        "#).unwrap();
+        let listen_addr = "127.0.0.1:8080".parse().unwrap();
+        let selected = |host_name| select_server(&config.servers, &listen_addr, host_name, false)
+            .map(|(_, server)| server.name.as_str());
+
+        assert_eq!(selected("api.example.org"), Some("api.example.org"));
+        assert_eq!(selected("API.example.org"), Some("api.example.org"));
+        assert_eq!(selected("apps.example.org"), Some("apps.example.org"));
+        assert_eq!(selected("www.example.org"), Some("*.example.org"));
+        // Only the pattern covers several labels
+        assert_eq!(selected("a.b.apps.example.org"), Some("apps.example.org"));
+        assert_eq!(selected("a.b.example.org"), None);
+        // Servers on other listeners are never picked
+        assert_eq!(selected("other.example.org"), Some("*.example.org"));
+    }
+
+    #[test]
+    fn ambiguous_framing_is_detected() {
+        let headers = |pairs: &[(&'static str, &'static str)]| pairs.iter()
+            .map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect::<HeaderMap>();
+
+        assert!(!has_ambiguous_framing(&headers(&[])));
+        assert!(!has_ambiguous_framing(&headers(&[("transfer-encoding", "chunked")])));
+        assert!(!has_ambiguous_framing(&headers(&[("content-length", "5"), ("content-length", "5, 5")])));
+        assert!(has_ambiguous_framing(&headers(&[("content-length", "5"), ("transfer-encoding", "chunked")])));
+        assert!(has_ambiguous_framing(&headers(&[("content-length", "5"), ("content-length", "6")])));
+        assert!(has_ambiguous_framing(&headers(&[("content-length", "5, 6")])));
+        assert!(has_ambiguous_framing(&headers(&[("content-length", "-1")])));
+    }
+
+    #[tokio::test]
+    async fn requests_with_ambiguous_framing_are_rejected() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_gateway(upstream, "").await;
+        let send = |request: &'static [u8]| async move {
+            let mut stream = TcpStream::connect(gateway).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            read_until(&mut stream, "\r\n").await
+        };
+
+        let response = send(
+            b"POST / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Content-Length: 5\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            0\r\n\r\n"
+        ).await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{:?}", response);
+
+        let response = send(
+            b"POST / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Content-Length: 5\r\n\
+            Content-Length: 6\r\n\
+            \r\n\
+            hello!"
+        ).await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{:?}", response);
+
+        let response = send(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn debug_headers_report_server_and_route_decision() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, r#"
+            protected_routes = ['/private', '/ops']
+            authorization = [{ routes = ['/ops'], roles = "ops" }]
+            debug_headers = true
+        "#).await;
+        let debug_headers = |path: &'static str| async move {
+            let request = Request::get(format!("http://localhost:{}{}", gateway.port(), path))
+                .header(AUTHORIZATION, "Bearer good")
+                .body(Body::empty())
+                .unwrap();
+            let response = hyper::Client::new().request(request).await.unwrap();
+            let headers = response.headers();
+
+            let header = |name| headers[name].to_str().unwrap().to_owned();
+
+            (header(X_GATEWAY_SERVER), header(X_GATEWAY_ROUTE))
+        };
+
+        assert_eq!(debug_headers("/").await, ("localhost".into(), "public".into()));
+        assert_eq!(debug_headers("/private").await, ("localhost".into(), "protected".into()));
+        assert_eq!(debug_headers("/ops").await, ("localhost".into(), "403".into()));
+
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']").await;
+        let response = hyper::Client::new()
+            .get(format!("http://localhost:{}/", gateway.port()).parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(!response.headers().contains_key(X_GATEWAY_SERVER));
+        assert!(!response.headers().contains_key(X_GATEWAY_ROUTE));
+    }
+
+    #[tokio::test]
+    async fn sessions_log_in_and_refresh_expiring_tokens() {
+        let upstream = spawn_header_echo_upstream(&["authorization", "cookie", X_USER_ID]);
+        let gateway = spawn_authenticating_gateway(upstream, r#"
+            protected_routes = ['/private']
+            forward_token = true
+            session = { redirect_url = "http://localhost/oauth/callback" }
+        "#).await;
+        let client = hyper::Client::new();
+        let get = |path: String, cookie: Option<String>| {
+            let mut request = Request::get(format!("http://localhost:{}{}", gateway.port(), path));
+
+            if let Some(cookie) = cookie {
+                request = request.header(COOKIE, cookie);
+            }
+
+            client.request(request.body(Body::empty()).unwrap())
+        };
+
+        let login = get("/login?return_to=%2Fprivate".into(), None).await.unwrap();
+        assert_eq!(login.status(), StatusCode::FOUND);
+
+        let authorization_url = login.headers()[LOCATION].to_str().unwrap().parse::<Uri>().unwrap();
+        let state = query_param(&authorization_url, "state").unwrap();
+        let callback = format!("/oauth/callback?code=abc&state={}", state);
+
+        let login_cookie = login.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(login_cookie.contains("HttpOnly"));
+        let login_cookie = login_cookie.split(';').next().unwrap().to_owned();
+
+        // Callbacks of logins the browser didn't start itself are refused
+        assert_eq!(get(callback.clone(), None).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get(callback.clone(), Some("gateway_session_login=other".into())).await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        let callback_response = get(callback.clone(), Some(login_cookie.clone())).await.unwrap();
+        assert_eq!(callback_response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(callback_response.headers()[LOCATION], "/private");
+
+        let session_cookie = callback_response.headers().get_all(SET_COOKIE).iter()
+            .map(|cookie| cookie.to_str().unwrap())
+            .find(|cookie| cookie.starts_with("gateway_session="))
+            .unwrap();
+        assert!(session_cookie.contains("HttpOnly"));
+        let session_cookie = session_cookie.split(';').next().unwrap().to_owned();
+
+        // The `stale` token is about to expire, so the upstream gets a refreshed one, but never the session
+        let response = get("/private".into(), Some(format!("{}; theme=dark", session_cookie))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Bearer good theme=dark user-1");
+
+        assert_eq!(get(callback, Some(login_cookie)).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get("/private".into(), Some("gateway_session=unknown".into())).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let logout = get("/logout".into(), Some(session_cookie.clone())).await.unwrap();
+        assert_eq!(logout.status(), StatusCode::SEE_OTHER);
+        assert!(logout.headers()[SET_COOKIE].to_str().unwrap().contains("Max-Age=0"));
+        assert_eq!(get("/private".into(), Some(session_cookie)).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn stalled_tls_handshakes_are_dropped() {
+        let upstream = spawn_echo_upstream();
+        let (listener, mut app) = bind_gateway(upstream, "").await;
+        let certified_key = load_certified_key(PemSource::Inline(CERT), PemSource::Inline(KEY)).unwrap();
+        app.tls_manager.add_certified_key(&[listener.local_addr().unwrap()], &["localhost".into()], certified_key, true).unwrap();
+        app.config.tls.handshake_timeout_ms = 100;
+        let gateway = serve_gateway(listener, app);
+
+        // The start of a TLS record, but never a complete ClientHello
+        let mut stream = TcpStream::connect(gateway).await.unwrap();
+        stream.write_all(&[0x16]).await.unwrap();
+
+        let mut buf = [0; 1];
+        let read = time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await
+            .expect("connection should have been closed");
+
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn silent_clients_are_dropped() {
+        let upstream = spawn_echo_upstream();
+        let (listener, mut app) = bind_gateway(upstream, "").await;
+        app.config.tls.handshake_timeout_ms = 100;
+        let gateway = serve_gateway(listener, app);
+
+        let mut stream = TcpStream::connect(gateway).await.unwrap();
+
+        let mut buf = [0; 1];
+        let read = time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await
+            .expect("connection should have been closed");
+
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn servers_can_read_tokens_from_other_headers() {
+        let upstream = spawn_header_echo_upstream(&["x-access-token", X_USER_ID]);
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']\ntoken_header = 'X-Access-Token'").await;
+        let get = |header: &'static str| {
+            let request = Request::get(format!("http://localhost:{}/private", gateway.port()))
+                .header(header, "Bearer good")
+                .body(Body::empty())
+                .unwrap();
+
+            hyper::Client::new().request(request)
+        };
+
+        let response = get("x-access-token").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Not passed on without `forward_token`
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "user-1");
+
+        assert_eq!(get("authorization").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn token_cookies_are_only_passed_on_with_forward_token() {
+        let upstream = spawn_header_echo_upstream(&["cookie", X_USER_ID]);
+        let get = |server_config: &'static str| async move {
+            let gateway = spawn_authenticating_gateway(upstream, server_config).await;
+            let request = Request::get(format!("http://localhost:{}/private", gateway.port()))
+                .header(COOKIE, "theme=dark; access_token=good")
+                .body(Body::empty())
+                .unwrap();
+            let response = hyper::Client::new().request(request).await.unwrap();
+
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        assert_eq!(get("protected_routes = ['/private']\ntoken_cookie = 'access_token'").await, "theme=dark user-1");
+        assert_eq!(
+            get("protected_routes = ['/private']\ntoken_cookie = 'access_token'\nforward_token = true").await,
+            "theme=dark; access_token=good user-1",
+        );
+    }
+
+    #[tokio::test]
+    async fn server_timing_breaks_down_latency() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, "protected_routes = ['/private']\nserver_timing = true").await;
+        let server_timing = |path: &'static str| async move {
+            let request = Request::get(format!("http://localhost:{}{}", gateway.port(), path))
+                .header(AUTHORIZATION, "Bearer good")
+                .body(Body::empty())
+                .unwrap();
+            let response = hyper::Client::new().request(request).await.unwrap();
+
+            response.headers()[SERVER_TIMING].to_str().unwrap()
+                .split(", ")
+                .map(|metric| metric.split(';').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(server_timing("/").await, ["upstream", "total"]);
+        assert_eq!(server_timing("/private").await, ["auth", "upstream", "total"]);
+
+        let gateway = spawn_gateway(upstream, "").await;
+        let response = hyper::Client::new()
+            .get(format!("http://localhost:{}/", gateway.port()).parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(!response.headers().contains_key(SERVER_TIMING));
+    }
+
+    #[tokio::test]
+    async fn concurrent_cache_misses_share_one_upstream_request() {
+        let upstream_requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let upstream = spawn_upstream({
+            let upstream_requests = upstream_requests.clone();
+
+            move |_request| {
+                upstream_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+                async {
+                    // Long enough for all requests to arrive while the first one is in flight
+                    time::sleep(Duration::from_millis(200)).await;
+
+                    Response::builder()
+                        .header("cache-control", "max-age=60")
+                        .body(Body::from("collapsed"))
+                        .unwrap()
+                }
+            }
+        });
+
+        let gateway = spawn_gateway(upstream, "[cache]").await;
+        let client = hyper::Client::new();
+        let uri = format!("http://localhost:{}/items", gateway.port());
+
+        let bodies = futures::future::join_all((0..10).map(|_| async {
+            let response = client.get(uri.parse().unwrap()).await.unwrap();
+
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        })).await;
+
+        assert!(bodies.iter().all(|body| body == "collapsed"));
+        assert_eq!(upstream_requests.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn idle_keep_alive_connections_hold_no_request_slot() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_gateway(upstream, "[limits]\nmax_concurrent_requests = 1").await;
+        let uri: Uri = format!("http://localhost:{}/", gateway.port()).parse().unwrap();
+
+        // Each client keeps its connection open until the end of the test
+        let first = hyper::Client::new();
+        let second = hyper::Client::new();
+
+        for client in [&first, &second] {
+            let response = client.get(uri.clone()).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_proxies_refuse_internal_targets_and_unknown_hosts() {
+        let upstream = spawn_echo_upstream();
+        let gateway = spawn_authenticating_gateway(upstream, r#"mode = "forward_proxy""#).await;
+        let mut stream = TcpStream::connect(gateway).await.unwrap();
+
+        // E.g. the admin listener
+        stream.write_all(format!(
+            "CONNECT 127.0.0.1:{0} HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\nAuthorization: Bearer good\r\n\r\n",
+            upstream.port(),
+        ).as_bytes()).await.unwrap();
+
+        let response = read_until(&mut stream, "\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+        let request = Request::get(format!("http://{}/", gateway))
+            .header(HOST, "unknown.example.org")
+            .body(Body::empty())
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,59 @@
+//! Takes servers offline with a `503`, without stopping listeners or dropping connections.
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Result, Context};
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+use crate::config;
+use crate::error_response::error_response;
+
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after_secs: u64,
+    page: Option<String>,
+    message: String,
+}
+
+impl Maintenance {
+    pub fn new(config: &config::server::Maintenance, default_retry_after_secs: u64) -> Result<Self> {
+        let page = config.page.as_ref()
+            .map(|page| fs::read_to_string(page).with_context(|| format!("Failed to read maintenance page {:?}", page)))
+            .transpose()?;
+
+        Ok(Self {
+            enabled: AtomicBool::new(config.enabled),
+            retry_after_secs: config.retry_after_secs.unwrap_or(default_retry_after_secs),
+            page,
+            message: config.message.clone().unwrap_or_else(|| "The service is down for maintenance".into()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn response(&self, wants_html: bool) -> Response<Body> {
+        let mut response = match (&self.page, wants_html) {
+            (Some(page), true) => {
+                let mut response = Response::new(Body::from(page.clone()));
+
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+
+                response
+            },
+            _ => error_response(StatusCode::SERVICE_UNAVAILABLE, "maintenance", self.message.clone()),
+        };
+
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(self.retry_after_secs));
+
+        response
+    }
+}
@@ -0,0 +1,216 @@
+//! Fetches OCSP responses for stapling.
+
+use anyhow::{Result, Context, bail, ensure};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use reqwest::header::CONTENT_TYPE;
+use ring::digest::{self, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+
+use crate::x509::{self, TbsCertificate};
+
+/// 1.3.14.3.2.26
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+/// 2.16.840.1.101.3.4.2.1
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+/// 1.3.6.1.5.5.7.48.1.1
+const OID_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+const TAG_EXPLICIT_0: u8 = 0xa0;
+const TAG_CERT_STATUS_GOOD: u8 = 0x80;
+
+pub struct OcspResponse {
+    pub der: Vec<u8>,
+    pub next_update: Option<DateTime<Utc>>,
+}
+
+/// Requests a fresh OCSP response for `cert` from the responder named in the certificate.
+pub async fn fetch(http: &Client, cert: &[u8], issuer: &[u8]) -> Result<OcspResponse> {
+    let url = x509::ocsp_responder_url(cert)?
+        .context("Certificate has no OCSP responder")?;
+    let request = build_request(cert, issuer)?;
+
+    let response = http.post(&url)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(request)
+        .send().await
+        .with_context(|| format!("OCSP request to {} failed", url))?;
+
+    ensure!(response.status().is_success(), "OCSP responder {} returned {}", url, response.status());
+
+    let der = response.bytes().await
+        .context("Failed to read OCSP response")?
+        .to_vec();
+
+    parse_response(der, cert, Some(issuer))
+}
+
+/// Validates the structure and status of a DER encoded OCSP response, and that it is about `cert`.
+/// Its issuer's key is checked as well if the chain includes the `issuer`.
+/// The signature is left for clients to verify.
+pub fn parse_response(der: Vec<u8>, cert: &[u8], issuer: Option<&[u8]>) -> Result<OcspResponse> {
+    let cert = TbsCertificate::parse(cert).context("Invalid certificate")?;
+    let issuer = issuer.map(TbsCertificate::parse).transpose().context("Invalid issuer certificate")?;
+    let next_update = next_update(&der, &cert, issuer.as_ref())?;
+
+    Ok(OcspResponse {
+        der,
+        next_update,
+    })
+}
+
+fn build_request(cert: &[u8], issuer: &[u8]) -> Result<Vec<u8>> {
+    let cert = TbsCertificate::parse(cert).context("Invalid certificate")?;
+    let issuer = TbsCertificate::parse(issuer).context("Invalid issuer certificate")?;
+
+    let issuer_name_hash = digest::digest(&SHA1_FOR_LEGACY_USE_ONLY, cert.issuer);
+    let issuer_key_hash = digest::digest(&SHA1_FOR_LEGACY_USE_ONLY, issuer.subject_public_key()?);
+
+    let hash_algorithm = [
+        x509::encode(x509::TAG_OID, OID_SHA1),
+        x509::encode(x509::TAG_NULL, &[]),
+    ]
+    .concat();
+
+    let cert_id = [
+        x509::encode(x509::TAG_SEQUENCE, &hash_algorithm),
+        x509::encode(x509::TAG_OCTET_STRING, issuer_name_hash.as_ref()),
+        x509::encode(x509::TAG_OCTET_STRING, issuer_key_hash.as_ref()),
+        cert.serial_number.to_vec(),
+    ]
+    .concat();
+
+    let request = x509::encode(x509::TAG_SEQUENCE, &x509::encode(x509::TAG_SEQUENCE, &cert_id));
+    let request_list = x509::encode(x509::TAG_SEQUENCE, &request);
+    let tbs_request = x509::encode(x509::TAG_SEQUENCE, &request_list);
+
+    Ok(x509::encode(x509::TAG_SEQUENCE, &tbs_request))
+}
+
+fn next_update(der: &[u8], cert: &TbsCertificate, issuer: Option<&TbsCertificate>) -> Result<Option<DateTime<Utc>>> {
+    let (response, _) = x509::read(der, x509::TAG_SEQUENCE).context("Invalid OCSPResponse")?;
+    let (status, rest) = x509::read(response, x509::TAG_ENUMERATED).context("Invalid responseStatus")?;
+
+    ensure!(status == [0], "OCSP response status is {:?}", status);
+
+    let (response_bytes, _) = x509::read(rest, TAG_EXPLICIT_0).context("Missing responseBytes")?;
+    let (response_bytes, _) = x509::read(response_bytes, x509::TAG_SEQUENCE)?;
+    let (response_type, rest) = x509::read(response_bytes, x509::TAG_OID)?;
+
+    ensure!(response_type == OID_OCSP_BASIC, "Unsupported OCSP response type");
+
+    let (basic_response, _) = x509::read(rest, x509::TAG_OCTET_STRING)?;
+    let (basic_response, _) = x509::read(basic_response, x509::TAG_SEQUENCE)?;
+    let (response_data, _) = x509::read(basic_response, x509::TAG_SEQUENCE).context("Invalid tbsResponseData")?;
+
+    let mut rest = response_data;
+
+    if rest.first() == Some(&TAG_EXPLICIT_0) {
+        rest = x509::skip(rest)?;
+    }
+
+    let rest = x509::skip(rest).context("Invalid responderID")?;
+    let (_produced_at, rest) = x509::read_time(rest).context("Invalid producedAt")?;
+    let (responses, _) = x509::read(rest, x509::TAG_SEQUENCE).context("Invalid responses")?;
+    let (single_response, _) = x509::read(responses, x509::TAG_SEQUENCE).context("Missing SingleResponse")?;
+
+    let (cert_id, rest) = x509::read(single_response, x509::TAG_SEQUENCE).context("Invalid certID")?;
+
+    // Otherwise, a response for any other certificate would be stapled
+    ensure!(is_cert_id_of(cert_id, cert, issuer)?, "OCSP response is for a different certificate");
+
+    let cert_status = *rest.first().context("Missing certStatus")?;
+
+    if cert_status != TAG_CERT_STATUS_GOOD {
+        bail!("OCSP responder does not report the certificate as good");
+    }
+
+    let rest = x509::skip(rest)?;
+    let (_this_update, rest) = x509::read_time(rest).context("Invalid thisUpdate")?;
+
+    if rest.first() != Some(&TAG_EXPLICIT_0) {
+        return Ok(None);
+    }
+
+    let (next_update, _) = x509::read(rest, TAG_EXPLICIT_0)?;
+    let (next_update, _) = x509::read_time(next_update).context("Invalid nextUpdate")?;
+
+    Ok(Some(next_update))
+}
+
+/// Whether the contents of a certID identify `cert`.
+fn is_cert_id_of(cert_id: &[u8], cert: &TbsCertificate, issuer: Option<&TbsCertificate>) -> Result<bool> {
+    let (hash_algorithm, rest) = x509::read(cert_id, x509::TAG_SEQUENCE).context("Invalid hashAlgorithm")?;
+    let (hash_algorithm, _) = x509::read(hash_algorithm, x509::TAG_OID).context("Invalid hashAlgorithm")?;
+    let (issuer_name_hash, rest) = x509::read(rest, x509::TAG_OCTET_STRING).context("Invalid issuerNameHash")?;
+    let (issuer_key_hash, rest) = x509::read(rest, x509::TAG_OCTET_STRING).context("Invalid issuerKeyHash")?;
+    let (serial_number, _) = x509::read_raw(rest, x509::TAG_INTEGER).context("Invalid serialNumber")?;
+
+    let hash_algorithm = if hash_algorithm == OID_SHA1 {
+        &SHA1_FOR_LEGACY_USE_ONLY
+    } else if hash_algorithm == OID_SHA256 {
+        &SHA256
+    } else {
+        bail!("Unsupported certID hash algorithm");
+    };
+
+    let issuer_key_matches = match issuer {
+        Some(issuer) => issuer_key_hash == digest::digest(hash_algorithm, issuer.subject_public_key()?).as_ref(),
+        None => true,
+    };
+
+    Ok(serial_number == cert.serial_number
+        && issuer_name_hash == digest::digest(hash_algorithm, cert.issuer).as_ref()
+        && issuer_key_matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated with `openssl ocsp` for certificates of a test CA, see `x509::tests`
+    const ISSUER: &[u8] = include_bytes!("../testdata/ocsp/issuer.der");
+    const LEAF: &[u8] = include_bytes!("../testdata/ocsp/leaf.der");
+    /// `openssl ocsp -issuer ... -cert ... -no_nonce` for `LEAF`
+    const LEAF_REQUEST: &[u8] = include_bytes!("../testdata/ocsp/leaf.req.der");
+    /// Reports `LEAF` as good until 2026-10-23 08:53:27 UTC.
+    const LEAF_RESPONSE: &[u8] = include_bytes!("../testdata/ocsp/leaf.ocsp.der");
+    /// Reports another certificate of the same CA as good.
+    const OTHER_RESPONSE: &[u8] = include_bytes!("../testdata/ocsp/other.ocsp.der");
+    /// Reports another certificate of the same CA as revoked.
+    const REVOKED_RESPONSE: &[u8] = include_bytes!("../testdata/ocsp/revoked.ocsp.der");
+
+    #[test]
+    fn requests_match_openssl() {
+        assert_eq!(build_request(LEAF, ISSUER).unwrap(), LEAF_REQUEST);
+    }
+
+    #[test]
+    fn good_responses_are_accepted() {
+        let next_update = "2026-10-23T08:53:27Z".parse::<DateTime<Utc>>().unwrap();
+
+        for issuer in [Some(ISSUER), None] {
+            let response = parse_response(LEAF_RESPONSE.to_vec(), LEAF, issuer).unwrap();
+
+            assert_eq!(response.der, LEAF_RESPONSE);
+            assert_eq!(response.next_update, Some(next_update));
+        }
+    }
+
+    #[test]
+    fn responses_for_other_certificates_are_rejected() {
+        for issuer in [Some(ISSUER), None] {
+            let err = parse_response(OTHER_RESPONSE.to_vec(), LEAF, issuer).err().unwrap();
+
+            assert!(err.to_string().contains("different certificate"), "{:#}", err);
+        }
+
+        // Right serial number and issuer name, but `LEAF` didn't issue `LEAF`
+        assert!(parse_response(LEAF_RESPONSE.to_vec(), LEAF, Some(LEAF)).is_err());
+    }
+
+    #[test]
+    fn revoked_and_malformed_responses_are_rejected() {
+        assert!(parse_response(REVOKED_RESPONSE.to_vec(), LEAF, Some(ISSUER)).is_err());
+        assert!(parse_response(LEAF_RESPONSE[..LEAF_RESPONSE.len() / 2].to_vec(), LEAF, Some(ISSUER)).is_err());
+        assert!(parse_response(Vec::new(), LEAF, Some(ISSUER)).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! The body of responses to clients: streams the proxied body and keeps what the request
+//! holds, like concurrency permits, until it is sent or the client goes away.
+
+use std::any::Any;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{Body, HeaderMap, Response};
+
+/// Guards added to a response with `hold`, moved into its `ResponseBody` by the request handler.
+#[derive(Default)]
+struct Held(Vec<Box<dyn Any + Send + Sync>>);
+
+/// Keeps `guard` until the body of `response` ends.
+pub fn hold(response: &mut Response<Body>, guard: impl Send + Sync + 'static) {
+    let extensions = response.extensions_mut();
+
+    match extensions.get_mut::<Held>() {
+        Some(held) => held.0.push(Box::new(guard)),
+        None => {
+            extensions.insert(Held(vec![Box::new(guard)]));
+        },
+    }
+}
+
+pub struct ResponseBody {
+    body: Body,
+    bytes_sent: u64,
+    held: Vec<Box<dyn Any + Send + Sync>>,
+    on_end: Vec<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl ResponseBody {
+    /// Takes over the body of `response` and the guards it holds.
+    pub fn new(response: Response<Body>) -> Response<Self> {
+        let (mut parts, body) = response.into_parts();
+        let held = parts.extensions.remove::<Held>().unwrap_or_default();
+        let body = Self {
+            body,
+            bytes_sent: 0,
+            held: held.0,
+            on_end: Vec::new(),
+        };
+
+        Response::from_parts(parts, body)
+    }
+
+    /// Calls `f` with the number of bytes sent once the body ended, or the client went away.
+    pub fn on_end(&mut self, f: impl FnOnce(u64) + Send + 'static) {
+        self.on_end.push(Box::new(f));
+    }
+
+    /// Keeps `guard` until the body ends.
+    pub fn hold(&mut self, guard: impl Send + Sync + 'static) {
+        self.held.push(Box::new(guard));
+    }
+}
+
+impl HttpBody for ResponseBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let data = Pin::new(&mut self.body).poll_data(cx);
+
+        if let Poll::Ready(Some(Ok(chunk))) = &data {
+            self.bytes_sent += chunk.len() as u64;
+        }
+
+        data
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// hyper drops bodies as soon as they are sent, or when the client disconnects.
+impl Drop for ResponseBody {
+    fn drop(&mut self) {
+        self.held.clear();
+
+        for on_end in self.on_end.drain(..) {
+            on_end(self.bytes_sent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn guards_are_held_until_the_body_ends() {
+        let guard = Arc::new(());
+        let bytes_sent = Arc::new(AtomicU64::new(u64::MAX));
+        let mut response = Response::new(Body::from("hello"));
+
+        hold(&mut response, guard.clone());
+
+        let mut response = ResponseBody::new(response);
+        response.body_mut().on_end({
+            let bytes_sent = bytes_sent.clone();
+            move |bytes| bytes_sent.store(bytes, Ordering::SeqCst)
+        });
+
+        assert_eq!(Arc::strong_count(&guard), 2);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(body, "hello");
+        assert_eq!(Arc::strong_count(&guard), 1);
+        assert_eq!(bytes_sent.load(Ordering::SeqCst), 5);
+    }
+}
@@ -0,0 +1,499 @@
+//! Serves repeated `GET` requests from memory, honoring `Cache-Control`, `Expires` and `ETag`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use hyper::body::Bytes;
+use hyper::header::{AGE, CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES, SET_COOKIE, VARY, HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+use crate::config;
+
+/// The largest delta-seconds value honored, see RFC 9111 section 1.2.2.
+const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+pub struct ResponseCache {
+    max_entries: usize,
+    max_object_bytes: u64,
+    state: Mutex<State>,
+    /// Upstream fetches in flight, by key. Closed once the response is stored or turned out not to be storable.
+    fetches: Mutex<HashMap<String, watch::Receiver<()>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Keys by last use, least recently used first.
+    recency: BTreeMap<u64, String>,
+    next_use: u64,
+}
+
+struct Entry {
+    headers: HeaderMap,
+    body: Bytes,
+    /// Request headers named by `Vary`, with the values they had when the response was stored.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    /// Whether `Cache-Control: public` allows serving the entry to authenticated users.
+    public: bool,
+    stored_at: Instant,
+    fresh_until: Instant,
+    last_use: u64,
+}
+
+pub enum Lookup {
+    Fresh(Response<Body>),
+    /// Expired, but can be revalidated by sending this `If-None-Match`.
+    Stale(HeaderValue),
+    Miss,
+}
+
+impl ResponseCache {
+    pub fn new(config: &config::Cache) -> Self {
+        Self {
+            max_entries: config.max_entries.max(1),
+            max_object_bytes: config.max_object_bytes,
+            state: <_>::default(),
+            fetches: <_>::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the key of a cacheable request, i.e. a `GET` the client allows to store.
+    pub fn key(request: &Request<Body>, host: &str) -> Option<String> {
+        if request.method() != Method::GET || has_directive(request.headers(), "no-store") {
+            return None;
+        }
+
+        let path_and_query = request.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+        Some(format!("GET {}{}", host.to_ascii_lowercase(), path_and_query))
+    }
+
+    pub fn lookup(&self, key: &str, request_headers: &HeaderMap, authenticated: bool) -> Lookup {
+        let lookup = self.lookup_entry(key, request_headers, authenticated);
+
+        self.count(&lookup);
+
+        lookup
+    }
+
+    /// Like `lookup`, but collapses concurrent misses of the same key into one upstream fetch:
+    /// the first gets a `Fetch` to drop once it stored the response, the others wait for that
+    /// and look again. They fetch on their own if it didn't store anything that fits them.
+    pub async fn lookup_collapsing(&self, key: &str, request_headers: &HeaderMap, authenticated: bool) -> (Lookup, Option<Fetch<'_>>) {
+        let lookup = self.lookup_entry(key, request_headers, authenticated);
+
+        if let Lookup::Fresh(_) = lookup {
+            self.count(&lookup);
+            return (lookup, None);
+        }
+
+        let fetch = self.join_fetch(key).await;
+        // Also catches a fetch finishing between the first lookup and joining
+        let lookup = self.lookup_entry(key, request_headers, authenticated);
+        let fetch = fetch.filter(|_| !matches!(lookup, Lookup::Fresh(_)));
+
+        self.count(&lookup);
+
+        (lookup, fetch)
+    }
+
+    /// Waits for the fetch of `key` in flight, or starts one if there is none.
+    async fn join_fetch(&self, key: &str) -> Option<Fetch<'_>> {
+        let mut receiver = {
+            let mut fetches = self.fetches.lock();
+
+            match fetches.get(key) {
+                Some(receiver) => receiver.clone(),
+                None => {
+                    let (sender, receiver) = watch::channel(());
+
+                    fetches.insert(key.to_owned(), receiver);
+
+                    return Some(Fetch {
+                        cache: self,
+                        key: key.to_owned(),
+                        _done: sender,
+                    });
+                },
+            }
+        };
+
+        // Nothing is ever sent, this returns once the sender is dropped
+        while receiver.changed().await.is_ok() {}
+
+        None
+    }
+
+    fn count(&self, lookup: &Lookup) {
+        match lookup {
+            Lookup::Fresh(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            Lookup::Stale(_) | Lookup::Miss => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn lookup_entry(&self, key: &str, request_headers: &HeaderMap, authenticated: bool) -> Lookup {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+
+        let entry = match state.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+
+        let vary_matches = entry.vary.iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref());
+
+        if !vary_matches || (authenticated && !entry.public) {
+            return Lookup::Miss;
+        }
+
+        state.recency.remove(&entry.last_use);
+        entry.last_use = state.next_use;
+        state.recency.insert(entry.last_use, key.to_owned());
+        state.next_use += 1;
+
+        if entry.fresh_until > Instant::now() && !has_directive(request_headers, "no-cache") {
+            return Lookup::Fresh(entry.response());
+        }
+
+        match entry.headers.get(ETAG) {
+            Some(etag) => Lookup::Stale(etag.clone()),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Whether a response may be buffered for `store`, judging by its headers.
+    /// Never if it sets cookies, replaying those to other clients would hand them the session.
+    pub fn is_storable(&self, response: &Response<Body>, authenticated: bool) -> bool {
+        let content_length = response.headers().get(CONTENT_LENGTH)
+            .and_then(|content_length| content_length.to_str().ok())
+            .and_then(|content_length| content_length.parse::<u64>().ok());
+
+        response.status() == StatusCode::OK
+            && content_length.is_some_and(|content_length| content_length <= self.max_object_bytes)
+            && freshness_lifetime(response.headers(), authenticated).is_some()
+            && !vary_names(response.headers()).any(|name| name == "*")
+            && !response.headers().contains_key(SET_COOKIE)
+    }
+
+    pub fn store(&self, key: String, request_headers: &HeaderMap, authenticated: bool, headers: HeaderMap, body: Bytes) {
+        let lifetime = match freshness_lifetime(&headers, authenticated) {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+
+        // Without a validator, an entry that is stale right away is useless
+        if lifetime.is_zero() && !headers.contains_key(ETAG) {
+            return;
+        }
+
+        let vary = vary_names(&headers)
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .map(|name| {
+                let value = request_headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let last_use = state.next_use;
+
+        state.next_use += 1;
+
+        let entry = Entry {
+            public: has_directive(&headers, "public"),
+            headers,
+            body,
+            vary,
+            stored_at: now,
+            fresh_until: fresh_until(now, lifetime),
+            last_use,
+        };
+
+        if let Some(old) = state.entries.insert(key.clone(), entry) {
+            state.recency.remove(&old.last_use);
+        }
+
+        state.recency.insert(last_use, key);
+
+        while state.entries.len() > self.max_entries {
+            let (_, key) = match state.recency.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            state.entries.remove(&key);
+        }
+    }
+
+    /// Applies a `304 Not Modified` answer to a revalidated entry and returns the stored response.
+    pub fn refresh(&self, key: &str, not_modified: &HeaderMap, authenticated: bool) -> Option<Response<Body>> {
+        let mut state = self.state.lock();
+        let entry = state.entries.get_mut(key)?;
+
+        for name in [CACHE_CONTROL, DATE, ETAG, EXPIRES] {
+            if let Some(value) = not_modified.get(&name) {
+                entry.headers.insert(name, value.clone());
+            }
+        }
+
+        let now = Instant::now();
+
+        entry.stored_at = now;
+        entry.fresh_until = fresh_until(now, freshness_lifetime(&entry.headers, authenticated).unwrap_or_default());
+        entry.public = has_directive(&entry.headers, "public");
+
+        Some(entry.response())
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// An upstream fetch in flight, requests for the same key wait until it is dropped.
+pub struct Fetch<'a> {
+    cache: &'a ResponseCache,
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for Fetch<'_> {
+    fn drop(&mut self) {
+        self.cache.fetches.lock().remove(&self.key);
+    }
+}
+
+impl Entry {
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert(AGE, HeaderValue::from(self.stored_at.elapsed().as_secs()));
+
+        response
+    }
+}
+
+/// Returns how long a response stays fresh, or `None` if it must not be stored.
+/// Lifetimes are capped at 2^31 seconds, as RFC 9111 allows, so they can't overflow an `Instant`.
+fn freshness_lifetime(headers: &HeaderMap, authenticated: bool) -> Option<Duration> {
+    if has_directive(headers, "no-store") || has_directive(headers, "private") {
+        return None;
+    }
+
+    if authenticated && !has_directive(headers, "public") {
+        return None;
+    }
+
+    if has_directive(headers, "no-cache") {
+        return Some(Duration::ZERO);
+    }
+
+    if let Some(max_age) = directive_value(headers, "s-maxage").or_else(|| directive_value(headers, "max-age")) {
+        return Some(Duration::from_secs(max_age.min(MAX_DELTA_SECONDS)));
+    }
+
+    let expires = http_date(headers, EXPIRES)?;
+    let date = http_date(headers, DATE).unwrap_or_else(SystemTime::now);
+
+    Some(expires.duration_since(date).unwrap_or_default().min(Duration::from_secs(MAX_DELTA_SECONDS)))
+}
+
+/// Entries whose lifetime doesn't fit are treated as stale right away.
+fn fresh_until(now: Instant, lifetime: Duration) -> Instant {
+    now.checked_add(lifetime).unwrap_or(now)
+}
+
+fn cache_directives(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers.get_all(CACHE_CONTROL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+}
+
+fn has_directive(headers: &HeaderMap, name: &str) -> bool {
+    cache_directives(headers).any(|directive| directive == name)
+}
+
+pub fn directive_value(headers: &HeaderMap, name: &str) -> Option<u64> {
+    cache_directives(headers)
+        .filter_map(|directive| {
+            let (directive_name, value) = directive.split_once('=')?;
+
+            if directive_name.trim() != name {
+                return None;
+            }
+
+            value.trim().trim_matches('"').parse().ok()
+        })
+        .next()
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+
+    httpdate::parse_http_date(value).ok()
+}
+
+fn vary_names(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers.get_all(VARY).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs.iter()
+            .map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn cache(max_entries: usize) -> ResponseCache {
+        ResponseCache::new(&config::Cache {
+            max_entries,
+            max_object_bytes: 1024,
+        })
+    }
+
+    #[test]
+    fn freshness_honors_cache_control_and_expires() {
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "max-age=60")]), false), Some(Duration::from_secs(60)));
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "max-age=60, s-maxage=10")]), false), Some(Duration::from_secs(10)));
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "private, max-age=60")]), false), None);
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "no-store")]), false), None);
+        assert_eq!(freshness_lifetime(&headers(&[]), false), None);
+        assert_eq!(
+            freshness_lifetime(&headers(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 08:50:37 GMT"),
+            ]), false),
+            Some(Duration::from_secs(60)),
+        );
+    }
+
+    #[test]
+    fn huge_lifetimes_are_capped() {
+        let cache = cache(10);
+        let response = headers(&[("cache-control", "max-age=18446744073709551615")]);
+
+        assert_eq!(freshness_lifetime(&response, false), Some(Duration::from_secs(MAX_DELTA_SECONDS)));
+
+        cache.store("a".into(), &HeaderMap::new(), false, response, Bytes::from_static(b"body"));
+
+        assert!(matches!(cache.lookup("a", &HeaderMap::new(), false), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn authenticated_responses_require_public() {
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "max-age=60")]), true), None);
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "public, max-age=60")]), true), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn vary_headers_must_match() {
+        let cache = cache(10);
+        let response = headers(&[("cache-control", "max-age=60"), ("vary", "Accept-Language")]);
+
+        cache.store("a".into(), &headers(&[("accept-language", "de")]), false, response, Bytes::from_static(b"hallo"));
+
+        assert!(matches!(cache.lookup("a", &headers(&[("accept-language", "de")]), false), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup("a", &headers(&[("accept-language", "en")]), false), Lookup::Miss));
+        assert!(matches!(cache.lookup("a", &headers(&[]), false), Lookup::Miss));
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+    }
+
+    #[test]
+    fn stale_entries_with_etag_are_revalidated() {
+        let cache = cache(10);
+        let response = headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]);
+
+        cache.store("a".into(), &HeaderMap::new(), false, response, Bytes::from_static(b"body"));
+
+        match cache.lookup("a", &HeaderMap::new(), false) {
+            Lookup::Stale(etag) => assert_eq!(etag, "\"v1\""),
+            _ => panic!("expected a stale entry"),
+        }
+
+        let response = cache.refresh("a", &headers(&[("cache-control", "max-age=60")]), false).unwrap();
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=60");
+        assert!(matches!(cache.lookup("a", &HeaderMap::new(), false), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn responses_setting_cookies_are_not_stored() {
+        let cache = cache(10);
+        let response = |set_cookie: Option<&'static str>| {
+            let mut response = Response::builder()
+                .header(CACHE_CONTROL, "public, max-age=60")
+                .header(CONTENT_LENGTH, 4);
+
+            if let Some(set_cookie) = set_cookie {
+                response = response.header(SET_COOKIE, set_cookie);
+            }
+
+            response.body(Body::from("body")).unwrap()
+        };
+
+        assert!(cache.is_storable(&response(None), false));
+        assert!(!cache.is_storable(&response(Some("session=abc")), false));
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = cache(2);
+        let store = |key: &str| cache.store(key.into(), &HeaderMap::new(), false, headers(&[("cache-control", "max-age=60")]), Bytes::new());
+
+        store("a");
+        store("b");
+        assert!(matches!(cache.lookup("a", &HeaderMap::new(), false), Lookup::Fresh(_)));
+        store("c");
+
+        assert!(matches!(cache.lookup("a", &HeaderMap::new(), false), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup("b", &HeaderMap::new(), false), Lookup::Miss));
+        assert!(matches!(cache.lookup("c", &HeaderMap::new(), false), Lookup::Fresh(_)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_wait_for_one_fetch() {
+        let cache = cache(10);
+        let (lookup, fetch) = cache.lookup_collapsing("a", &HeaderMap::new(), false).await;
+
+        assert!(matches!(lookup, Lookup::Miss));
+        assert!(fetch.is_some());
+
+        let mut waiter = Box::pin(cache.lookup_collapsing("a", &HeaderMap::new(), false));
+        assert!(futures::poll!(waiter.as_mut()).is_pending());
+
+        cache.store("a".into(), &HeaderMap::new(), false, headers(&[("cache-control", "max-age=60")]), Bytes::new());
+        drop(fetch);
+
+        let (lookup, fetch) = waiter.await;
+        assert!(matches!(lookup, Lookup::Fresh(_)));
+        assert!(fetch.is_none());
+
+        // Nothing stored, so the next miss fetches on its own
+        let (_, fetch) = cache.lookup_collapsing("b", &HeaderMap::new(), false).await;
+        drop(fetch);
+        let (lookup, fetch) = cache.lookup_collapsing("b", &HeaderMap::new(), false).await;
+        assert!(matches!(lookup, Lookup::Miss));
+        assert!(fetch.is_some());
+    }
+}
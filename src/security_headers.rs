@@ -0,0 +1,87 @@
+//! Security headers like `Strict-Transport-Security` added to upstream responses.
+
+use anyhow::{Result, Context};
+use hyper::HeaderMap;
+use hyper::header::{
+    CONTENT_SECURITY_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    HeaderName, HeaderValue,
+};
+
+use crate::config;
+
+pub struct SecurityHeaders {
+    /// Headers and whether they are only sent on TLS listeners.
+    headers: Vec<(HeaderName, HeaderValue, bool)>,
+    force: bool,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &config::server::SecurityHeaders) -> Result<Self> {
+        let configured = [
+            (STRICT_TRANSPORT_SECURITY, Some(&config.strict_transport_security), true),
+            (X_CONTENT_TYPE_OPTIONS, Some(&config.content_type_options), false),
+            (X_FRAME_OPTIONS, Some(&config.frame_options), false),
+            (CONTENT_SECURITY_POLICY, config.content_security_policy.as_ref(), false),
+        ];
+        let mut headers = Vec::new();
+
+        for (name, value, tls_only) in configured {
+            let value = match value {
+                Some(value) if !value.is_empty() => value,
+                _ => continue,
+            };
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for {}", name))?;
+
+            headers.push((name, value, tls_only));
+        }
+
+        Ok(Self {
+            headers,
+            force: config.force,
+        })
+    }
+
+    /// Adds the headers the upstream didn't set, or all of them with `force`.
+    /// HSTS is left out on plaintext listeners, where browsers ignore it anyway.
+    pub fn apply(&self, headers: &mut HeaderMap, is_tls: bool) {
+        for (name, value, tls_only) in &self.headers {
+            if *tls_only && !is_tls {
+                continue;
+            }
+
+            if self.force || !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security_headers(config: &str) -> SecurityHeaders {
+        SecurityHeaders::new(&toml::from_str(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn upstream_headers_are_kept_unless_forced() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static("SAMEORIGIN"));
+
+        security_headers("").apply(&mut headers, false);
+        assert_eq!(headers[X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+
+        security_headers(r#"
+            force = true
+            content_security_policy = "default-src 'self'"
+        "#).apply(&mut headers, true);
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+        assert_eq!(headers[CONTENT_SECURITY_POLICY], "default-src 'self'");
+    }
+}
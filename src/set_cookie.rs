@@ -0,0 +1,151 @@
+//! Rewrites `Set-Cookie` headers of upstream responses, so cookies set for
+//! the upstream's internal host name are accepted by browsers.
+
+use hyper::HeaderMap;
+use hyper::header::{SET_COOKIE, HeaderValue};
+
+use crate::config::server::CookieRewrite;
+
+/// Where cookies are rewritten from and to.
+pub struct Origins<'a> {
+    pub upstream_host: &'a str,
+    pub upstream_base_path: &'a str,
+    /// Without port, since cookies don't care about ports.
+    pub public_host: &'a str,
+}
+
+pub fn rewrite_headers(headers: &mut HeaderMap, config: &CookieRewrite, origins: &Origins) {
+    if !headers.contains_key(SET_COOKIE) {
+        return;
+    }
+
+    let set_cookies = headers.get_all(SET_COOKIE).iter()
+        .map(|value| match value.to_str() {
+            Ok(set_cookie) => HeaderValue::from_str(&rewrite(set_cookie, config, origins))
+                .unwrap_or_else(|_| value.clone()),
+            // Can't be parsed, so pass it on as is
+            Err(_) => value.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    headers.remove(SET_COOKIE);
+
+    for set_cookie in set_cookies {
+        headers.append(SET_COOKIE, set_cookie);
+    }
+}
+
+/// Rewrites a single `Set-Cookie` value, e.g. `id=1; Domain=backend; Path=/v2/app`
+/// to `id=1; Domain=example.org; Path=/app; Secure`.
+fn rewrite(set_cookie: &str, config: &CookieRewrite, origins: &Origins) -> String {
+    let mut parts = set_cookie.split(';').map(str::trim);
+    let mut rewritten = vec![parts.next().unwrap_or_default().to_owned()];
+    let mut has_secure = false;
+    let mut has_http_only = false;
+
+    for attribute in parts.filter(|attribute| !attribute.is_empty()) {
+        let (name, value) = match attribute.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (attribute, None),
+        };
+
+        match (name.to_ascii_lowercase().as_str(), value) {
+            ("domain", Some(domain)) if config.domain && is_upstream_domain(domain, origins.upstream_host) => {
+                rewritten.push(format!("Domain={}", origins.public_host));
+            },
+            ("path", Some(path)) if config.path => {
+                rewritten.push(format!("Path={}", strip_base_path(path, origins.upstream_base_path)));
+            },
+            ("samesite", _) if config.same_site.is_some() => {},
+            ("secure", _) => {
+                has_secure = true;
+                rewritten.push(attribute.to_owned());
+            },
+            ("httponly", _) => {
+                has_http_only = true;
+                rewritten.push(attribute.to_owned());
+            },
+            _ => rewritten.push(attribute.to_owned()),
+        }
+    }
+
+    if config.secure && !has_secure {
+        rewritten.push("Secure".into());
+    }
+
+    if config.http_only && !has_http_only {
+        rewritten.push("HttpOnly".into());
+    }
+
+    if let Some(same_site) = config.same_site {
+        rewritten.push(format!("SameSite={}", same_site.as_str()));
+    }
+
+    rewritten.join("; ")
+}
+
+fn is_upstream_domain(domain: &str, upstream_host: &str) -> bool {
+    domain.trim_start_matches('.').eq_ignore_ascii_case(upstream_host)
+}
+
+/// Maps a path under the upstream base path to the public path, which has no prefix.
+fn strip_base_path<'a>(path: &'a str, base_path: &str) -> &'a str {
+    if base_path.is_empty() {
+        return path;
+    }
+
+    match path.strip_prefix(base_path) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINS: Origins = Origins {
+        upstream_host: "backend.internal",
+        upstream_base_path: "/v2",
+        public_host: "example.org",
+    };
+
+    fn config(config: &str) -> CookieRewrite {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn domain_and_path_are_rewritten() {
+        let config = config("");
+
+        assert_eq!(
+            rewrite("id=1; Domain=.Backend.internal; Path=/v2/app; Max-Age=60", &config, &ORIGINS),
+            "id=1; Domain=example.org; Path=/app; Max-Age=60",
+        );
+        assert_eq!(rewrite("id=1; Path=/v2", &config, &ORIGINS), "id=1; Path=/");
+        assert_eq!(rewrite("id=1; Path=/v20", &config, &ORIGINS), "id=1; Path=/v20");
+    }
+
+    #[test]
+    fn foreign_domains_are_kept() {
+        assert_eq!(
+            rewrite("id=1; Domain=other.example", &config(""), &ORIGINS),
+            "id=1; Domain=other.example",
+        );
+    }
+
+    #[test]
+    fn attributes_are_forced() {
+        let config = config(r#"
+            secure = true
+            http_only = true
+            same_site = "strict"
+        "#);
+
+        assert_eq!(
+            rewrite("id=1; secure; SameSite=None", &config, &ORIGINS),
+            "id=1; secure; HttpOnly; SameSite=Strict",
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Fixed responses of servers with `mode = "static"`, e.g. holding pages or health stubs.
+
+use std::fs;
+
+use anyhow::{Result, Context, bail};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+use crate::config;
+
+pub struct StaticResponse {
+    status: StatusCode,
+    content_type: HeaderValue,
+    body: Bytes,
+}
+
+impl StaticResponse {
+    pub fn new(config: &config::server::StaticResponse) -> Result<Self> {
+        let status = StatusCode::from_u16(config.status)
+            .with_context(|| format!("Invalid status {}", config.status))?;
+        let content_type = HeaderValue::from_str(&config.content_type)
+            .context("Invalid content type")?;
+        let body = match (&config.body, &config.body_file) {
+            (Some(body), None) => Bytes::from(body.clone()),
+            (None, Some(body_file)) => fs::read(body_file)
+                .with_context(|| format!("Failed to read {:?}", body_file))?
+                .into(),
+            (None, None) => Bytes::new(),
+            (Some(_), Some(_)) => bail!("Only one of `body` and `body_file` may be set"),
+        };
+
+        Ok(Self {
+            status,
+            content_type,
+            body,
+        })
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+
+        *response.status_mut() = self.status;
+        response.headers_mut().insert(CONTENT_TYPE, self.content_type.clone());
+
+        response
+    }
+}
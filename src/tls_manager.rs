@@ -1,102 +1,450 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt::{self, Write};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, Context, anyhow};
+use chrono::{DateTime, Duration, Utc};
 use parking_lot::RwLock;
-use rustls::ServerConfig;
+use rustls::{ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use rustls::kx::SupportedKxGroup;
 use rustls::server::{ClientHello, ResolvesServerCert};
 use rustls::sign::CertifiedKey;
-use tokio_rustls::TlsAcceptor;
 use unicase::Ascii;
-use webpki::DnsNameRef;
+use webpki::{DnsNameRef, EndEntityCert};
+
+use crate::config;
+use crate::config::tls::UnknownSni;
 
 pub struct TlsManager {
-    acceptors: HashMap<SocketAddr, (TlsAcceptor, Arc<CertResolver>)>,
+    acceptors: HashMap<SocketAddr, (Arc<ServerConfig>, Arc<CertResolver>)>,
+    unknown_sni: UnknownSni,
+    /// Shared with the cert resolvers, which reject unknown names during the handshake.
+    unknown_sni_rejections: Arc<AtomicU64>,
+    expiries: Vec<CertExpiry>,
+    default_certified_key: Option<Arc<CertifiedKey>>,
+    protocol_versions: &'static [&'static SupportedProtocolVersion],
+    cipher_suites: Vec<SupportedCipherSuite>,
+    kx_groups: Vec<&'static SupportedKxGroup>,
 }
 
 impl TlsManager {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(config: &config::Tls) -> Result<Self> {
+        let this = Self {
             acceptors: <_>::default(),
+            unknown_sni: config.unknown_sni,
+            unknown_sni_rejections: <_>::default(),
+            expiries: <_>::default(),
+            default_certified_key: None,
+            protocol_versions: config.protocol_versions(),
+            cipher_suites: config.cipher_suites()?,
+            kx_groups: config.kx_groups()?,
+        };
+
+        // Reject unusable combinations, e.g. TLS 1.3 only with TLS 1.2 cipher suites, at startup
+        this.server_config(Arc::new(CertResolver::new(None, false, this.unknown_sni_rejections.clone())))
+            .context("Invalid TLS protocol settings")?;
+
+        Ok(this)
+    }
+
+    fn server_config(&self, cert_resolver: Arc<CertResolver>) -> Result<ServerConfig> {
+        let mut server_config = ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_kx_groups(&self.kx_groups)
+            .with_protocol_versions(self.protocol_versions)?
+            .with_no_client_auth()
+            .with_cert_resolver(cert_resolver as _);
+
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(server_config)
+    }
+
+    /// Sets the certificate served to clients without SNI or with an unknown server name.
+    pub fn set_default_certified_key(&mut self, certified_key: CertifiedKey) -> Result<()> {
+        let end_entity_cert = certified_key.end_entity_cert()
+            .map_err(|_| anyhow!("No default certificate"))?;
+        let not_after = crate::x509::not_after(&end_entity_cert.0)
+            .context("Failed to read default certificate expiry")?;
+
+        self.expiries.push(CertExpiry {
+            server_name: "<default>".into(),
+            not_after,
+        });
+
+        let certified_key = Arc::new(certified_key);
+
+        for (_server_config, cert_resolver) in self.acceptors.values() {
+            *cert_resolver.default_certified_key.write() = Some(certified_key.clone());
         }
+
+        self.default_certified_key = Some(certified_key);
+
+        Ok(())
     }
 
+    /// Serves `certified_key` for all `server_names` on `listen_addrs`.
     pub fn add_certified_key(
         &mut self,
-        listen_addr: SocketAddr,
-        server_name: String,
+        listen_addrs: &[SocketAddr],
+        server_names: &[String],
         certified_key: CertifiedKey,
-    ) -> Result<()> {
-        let (_tls_acceptor, cert_resolver) = self.acceptors.entry(listen_addr)
-            .or_insert_with(|| {
-                let cert_resolver = Arc::new(CertResolver::new());
+        check_name: bool,
+    ) -> Result<Arc<CertifiedKey>> {
+        let server_name = server_names.first()
+            .context("No server name for certificate")?;
+        let end_entity_cert = certified_key.end_entity_cert()
+            .map_err(|_| anyhow!("No certificate for {:?}", server_name))?;
+        let not_after = crate::x509::not_after(&end_entity_cert.0)
+            .with_context(|| format!("Failed to read certificate expiry for {:?}", server_name))?;
+
+        self.expiries.push(CertExpiry {
+            server_name: server_name.clone(),
+            not_after,
+        });
+
+        let certified_key = Arc::new(certified_key);
+
+        for listen_addr in listen_addrs {
+            if !self.acceptors.contains_key(listen_addr) {
+                let reject_unknown = self.unknown_sni != UnknownSni::DefaultCert;
+                let cert_resolver = Arc::new(CertResolver::new(
+                    self.default_certified_key.clone(),
+                    reject_unknown,
+                    self.unknown_sni_rejections.clone(),
+                ));
+                let server_config = self.server_config(cert_resolver.clone())?;
+
+                self.acceptors.insert(*listen_addr, (Arc::new(server_config), cert_resolver));
+            }
+
+            let (_server_config, cert_resolver) = &self.acceptors[listen_addr];
 
-                let server_config = ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_cert_resolver(Arc::clone(&cert_resolver) as _);
+            for server_name in server_names {
+                cert_resolver.add_certified_key(server_name.clone(), certified_key.clone(), check_name)?;
+            }
+        }
+
+        Ok(certified_key)
+    }
+
+    /// Swaps `old` for `new` wherever it is served, e.g. to staple a fresh OCSP response.
+    pub fn replace_certified_key(&self, old: &Arc<CertifiedKey>, new: CertifiedKey) -> Arc<CertifiedKey> {
+        let new = Arc::new(new);
 
-                let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+        for (_server_config, cert_resolver) in self.acceptors.values() {
+            for certified_key in cert_resolver.certified_keys.write().values_mut() {
+                if Arc::ptr_eq(certified_key, old) {
+                    *certified_key = new.clone();
+                }
+            }
+        }
+
+        new
+    }
 
-                (tls_acceptor, cert_resolver)
-            });
+    /// Logs a warning for each certificate that expires within `warning_period`.
+    pub fn check_expiry(&self, warning_period: Duration) {
+        let now = Utc::now();
 
-        cert_resolver.add_certified_key(server_name, certified_key)?;
+        for expiry in &self.expiries {
+            let remaining = expiry.not_after - now;
+
+            if remaining <= Duration::zero() {
+                eprintln!("WARNING: certificate for {:?} expired at {}", expiry.server_name, expiry.not_after);
+            } else if remaining <= warning_period {
+                eprintln!(
+                    "WARNING: certificate for {:?} expires in {} days ({})",
+                    expiry.server_name,
+                    remaining.num_days(),
+                    expiry.not_after,
+                );
+            }
+        }
+    }
+
+    pub fn server_config_for(&self, listen_addr: &SocketAddr) -> Option<Arc<ServerConfig>> {
+        let (server_config, _cert_resolver) = self.acceptors.get(listen_addr)?;
+
+        Some(server_config.clone())
+    }
+
+    /// Whether a certificate is configured for `server_name` on `listen_addr`.
+    pub fn knows_server_name(&self, listen_addr: &SocketAddr, server_name: &str) -> bool {
+        self.acceptors.get(listen_addr)
+            .is_some_and(|(_server_config, cert_resolver)| lookup(&cert_resolver.certified_keys.read(), server_name).is_some())
+    }
+
+    pub fn log_unknown_sni_rejection(&self, server_name: Option<&str>) {
+        log_unknown_sni_rejection(&self.unknown_sni_rejections, server_name);
+    }
+
+    /// Appends the certificate expiries and rejected handshakes in the Prometheus text format.
+    pub fn render_metrics(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "# HELP oauth_gateway_cert_expiry_seconds Unix time at which each certificate expires.")?;
+        writeln!(out, "# TYPE oauth_gateway_cert_expiry_seconds gauge")?;
+
+        for expiry in &self.expiries {
+            writeln!(
+                out,
+                "oauth_gateway_cert_expiry_seconds{{server={:?}}} {}",
+                expiry.server_name,
+                expiry.not_after.timestamp(),
+            )?;
+        }
+
+        writeln!(out, "# HELP oauth_gateway_unknown_sni_rejections_total TLS handshakes rejected for an unknown server name.")?;
+        writeln!(out, "# TYPE oauth_gateway_unknown_sni_rejections_total counter")?;
+        writeln!(out, "oauth_gateway_unknown_sni_rejections_total {}", self.unknown_sni_rejections.load(Ordering::Relaxed))?;
 
         Ok(())
     }
+}
 
-    pub fn acceptor(&self, listen_addr: &SocketAddr) -> Option<TlsAcceptor> {
-        let (tls_acceptor, _cert_resolver) = self.acceptors.get(listen_addr)?;
+/// Scanners send lots of unknown names, so only every power of two rejection is logged.
+fn log_unknown_sni_rejection(rejections: &AtomicU64, server_name: Option<&str>) {
+    let rejections = rejections.fetch_add(1, Ordering::Relaxed) + 1;
 
-        Some(tls_acceptor.clone())
+    if rejections.is_power_of_two() {
+        eprintln!("Rejected TLS client with unknown SNI {:?} ({} rejections so far)", server_name, rejections);
     }
 }
 
+struct CertExpiry {
+    server_name: String,
+    not_after: DateTime<Utc>,
+}
+
 struct CertResolver {
     certified_keys: RwLock<HashMap<Ascii<Cow<'static, str>>, Arc<CertifiedKey>>>,
+    default_certified_key: RwLock<Option<Arc<CertifiedKey>>>,
+    /// Fail handshakes instead of serving the default certificate.
+    reject_unknown: bool,
+    unknown_sni_rejections: Arc<AtomicU64>,
 }
 
 impl CertResolver {
-    pub fn new() -> Self {
+    pub fn new(
+        default_certified_key: Option<Arc<CertifiedKey>>,
+        reject_unknown: bool,
+        unknown_sni_rejections: Arc<AtomicU64>,
+    ) -> Self {
         Self {
             certified_keys: <_>::default(),
+            default_certified_key: RwLock::new(default_certified_key),
+            reject_unknown,
+            unknown_sni_rejections,
         }
     }
 
     pub fn add_certified_key(&self,
         server_name: String,
-        certified_key: CertifiedKey,
+        certified_key: Arc<CertifiedKey>,
+        check_name: bool,
     ) -> Result<()> {
-        DnsNameRef::try_from_ascii_str(&server_name)
+        // Wildcards aren't valid DNS names, so a wildcard server is checked with an example name it covers
+        let example_name = match server_name.strip_prefix("*.") {
+            Some(domain) => format!("wildcard.{}", domain),
+            None => server_name.clone(),
+        };
+        let dns_name = DnsNameRef::try_from_ascii_str(&example_name)
             .map_err(|_| anyhow!("Bad DNS name: {:?}", server_name))?;
 
+        if check_name {
+            let end_entity_cert = certified_key.end_entity_cert()
+                .map_err(|_| anyhow!("No certificate for {:?}", server_name))?;
+            let end_entity_cert = EndEntityCert::try_from(end_entity_cert.0.as_slice())
+                .map_err(|err| anyhow!("Invalid certificate for {:?}: {:?}", server_name, err))?;
+
+            end_entity_cert.verify_is_valid_for_dns_name(dns_name)
+                .map_err(|err| anyhow!(
+                    "Certificate is not valid for {:?} ({:?}). \
+                    Set `skip_cert_name_check = true` to load it anyway.",
+                    server_name,
+                    err,
+                ))?;
+        }
+
+        let dns_names = match certified_key.end_entity_cert() {
+            Ok(cert) => crate::x509::dns_names(&cert.0).unwrap_or_else(|err| {
+                eprintln!("Failed to read DNS names of certificate for {:?}: {:#}", server_name, err);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
         let server_name = Ascii::new(Cow::Owned(server_name));
-        let certified_key = Arc::new(certified_key);
+        let mut certified_keys = self.certified_keys.write();
 
-        // TODO: implement cross check
-        // certified_key.cross_check_end_entity_cert(Some(checked_name))?;
+        // Names from the certificate never replace explicitly configured ones
+        for dns_name in dns_names {
+            certified_keys.entry(Ascii::new(Cow::Owned(dns_name)))
+                .or_insert_with(|| certified_key.clone());
+        }
 
-        self.certified_keys.write().insert(server_name, certified_key);
+        certified_keys.insert(server_name, certified_key);
 
         Ok(())
     }
 }
 
+/// Looks up `server_name`, falling back to a wildcard entry for its parent domain.
+/// Wildcards only cover a single label, so `*.example.com` doesn't match `a.b.example.com`.
+fn lookup<'a, T>(map: &'a HashMap<Ascii<Cow<'static, str>>, T>, server_name: &'a str) -> Option<&'a T> {
+    if let Some(value) = map.get(&Ascii::new(Cow::Borrowed(server_name))) {
+        return Some(value);
+    }
+
+    let (_, parent) = server_name.split_once('.')?;
+    let wildcard = format!("*.{}", parent);
+
+    map.get(&Ascii::new(Cow::Owned(wildcard)))
+}
+
 impl ResolvesServerCert for CertResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        let server_name = client_hello.server_name()?; 
-        let server_name = Ascii::new(Cow::Borrowed(server_name));
+        let server_name = client_hello.server_name();
+
+        let certified_key = server_name
+            .and_then(|server_name| lookup(&self.certified_keys.read(), server_name).map(Arc::clone));
+
+        if certified_key.is_some() {
+            return certified_key;
+        }
+
+        if self.reject_unknown {
+            log_unknown_sni_rejection(&self.unknown_sni_rejections, server_name);
+            return None;
+        }
+
+        let default_certified_key = self.default_certified_key.read().clone();
+
+        match (server_name, &default_certified_key) {
+            (Some(server_name), Some(_)) => eprintln!("No certchain found for {:?}, using default", server_name),
+            (Some(server_name), None) => eprintln!("No certchain found for {:?}", server_name),
+            (None, Some(_)) => eprintln!("No SNI sent, using default certchain"),
+            (None, None) => eprintln!("No SNI sent and no default certchain configured"),
+        }
+
+        default_certified_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::config::server::PemSource;
+
+    use super::*;
 
-        let certified_key = self.certified_keys.read().get(&server_name).map(Arc::clone);
+    fn names(names: &[&'static str]) -> HashMap<Ascii<Cow<'static, str>>, &'static str> {
+        names.iter()
+            .map(|name| (Ascii::new(Cow::Borrowed(*name)), *name))
+            .collect()
+    }
+
+    #[test]
+    fn exact_match_wins_over_wildcard() {
+        let map = names(&["*.example.com", "api.example.com"]);
+
+        assert_eq!(lookup(&map, "api.example.com"), Some(&"api.example.com"));
+        assert_eq!(lookup(&map, "API.example.com"), Some(&"api.example.com"));
+        assert_eq!(lookup(&map, "www.example.com"), Some(&"*.example.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_a_single_label() {
+        let map = names(&["*.example.com"]);
+
+        assert_eq!(lookup(&map, "a.example.com"), Some(&"*.example.com"));
+        assert_eq!(lookup(&map, "a.b.example.com"), None);
+        assert_eq!(lookup(&map, "example.com"), None);
+    }
 
-        if certified_key.is_none() {
-            eprintln!("No certchain found for {:?}", server_name.as_ref());
-            dbg!(self.certified_keys.read().keys().collect::<Vec<_>>());
+    fn tls_config(config: &str) -> config::Tls {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn tls13_only_accepts_tls13_cipher_suites() {
+        let config = tls_config(r#"
+            min_version = "1.3"
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384"]
+        "#);
+
+        assert!(TlsManager::new(&config).is_ok());
+
+        let config = tls_config(r#"
+            min_version = "1.3"
+            cipher_suites = ["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"]
+        "#);
+
+        assert!(TlsManager::new(&config).is_err());
+    }
+
+    #[test]
+    fn unknown_cipher_suites_and_kx_groups_are_rejected() {
+        assert!(TlsManager::new(&tls_config(r#"cipher_suites = ["TLS_RSA_WITH_RC4_128_MD5"]"#)).is_err());
+        assert!(TlsManager::new(&tls_config(r#"kx_groups = ["x448"]"#)).is_err());
+        assert!(TlsManager::new(&tls_config(r#"kx_groups = ["x25519", "secp384r1"]"#)).is_ok());
+    }
+
+    #[test]
+    fn aliases_share_the_certificate() {
+        let mut tls_manager = TlsManager::new(&tls_config("")).unwrap();
+        let certified_key = crate::load_certified_key(
+            PemSource::File(Path::new("testdata/localhost.cert.pem")),
+            PemSource::File(Path::new("testdata/localhost.key.pem")),
+        ).unwrap();
+        let listen_addr = "127.0.0.1:8443".parse().unwrap();
+        let server_names = ["localhost".into(), "alias.localhost".into()];
+        let certified_key = tls_manager.add_certified_key(&[listen_addr], &server_names, certified_key, false).unwrap();
+
+        let (_server_config, cert_resolver) = &tls_manager.acceptors[&listen_addr];
+        let certified_keys = cert_resolver.certified_keys.read();
+
+        for server_name in &server_names {
+            let resolved = lookup(&*certified_keys, server_name).unwrap();
+
+            assert!(Arc::ptr_eq(resolved, &certified_key));
         }
+    }
+
+    #[test]
+    fn only_configured_server_names_are_known() {
+        let mut tls_manager = TlsManager::new(&tls_config(r#"unknown_sni = "close""#)).unwrap();
+        let certified_key = crate::load_certified_key(
+            PemSource::File(Path::new("testdata/localhost.cert.pem")),
+            PemSource::File(Path::new("testdata/localhost.key.pem")),
+        ).unwrap();
+        let listen_addr = "127.0.0.1:8443".parse().unwrap();
+        let other_addr = "127.0.0.1:9443".parse().unwrap();
+        tls_manager.add_certified_key(&[listen_addr], &["localhost".into()], certified_key, false).unwrap();
+
+        assert!(tls_manager.knows_server_name(&listen_addr, "LOCALHOST"));
+        assert!(!tls_manager.knows_server_name(&listen_addr, "scanner.example"));
+        assert!(!tls_manager.knows_server_name(&other_addr, "localhost"));
+    }
+
+    #[test]
+    fn expiries_and_rejections_are_exported() {
+        let mut tls_manager = TlsManager::new(&tls_config("")).unwrap();
+        let certified_key = crate::load_certified_key(
+            PemSource::File(Path::new("testdata/localhost.cert.pem")),
+            PemSource::File(Path::new("testdata/localhost.key.pem")),
+        ).unwrap();
+        let listen_addr = "127.0.0.1:8443".parse().unwrap();
+        tls_manager.add_certified_key(&[listen_addr], &["localhost".into()], certified_key, false).unwrap();
+        tls_manager.log_unknown_sni_rejection(Some("scanner.example"));
+
+        let mut out = String::new();
+        tls_manager.render_metrics(&mut out).unwrap();
 
-        certified_key
+        assert!(out.contains("oauth_gateway_cert_expiry_seconds{server=\"localhost\"} "), "{}", out);
+        assert!(out.contains("oauth_gateway_unknown_sni_rejections_total 1\n"), "{}", out);
     }
 }
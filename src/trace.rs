@@ -0,0 +1,347 @@
+//! Distributed tracing: W3C trace context propagation and span export to an
+//! OpenTelemetry collector via OTLP/HTTP with JSON encoding.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, Context, bail};
+use hyper::HeaderMap;
+use hyper::header::{CONTENT_TYPE, HeaderValue};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde_json::{Value, json};
+use tokio::time::{self, Duration};
+
+use crate::config;
+use crate::header::{TRACEPARENT, TRACESTATE};
+
+/// Identifies a span across services. Travels with requests as an extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+    /// Vendor specific state of a trusted peer, passed on as is.
+    tracestate: Option<HeaderValue>,
+}
+
+impl SpanContext {
+    /// Reads the context of a trusted peer's `traceparent` and `tracestate`.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let mut context = Self::parse_traceparent(traceparent)?;
+
+        context.tracestate = headers.get(TRACESTATE).cloned();
+
+        Some(context)
+    }
+
+    /// Parses `00-<trace id>-<parent span id>-<flags>`.
+    fn parse_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parse_hex::<16>(parts.next()?)?;
+        let span_id = parse_hex::<8>(parts.next()?)?;
+        let [flags] = parse_hex::<1>(parts.next()?)?;
+
+        // Later versions may append fields, but must keep these
+        if version == "ff" || parse_hex::<1>(version).is_none() || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+            tracestate: None,
+        })
+    }
+
+    /// Sets `traceparent` and `tracestate` for the next hop.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        let traceparent = format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8,
+        );
+
+        headers.insert(TRACEPARENT, HeaderValue::from_str(&traceparent).unwrap());
+        headers.remove(TRACESTATE);
+
+        if let Some(tracestate) = &self.tracestate {
+            headers.insert(TRACESTATE, tracestate.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// Queued for export when dropped, if sampled.
+pub struct Span {
+    tracer: Tracer,
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+impl Span {
+    pub fn context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let attributes = self.attributes.iter()
+            .map(|(key, value)| json!({ "key": key, "value": otlp_value(value) }))
+            .collect::<Vec<_>>();
+
+        // STATUS_CODE_ERROR or STATUS_CODE_UNSET
+        let status_code = match self.error {
+            true => 2,
+            false => 0,
+        };
+
+        json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "parentSpanId": self.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(end).to_string(),
+            "attributes": attributes,
+            "status": { "code": status_code },
+        })
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if self.context.sampled {
+            let span = self.to_otlp(SystemTime::now());
+
+            self.tracer.queue(span);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tracer(Arc<Inner>);
+
+struct Inner {
+    config: config::Tracing,
+    queue: Mutex<Vec<Value>>,
+}
+
+impl Tracer {
+    pub fn new(config: &config::Tracing) -> Result<Self> {
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            bail!("`sample_rate` in `[tracing]` must be between 0 and 1");
+        }
+
+        if config.export_interval_ms == 0 {
+            bail!("`export_interval_ms` in `[tracing]` must be positive");
+        }
+
+        Ok(Self(Arc::new(Inner {
+            config: config.clone(),
+            queue: Mutex::new(Vec::new()),
+        })))
+    }
+
+    /// Starts a span, continuing the trace of `parent` if given.
+    /// New traces are sampled at `sample_rate`.
+    pub fn start(&self, name: &'static str, kind: SpanKind, parent: Option<&SpanContext>) -> Span {
+        let mut rng = rand::thread_rng();
+        let context = match parent {
+            Some(parent) => SpanContext {
+                span_id: rng.gen(),
+                ..parent.clone()
+            },
+            None => SpanContext {
+                trace_id: rng.gen(),
+                span_id: rng.gen(),
+                sampled: rng.gen_bool(self.0.config.sample_rate),
+                tracestate: None,
+            },
+        };
+
+        Span {
+            tracer: self.clone(),
+            context,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    fn queue(&self, span: Value) {
+        let mut queue = self.0.queue.lock();
+
+        if queue.len() < self.0.config.max_queued_spans {
+            queue.push(span);
+        }
+    }
+
+    /// Sends the queued spans to the collector every `export_interval_ms`.
+    pub async fn export_periodically(self, http: reqwest::Client) {
+        let mut interval = time::interval(Duration::from_millis(self.0.config.export_interval_ms));
+
+        loop {
+            interval.tick().await;
+
+            let spans = std::mem::take(&mut *self.0.queue.lock());
+
+            if spans.is_empty() {
+                continue;
+            }
+
+            if let Err(err) = self.export(&http, spans).await {
+                eprintln!("Failed to export spans: {:#}", err);
+            }
+        }
+    }
+
+    async fn export(&self, http: &reqwest::Client, spans: Vec<Value>) -> Result<()> {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": self.0.config.service_name } },
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        });
+
+        let response = http.post(&self.0.config.endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .context("collector is unreachable")?;
+
+        if !response.status().is_success() {
+            bail!("collector responded with {}", response.status());
+        }
+
+        Ok(())
+    }
+}
+
+fn otlp_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(value) if value.is_i64() || value.is_u64() => json!({ "intValue": value.to_string() }),
+        Value::Number(value) => json!({ "doubleValue": value }),
+        Value::String(value) => json!({ "stringValue": value }),
+        value => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+
+    hex
+}
+
+/// Parses exactly `N` bytes of lowercase hex.
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT_VALUE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent_round_trips() {
+        let context = SpanContext::parse_traceparent(TRACEPARENT_VALUE).unwrap();
+        let mut headers = HeaderMap::new();
+
+        assert!(context.sampled);
+
+        context.inject(&mut headers);
+        assert_eq!(headers[TRACEPARENT], TRACEPARENT_VALUE);
+    }
+
+    #[test]
+    fn invalid_traceparents_are_ignored() {
+        assert_eq!(SpanContext::parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(SpanContext::parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(SpanContext::parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(SpanContext::parse_traceparent(&format!("{}-extra", TRACEPARENT_VALUE)), None);
+    }
+
+    #[test]
+    fn children_continue_the_trace() {
+        let tracer = Tracer::new(&toml::from_str(r#"
+            endpoint = "http://127.0.0.1:1/v1/traces"
+            sample_rate = 0.0
+        "#).unwrap()).unwrap();
+        let parent = SpanContext::parse_traceparent(TRACEPARENT_VALUE).unwrap();
+
+        let span = tracer.start("request", SpanKind::Server, Some(&parent));
+        let child = tracer.start("upstream", SpanKind::Client, Some(span.context()));
+
+        assert_eq!(child.context().trace_id, parent.trace_id);
+        assert_eq!(child.parent_span_id, Some(span.context().span_id));
+        // The parent's decision wins over `sample_rate`
+        assert!(child.context().sampled);
+
+        drop(child);
+        drop(span);
+        assert_eq!(tracer.0.queue.lock().len(), 2);
+
+        assert!(!tracer.start("request", SpanKind::Server, None).context().sampled);
+    }
+}
@@ -0,0 +1,83 @@
+//! Connects the upstream http client to a Unix socket, whatever authority the request names.
+
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::{BoxFuture, FutureExt};
+use hyper::Uri;
+use hyper::client::connect::{Connected, Connection};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::time::{self, Duration};
+
+#[derive(Clone)]
+pub struct UnixConnector {
+    path: Arc<PathBuf>,
+    connect_timeout: Option<Duration>,
+}
+
+impl UnixConnector {
+    pub fn new(path: PathBuf, connect_timeout: Option<Duration>) -> Self {
+        Self {
+            path: Arc::new(path),
+            connect_timeout,
+        }
+    }
+}
+
+impl tower::Service<Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<UnixConnection>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let path = self.path.clone();
+        let connect_timeout = self.connect_timeout;
+
+        async move {
+            let connect = UnixStream::connect(&*path);
+            let stream = match connect_timeout {
+                Some(connect_timeout) => time::timeout(connect_timeout, connect).await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("connecting to {:?} timed out", path)))??,
+                None => connect.await?,
+            };
+
+            Ok(UnixConnection(stream))
+        }.boxed()
+    }
+}
+
+pub struct UnixConnection(UnixStream);
+
+impl Connection for UnixConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for UnixConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::SystemTime;
+use std::convert::TryFrom;
+
+use anyhow::{Result, Context, Error, bail};
+use futures::future::{BoxFuture, FutureExt};
+use hyper::client::HttpConnector;
+use hyper::client::connect::Connect;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::http::uri::Authority;
+use hyper::{Body, HeaderMap, Request, Response};
+use hyper::header::CONTENT_TYPE;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use reqwest::Certificate;
+use reqwest::redirect::Policy;
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio::net;
+use tokio::time::{self, Duration};
+use tower::Service;
+
+use crate::config::{self, Server};
+use crate::config::server::Upstream;
+#[cfg(unix)]
+use crate::unix_connector::UnixConnector;
+
+const CONNECT_HINT: &str = " (if this happens under load, the upstream may be refusing connections: \
+    consider raising its connection limit or `pool_max_idle_per_host` in `[http]` \
+    to reuse more connections)";
+
+#[derive(Clone)]
+pub enum UpstreamClient {
+    /// Drops request and response trailers, reqwest doesn't expose them.
+    Reqwest(reqwest::Client),
+    /// Speaks HTTP/2 only and passes bodies through untouched, preserving trailers.
+    Http2(hyper::Client<HttpsConnector<HttpConnector<Resolver>>>),
+    /// Plain HTTP to a Unix socket. Passes bodies through like `Http2`.
+    #[cfg(unix)]
+    Unix(hyper::Client<UnixConnector>),
+}
+
+impl UpstreamClient {
+    /// Sends `request` upstream. If `timeout` is given, it covers the whole exchange
+    /// including streaming the response body, except for HTTP/2 and Unix socket upstreams where it
+    /// only covers the response headers, since bodies are passed through as-is to keep trailers.
+    pub async fn send(&self, request: Request<Body>, timeout: Option<Duration>) -> Result<Response<Body>> {
+        match self {
+            Self::Reqwest(client) => {
+                let mut request = reqwest::Request::try_from(request)
+                    .context("failed to convert request")?;
+
+                *request.timeout_mut() = timeout;
+
+                let mut response = client.execute(request).await
+                    .map_err(|err| {
+                        let hint = if err.is_connect() { CONNECT_HINT } else { "" };
+
+                        Error::new(err).context(format!("upstream request failed{}", hint))
+                    })?;
+
+                let mut builder = Response::builder()
+                    // loses status line text
+                    .status(response.status());
+
+                *builder.headers_mut().context("failed to get builder headers")? = std::mem::take(response.headers_mut());
+
+                // Trailers are dropped here, see `upstream_http2`
+                let body = Body::wrap_stream(response.bytes_stream());
+
+                builder.body(body).context("failed to set response body")
+            },
+            Self::Http2(client) => send_hyper(client, request, timeout).await,
+            #[cfg(unix)]
+            Self::Unix(client) => send_hyper(client, request, timeout).await,
+        }
+    }
+}
+
+async fn send_hyper<C>(client: &hyper::Client<C>, request: Request<Body>, timeout: Option<Duration>) -> Result<Response<Body>>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let response = client.request(request);
+    let response = match timeout {
+        Some(timeout) => time::timeout(timeout, response).await
+            .context("upstream request timed out")?,
+        None => response.await,
+    };
+    let response = response.map_err(|err| {
+        let hint = if err.is_connect() { CONNECT_HINT } else { "" };
+
+        Error::new(err).context(format!("upstream request failed{}", hint))
+    })?;
+
+    Ok(response)
+}
+
+/// The clients used for the upstreams of a single server.
+#[derive(Clone)]
+pub struct UpstreamClients {
+    pub default: UpstreamClient,
+    /// Always speaks HTTP/2, since gRPC relies on it and on trailers end-to-end.
+    pub grpc: UpstreamClient,
+    /// The clients of `unix:` upstreams, by socket path.
+    pub unix: HashMap<PathBuf, UpstreamClients>,
+    /// The clients of upstreams requested under `upstream_sni`, by upstream authority.
+    pub sni: HashMap<Authority, UpstreamClients>,
+}
+
+impl UpstreamClients {
+    /// Unix socket upstreams and upstreams with `upstream_sni` have dedicated clients.
+    pub fn for_upstream(&self, upstream: &Upstream) -> &UpstreamClients {
+        match &upstream.unix_socket {
+            // Built for every configured upstream, and debug upstreams can't be sockets
+            Some(unix_socket) => &self.unix[unix_socket],
+            // Debug upstreams have none and use the server's own
+            None => self.sni.get(&upstream.authority).unwrap_or(self),
+        }
+    }
+
+    pub fn for_request(&self, request: &Request<Body>) -> &UpstreamClient {
+        match is_grpc(request.headers()) {
+            true => &self.grpc,
+            false => &self.default,
+        }
+    }
+}
+
+/// Matches `application/grpc` and its variants like `application/grpc+proto`.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) {
+        Some(content_type) => content_type.to_ascii_lowercase(),
+        None => return false,
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+    essence == "application/grpc" || essence.starts_with("application/grpc+")
+}
+
+/// Everything that requires a dedicated http client.
+/// Servers with equal settings share a client (and its connection pool).
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+struct ClientSettings {
+    http2: bool,
+    follow_redirects: bool,
+    ca_bundle: Option<PathBuf>,
+    ca_only: bool,
+    tls_insecure: bool,
+    connect_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    unix_socket: Option<PathBuf>,
+    /// Connects to these addresses whatever host is requested, for `upstream_sni`.
+    resolve: Option<(String, Vec<SocketAddr>)>,
+}
+
+impl ClientSettings {
+    fn new(http: &config::Http, server: &Server) -> Self {
+        Self {
+            http2: server.upstream_http2,
+            follow_redirects: server.upstream_follow_redirects,
+            ca_bundle: server.upstream_ca_bundle.clone(),
+            ca_only: server.upstream_ca_only,
+            tls_insecure: server.upstream_tls_insecure,
+            connect_timeout: server.connect_timeout_ms.map(Duration::from_millis),
+            pool_max_idle_per_host: http.pool_max_idle_per_host,
+            pool_idle_timeout: http.pool_idle_timeout_secs.map(Duration::from_secs),
+            unix_socket: None,
+            resolve: None,
+        }
+    }
+
+    fn build(&self) -> Result<UpstreamClient> {
+        if self.http2 && self.follow_redirects {
+            bail!("`upstream_follow_redirects` is not supported with `upstream_http2`");
+        }
+
+        if self.ca_only && self.ca_bundle.is_none() {
+            bail!("`upstream_ca_only` requires `upstream_ca_bundle`");
+        }
+
+        if let Some(unix_socket) = &self.unix_socket {
+            if self.follow_redirects {
+                bail!("`upstream_follow_redirects` is not supported with Unix socket upstreams");
+            }
+
+            return self.build_unix(unix_socket);
+        }
+
+        match self.http2 {
+            true => self.build_http2().map(UpstreamClient::Http2),
+            false => self.build_reqwest().map(UpstreamClient::Reqwest),
+        }
+    }
+
+    fn build_reqwest(&self) -> Result<reqwest::Client> {
+        // Redirects are passed through to the client unless explicitly requested
+        let redirect_policy = match self.follow_redirects {
+            true => Policy::default(),
+            false => Policy::none(),
+        };
+        let mut builder = reqwest::Client::builder()
+            .redirect(redirect_policy)
+            .danger_accept_invalid_certs(self.tls_insecure)
+            .tls_built_in_root_certs(!self.ca_only);
+
+        // Every certificate of the bundle, `Certificate::from_pem` would only read the first
+        for cert in self.ca_bundle_certs()? {
+            let cert = Certificate::from_der(&cert)
+                .context("Failed to parse CA bundle")?;
+
+            builder = builder.add_root_certificate(cert);
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some((host, addrs)) = &self.resolve {
+            builder = builder.resolve_to_addrs(host, addrs);
+        }
+
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(pool_idle_timeout);
+        }
+
+        builder.build().context("Failed to build upstream http client")
+    }
+
+    fn build_http2(&self) -> Result<hyper::Client<HttpsConnector<HttpConnector<Resolver>>>> {
+        let resolver = match &self.resolve {
+            Some((_, addrs)) => Resolver::Static(addrs.clone()),
+            None => Resolver::Gai(GaiResolver::new()),
+        };
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connect_timeout);
+
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(self.rustls_config()?)
+            .https_or_http()
+            .enable_http2()
+            .wrap_connector(http);
+
+        Ok(self.hyper_builder().build(connector))
+    }
+
+    #[cfg(unix)]
+    fn build_unix(&self, unix_socket: &Path) -> Result<UpstreamClient> {
+        let connector = UnixConnector::new(unix_socket.to_owned(), self.connect_timeout);
+
+        Ok(UpstreamClient::Unix(self.hyper_builder().build(connector)))
+    }
+
+    #[cfg(not(unix))]
+    fn build_unix(&self, _unix_socket: &Path) -> Result<UpstreamClient> {
+        bail!("Unix socket upstreams are only supported on Unix");
+    }
+
+    fn hyper_builder(&self) -> hyper::client::Builder {
+        let mut builder = hyper::Client::builder();
+        builder.http2_only(self.http2);
+
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            builder.pool_idle_timeout(pool_idle_timeout);
+        }
+
+        builder
+    }
+
+    fn rustls_config(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+
+        if !self.ca_only {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+
+        let (_added, ignored) = roots.add_parsable_certificates(&self.ca_bundle_certs()?);
+
+        if ignored > 0 {
+            bail!("{} certificates in CA bundle {:?} are invalid", ignored, self.ca_bundle);
+        }
+
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        if self.tls_insecure {
+            config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+        }
+
+        Ok(config)
+    }
+
+    /// The DER encoded certificates of `ca_bundle`, if any.
+    fn ca_bundle_certs(&self) -> Result<Vec<Vec<u8>>> {
+        let ca_bundle = match &self.ca_bundle {
+            Some(ca_bundle) => ca_bundle,
+            None => return Ok(Vec::new()),
+        };
+
+        let file = File::open(ca_bundle)
+            .with_context(|| format!("Failed to open CA bundle {:?}", ca_bundle))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .with_context(|| format!("Failed to parse CA bundle {:?}", ca_bundle))?;
+
+        if certs.is_empty() {
+            bail!("CA bundle {:?} contains no certificates", ca_bundle);
+        }
+
+        Ok(certs)
+    }
+}
+
+/// Resolves host names for HTTP/2 clients, or always to the same addresses for `upstream_sni`.
+#[derive(Clone)]
+pub enum Resolver {
+    Gai(GaiResolver),
+    Static(Vec<SocketAddr>),
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Self::Gai(resolver) => resolver.poll_ready(cx),
+            Self::Static(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        match self {
+            Self::Gai(resolver) => resolver.call(name)
+                .map(|addrs| Ok(addrs?.collect::<Vec<_>>().into_iter()))
+                .boxed(),
+            Self::Static(addrs) => futures::future::ready(Ok(addrs.clone().into_iter())).boxed(),
+        }
+    }
+}
+
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Builds the clients of each server, in the same order as `servers`.
+pub async fn build_clients(http: &config::Http, servers: &[Server]) -> Result<Vec<UpstreamClients>> {
+    let sni_addrs = resolve_sni_upstreams(servers).await?;
+    let mut clients = HashMap::<ClientSettings, UpstreamClient>::new();
+    let mut get_or_build = |settings: ClientSettings, server: &Server| -> Result<UpstreamClient> {
+        if let Some(client) = clients.get(&settings) {
+            return Ok(client.clone());
+        }
+
+        let client = settings.build()
+            .with_context(|| format!("Failed to build http client for {}", server.name))?;
+
+        clients.insert(settings, client.clone());
+
+        Ok(client)
+    };
+
+    servers.iter()
+        .map(|server| {
+            let settings = ClientSettings::new(http, server);
+
+            if settings.tls_insecure {
+                eprintln!(
+                    "WARNING: TLS certificate verification is DISABLED for the upstream of {}. \
+                    Connections to it can be intercepted. Never use `upstream_tls_insecure` in production!",
+                    server.name,
+                );
+            }
+
+            let unix_sockets = server.upstream.iter()
+                .filter_map(|upstream| upstream.unix_socket.clone())
+                .collect::<Vec<_>>();
+
+            if server.upstream_tls && !unix_sockets.is_empty() {
+                bail!("TLS is not supported for the Unix socket upstreams of {}", server.name);
+            }
+
+            if server.upstream_sni.is_some() && !server.upstream.iter().all(|upstream| upstream.is_tls(server)) {
+                bail!("`upstream_sni` of {} requires TLS to all upstreams", server.name);
+            }
+
+            let mut build = |settings: &ClientSettings| -> Result<UpstreamClients> {
+                let grpc_settings = ClientSettings {
+                    http2: true,
+                    follow_redirects: false,
+                    ..settings.clone()
+                };
+
+                Ok(UpstreamClients {
+                    default: get_or_build(settings.clone(), server)?,
+                    grpc: get_or_build(grpc_settings, server)?,
+                    unix: HashMap::new(),
+                    sni: HashMap::new(),
+                })
+            };
+
+            let mut clients = build(&settings)?;
+
+            for unix_socket in unix_sockets {
+                let unix_settings = ClientSettings {
+                    unix_socket: Some(unix_socket.clone()),
+                    ..settings.clone()
+                };
+
+                clients.unix.insert(unix_socket, build(&unix_settings)?);
+            }
+
+            if let Some(upstream_sni) = &server.upstream_sni {
+                for upstream in &server.upstream {
+                    let sni_settings = ClientSettings {
+                        resolve: Some((upstream_sni.clone(), sni_addrs[&upstream.authority].clone())),
+                        ..settings.clone()
+                    };
+
+                    clients.sni.insert(upstream.authority.clone(), build(&sni_settings)?);
+                }
+            }
+
+            Ok(clients)
+        })
+        .collect()
+}
+
+/// Resolves the upstreams of servers with `upstream_sni` once,
+/// their clients only connect to the addresses given up front.
+async fn resolve_sni_upstreams(servers: &[Server]) -> Result<HashMap<Authority, Vec<SocketAddr>>> {
+    let mut sni_addrs = HashMap::new();
+
+    for server in servers.iter().filter(|server| server.upstream_sni.is_some()) {
+        // Others are rejected by `build_clients`
+        for upstream in server.upstream.iter().filter(|upstream| upstream.is_tls(server)) {
+            if sni_addrs.contains_key(&upstream.authority) {
+                continue;
+            }
+
+            let host = upstream.authority.host();
+            let port = upstream.authority.port_u16().unwrap_or(443);
+            let addrs = net::lookup_host((host, port)).await
+                .with_context(|| format!("Failed to resolve upstream {} of {}", upstream.authority, server.name))?
+                .collect::<Vec<_>>();
+
+            if addrs.is_empty() {
+                bail!("Upstream {} of {} resolved to no addresses", upstream.authority, server.name);
+            }
+
+            sni_addrs.insert(upstream.authority.clone(), addrs);
+        }
+    }
+
+    Ok(sni_addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn build(server_config: &str) -> Result<Vec<UpstreamClients>> {
+        let server = toml::from_str::<Server>(&format!(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = "https://backend.internal"
+            {}
+        "#, server_config)).unwrap();
+
+        build_clients(&config::Http::default(), &[server]).await
+    }
+
+    #[tokio::test]
+    async fn upstream_sni_gets_a_client_per_upstream() {
+        let server = |upstream: &str| toml::from_str::<Server>(&format!(r#"
+            name = "example.org"
+            listen = "127.0.0.1:8080"
+            upstream = {}
+            upstream_sni = "service.internal"
+        "#, upstream)).unwrap();
+
+        let tls = server(r#"["https://10.0.0.1:8443", "https://10.0.0.2:8443"]"#);
+        let clients = build_clients(&config::Http::default(), &[tls.clone()]).await.unwrap();
+
+        assert_eq!(clients[0].sni.len(), 2);
+        assert_eq!(tls.request_authority(&tls.upstream[1]).unwrap(), "service.internal:8443");
+
+        assert!(build_clients(&config::Http::default(), &[server("'http://10.0.0.1'")]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ca_only_requires_a_ca_bundle() {
+        assert!(build("upstream_ca_only = true").await.is_err());
+        assert!(build("upstream_ca_only = true\nupstream_ca_bundle = 'testdata/localhost.cert.pem'").await.is_ok());
+        assert!(build("upstream_ca_bundle = 'testdata/discovery.json'").await.is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Picks which of a server's upstreams receives the next request.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::config::server::{Balance, Upstream};
+use crate::counter::{Counter, OwnedCounterGuard};
+
+pub trait UpstreamSelector: Send + Sync {
+    /// Returns the index of the upstream to use.
+    /// The selection must be kept alive until the upstream response body is done.
+    fn select(&self) -> Selection;
+
+    /// Uses the upstream at `index`, bypassing balancing.
+    fn pin(&self, index: usize) -> Selection {
+        Selection::new(index)
+    }
+}
+
+pub struct Selection {
+    pub index: usize,
+    _guard: Option<OwnedCounterGuard>,
+}
+
+impl Selection {
+    fn new(index: usize) -> Self {
+        Self {
+            index,
+            _guard: None,
+        }
+    }
+}
+
+/// Balancing leaves out the upstream at `excluded`, which is only used when pinned.
+pub fn build(balance: Balance, upstreams: &[Upstream], excluded: Option<usize>) -> Box<dyn UpstreamSelector> {
+    if let Some(excluded) = excluded {
+        let indices = (0..upstreams.len())
+            .filter(|&index| index != excluded)
+            .collect::<Vec<_>>();
+        let upstreams = indices.iter()
+            .map(|&index| upstreams[index].clone())
+            .collect::<Vec<_>>();
+
+        return Box::new(Subset {
+            inner: build(balance, &upstreams, None),
+            indices,
+        })
+    }
+
+    match balance {
+        Balance::RoundRobin => Box::new(RoundRobin::new(upstreams.len())),
+        Balance::Weighted => Box::new(Weighted::new(upstreams)),
+        Balance::LeastConnections => Box::new(LeastConnections::new(upstreams.len())),
+    }
+}
+
+/// Balances across some of the upstreams, e.g. all but a canary.
+pub struct Subset {
+    inner: Box<dyn UpstreamSelector>,
+    /// The upstream index of each index of `inner`.
+    indices: Vec<usize>,
+}
+
+impl UpstreamSelector for Subset {
+    fn select(&self) -> Selection {
+        let mut selection = self.inner.select();
+        selection.index = self.indices[selection.index];
+
+        selection
+    }
+
+    fn pin(&self, index: usize) -> Selection {
+        match self.indices.iter().position(|&candidate| candidate == index) {
+            Some(inner_index) => Selection {
+                index,
+                ..self.inner.pin(inner_index)
+            },
+            None => Selection::new(index),
+        }
+    }
+}
+
+pub struct RoundRobin {
+    next: AtomicUsize,
+    len: usize,
+}
+
+impl RoundRobin {
+    pub fn new(len: usize) -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            len,
+        }
+    }
+}
+
+impl UpstreamSelector for RoundRobin {
+    fn select(&self) -> Selection {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.len;
+
+        Selection::new(index)
+    }
+}
+
+/// Smooth weighted round-robin, i.e. `a = 2, b = 1` yields `a, b, a` instead of `a, a, b`.
+pub struct Weighted {
+    weights: Vec<i64>,
+    total: i64,
+    /// Each pick adds the weights and takes the total from the highest, which is picked.
+    current: Mutex<Vec<i64>>,
+}
+
+impl Weighted {
+    pub fn new(upstreams: &[Upstream]) -> Self {
+        let weights = upstreams.iter()
+            .map(|upstream| upstream.weight as i64)
+            .collect::<Vec<_>>();
+
+        Self {
+            total: weights.iter().sum(),
+            current: Mutex::new(vec![0; weights.len()]),
+            weights,
+        }
+    }
+}
+
+impl UpstreamSelector for Weighted {
+    fn select(&self) -> Selection {
+        let mut current = self.current.lock();
+
+        for (current, weight) in current.iter_mut().zip(&self.weights) {
+            *current += weight;
+        }
+
+        let (index, _) = current.iter()
+            .enumerate()
+            .max_by_key(|&(index, current)| (*current, std::cmp::Reverse(index)))
+            .expect("weighted upstreams are not empty");
+
+        current[index] -= self.total;
+
+        Selection::new(index)
+    }
+}
+
+pub struct LeastConnections {
+    in_flight: Vec<Arc<Counter>>,
+}
+
+impl LeastConnections {
+    pub fn new(len: usize) -> Self {
+        Self {
+            in_flight: (0..len).map(|_| Arc::new(Counter::new())).collect(),
+        }
+    }
+}
+
+impl UpstreamSelector for LeastConnections {
+    fn select(&self) -> Selection {
+        let (index, counter) = self.in_flight.iter()
+            .enumerate()
+            .min_by_key(|(_, counter)| counter.get())
+            .expect("upstreams are not empty");
+
+        Selection {
+            index,
+            _guard: Some(counter.enter_owned()),
+        }
+    }
+
+    fn pin(&self, index: usize) -> Selection {
+        Selection {
+            index,
+            _guard: Some(self.in_flight[index].enter_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::http::uri::Authority;
+
+    use super::*;
+
+    fn upstream(weight: u32) -> Upstream {
+        Upstream {
+            authority: Authority::from_static("localhost"),
+            tls: None,
+            unix_socket: None,
+            base_path: String::new(),
+            weight,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn weighted_spreads_picks_by_weight() {
+        let selector = Weighted::new(&[upstream(4), upstream(2), upstream(2)]);
+        let picks = (0..8).map(|_| selector.select().index).collect::<Vec<_>>();
+
+        assert_eq!(picks, [0, 1, 2, 0, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn weighted_handles_large_weights() {
+        let selector = Weighted::new(&[upstream(1000), upstream(999)]);
+        let picks = (0..1999).map(|_| selector.select().index).collect::<Vec<_>>();
+
+        assert_eq!(&picks[..4], [0, 1, 0, 1]);
+        assert_eq!(picks.iter().filter(|&&index| index == 0).count(), 1000);
+    }
+
+    #[test]
+    fn excluded_upstreams_are_only_pinned() {
+        let selector = build(Balance::RoundRobin, &[upstream(1), upstream(1), upstream(1)], Some(1));
+        let picks = (0..4).map(|_| selector.select().index).collect::<Vec<_>>();
+
+        assert_eq!(picks, [0, 2, 0, 2]);
+        assert_eq!(selector.pin(1).index, 1);
+        assert_eq!(selector.pin(2).index, 2);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_upstreams() {
+        let selector = LeastConnections::new(2);
+        let first = selector.select();
+        let second = selector.select();
+
+        assert_ne!(first.index, second.index);
+
+        drop(first);
+        let first_index = selector.in_flight.iter().position(|counter| counter.get() == 0).unwrap();
+
+        assert_eq!(selector.select().index, first_index);
+    }
+}
@@ -0,0 +1,109 @@
+//! A debug endpoint answering with the gateway's view of the caller's token
+//! and how it would treat a route, without proxying anything.
+//!
+//! `GET <whoami_path>?method=POST&path=/items` reports on `POST /items`, both default to `GET /`.
+//! The token itself is never echoed.
+
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, HeaderValue};
+use hyper::{Body, Method, Response, Uri};
+use oauth2::TokenIntrospectionResponse;
+use oauth2::url::form_urlencoded;
+use serde_json::json;
+
+use crate::auth::IntrospectionResult;
+use crate::config::Server;
+use crate::config::server::RouteAuth;
+
+/// The caller's identity as extracted from a verified token.
+pub struct Identity<'a> {
+    pub token_info: &'a IntrospectionResult,
+    pub roles: Vec<&'a str>,
+    pub groups: Vec<&'a str>,
+}
+
+/// The route to report on, taken from the query of the whoami request.
+pub fn target_route(uri: &Uri) -> Result<(Method, Uri), String> {
+    let mut method = Method::GET;
+    let mut path = String::from("/");
+
+    for (key, value) in form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        match &*key {
+            "method" => method = Method::from_bytes(value.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("invalid method '{}'", value))?,
+            "path" => path = value.into_owned(),
+            _ => {},
+        }
+    }
+
+    let path = path.parse::<Uri>()
+        .ok()
+        .filter(|path| path.scheme().is_none() && path.path().starts_with('/'))
+        .ok_or_else(|| format!("invalid path '{}'", path))?;
+
+    Ok((method, path))
+}
+
+/// `verification` is the outcome of verifying the request's token,
+/// `Err` with a description if verification itself failed.
+pub fn response(
+    server: &Server,
+    method: &Method,
+    path: &Uri,
+    verification: Result<Option<Identity<'_>>, String>,
+) -> Response<Body> {
+    let route_auth = server.route_auth(method, path);
+    let authorization = server.authorization(method, path);
+    let identity = verification.as_ref().ok().and_then(Option::as_ref);
+
+    let allowed = match (route_auth, identity) {
+        (RouteAuth::Public | RouteAuth::Optional, _) => true,
+        (RouteAuth::Required, None) => false,
+        (RouteAuth::Required, Some(identity)) => authorization
+            .is_none_or(|authorization| authorization.allows(&identity.roles, &identity.groups)),
+    };
+
+    let route_auth = match route_auth {
+        RouteAuth::Public => "public",
+        RouteAuth::Optional => "optional",
+        RouteAuth::Required => "required",
+    };
+
+    let body = json!({
+        "authenticated": identity.is_some(),
+        "error": verification.as_ref().err(),
+        "subject": identity.and_then(|identity| identity.token_info.sub()),
+        "username": identity.and_then(|identity| identity.token_info.username()),
+        "claims": identity.map(|identity| identity.token_info),
+        "roles": identity.map(|identity| identity.roles.as_slice()).unwrap_or_default(),
+        "groups": identity.map(|identity| identity.groups.as_slice()).unwrap_or_default(),
+        "route": {
+            "method": method.as_str(),
+            "path": path.path(),
+            "auth": route_auth,
+            "has_authorization_rule": authorization.is_some(),
+            "allowed": allowed,
+        },
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .header(CACHE_CONTROL, HeaderValue::from_static("no-store"))
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_route_defaults_to_get_root() {
+        let (method, path) = target_route(&Uri::from_static("/whoami")).unwrap();
+        assert_eq!((method, path.path()), (Method::GET, "/"));
+
+        let (method, path) = target_route(&Uri::from_static("/whoami?method=delete&path=%2Fitems%2F1")).unwrap();
+        assert_eq!((method, path.path()), (Method::DELETE, "/items/1"));
+
+        assert!(target_route(&Uri::from_static("/whoami?path=http%3A%2F%2Fexample.org%2F")).is_err());
+    }
+}
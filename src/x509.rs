@@ -0,0 +1,326 @@
+//! Just enough DER parsing to read the validity, DNS names
+//! and OCSP related fields of a certificate.
+
+use anyhow::{Result, Context, bail, ensure};
+use chrono::{DateTime, TimeZone, Utc};
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_NULL: u8 = 0x05;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_ENUMERATED: u8 = 0x0a;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_EXPLICIT_VERSION: u8 = 0xa0;
+const TAG_EXPLICIT_EXTENSIONS: u8 = 0xa3;
+const TAG_DNS_NAME: u8 = 0x82;
+const TAG_URI: u8 = 0x86;
+
+/// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// 1.3.6.1.5.5.7.1.1
+const OID_AUTHORITY_INFO_ACCESS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+/// 1.3.6.1.5.5.7.48.1
+const OID_OCSP: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+
+/// Returns the `notAfter` time of a DER encoded X.509 certificate.
+pub fn not_after(cert: &[u8]) -> Result<DateTime<Utc>> {
+    let tbs_certificate = TbsCertificate::parse(cert)?;
+
+    let (_not_before, rest) = read_time(tbs_certificate.validity).context("Invalid notBefore")?;
+    let (not_after, _) = read_time(rest).context("Invalid notAfter")?;
+
+    Ok(not_after)
+}
+
+/// Returns the DNS names from the subjectAltName extension of a DER encoded X.509 certificate.
+pub fn dns_names(cert: &[u8]) -> Result<Vec<String>> {
+    let tbs_certificate = TbsCertificate::parse(cert)?;
+
+    let value = match tbs_certificate.extension(OID_SUBJECT_ALT_NAME)? {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+
+    let (mut general_names, _) = read(value, TAG_SEQUENCE).context("Invalid subjectAltName")?;
+    let mut dns_names = Vec::new();
+
+    while let Some(&tag) = general_names.first() {
+        let (name, rest) = read(general_names, tag)?;
+        general_names = rest;
+
+        if tag == TAG_DNS_NAME {
+            let name = std::str::from_utf8(name).context("DNS name is not ASCII")?;
+            dns_names.push(name.to_owned());
+        }
+    }
+
+    Ok(dns_names)
+}
+
+/// Returns the OCSP responder URL from the authorityInfoAccess extension.
+pub fn ocsp_responder_url(cert: &[u8]) -> Result<Option<String>> {
+    let tbs_certificate = TbsCertificate::parse(cert)?;
+
+    let value = match tbs_certificate.extension(OID_AUTHORITY_INFO_ACCESS)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let (mut descriptions, _) = read(value, TAG_SEQUENCE).context("Invalid authorityInfoAccess")?;
+
+    while !descriptions.is_empty() {
+        let (description, rest) = read(descriptions, TAG_SEQUENCE)?;
+        descriptions = rest;
+
+        let (method, location) = read(description, TAG_OID)?;
+
+        if method != OID_OCSP || location.first() != Some(&TAG_URI) {
+            continue;
+        }
+
+        let (url, _) = read(location, TAG_URI)?;
+        let url = std::str::from_utf8(url).context("OCSP responder URL is not ASCII")?;
+
+        return Ok(Some(url.to_owned()));
+    }
+
+    Ok(None)
+}
+
+pub struct TbsCertificate<'a> {
+    /// The complete serialNumber TLV
+    pub serial_number: &'a [u8],
+    /// The complete issuer TLV
+    pub issuer: &'a [u8],
+    validity: &'a [u8],
+    /// The contents of the subjectPublicKeyInfo SEQUENCE
+    pub subject_public_key_info: &'a [u8],
+    extensions: Option<&'a [u8]>,
+}
+
+impl<'a> TbsCertificate<'a> {
+    pub fn parse(cert: &'a [u8]) -> Result<Self> {
+        let (certificate, _) = read(cert, TAG_SEQUENCE).context("Invalid certificate")?;
+        let (tbs_certificate, _) = read(certificate, TAG_SEQUENCE).context("Invalid tbsCertificate")?;
+
+        let mut rest = tbs_certificate;
+
+        if rest.first() == Some(&TAG_EXPLICIT_VERSION) {
+            rest = skip(rest)?;
+        }
+
+        let (serial_number, rest) = read_raw(rest, TAG_INTEGER).context("Invalid serialNumber")?;
+        let rest = skip(rest).context("Invalid signature")?;
+        let (issuer, rest) = read_raw(rest, TAG_SEQUENCE).context("Invalid issuer")?;
+        let (validity, rest) = read(rest, TAG_SEQUENCE).context("Invalid validity")?;
+        let rest = skip(rest).context("Invalid subject")?;
+        let (subject_public_key_info, mut rest) = read(rest, TAG_SEQUENCE).context("Invalid subjectPublicKeyInfo")?;
+
+        // Skip the optional issuerUniqueID and subjectUniqueID
+        while let Some(&tag) = rest.first() {
+            if tag == TAG_EXPLICIT_EXTENSIONS {
+                break;
+            }
+
+            rest = skip(rest)?;
+        }
+
+        let extensions = match rest.is_empty() {
+            true => None,
+            false => {
+                let (extensions, _) = read(rest, TAG_EXPLICIT_EXTENSIONS).context("Invalid extensions")?;
+                let (extensions, _) = read(extensions, TAG_SEQUENCE).context("Invalid extensions")?;
+
+                Some(extensions)
+            },
+        };
+
+        Ok(Self {
+            serial_number,
+            issuer,
+            validity,
+            subject_public_key_info,
+            extensions,
+        })
+    }
+
+    /// Returns the subjectPublicKey without the BIT STRING header and unused bits byte.
+    pub fn subject_public_key(&self) -> Result<&'a [u8]> {
+        let rest = skip(self.subject_public_key_info).context("Invalid algorithm")?;
+        let (key, _) = read(rest, TAG_BIT_STRING).context("Invalid subjectPublicKey")?;
+
+        key.get(1..).context("Empty subjectPublicKey")
+    }
+
+    /// Returns the contents of the extnValue of the extension with the given OID.
+    fn extension(&self, oid: &[u8]) -> Result<Option<&'a [u8]>> {
+        let mut extensions = match self.extensions {
+            Some(extensions) => extensions,
+            None => return Ok(None),
+        };
+
+        while !extensions.is_empty() {
+            let (extension, rest) = read(extensions, TAG_SEQUENCE).context("Invalid extension")?;
+            extensions = rest;
+
+            let (extension_oid, extension) = read(extension, TAG_OID).context("Invalid extension id")?;
+
+            if extension_oid != oid {
+                continue;
+            }
+
+            let extension = match extension.first() {
+                Some(&TAG_BOOLEAN) => skip(extension)?,
+                _ => extension,
+            };
+            let (value, _) = read(extension, TAG_OCTET_STRING).context("Invalid extension value")?;
+
+            return Ok(Some(value));
+        }
+
+        Ok(None)
+    }
+}
+
+pub fn read_time(input: &[u8]) -> Result<(DateTime<Utc>, &[u8])> {
+    let tag = *input.first().context("Unexpected end of input")?;
+    let (contents, rest) = read(input, tag)?;
+    let contents = std::str::from_utf8(contents).context("Time is not ASCII")?;
+
+    let (year, contents) = match tag {
+        TAG_UTC_TIME => {
+            let year = parse_digits(contents, 0..2)? as i32;
+            let year = if year >= 50 { 1900 + year } else { 2000 + year };
+
+            (year, &contents[2..])
+        },
+        TAG_GENERALIZED_TIME => (parse_digits(contents, 0..4)? as i32, &contents[4..]),
+        _ => bail!("Unexpected tag {:#x} for time", tag),
+    };
+
+    ensure!(contents.len() == 11 && contents.ends_with('Z'), "Unsupported time format");
+
+    let time = Utc
+        .ymd_opt(year, parse_digits(contents, 0..2)?, parse_digits(contents, 2..4)?)
+        .single()
+        .and_then(|date| date.and_hms_opt(
+            parse_digits(contents, 4..6).ok()?,
+            parse_digits(contents, 6..8).ok()?,
+            parse_digits(contents, 8..10).ok()?,
+        ))
+        .context("Time out of range")?;
+
+    Ok((time, rest))
+}
+
+fn parse_digits(value: &str, range: std::ops::Range<usize>) -> Result<u32> {
+    let digits = value.get(range).context("Time too short")?;
+
+    ensure!(digits.bytes().all(|b| b.is_ascii_digit()), "Time contains non-digits");
+
+    Ok(digits.parse()?)
+}
+
+pub fn skip(input: &[u8]) -> Result<&[u8]> {
+    let tag = *input.first().context("Unexpected end of input")?;
+    let (_, rest) = read(input, tag)?;
+
+    Ok(rest)
+}
+
+/// Reads a TLV with the given tag and returns the complete TLV and the remaining input.
+pub fn read_raw(input: &[u8], expected_tag: u8) -> Result<(&[u8], &[u8])> {
+    let (contents, rest) = read(input, expected_tag)?;
+    let raw_len = input.len() - rest.len();
+
+    debug_assert!(raw_len >= contents.len());
+
+    Ok(input.split_at(raw_len))
+}
+
+/// Reads a TLV with the given tag and returns its contents and the remaining input.
+pub fn read(input: &[u8], expected_tag: u8) -> Result<(&[u8], &[u8])> {
+    let (&tag, input) = input.split_first().context("Unexpected end of input")?;
+
+    ensure!(tag == expected_tag, "Expected tag {:#x}, found {:#x}", expected_tag, tag);
+
+    let (&len, mut input) = input.split_first().context("Unexpected end of input")?;
+
+    let len = if len & 0x80 == 0 {
+        len as usize
+    } else {
+        let num_bytes = (len & 0x7f) as usize;
+
+        ensure!(num_bytes > 0 && num_bytes <= 4, "Unsupported length encoding");
+        ensure!(input.len() >= num_bytes, "Unexpected end of input");
+
+        let len = input[..num_bytes].iter().fold(0, |len, &b| (len << 8) | b as usize);
+        input = &input[num_bytes..];
+
+        len
+    };
+
+    ensure!(input.len() >= len, "Unexpected end of input");
+
+    Ok(input.split_at(len))
+}
+
+/// Encodes a TLV.
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let len = contents.len();
+    let mut der = vec![tag];
+
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let len_bytes = &len_bytes[len_bytes.iter().take_while(|&&b| b == 0).count()..];
+
+        der.push(0x80 | len_bytes.len() as u8);
+        der.extend_from_slice(len_bytes);
+    }
+
+    der.extend_from_slice(contents);
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Issued by a test CA for `localhost` and `www.localhost`, with an OCSP responder.
+    /// Valid from 2026 to 2126, so `notBefore` is a UTCTime and `notAfter` a GeneralizedTime.
+    const LEAF: &[u8] = include_bytes!("../testdata/ocsp/leaf.der");
+    const ISSUER: &[u8] = include_bytes!("../testdata/ocsp/issuer.der");
+
+    #[test]
+    fn fields_of_certificates_are_read() {
+        assert_eq!(not_after(LEAF).unwrap(), "2126-09-22T08:53:22Z".parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(dns_names(LEAF).unwrap(), ["localhost", "www.localhost"]);
+        assert_eq!(ocsp_responder_url(LEAF).unwrap().as_deref(), Some("http://ocsp.example.org"));
+
+        assert!(dns_names(ISSUER).unwrap().is_empty());
+        assert_eq!(ocsp_responder_url(ISSUER).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_certificates_are_rejected() {
+        assert!(TbsCertificate::parse(&LEAF[..LEAF.len() / 2]).is_err());
+        assert!(not_after(&[]).is_err());
+        assert!(read(&[TAG_SEQUENCE, 0x85, 1, 2, 3, 4, 5], TAG_SEQUENCE).is_err());
+    }
+
+    #[test]
+    fn encoded_lengths_are_read_back() {
+        for len in [0, 0x7f, 0x80, 0x1234] {
+            let contents = vec![7; len];
+            let der = encode(TAG_OCTET_STRING, &contents);
+
+            assert_eq!(read(&der, TAG_OCTET_STRING).unwrap(), (&contents[..], &[][..]));
+        }
+    }
+}